@@ -0,0 +1,102 @@
+//! Measures the per-token overhead of processing and sampling the logits, on synthetic logits.
+//!
+//! Run with `cargo bench --bench sampling`.
+//! With `--features candle`, the previous path (building candle tensors for each token and
+//! sampling with candle's sampler) is also measured, for comparison.
+
+use mamba_minimal_dfdx_example::LogitsProcessorWrapper;
+use rand::distributions::{Distribution, Uniform};
+use rand::SeedableRng;
+use std::time::{Duration, Instant};
+
+/// The (padded) vocab size of the state-spaces Mamba models.
+const VOCAB: usize = 50280;
+const TOKENS: usize = 500;
+const SEED: u64 = 299792458;
+const REPEAT_LAST_N: usize = 64;
+
+/// `(name, temp, top_p, repeat_penalty)`.
+const CASES: &[(&str, Option<f64>, Option<f64>, f32)] = &[
+    ("greedy", None, None, 1.),
+    ("temp", Some(0.8), None, 1.),
+    ("temp+top-p", Some(0.8), Some(0.9), 1.),
+    ("temp+top-p+repeat", Some(0.8), Some(0.9), 1.1),
+];
+
+fn main() -> anyhow::Result<()> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(SEED);
+    let uniform = Uniform::new(-10f32, 10.);
+    let logits: Vec<Vec<f32>> = (0..TOKENS)
+        .map(|_| uniform.sample_iter(&mut rng).take(VOCAB).collect())
+        .collect();
+
+    println!("{TOKENS} tokens, vocab of {VOCAB}");
+    for &(name, temp, top_p, repeat_penalty) in CASES {
+        let elapsed = bench_slice(&logits, temp, top_p, repeat_penalty)?;
+        report(name, "slice", elapsed);
+
+        #[cfg(feature = "candle")]
+        {
+            let elapsed = bench_candle(&logits, temp, top_p, repeat_penalty)?;
+            report(name, "candle", elapsed);
+        }
+    }
+    Ok(())
+}
+
+fn report(name: &str, path: &str, elapsed: Duration) {
+    let per_token = elapsed.as_secs_f64() * 1e6 / TOKENS as f64;
+    println!("{name:>20} {path:>8}: {per_token:>10.1} µs/token");
+}
+
+/// Copies the logits out (as [MambaWrapper::step] does from the dfdx tensor) and samples in place.
+///
+/// [MambaWrapper::step]: mamba_minimal_dfdx_example::MambaWrapper::step
+fn bench_slice(
+    logits: &[Vec<f32>],
+    temp: Option<f64>,
+    top_p: Option<f64>,
+    repeat_penalty: f32,
+) -> anyhow::Result<Duration> {
+    let mut processor =
+        LogitsProcessorWrapper::new(SEED, temp, top_p, repeat_penalty, REPEAT_LAST_N)?;
+    let mut tokens = vec![0];
+    let start = Instant::now();
+    for (i, logits) in logits.iter().enumerate() {
+        let mut logits = logits.clone();
+        processor.add_logits(i, &mut tokens, &mut logits)?;
+    }
+    Ok(start.elapsed())
+}
+
+/// Builds a candle tensor for each token, and penalizes and samples with candle's utilities,
+/// as the sampling did before the logits were processed in place.
+#[cfg(feature = "candle")]
+fn bench_candle(
+    logits: &[Vec<f32>],
+    temp: Option<f64>,
+    top_p: Option<f64>,
+    repeat_penalty: f32,
+) -> anyhow::Result<Duration> {
+    use candle_core::{DType, Device, Tensor};
+
+    let mut sampler = candle_transformers::generation::LogitsProcessor::new(SEED, temp, top_p);
+    let mut tokens = vec![0];
+    let start = Instant::now();
+    for logits in logits {
+        let shape = (logits.len(),);
+        let mut logits =
+            Tensor::from_vec(logits.clone(), shape, &Device::Cpu)?.to_dtype(DType::F32)?;
+        if repeat_penalty != 1. {
+            // the current token and the `last_n` before it, as in [LogitsProcessorWrapper]
+            let start_at = tokens.len().saturating_sub(REPEAT_LAST_N + 1);
+            logits = candle_transformers::utils::apply_repeat_penalty(
+                &logits,
+                repeat_penalty,
+                &tokens[start_at..],
+            )?;
+        }
+        tokens.push(sampler.sample(&logits)?);
+    }
+    Ok(start.elapsed())
+}
@@ -0,0 +1,353 @@
+//! Stateful generation for a batch of prompts.
+//!
+//! Each prompt is a row with its own [Session], tokenizer stream and logits processor,
+//! while all unfinished rows share the same states batch and are stepped together.
+//! Once a row finishes, its instance gets dropped from the states batch.
+//!
+//! With [GenerationConfig::prefill], each prompt is fed by a single [MambaWrapper::prefill]
+//! call and the resulting states are stacked into the batch, so all rows start sampling on
+//! the same step. Otherwise the prompts are fed one token per step, alongside the other rows.
+
+use crate::generation::{FinishReason, GenerationConfig, GenerationEvent, Mode, Session};
+use crate::token_output_stream::TokenOutputStream;
+use crate::{mamba, LogitsProcessorWrapper, MambaModel, MambaWrapper};
+use dfdx::prelude::*;
+
+/// A single prompt of a [BatchSession].
+pub struct BatchRow {
+    pub session: Session,
+    pub tokenizer: TokenOutputStream,
+    pub processor: LogitsProcessorWrapper,
+}
+
+/// The mutable data of an ongoing batched generation.
+pub struct BatchSession {
+    pub rows: Vec<BatchRow>,
+    /// For each instance of the states batch, the index of the row it belongs to.
+    pub active: Vec<usize>,
+    /// The last states, containing only the instances of the unfinished rows.
+    pub states: mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+    is_started: bool,
+}
+
+/// The result of a single prompt from a batched generation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchOutput {
+    /// The prompt and the generated text.
+    pub text: String,
+    /// The prompt and the generated tokens.
+    pub tokens: Vec<u32>,
+    pub finish_reason: FinishReason,
+}
+
+impl BatchSession {
+    /// Prepares the generation for each prompt.
+    ///
+    /// Each prompt requires its own logits processor, so `processors.len()` must match `prompts.len()`.
+    pub fn new<M: MambaModel>(
+        models: &MambaWrapper<M>,
+        prompts: &[&str],
+        config: GenerationConfig,
+        processors: Vec<LogitsProcessorWrapper>,
+    ) -> anyhow::Result<Self> {
+        if config.mode != Mode::Stateful {
+            anyhow::bail!("batched generation only supports the stateful mode");
+        }
+        if prompts.len() != processors.len() {
+            anyhow::bail!(
+                "got {} prompts but {} logits processors",
+                prompts.len(),
+                processors.len()
+            );
+        }
+        let mut rows = Vec::with_capacity(prompts.len());
+        for (prompt, processor) in prompts.iter().zip(processors.into_iter()) {
+            let (tokens, eos_token) = models.encode_prompt(prompt)?;
+            rows.push(BatchRow {
+                session: Session::with_tokens(tokens, eos_token, config.clone()),
+                tokenizer: TokenOutputStream::new(models.tokenizer.tokenizer().clone()),
+                processor,
+            });
+        }
+        Ok(Self {
+            active: (0..rows.len()).collect(),
+            states: models.empty_states(rows.len())?,
+            rows,
+            is_started: false,
+        })
+    }
+
+    /// Whether all rows have finished.
+    pub fn is_finished(&self) -> bool {
+        self.rows.iter().all(|row| row.session.is_finished())
+    }
+
+    /// Makes a single generation step for all unfinished rows.
+    ///
+    /// Returns the events from this step, each paired with the index of its row.
+    /// Finished rows are dropped from the states batch.
+    ///
+    /// With [GenerationConfig::prefill], the first step also feeds the prompts and emits all of
+    /// the prompt tokens.
+    pub fn next_events<M: MambaModel>(
+        &mut self,
+        models: &MambaWrapper<M>,
+    ) -> anyhow::Result<Vec<(usize, GenerationEvent)>> {
+        let mut events = vec![];
+
+        // the first step only emits the first prompt token of each row
+        if !self.is_started {
+            self.is_started = true;
+            for (r, row) in self.rows.iter_mut().enumerate() {
+                if let Some(event) = row.session.start(&mut row.tokenizer)? {
+                    events.push((r, event));
+                }
+            }
+            if self.rows.iter().any(|row| row.session.config.prefill) {
+                self.prefill(models, &mut events)?;
+            }
+            return Ok(events);
+        }
+
+        for &r in self.active.iter() {
+            let row = &mut self.rows[r];
+            if let Some(event) = row.session.check_limits(&mut row.tokenizer)? {
+                events.push((r, event));
+            }
+        }
+        self.drop_finished()?;
+        if self.active.is_empty() {
+            return Ok(events);
+        }
+
+        let is_prefilled = self
+            .active
+            .iter()
+            .all(|&r| self.rows[r].session.prefilled_logits.is_some());
+        let logits_list = if is_prefilled {
+            // the logits of the last prompt tokens are already known
+            self.active
+                .iter()
+                .map(|&r| self.rows[r].session.prefilled_logits.take().unwrap())
+                .collect()
+        } else {
+            let inputs: Vec<u32> = self
+                .active
+                .iter()
+                .map(|&r| {
+                    let session = &self.rows[r].session;
+                    session.tokens[session.step]
+                })
+                .collect();
+            models.step_batch(&inputs, &mut self.states)?
+        };
+        for (&r, logits) in self.active.iter().zip(logits_list.into_iter()) {
+            let row = &mut self.rows[r];
+            let event =
+                row.session
+                    .consume_logits(&mut row.tokenizer, &mut row.processor, logits)?;
+            events.push((r, event));
+        }
+        self.drop_finished()?;
+        Ok(events)
+    }
+
+    /// Feeds each prompt with a single [MambaWrapper::prefill] call, stacks the resulting
+    /// states into the states batch, and emits the remaining prompt tokens.
+    fn prefill<M: MambaModel>(
+        &mut self,
+        models: &MambaWrapper<M>,
+        events: &mut Vec<(usize, GenerationEvent)>,
+    ) -> anyhow::Result<()> {
+        let mut batches = Vec::with_capacity(self.active.len());
+        for &r in self.active.iter() {
+            let row = &mut self.rows[r];
+            let session = &mut row.session;
+            let mut states = models.empty_states(1)?;
+            // an empty prompt gets finished by the next limits check
+            if !session.tokens.is_empty() {
+                session.prefilled_logits = Some(models.prefill(&session.tokens, &mut states)?);
+                session.is_prefilled = true;
+                session.fed = session.tokens.len();
+            }
+            batches.push(states);
+            while session.step + 1 < session.tokens.len() {
+                if let Some(event) = session.check_limits(&mut row.tokenizer)? {
+                    events.push((r, event));
+                    break;
+                }
+                events.push((r, session.skip_prompt_token(&mut row.tokenizer)?));
+            }
+        }
+        self.states = mamba::stateful::concat_batches(batches)?;
+        self.drop_finished()
+    }
+
+    /// Removes the instances of the finished rows from the states batch.
+    fn drop_finished(&mut self) -> anyhow::Result<()> {
+        let positions: Vec<usize> = self
+            .active
+            .iter()
+            .enumerate()
+            .filter(|(_pos, &r)| !self.rows[r].session.is_finished())
+            .map(|(pos, _r)| pos)
+            .collect();
+        if positions.len() == self.active.len() {
+            return Ok(());
+        }
+        self.active = positions.iter().map(|&pos| self.active[pos]).collect();
+        if self.active.is_empty() {
+            self.states.clear();
+        } else {
+            mamba::stateful::select_batch(&mut self.states, &positions)?;
+        }
+        Ok(())
+    }
+}
+
+impl<M: MambaModel> MambaWrapper<M> {
+    /// Generates for all `prompts` at once, until each of them finishes.
+    ///
+    /// Each prompt requires its own logits processor, so `processors.len()` must match `prompts.len()`.
+    pub fn generate_batch(
+        &self,
+        prompts: &[&str],
+        config: GenerationConfig,
+        processors: Vec<LogitsProcessorWrapper>,
+    ) -> anyhow::Result<Vec<BatchOutput>> {
+        let mut session = BatchSession::new(self, prompts, config, processors)?;
+        let mut texts = vec![String::new(); prompts.len()];
+        while !session.is_finished() {
+            for (r, event) in session.next_events(self)? {
+                let text = match event {
+                    GenerationEvent::Token(token) => token.text,
+                    GenerationEvent::Finish { text, .. } => text,
+                };
+                if let Some(t) = text {
+                    texts[r] += &t;
+                }
+            }
+        }
+        let outputs = session
+            .rows
+            .into_iter()
+            .zip(texts)
+            .map(|(row, text)| BatchOutput {
+                text,
+                finish_reason: row.session.finish_reason().cloned().unwrap(),
+                tokens: row.session.tokens,
+            })
+            .collect();
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logits::LogitBias;
+    use crate::test_utils;
+    use std::collections::HashMap;
+
+    const PROMPTS: [&str; 3] = ["the", "the cat sat", "a b c d e"];
+
+    /// Greedy processors, where the second row is biased into finishing with eos,
+    /// and the others never sample it.
+    fn processor(r: usize) -> LogitsProcessorWrapper {
+        let bias = if r == 1 { 100. } else { f32::NEG_INFINITY };
+        LogitsProcessorWrapper::new(0, None, None, 1., 0)
+            .unwrap()
+            .with(LogitBias(HashMap::from([(test_utils::EOS, bias)])))
+    }
+
+    fn processors() -> Vec<LogitsProcessorWrapper> {
+        (0..PROMPTS.len()).map(processor).collect()
+    }
+
+    fn config(prefill: bool) -> GenerationConfig {
+        GenerationConfig {
+            max_new_tokens: Some(6),
+            prefill,
+            ..Default::default()
+        }
+    }
+
+    /// The same prompt, generated on its own.
+    fn single(models: &mut MambaWrapper, r: usize, config: GenerationConfig) -> BatchOutput {
+        let mut processor = processor(r);
+        let mut generation = models.generate(PROMPTS[r], config, &mut processor).unwrap();
+        let mut text = String::new();
+        for event in generation.by_ref() {
+            let fragment = match event.unwrap() {
+                GenerationEvent::Token(token) => token.text,
+                GenerationEvent::Finish { text, .. } => text,
+            };
+            text += &fragment.unwrap_or_default();
+        }
+        BatchOutput {
+            text,
+            tokens: generation.session.tokens.clone(),
+            finish_reason: generation.session.finish_reason().cloned().unwrap(),
+        }
+    }
+
+    #[test]
+    fn rows_match_the_single_prompt_generations() -> anyhow::Result<()> {
+        let mut models = test_utils::models(0);
+        for prefill in [false, true] {
+            let outputs = models.generate_batch(&PROMPTS, config(prefill), processors())?;
+            assert_eq!(outputs.len(), PROMPTS.len());
+            for (r, output) in outputs.into_iter().enumerate() {
+                assert_eq!(output, single(&mut models, r, config(prefill)));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn finished_rows_are_dropped_from_the_states() -> anyhow::Result<()> {
+        let models = test_utils::models(0);
+        for prefill in [false, true] {
+            let mut session = BatchSession::new(&models, &PROMPTS, config(prefill), processors())?;
+            let mut finished_at = vec![None; PROMPTS.len()];
+            let mut step = 0;
+            while !session.is_finished() {
+                for (r, event) in session.next_events(&models)? {
+                    if matches!(event, GenerationEvent::Finish { .. }) {
+                        finished_at[r] = Some(step);
+                    }
+                }
+                let batch_size = if session.states.is_empty() {
+                    0
+                } else {
+                    models.mamba.check_states(&session.states)?
+                };
+                assert_eq!(batch_size, session.active.len());
+                for (r, row) in session.rows.iter().enumerate() {
+                    assert_eq!(session.active.contains(&r), !row.session.is_finished());
+                }
+                step += 1;
+            }
+            let reasons: Vec<_> = session
+                .rows
+                .iter()
+                .map(|row| row.session.finish_reason().cloned().unwrap())
+                .collect();
+            assert_eq!(
+                reasons,
+                vec![
+                    FinishReason::Length,
+                    FinishReason::Eos,
+                    FinishReason::Length
+                ]
+            );
+            // the eos row finishes first, while the others keep going
+            assert!(finished_at[1] < finished_at[0] && finished_at[1] < finished_at[2]);
+            if !prefill {
+                // the longer prompts are fed one token per step
+                assert!(finished_at[0] < finished_at[2]);
+            }
+        }
+        Ok(())
+    }
+}
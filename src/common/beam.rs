@@ -0,0 +1,314 @@
+//! Beam search decoding.
+//!
+//! Each beam is an instance of the states batch, so forking a beam is just duplicating
+//! its instance with [select_batch](mamba::stateful::select_batch), which also drops and
+//! reorders the instances of the beams that were not selected.
+
+use crate::logits::log_softmax;
+use crate::{mamba, MambaModel, MambaWrapper};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BeamConfig {
+    /// How many beams are kept at each step.
+    pub width: usize,
+    /// How many hypotheses are returned. Must not be larger than `width`.
+    pub n_best: usize,
+    /// The score of a hypothesis is `log_prob / len.powf(length_penalty)`, where `len` is
+    /// the amount of generated tokens.
+    ///
+    /// Values above `0` favor longer hypotheses. Defaults to `1`.
+    pub length_penalty: f32,
+    /// Whether to stop as soon as `width` hypotheses have finished (with the eos token).
+    ///
+    /// Otherwise, the search stops once the best score that the running beams could still reach
+    /// is not better than the `width`-th best finished hypothesis. The log-probability of a
+    /// beam can only decrease, but with `length_penalty > 0` a longer hypothesis is divided by
+    /// more, so the running beams are then bounded as if they reached `max_new_tokens`.
+    pub early_stopping: bool,
+    pub max_new_tokens: usize,
+}
+
+impl Default for BeamConfig {
+    fn default() -> Self {
+        Self {
+            width: 4,
+            n_best: 1,
+            length_penalty: 1.,
+            early_stopping: false,
+            max_new_tokens: 64,
+        }
+    }
+}
+
+/// A generated sequence from the beam search.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hypothesis {
+    /// The generated tokens, not counting the prompt nor the eos token.
+    pub tokens: Vec<u32>,
+    /// The decoded generated tokens.
+    pub text: String,
+    /// The cumulative log-probability of the generated tokens (including the eos token, if any).
+    pub log_prob: f32,
+    /// The `log_prob` after the length penalty, which is used for the ranking.
+    pub score: f32,
+    /// Whether the hypothesis ended with the eos token, as opposed to reaching `max_new_tokens`.
+    pub is_finished: bool,
+}
+
+struct Beam {
+    tokens: Vec<u32>,
+    log_prob: f32,
+}
+
+impl BeamConfig {
+    fn score(&self, log_prob: f32, len: usize) -> f32 {
+        log_prob / (len.max(1) as f32).powf(self.length_penalty)
+    }
+
+    /// The best score that a running beam of `len` tokens could still reach.
+    fn best_attainable(&self, log_prob: f32, len: usize) -> f32 {
+        if self.length_penalty > 0. {
+            self.score(log_prob, self.max_new_tokens.max(len))
+        } else {
+            self.score(log_prob, len)
+        }
+    }
+}
+
+impl<M: MambaModel> MambaWrapper<M> {
+    /// Runs a beam search from the `prompt`, and returns the `n_best` hypotheses, best first.
+    ///
+    /// The prompt is prefilled once, and its states are then duplicated for each beam.
+    pub fn beam_search(
+        &self,
+        prompt: &str,
+        config: &BeamConfig,
+    ) -> anyhow::Result<Vec<Hypothesis>> {
+        if config.width == 0 || config.n_best == 0 || config.n_best > config.width {
+            anyhow::bail!(
+                "expected 0 < n_best <= width, got n_best = {} and width = {}",
+                config.n_best,
+                config.width
+            );
+        }
+        let (prompt_tokens, eos_token) = self.encode_prompt(prompt)?;
+        if prompt_tokens.is_empty() {
+            anyhow::bail!("the prompt must have at least one token");
+        }
+
+        let mut states = self.empty_states(1)?;
+        let mut logits_list = vec![self.prefill(&prompt_tokens, &mut states)?];
+        let mut beams = vec![Beam {
+            tokens: vec![],
+            log_prob: 0.,
+        }];
+        let mut finished: Vec<Hypothesis> = vec![];
+
+        for step in 0..config.max_new_tokens {
+            // the best continuations from all beams
+            let n_candidates = 2 * config.width;
+            let mut candidates: Vec<(usize, u32, f32)> = vec![];
+            for (b, (beam, logits)) in beams.iter().zip(logits_list.iter()).enumerate() {
+                let log_probs = log_softmax(logits);
+                candidates.extend(
+                    top_k(&log_probs, n_candidates)
+                        .into_iter()
+                        .map(|token| (b, token as u32, beam.log_prob + log_probs[token])),
+                );
+            }
+            candidates.sort_by(|x, y| y.2.total_cmp(&x.2));
+            candidates.truncate(n_candidates);
+
+            let mut parents = vec![];
+            let mut next_beams = vec![];
+            for (rank, (b, token, log_prob)) in candidates.into_iter().enumerate() {
+                if token == eos_token {
+                    // only the eos tokens that are among the best `width` candidates are accepted
+                    if rank < config.width {
+                        let tokens = beams[b].tokens.clone();
+                        finished.push(self.hypothesis(tokens, log_prob, config, true)?);
+                    }
+                    continue;
+                }
+                let mut tokens = beams[b].tokens.clone();
+                tokens.push(token);
+                parents.push(b);
+                next_beams.push(Beam { tokens, log_prob });
+                if next_beams.len() == config.width {
+                    break;
+                }
+            }
+            beams = next_beams;
+            // only the `width` best finished hypotheses can still be returned
+            finished.sort_by(|x, y| y.score.total_cmp(&x.score));
+            finished.truncate(config.width);
+
+            if finished.len() == config.width {
+                if config.early_stopping {
+                    break;
+                }
+                let worst_finished = finished[config.width - 1].score;
+                let best_running = beams
+                    .iter()
+                    .map(|beam| beam.log_prob)
+                    .fold(f32::NEG_INFINITY, f32::max);
+                if config.best_attainable(best_running, step + 1) <= worst_finished {
+                    break;
+                }
+            }
+            if beams.is_empty() || step + 1 == config.max_new_tokens {
+                break;
+            }
+
+            // forks the states of the selected beams
+            mamba::stateful::select_batch(&mut states, &parents)?;
+            let inputs: Vec<u32> = beams
+                .iter()
+                .map(|beam| *beam.tokens.last().unwrap())
+                .collect();
+            logits_list = self.step_batch(&inputs, &mut states)?;
+        }
+
+        for beam in beams {
+            finished.push(self.hypothesis(beam.tokens, beam.log_prob, config, false)?);
+        }
+        finished.sort_by(|x, y| y.score.total_cmp(&x.score));
+        finished.truncate(config.n_best);
+        Ok(finished)
+    }
+
+    fn hypothesis(
+        &self,
+        tokens: Vec<u32>,
+        log_prob: f32,
+        config: &BeamConfig,
+        is_finished: bool,
+    ) -> anyhow::Result<Hypothesis> {
+        // the eos token also counts for the length
+        let len = tokens.len() + is_finished as usize;
+        let text = self
+            .tokenizer
+            .tokenizer()
+            .decode(&tokens, true)
+            .map_err(anyhow::Error::msg)?;
+        Ok(Hypothesis {
+            tokens,
+            text,
+            log_prob,
+            score: config.score(log_prob, len),
+            is_finished,
+        })
+    }
+}
+
+/// The indices of the `k` largest `values`, in no particular order.
+fn top_k(values: &[f32], k: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..values.len()).collect();
+    if k < indices.len() {
+        indices.select_nth_unstable_by(k, |&x, &y| values[y].total_cmp(&values[x]));
+        indices.truncate(k);
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn top_k_selects_the_largest() {
+        let mut indices = top_k(&[0.5, -1., 3., 2., 0.], 3);
+        indices.sort();
+        assert_eq!(indices, vec![0, 2, 3]);
+        assert_eq!(top_k(&[1., 2.], 4).len(), 2);
+    }
+
+    #[test]
+    fn longer_hypotheses_are_bounded_by_max_new_tokens() {
+        let config = BeamConfig {
+            length_penalty: 1.,
+            max_new_tokens: 10,
+            ..Default::default()
+        };
+        // a running beam can still get a better score by growing
+        assert_eq!(config.best_attainable(-4., 2), -0.4);
+        let config = BeamConfig {
+            length_penalty: 0.,
+            ..config
+        };
+        assert_eq!(config.best_attainable(-4., 2), -4.);
+    }
+
+    #[test]
+    fn a_single_beam_is_greedy() -> anyhow::Result<()> {
+        let models = test_utils::models(0);
+        let config = BeamConfig {
+            width: 1,
+            n_best: 1,
+            length_penalty: 0.,
+            early_stopping: false,
+            max_new_tokens: 8,
+        };
+        let hypotheses = models.beam_search("the cat sat", &config)?;
+        assert_eq!(hypotheses.len(), 1);
+
+        let (prompt, eos) = models.encode_prompt("the cat sat")?;
+        let mut states = models.empty_states(1)?;
+        let mut logits = models.prefill(&prompt, &mut states)?;
+        let mut tokens = vec![];
+        let mut log_prob = 0.;
+        let mut is_finished = false;
+        for _ in 0..config.max_new_tokens {
+            let token = top_k(&logits, 1)[0] as u32;
+            log_prob += log_softmax(&logits)[token as usize];
+            if token == eos {
+                is_finished = true;
+                break;
+            }
+            tokens.push(token);
+            logits = models.step(token, &mut states)?;
+        }
+        let hypothesis = &hypotheses[0];
+        assert_eq!(hypothesis.tokens, tokens);
+        assert_eq!(hypothesis.is_finished, is_finished);
+        assert!((hypothesis.log_prob - log_prob).abs() < 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn hypotheses_are_ranked_by_the_penalized_score() -> anyhow::Result<()> {
+        let models = test_utils::models(1);
+        for length_penalty in [0., 1., 2.] {
+            let config = BeamConfig {
+                width: 4,
+                n_best: 3,
+                length_penalty,
+                early_stopping: false,
+                max_new_tokens: 6,
+            };
+            let hypotheses = models.beam_search("a b c", &config)?;
+            assert_eq!(hypotheses.len(), 3);
+            for hypothesis in &hypotheses {
+                let len = hypothesis.tokens.len() + hypothesis.is_finished as usize;
+                assert_eq!(hypothesis.score, config.score(hypothesis.log_prob, len));
+                assert!(hypothesis.tokens.len() <= config.max_new_tokens);
+            }
+            assert!(hypotheses.windows(2).all(|w| w[0].score >= w[1].score));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let models = test_utils::models(0);
+        for (width, n_best) in [(0, 0), (2, 0), (2, 3)] {
+            let config = BeamConfig {
+                width,
+                n_best,
+                ..Default::default()
+            };
+            assert!(models.beam_search("a", &config).is_err());
+        }
+    }
+}
@@ -0,0 +1,518 @@
+//! Regex- and grammar-constrained decoding.
+//!
+//! A [RegexConstraint] is a [LogitsProcessor] that only allows the tokens which keep the
+//! generated text matching a regex, plus the eos token once the text is a complete match.
+//! The regex is compiled into a DFA, and the vocab tokens are walked through it in order to
+//! precompute which tokens are valid in each DFA state (lazily, once per visited state).
+//!
+//! JSON schemas are supported by converting them into a regex, see [json_schema_to_regex].
+
+use crate::{LogitsProcessor, MambaModel, MambaWrapper};
+use regex_automata::dfa::{dense, Automaton, StartKind};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::{Anchored, MatchKind};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Constrains the generated text (not counting the prompt) to fully match a regex.
+///
+/// The text can only end (with the eos token) when it's a complete match, so as long as the
+/// generation is not interrupted (eg. by `max_new_tokens`), the output is guaranteed to match.
+pub struct RegexConstraint {
+    dfa: Arc<dense::DFA<Vec<u32>>>,
+    /// The bytes of each token, by token id. Special tokens have no bytes and are never allowed.
+    token_bytes: Arc<Vec<Option<Vec<u8>>>>,
+    eos_token: u32,
+    /// For each visited DFA state, the allowed tokens and the state they lead into.
+    allowed: HashMap<StateID, Vec<(u32, StateID)>>,
+    state: StateID,
+    /// How many of the context tokens were already fed into the DFA.
+    ///
+    /// This is `None` before the first call, when the context contains only the prompt.
+    fed: Option<usize>,
+}
+
+impl RegexConstraint {
+    pub fn new(
+        pattern: &str,
+        tokenizer: &tokenizers::Tokenizer,
+        eos_token: u32,
+    ) -> anyhow::Result<Self> {
+        // all matches are kept, otherwise alternations stop at the first alternative that
+        // matches (eg. `1` in `(?:integer|number)` would make `1.5` unreachable)
+        let dfa = dense::Builder::new()
+            .configure(
+                dense::DFA::config()
+                    .start_kind(StartKind::Anchored)
+                    .match_kind(MatchKind::All),
+            )
+            .build(pattern)
+            .map_err(|e| anyhow::anyhow!("failed to compile the regex {pattern:?}: {e}"))?;
+        let token_bytes = vocab_bytes(tokenizer);
+        Self::from_parts(Arc::new(dfa), Arc::new(token_bytes), eos_token)
+    }
+
+    /// Creates a constraint that shares the compiled `dfa` and `token_bytes`, eg. for a batch of generations.
+    pub fn from_parts(
+        dfa: Arc<dense::DFA<Vec<u32>>>,
+        token_bytes: Arc<Vec<Option<Vec<u8>>>>,
+        eos_token: u32,
+    ) -> anyhow::Result<Self> {
+        let state = dfa
+            .start_state(&start::Config::new().anchored(Anchored::Yes))
+            .map_err(|e| anyhow::anyhow!("failed to get the regex start state: {e}"))?;
+        Ok(Self {
+            dfa,
+            token_bytes,
+            eos_token,
+            allowed: HashMap::new(),
+            state,
+            fed: None,
+        })
+    }
+
+    /// Whether the text generated so far is a complete match.
+    pub fn is_match(&self) -> bool {
+        let eoi = self.dfa.next_eoi_state(self.state);
+        self.dfa.is_match_state(eoi)
+    }
+
+    /// Walks the `bytes` from the `state`, returning `None` if the regex can no longer match.
+    fn walk(&self, mut state: StateID, bytes: &[u8]) -> Option<StateID> {
+        for &b in bytes {
+            state = self.dfa.next_state(state, b);
+            if self.dfa.is_dead_state(state) || self.dfa.is_quit_state(state) {
+                return None;
+            }
+        }
+        Some(state)
+    }
+
+    /// The tokens that are allowed in the `state`, calculated on the first visit.
+    fn allowed(&mut self, state: StateID) -> &[(u32, StateID)] {
+        if !self.allowed.contains_key(&state) {
+            let allowed = self
+                .token_bytes
+                .iter()
+                .enumerate()
+                .filter_map(|(id, bytes)| {
+                    let bytes = bytes.as_ref().filter(|bytes| !bytes.is_empty())?;
+                    let next = self.walk(state, bytes)?;
+                    Some((id as u32, next))
+                })
+                .collect();
+            self.allowed.insert(state, allowed);
+        }
+        &self.allowed[&state]
+    }
+}
+
+impl LogitsProcessor for RegexConstraint {
+    fn process(&mut self, tokens: &[u32], logits: &mut [f32]) -> anyhow::Result<()> {
+        // the prompt is not constrained
+        let fed = *self.fed.get_or_insert(tokens.len());
+        for &token in &tokens[fed..] {
+            if token == self.eos_token {
+                continue;
+            }
+            let state = self.state;
+            let Some(&(_, next)) = self.allowed(state).iter().find(|(id, _)| *id == token) else {
+                anyhow::bail!("the token {token} violates the regex constraint");
+            };
+            self.state = next;
+        }
+        self.fed = Some(tokens.len());
+
+        let is_match = self.is_match();
+        let eos_token = self.eos_token;
+        let state = self.state;
+        let allowed = self.allowed(state);
+        if allowed.is_empty() && !is_match {
+            anyhow::bail!("no token can continue the regex constraint");
+        }
+        let mut masked = vec![f32::NEG_INFINITY; logits.len()];
+        for &(id, _next) in allowed {
+            if let Some(logit) = logits.get(id as usize) {
+                masked[id as usize] = *logit;
+            }
+        }
+        if is_match {
+            if let Some(logit) = logits.get(eos_token as usize) {
+                masked[eos_token as usize] = *logit;
+            }
+        }
+        logits.copy_from_slice(&masked);
+        Ok(())
+    }
+}
+
+/// The bytes that each vocab token decodes into, by token id.
+///
+/// Byte-level (GPT-2 style) tokens are mapped back into their raw bytes, so that tokens that
+/// contain only part of an utf-8 character are also handled.
+/// Special (added) tokens have no bytes.
+pub fn vocab_bytes(tokenizer: &tokenizers::Tokenizer) -> Vec<Option<Vec<u8>>> {
+    let vocab = tokenizer.get_vocab(true);
+    let size = vocab
+        .values()
+        .copied()
+        .max()
+        .map_or(0, |max| max as usize + 1);
+    let unicode_to_byte = byte_level_decoder();
+    let special: std::collections::HashSet<u32> = tokenizer
+        .get_added_vocabulary()
+        .get_vocab()
+        .values()
+        .copied()
+        .collect();
+
+    let mut bytes = vec![None; size];
+    for (token, id) in vocab {
+        if special.contains(&id) {
+            continue;
+        }
+        let byte_level: Option<Vec<u8>> = token
+            .chars()
+            .map(|c| unicode_to_byte.get(&c).copied())
+            .collect();
+        bytes[id as usize] = match byte_level {
+            Some(b) => Some(b),
+            // sentencepiece style tokens
+            None => Some(token.replace('\u{2581}', " ").into_bytes()),
+        };
+    }
+    bytes
+}
+
+/// The inverse of the GPT-2 `bytes_to_unicode` mapping.
+fn byte_level_decoder() -> HashMap<char, u8> {
+    let mut printable: Vec<u32> = vec![];
+    printable.extend(u32::from(b'!')..=u32::from(b'~'));
+    printable.extend(0xA1..=0xAC);
+    printable.extend(0xAE..=0xFF);
+    let mut map = HashMap::new();
+    let mut n = 0;
+    for b in 0..=255u32 {
+        let c = if printable.contains(&b) {
+            b
+        } else {
+            n += 1;
+            256 + n - 1
+        };
+        map.insert(char::from_u32(c).unwrap(), b as u8);
+    }
+    map
+}
+
+/// Converts a (subset of a) JSON schema into a regex that matches compact JSON values.
+///
+/// Supported: `type` (`string`, `integer`, `number`, `boolean`, `null`, `object`, `array`),
+/// `enum`, `const`, `properties` (emitted in order, where the ones not in `required` may be
+/// skipped), `required`, `items`, `minItems`/`maxItems`, `pattern` (for strings) and `anyOf`/`oneOf`.
+pub fn json_schema_to_regex(schema: &serde_json::Value) -> anyhow::Result<String> {
+    use serde_json::Value;
+    const STRING_CHAR: &str = r#"(?:[^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+    const INTEGER: &str = r"-?(?:0|[1-9][0-9]*)";
+
+    let Value::Object(obj) = schema else {
+        anyhow::bail!("expected a schema object, got {schema}");
+    };
+    if let Some(value) = obj.get("const") {
+        return Ok(escape(&value.to_string()));
+    }
+    if let Some(values) = obj.get("enum") {
+        let Value::Array(values) = values else {
+            anyhow::bail!("expected `enum` to be an array");
+        };
+        let alternatives: Vec<String> = values.iter().map(|v| escape(&v.to_string())).collect();
+        return Ok(format!("(?:{})", alternatives.join("|")));
+    }
+    for key in ["anyOf", "oneOf"] {
+        if let Some(schemas) = obj.get(key) {
+            let Value::Array(schemas) = schemas else {
+                anyhow::bail!("expected `{key}` to be an array");
+            };
+            let alternatives = schemas
+                .iter()
+                .map(json_schema_to_regex)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            return Ok(format!("(?:{})", alternatives.join("|")));
+        }
+    }
+
+    let ty = obj.get("type").and_then(Value::as_str);
+    let regex = match ty {
+        Some("string") => match obj.get("pattern").and_then(Value::as_str) {
+            // note: the pattern must not produce characters that require escaping in json
+            Some(pattern) => format!(
+                r#""(?:{})""#,
+                pattern.trim_start_matches('^').trim_end_matches('$')
+            ),
+            None => format!(r#""{STRING_CHAR}*""#),
+        },
+        Some("integer") => INTEGER.to_string(),
+        Some("number") => format!(r"{INTEGER}(?:\.[0-9]+)?(?:[eE][+-]?[0-9]+)?"),
+        Some("boolean") => "(?:true|false)".to_string(),
+        Some("null") => "null".to_string(),
+        Some("array") => {
+            let item = match obj.get("items") {
+                Some(items) => json_schema_to_regex(items)?,
+                None => anyhow::bail!("array schemas require `items`"),
+            };
+            let min = obj.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+            let max = obj.get("maxItems").and_then(Value::as_u64);
+            let rest_min = min.saturating_sub(1);
+            let rest = match max {
+                Some(max) => format!("{{{rest_min},{}}}", max.saturating_sub(1)),
+                None => format!("{{{rest_min},}}"),
+            };
+            let non_empty = format!(r"{item}(?:,{item}){rest}");
+            match (min, max) {
+                (_, Some(0)) => r"\[\]".to_string(),
+                (0, _) => format!(r"\[(?:{non_empty})?\]"),
+                _ => format!(r"\[{non_empty}\]"),
+            }
+        }
+        Some("object") => {
+            let Some(Value::Object(properties)) = obj.get("properties") else {
+                anyhow::bail!("object schemas require `properties`");
+            };
+            let required = match obj.get("required") {
+                None => vec![],
+                Some(Value::Array(names)) => names
+                    .iter()
+                    .map(|name| {
+                        name.as_str().ok_or_else(|| {
+                            anyhow::anyhow!("expected `required` to contain strings")
+                        })
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+                Some(_) => anyhow::bail!("expected `required` to be an array"),
+            };
+            if let Some(name) = required
+                .iter()
+                .find(|name| !properties.contains_key(**name))
+            {
+                anyhow::bail!("the required property {name:?} is not in `properties`");
+            }
+            let fields = properties
+                .iter()
+                .map(|(name, schema)| {
+                    let is_required = required.contains(&name.as_str());
+                    let name = escape(&Value::String(name.clone()).to_string());
+                    Ok((
+                        format!("{name}:{}", json_schema_to_regex(schema)?),
+                        is_required,
+                    ))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            format!(r"\{{{}\}}", object_fields(&fields))
+        }
+        Some(other) => anyhow::bail!("unsupported schema type {other:?}"),
+        None => anyhow::bail!("the schema requires a `type`, `enum`, `const`, `anyOf` or `oneOf`"),
+    };
+    Ok(regex)
+}
+
+/// The regex for the `(field, is_required)` of an object, in order, where the optional fields
+/// may be skipped.
+///
+/// Only the fields after the first emitted one are preceded by a comma, so this branches on
+/// which field is emitted first.
+fn object_fields(fields: &[(String, bool)]) -> String {
+    let after_first = |i: usize| -> String {
+        fields[i..]
+            .iter()
+            .map(|(field, is_required)| match is_required {
+                true => format!(",{field}"),
+                false => format!("(?:,{field})?"),
+            })
+            .collect()
+    };
+    // built from the last field, where nothing was emitted yet
+    let mut regex = String::new();
+    for (i, (field, is_required)) in fields.iter().enumerate().rev() {
+        let first = format!("{field}{}", after_first(i + 1));
+        regex = match is_required {
+            true => first,
+            false => format!("(?:{first}|{regex})"),
+        };
+    }
+    regex
+}
+
+/// Escapes the regex meta characters of `text`.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if r"\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl<M: MambaModel> MambaWrapper<M> {
+    /// A [RegexConstraint] for the model's tokenizer.
+    pub fn regex_constraint(&self, pattern: &str) -> anyhow::Result<RegexConstraint> {
+        RegexConstraint::new(pattern, self.tokenizer.tokenizer(), self.eos_token()?)
+    }
+
+    /// A [RegexConstraint] that only allows compact JSON values that follow the `schema`.
+    pub fn json_schema_constraint(
+        &self,
+        schema: &serde_json::Value,
+    ) -> anyhow::Result<RegexConstraint> {
+        self.regex_constraint(&json_schema_to_regex(schema)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{word_level_tokenizer, EOS};
+    use rand::{Rng, SeedableRng};
+    use serde_json::{json, Value};
+
+    /// Single-character tokens, and a few multi-character ones.
+    fn tokenizer() -> tokenizers::Tokenizer {
+        let words = r#"{}[]":,-.+0123456789abcdefghijklmnopqrstuvwxyzE"#
+            .chars()
+            .map(String::from)
+            .chain(["true", "false", "null", "\"a", "12", "\"}"].map(String::from));
+        word_level_tokenizer(words)
+    }
+
+    fn constraint(schema: &Value) -> RegexConstraint {
+        let pattern = json_schema_to_regex(schema).unwrap();
+        RegexConstraint::new(&pattern, &tokenizer(), EOS).unwrap()
+    }
+
+    fn accepts(constraint: &RegexConstraint, text: &str) -> bool {
+        constraint
+            .walk(constraint.state, text.as_bytes())
+            .is_some_and(|state| {
+                let eoi = constraint.dfa.next_eoi_state(state);
+                constraint.dfa.is_match_state(eoi)
+            })
+    }
+
+    /// The properties are in alphabetical order, in case `serde_json` doesn't preserve the order.
+    fn schemas() -> Vec<Value> {
+        vec![
+            json!({"anyOf": [{"type": "integer"}, {"type": "number"}]}),
+            json!({"enum": ["red", 1, null]}),
+            json!({
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string"},
+                    "age": {"type": "integer"},
+                    "tags": {"type": "array", "items": {"type": "string"}, "maxItems": 2}
+                },
+                "required": ["name"]
+            }),
+            json!({
+                "type": "object",
+                "properties": {"a": {"type": "boolean"}, "b": {"type": "null"}}
+            }),
+        ]
+    }
+
+    #[test]
+    fn schema_regexes_accept_valid_json() {
+        let cases: [(&[&str], &[&str]); 4] = [
+            (&["1", "1.5", "-3e2", "0.25E-1"], &["1.", "01", "+1"]),
+            (&[r#""red""#, "1", "null"], &[r#""blue""#, "2"]),
+            (
+                &[
+                    r#"{"name":"a"}"#,
+                    r#"{"age":3,"name":"a"}"#,
+                    r#"{"name":"","tags":["x","y"]}"#,
+                    r#"{"age":-3,"name":"a","tags":[]}"#,
+                ],
+                &[
+                    r#"{}"#,
+                    r#"{"age":3}"#,
+                    r#"{"name":"a",}"#,
+                    r#"{"name":"a","age":3}"#,
+                    r#"{"name":"a","tags":["x","y","z"]}"#,
+                ],
+            ),
+            (
+                &[
+                    r#"{}"#,
+                    r#"{"a":true}"#,
+                    r#"{"b":null}"#,
+                    r#"{"a":false,"b":null}"#,
+                ],
+                &[r#"{,"b":null}"#, r#"{"a":true,}"#],
+            ),
+        ];
+        for (schema, (valid, invalid)) in schemas().iter().zip(cases) {
+            let constraint = constraint(schema);
+            for text in valid {
+                serde_json::from_str::<Value>(text).unwrap();
+                assert!(accepts(&constraint, text), "{schema} should accept {text}");
+            }
+            for text in invalid {
+                assert!(!accepts(&constraint, text), "{schema} should reject {text}");
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_required_property_is_rejected() {
+        let schema = json!({"type": "object", "properties": {}, "required": ["a"]});
+        assert!(json_schema_to_regex(&schema).is_err());
+    }
+
+    /// Samples from random logits until eos, nudging towards the closing tokens after a while.
+    fn sample(constraint: &mut RegexConstraint, rng: &mut rand::rngs::StdRng) -> String {
+        let vocab = constraint.token_bytes.len();
+        let mut tokens = vec![EOS];
+        for step in 0..500 {
+            let mut logits: Vec<f32> = (0..vocab).map(|_| rng.gen()).collect();
+            if step > 20 {
+                for (id, bytes) in constraint.token_bytes.iter().enumerate() {
+                    if matches!(bytes.as_deref(), Some(b"}" | b"]" | b"\"")) {
+                        logits[id] += 10.;
+                    }
+                }
+                logits[EOS as usize] += 20.;
+            }
+            constraint.process(&tokens, &mut logits).unwrap();
+            let (token, logit) = logits
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .unwrap();
+            assert!(logit.is_finite());
+            if token as u32 == EOS {
+                let bytes: Vec<u8> = tokens[1..]
+                    .iter()
+                    .flat_map(|&t| constraint.token_bytes[t as usize].clone().unwrap())
+                    .collect();
+                return String::from_utf8(bytes).unwrap();
+            }
+            tokens.push(token as u32);
+        }
+        panic!("the generation did not finish");
+    }
+
+    #[test]
+    fn masked_sampling_produces_parseable_json() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for schema in schemas() {
+            for _ in 0..20 {
+                let text = sample(&mut constraint(&schema), &mut rng);
+                let value: Value = serde_json::from_str(&text)
+                    .unwrap_or_else(|e| panic!("{text:?} is not valid json: {e}"));
+                if schema.get("required").is_some() {
+                    assert!(value["name"].is_string(), "{text} misses the name");
+                }
+            }
+        }
+    }
+}
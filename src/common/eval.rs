@@ -0,0 +1,559 @@
+//! Log-likelihood evaluation over a text corpus.
+//!
+//! Each document is scored token by token, and the negative log-likelihood (in nats) is
+//! accumulated into an [EvalReport], which also reports the perplexity and bits-per-byte.
+//!
+//! There are three ways of running the model, which should agree with each other:
+//! - [EvalMode::Windowed]: sequence-mode calls over fixed-size windows, carrying the states
+//! between windows, so that memory stays constant regardless of the document length.
+//! - [EvalMode::Stateful]: a single-token call for each token.
+//! - [EvalMode::Stateless]: a single sequence-mode call over the whole document, without states.
+//!
+//! Documents can also be fed in consecutive [CorpusChunk]s (see [read_corpus]), in which case the
+//! states are carried across the chunks.
+
+use crate::logits::{log_softmax, TokenLogprobs};
+use crate::{mamba, MambaModel, MambaWrapper};
+use dfdx::prelude::*;
+
+/// How the documents are fed. For `f32` weights, each mode runs a different forward,
+/// so that they cross-check each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvalMode {
+    /// Sequence-mode calls of `window` tokens (see [mamba::prefill]), carrying the states across them.
+    #[default]
+    Windowed,
+    /// A stateful call per token (see [mamba::stateful]).
+    Stateful,
+    /// A single stateless call over each document (see [mamba::stateless]).
+    Stateless,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalConfig {
+    pub mode: EvalMode,
+    /// How many tokens are fed in each call, for [EvalMode::Windowed].
+    ///
+    /// Defaults to `128`.
+    pub window: usize,
+    /// Whether to prepend the eos token to each document, so that all of the document's tokens are scored.
+    ///
+    /// Otherwise, the first token is only used as context. Defaults to `true`.
+    pub prepend_eos: bool,
+}
+
+impl Default for EvalConfig {
+    fn default() -> Self {
+        Self {
+            mode: EvalMode::default(),
+            window: 128,
+            prepend_eos: true,
+        }
+    }
+}
+
+/// The accumulated evaluation results.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EvalReport {
+    pub documents: usize,
+    /// How many tokens were scored.
+    pub tokens: usize,
+    /// How many utf-8 bytes the scored tokens have.
+    ///
+    /// When the first token of a document is only used as context, its bytes are not counted.
+    pub bytes: usize,
+    /// The total negative log-likelihood, in nats.
+    pub nll: f64,
+}
+
+impl EvalReport {
+    /// The average negative log-likelihood per token, in nats.
+    ///
+    /// Fails if no token was scored.
+    pub fn mean_nll(&self) -> anyhow::Result<f64> {
+        if self.tokens == 0 {
+            anyhow::bail!("no tokens were scored");
+        }
+        Ok(self.nll / self.tokens as f64)
+    }
+
+    pub fn perplexity(&self) -> anyhow::Result<f64> {
+        Ok(self.mean_nll()?.exp())
+    }
+
+    pub fn bits_per_byte(&self) -> anyhow::Result<f64> {
+        if self.bytes == 0 {
+            anyhow::bail!("no bytes were scored");
+        }
+        Ok(self.nll / std::f64::consts::LN_2 / self.bytes as f64)
+    }
+}
+
+impl std::fmt::Display for EvalReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "documents: {}, tokens: {}, bytes: {}, nll: {:.4}",
+            self.documents, self.tokens, self.bytes, self.nll,
+        )?;
+        if let (Ok(mean_nll), Ok(perplexity), Ok(bits_per_byte)) =
+            (self.mean_nll(), self.perplexity(), self.bits_per_byte())
+        {
+            write!(
+                f,
+                ", mean nll: {mean_nll:.4}, perplexity: {perplexity:.4}, bits per byte: {bits_per_byte:.4}",
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A piece of a corpus document.
+///
+/// A [String] converts into a whole document.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CorpusChunk {
+    pub text: String,
+    /// Whether this continues the document of the previous chunk, instead of starting a new one.
+    pub continues: bool,
+}
+
+impl From<String> for CorpusChunk {
+    fn from(text: String) -> Self {
+        Self {
+            text,
+            continues: false,
+        }
+    }
+}
+
+impl From<&str> for CorpusChunk {
+    fn from(text: &str) -> Self {
+        text.to_string().into()
+    }
+}
+
+/// A token from [MambaWrapper::score_text].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScoredToken {
+    pub id: u32,
+    /// The decoded token.
+    pub text: String,
+    pub logprobs: TokenLogprobs,
+}
+
+impl<M: MambaModel> MambaWrapper<M> {
+    /// Scores all of the `documents` and returns the accumulated report.
+    ///
+    /// The documents may be split into consecutive [CorpusChunk]s, which are scored as they
+    /// arrive. Fails if no token was scored.
+    pub fn eval_corpus<D: Into<CorpusChunk>>(
+        &self,
+        documents: impl IntoIterator<Item = anyhow::Result<D>>,
+        config: &EvalConfig,
+    ) -> anyhow::Result<EvalReport> {
+        let mut report = EvalReport::default();
+        let mut scorer: Option<DocumentScorer> = None;
+        for chunk in documents {
+            let chunk = chunk?.into();
+            match &mut scorer {
+                Some(scorer) if chunk.continues => scorer.feed(self, &chunk.text, &mut report)?,
+                _ => {
+                    if let Some(scorer) = scorer.take() {
+                        scorer.finish(self, &mut report)?;
+                    }
+                    let mut document = DocumentScorer::new(self, config)?;
+                    report.documents += 1;
+                    document.feed(self, &chunk.text, &mut report)?;
+                    scorer = Some(document);
+                }
+            }
+        }
+        if let Some(scorer) = scorer {
+            scorer.finish(self, &mut report)?;
+        }
+        report.mean_nll()?;
+        Ok(report)
+    }
+
+    /// Scores a single `document`, accumulating into the `report`.
+    pub fn eval_document(
+        &self,
+        document: &str,
+        config: &EvalConfig,
+        report: &mut EvalReport,
+    ) -> anyhow::Result<()> {
+        let mut scorer = DocumentScorer::new(self, config)?;
+        report.documents += 1;
+        scorer.feed(self, document, report)?;
+        scorer.finish(self, report)
+    }
+
+    /// Scores each token of the `text`, including the `top_n` most likely alternatives at each position.
+    ///
+    /// The `text` is fed in windows, as in [EvalMode::Windowed]. Note that when `prepend_eos`
+    /// is `false`, the first token is not scored.
+    pub fn score_text(
+        &self,
+        text: &str,
+        top_n: usize,
+        config: &EvalConfig,
+    ) -> anyhow::Result<Vec<ScoredToken>> {
+        let (mut tokens, eos_token) = self.encode_prompt(text)?;
+        if config.prepend_eos {
+            tokens.insert(0, eos_token);
+        }
+        if config.window == 0 {
+            anyhow::bail!("the window must not be empty");
+        }
+        let mut scored = vec![];
+        if tokens.len() < 2 {
+            return Ok(scored);
+        }
+        let tokenizer = self.tokenizer.tokenizer();
+        let (inputs, targets) = (&tokens[..tokens.len() - 1], &tokens[1..]);
+        let mut states = self.empty_states(1)?;
+        for (inputs, targets) in inputs
+            .chunks(config.window)
+            .zip(targets.chunks(config.window))
+        {
+            let logits_list = self.sequence_logits(inputs, &mut states)?;
+            for (logits, &id) in logits_list.iter().zip(targets) {
+                scored.push(ScoredToken {
+                    id,
+                    text: tokenizer.decode(&[id], false).map_err(anyhow::Error::msg)?,
+                    logprobs: TokenLogprobs::from_logits(logits, id, top_n, tokenizer),
+                });
+            }
+        }
+        Ok(scored)
+    }
+
+    /// Make a sequence-mode call over all `tokens`, starting from the `states`,
+    /// and return the logits of each timestep.
+    ///
+    /// The `states` must have a batch size of `1`.
+    pub fn sequence_logits(
+        &self,
+        tokens: &[u32],
+        states: &mut mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        let batch_size = self.mamba.check_states(states)?;
+        if batch_size != 1 {
+            anyhow::bail!("expected states with a batch size of 1, got {batch_size}");
+        }
+        let hidden = self.forward_hidden(tokens, (1, tokens.len()), states)?;
+        let logits_list = self.mamba.try_lm_head_f32(hidden)?;
+
+        let vocab = logits_list.shape().2;
+        let logits_list = logits_list
+            .as_vec()
+            .chunks_exact(vocab)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        Ok(logits_list)
+    }
+}
+
+/// Scores a document that is fed in consecutive chunks.
+struct DocumentScorer<'a> {
+    config: &'a EvalConfig,
+    /// The last fed token, which predicts the first token of the next chunk.
+    ///
+    /// This is `None` until the first token, unless the eos token is prepended.
+    last: Option<u32>,
+    /// The states after the `last` token (not including it), for [EvalMode::Windowed] and [EvalMode::Stateful].
+    states: mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+    /// All of the document's tokens, for [EvalMode::Stateless], which are scored in [Self::finish].
+    tokens: Vec<u32>,
+    /// The end of the fed text, from the start of its last word (see [stable_len]), which is
+    /// tokenized together with the next chunk.
+    tail: String,
+}
+
+impl<'a> DocumentScorer<'a> {
+    fn new<M: MambaModel>(
+        models: &MambaWrapper<M>,
+        config: &'a EvalConfig,
+    ) -> anyhow::Result<Self> {
+        if config.mode == EvalMode::Windowed && config.window == 0 {
+            anyhow::bail!("the window must not be empty");
+        }
+        let last = match config.prepend_eos {
+            true => Some(models.eos_token()?),
+            false => None,
+        };
+        Ok(Self {
+            config,
+            last,
+            states: models.empty_states(1)?,
+            tokens: vec![],
+            tail: String::new(),
+        })
+    }
+
+    /// Scores the tokens of the `text`, accumulating into the `report`.
+    ///
+    /// The last word is held back until the next chunk (or [Self::finish]), so that a word
+    /// split across the chunks gets the same tokens as in the whole document.
+    fn feed<M: MambaModel>(
+        &mut self,
+        models: &MambaWrapper<M>,
+        text: &str,
+        report: &mut EvalReport,
+    ) -> anyhow::Result<()> {
+        let mut text = std::mem::take(&mut self.tail) + text;
+        self.tail = text.split_off(stable_len(&text));
+        self.score(models, &text, report)
+    }
+
+    /// Scores the tokens of the `text`, which continues the already scored text.
+    fn score<M: MambaModel>(
+        &mut self,
+        models: &MambaWrapper<M>,
+        text: &str,
+        report: &mut EvalReport,
+    ) -> anyhow::Result<()> {
+        let encoding = models
+            .tokenizer
+            .tokenizer()
+            .encode(text, true)
+            .map_err(anyhow::Error::msg)?;
+        // the first token of the document is only used as context
+        let unscored_bytes = match (self.last, encoding.get_offsets().first()) {
+            (None, Some(&(_start, end))) => end,
+            _ => 0,
+        };
+        report.bytes += text.len().saturating_sub(unscored_bytes);
+
+        let mut tokens: Vec<u32> = self.last.into_iter().collect();
+        tokens.extend(encoding.get_ids());
+        let Some(&last) = tokens.last() else {
+            return Ok(());
+        };
+
+        let (inputs, targets) = (&tokens[..tokens.len() - 1], &tokens[1..]);
+        match self.config.mode {
+            EvalMode::Windowed => {
+                for (inputs, targets) in inputs
+                    .chunks(self.config.window)
+                    .zip(targets.chunks(self.config.window))
+                {
+                    let logits_list = models.sequence_logits(inputs, &mut self.states)?;
+                    for (logits, &target) in logits_list.iter().zip(targets) {
+                        report.score(logits, target);
+                    }
+                }
+            }
+            EvalMode::Stateful => {
+                for (&input, &target) in inputs.iter().zip(targets) {
+                    let logits = models.step(input, &mut self.states)?;
+                    report.score(&logits, target);
+                }
+            }
+            // scored in `finish`, with all of the tokens at once
+            EvalMode::Stateless => {
+                if self.tokens.is_empty() {
+                    self.tokens.extend(self.last);
+                }
+                self.tokens.extend(encoding.get_ids());
+            }
+        }
+        self.last = Some(last);
+        Ok(())
+    }
+
+    /// Scores the held back text, and then the document for [EvalMode::Stateless], which requires
+    /// all of its tokens at once.
+    fn finish<M: MambaModel>(
+        mut self,
+        models: &MambaWrapper<M>,
+        report: &mut EvalReport,
+    ) -> anyhow::Result<()> {
+        let tail = std::mem::take(&mut self.tail);
+        self.score(models, &tail, report)?;
+        if self.tokens.len() < 2 {
+            return Ok(());
+        }
+        let tokens = &self.tokens;
+        let (inputs, targets) = (&tokens[..tokens.len() - 1], &tokens[1..]);
+        let logits_list = models.stateless_logits(inputs, 0)?;
+        for (logits, &target) in logits_list.iter().zip(targets) {
+            report.score(logits, target);
+        }
+        Ok(())
+    }
+}
+
+/// The length of the `text` up to its last word, including the whitespace before the word.
+///
+/// The pre-tokenizers never join a non-whitespace character with the whitespace that follows it,
+/// so the text up to there gets the same tokens whatever comes after it.
+fn stable_len(text: &str) -> usize {
+    let mut len = 0;
+    let mut previous: Option<char> = None;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() && previous.is_some_and(|previous| !previous.is_whitespace()) {
+            len = i;
+        }
+        previous = Some(c);
+    }
+    len
+}
+
+impl EvalReport {
+    fn score(&mut self, logits: &[f32], target: u32) {
+        self.nll -= log_softmax(logits)[target as usize] as f64;
+        self.tokens += 1;
+    }
+}
+
+/// How many bytes (at least, up to the end of a line) each chunk of a plain text corpus has.
+#[cfg(not(target_arch = "wasm32"))]
+pub const CORPUS_CHUNK_BYTES: usize = 1 << 16;
+
+/// Streams the documents of a local corpus.
+///
+/// For `.jsonl` files, each line is a document, either as a json string or as an object
+/// with a `"text"` field. Any other file is read as a single document, in chunks of
+/// [CORPUS_CHUNK_BYTES] that are split at line ends. The chunks still get the same tokens as
+/// the whole document, since the last word of each chunk is tokenized with the next one.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn read_corpus(
+    path: impl AsRef<std::path::Path>,
+) -> anyhow::Result<Box<dyn Iterator<Item = anyhow::Result<CorpusChunk>>>> {
+    use std::io::BufRead;
+    let path = path.as_ref();
+    if path.extension().is_some_and(|ext| ext == "jsonl") {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let documents = file
+            .lines()
+            .enumerate()
+            .filter(|(_i, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+            .map(|(i, line)| {
+                let value: serde_json::Value = serde_json::from_str(&line?)
+                    .map_err(|e| anyhow::anyhow!("line {}: invalid json: {e}", i + 1))?;
+                match value {
+                    serde_json::Value::String(text) => Ok(text.into()),
+                    serde_json::Value::Object(mut obj) => match obj.remove("text") {
+                        Some(serde_json::Value::String(text)) => Ok(text.into()),
+                        _ => anyhow::bail!("line {}: missing the \"text\" string field", i + 1),
+                    },
+                    _ => anyhow::bail!("line {}: expected a string or an object", i + 1),
+                }
+            });
+        Ok(Box::new(documents))
+    } else {
+        let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let mut continues = false;
+        let chunks = std::iter::from_fn(move || {
+            let mut text = String::new();
+            loop {
+                match file.read_line(&mut text) {
+                    Ok(0) => break,
+                    Ok(_) if text.len() >= CORPUS_CHUNK_BYTES => break,
+                    Ok(_) => {}
+                    Err(err) => return Some(Err(err.into())),
+                }
+            }
+            // an empty file is still a (single, empty) document
+            if text.is_empty() && continues {
+                return None;
+            }
+            let chunk = CorpusChunk { text, continues };
+            continues = true;
+            Some(Ok(chunk))
+        });
+        Ok(Box::new(chunks))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    const TEXT: &str = "the cat sat on the mat a b c the cat sat";
+
+    #[test]
+    fn empty_report_is_an_error() {
+        let report = EvalReport::default();
+        assert!(report.mean_nll().is_err());
+        assert!(report.perplexity().is_err());
+        assert!(report.bits_per_byte().is_err());
+    }
+
+    #[test]
+    fn plain_corpus_is_read_in_chunks() {
+        let line = "a line of the corpus\n";
+        let text = line.repeat(CORPUS_CHUNK_BYTES * 5 / 2 / line.len());
+        let path = std::env::temp_dir().join(format!("corpus-{}.txt", std::process::id()));
+        std::fs::write(&path, &text).unwrap();
+        let chunks = read_corpus(&path)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(chunks.len(), 3);
+        assert!(!chunks[0].continues);
+        assert!(chunks[1..].iter().all(|chunk| chunk.continues));
+        assert!(chunks.iter().all(|chunk| chunk.text.ends_with('\n')));
+        let joined: String = chunks.into_iter().map(|chunk| chunk.text).collect();
+        assert_eq!(joined, text);
+    }
+
+    fn eval(models: &MambaWrapper, chunks: &[&str], mode: EvalMode, window: usize) -> EvalReport {
+        let config = EvalConfig {
+            mode,
+            window,
+            ..Default::default()
+        };
+        let chunks = chunks.iter().enumerate().map(|(i, text)| {
+            anyhow::Ok(CorpusChunk {
+                text: text.to_string(),
+                continues: i > 0,
+            })
+        });
+        models.eval_corpus(chunks, &config).unwrap()
+    }
+
+    fn assert_same_nll(expected: &EvalReport, actual: &EvalReport) {
+        assert_eq!(expected.tokens, actual.tokens);
+        assert_eq!(expected.bytes, actual.bytes);
+        assert!(
+            (expected.nll - actual.nll).abs() <= 1e-4 * expected.nll.abs().max(1.),
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn the_modes_agree() {
+        let models = test_utils::models(0);
+        let stateless = eval(&models, &[TEXT], EvalMode::Stateless, 128);
+        assert_eq!(stateless.tokens, TEXT.split(' ').count());
+        for (mode, window) in [
+            (EvalMode::Stateful, 128),
+            (EvalMode::Windowed, 128),
+            (EvalMode::Windowed, 4),
+            (EvalMode::Windowed, 1),
+        ] {
+            assert_same_nll(&stateless, &eval(&models, &[TEXT], mode, window));
+        }
+    }
+
+    #[test]
+    fn words_split_across_chunks_are_tokenized_whole() {
+        assert_eq!(stable_len("the cat sat"), 7);
+        assert_eq!(stable_len("the cat  "), 7);
+        assert_eq!(stable_len("cat"), 0);
+
+        let models = test_utils::models(0);
+        let chunks = ["the cat s", "at on the mat a", " b c ", "the cat sat"];
+        assert_eq!(chunks.concat(), TEXT);
+        for mode in [EvalMode::Stateless, EvalMode::Stateful, EvalMode::Windowed] {
+            let whole = eval(&models, &[TEXT], mode, 4);
+            assert_same_nll(&whole, &eval(&models, &chunks, mode, 4));
+        }
+    }
+}
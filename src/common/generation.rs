@@ -0,0 +1,689 @@
+//! A generation loop that is shared by the native, wasm and yew frontends.
+//!
+//! A [Session] holds everything that changes during a generation (tokens, states, step index),
+//! and yields a [GenerationEvent] for each call to [Session::next_event].
+//! A [Generation] borrows the models and the logits processor so that the session can be
+//! consumed as an [Iterator].
+
+use crate::logits::{log_softmax, TokenLogprobs};
+use crate::token_output_stream::TokenOutputStream;
+use crate::{mamba, LogitsProcessorWrapper, MambaModel, MambaWrapper};
+use dfdx::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+#[cfg(target_arch = "wasm32")]
+use web_time::Instant;
+
+/// Which forward implementation to use for the generation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mode {
+    /// Each step feeds a single token and updates the states.
+    #[default]
+    Stateful,
+    /// Each step that needs new logits re-feeds the whole token sequence.
+    Stateless,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GenerationConfig {
+    pub mode: Mode,
+    /// Makes up to `sample_len` steps, counting both the prompt and the generated tokens.
+    pub sample_len: usize,
+    /// Generates up to `max_new_tokens` tokens, not counting the prompt.
+    pub max_new_tokens: Option<usize>,
+    /// The eos token is suppressed until `min_new_tokens` tokens are generated.
+    pub min_new_tokens: usize,
+    /// Whether a generated eos token should be treated as any other token.
+    pub ignore_eos: bool,
+    /// Stops the generation once any of these strings gets generated.
+    ///
+    /// The stop string itself (and anything after it) is not emitted.
+    pub stop: Vec<String>,
+    /// Wall-clock budget for the generation, counted from the [Session] creation.
+    pub time_budget: Option<Duration>,
+    /// For the [Mode::Stateful] generation, whether the prompt should be fed in a single
+    /// sequence-mode call (see [MambaWrapper::prefill]) instead of one token at a time.
+    ///
+    /// Defaults to `true`.
+    pub prefill: bool,
+    /// Whether each token should include its [TokenLogprobs], with up to this many top alternatives.
+    pub top_logprobs: Option<usize>,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self {
+            mode: Mode::default(),
+            sample_len: usize::MAX,
+            max_new_tokens: None,
+            min_new_tokens: 0,
+            ignore_eos: false,
+            stop: vec![],
+            time_budget: None,
+            prefill: true,
+            top_logprobs: None,
+        }
+    }
+}
+
+/// Why a generation has ended.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FinishReason {
+    /// The model produced the end-of-sequence token.
+    Eos,
+    /// The `sample_len` or `max_new_tokens` limit was reached.
+    Length,
+    /// One of the stop strings got generated. Contains the matched stop string.
+    Stop(String),
+    /// The `time_budget` was exhausted.
+    Deadline,
+    /// A step failed, and its error was returned instead of an event.
+    /// Contains the error message.
+    ///
+    /// There is no [GenerationEvent::Finish] for this reason.
+    Error(String),
+}
+
+/// A token that was introduced into (prompt) or produced by (sampled) the generation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenEvent {
+    /// The token id.
+    pub id: u32,
+    /// The decoded text fragment, if the token (together with the previous ones) has a valid representation.
+    pub text: Option<String>,
+    /// Whether the token was part of the prompt, as opposed to being sampled.
+    pub is_prompt: bool,
+    /// The raw logit that the model assigned to this token.
+    ///
+    /// This is `None` for the first prompt token, since no logits were produced before it,
+    /// and also for the prompt tokens that were prefilled, since their logits are not calculated.
+    pub logit: Option<f32>,
+    /// The probability (softmax over the raw logits) that the model assigned to this token.
+    ///
+    /// This is `None` whenever [TokenEvent::logit] is `None`.
+    pub prob: Option<f32>,
+    /// The logprob, rank and top alternatives of this token, if [GenerationConfig::top_logprobs] is set.
+    ///
+    /// This is `None` whenever [TokenEvent::logit] is `None`.
+    pub logprobs: Option<TokenLogprobs>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GenerationEvent {
+    Token(TokenEvent),
+    /// The generation has ended. Contains the last (not yet emitted) decoded text, if any.
+    Finish {
+        reason: FinishReason,
+        text: Option<String>,
+    },
+}
+
+/// The mutable data of an ongoing generation.
+pub struct Session {
+    pub config: GenerationConfig,
+    /// Current token step index (for logits selection).
+    pub step: usize,
+    /// Tokens being (at first) introduced into or (later) produced by the generation.
+    pub tokens: Vec<u32>,
+    /// The token the model uses to signal the end of the generation.
+    pub eos_token: u32,
+    /// The last states, for the [Mode::Stateful] generation.
+    pub states: mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+    /// How many tokens were generated (sampled), not counting the prompt.
+    pub new_tokens: usize,
+    /// How many of the `tokens` were already fed into the `states`.
+    pub fed: usize,
+    /// Logits that were already calculated but not yet consumed, for the [Mode::Stateless] generation.
+    pub(crate) stateless_logits: VecDeque<Vec<f32>>,
+    /// The logits of the last prompt token, for the prefilled [Mode::Stateful] generation.
+    pub(crate) prefilled_logits: Option<Vec<f32>>,
+    pub(crate) is_prefilled: bool,
+    /// Generated text that is held back because it may be the start of a stop string.
+    pub(crate) held_text: String,
+    pub(crate) started_at: Instant,
+    pub(crate) is_started: bool,
+    pub(crate) finish_reason: Option<FinishReason>,
+}
+
+impl Session {
+    /// Resets the tokenizer and prepares the generation for the `prompt`.
+    pub fn new<M: MambaModel>(
+        models: &mut MambaWrapper<M>,
+        prompt: &str,
+        config: GenerationConfig,
+    ) -> anyhow::Result<Self> {
+        let (tokens, eos_token) = models.reset_prompt(prompt)?;
+        let states = match config.mode {
+            Mode::Stateful => models.empty_states(1)?,
+            Mode::Stateless => vec![],
+        };
+        let mut session = Self::with_tokens(tokens, eos_token, config);
+        session.states = states;
+        Ok(session)
+    }
+
+    /// Prepares the generation for already encoded `tokens`.
+    ///
+    /// Note: the states are left empty, and the tokenizer is not reset.
+    pub fn with_tokens(tokens: Vec<u32>, eos_token: u32, config: GenerationConfig) -> Self {
+        Self {
+            config,
+            step: 0,
+            tokens,
+            eos_token,
+            states: vec![],
+            new_tokens: 0,
+            fed: 0,
+            stateless_logits: VecDeque::new(),
+            prefilled_logits: None,
+            is_prefilled: false,
+            held_text: String::new(),
+            started_at: Instant::now(),
+            is_started: false,
+            finish_reason: None,
+        }
+    }
+
+    /// Continues from `states` that already contain the first `fed` of the `tokens`,
+    /// so that only the remaining tokens need to be fed before sampling.
+    ///
+    /// At least one of the `tokens` must not have been fed, since its logits are required.
+    /// That first unfed token is not emitted, and the tokenizer is not reset.
+    pub fn continue_with(
+        tokens: Vec<u32>,
+        fed: usize,
+        states: mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+        eos_token: u32,
+        config: GenerationConfig,
+    ) -> anyhow::Result<Self> {
+        if fed >= tokens.len() {
+            anyhow::bail!("expected less than {} fed tokens, got {fed}", tokens.len());
+        }
+        let mut session = Self::with_tokens(tokens, eos_token, config);
+        session.step = fed;
+        session.fed = fed;
+        session.states = states;
+        session.is_started = true;
+        Ok(session)
+    }
+
+    /// Whether the [GenerationEvent::Finish] has already been emitted.
+    pub fn is_finished(&self) -> bool {
+        self.finish_reason.is_some()
+    }
+
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.finish_reason.as_ref()
+    }
+
+    /// Makes a single generation step.
+    ///
+    /// The first call emits the first prompt token (as if it were an implicit output),
+    /// and each call afterwards consumes one logits and emits the next token.
+    /// Returns `None` once the generation has finished.
+    pub fn next_event<M: MambaModel>(
+        &mut self,
+        models: &mut MambaWrapper<M>,
+        processor: &mut LogitsProcessorWrapper,
+    ) -> anyhow::Result<Option<GenerationEvent>> {
+        if self.is_finished() {
+            return Ok(None);
+        }
+        if let Some(event) = self.start(&mut models.tokenizer)? {
+            return Ok(Some(event));
+        }
+        if let Some(event) = self.check_limits(&mut models.tokenizer)? {
+            return Ok(Some(event));
+        }
+
+        let i = self.step;
+        let is_prompt = i + 1 < self.tokens.len();
+        let logits = match self.config.mode {
+            Mode::Stateful if self.config.prefill && is_prompt => {
+                // feeds all of the remaining prompt at once,
+                // and then only the (already known) prompt tokens get emitted
+                if !self.is_prefilled {
+                    self.is_prefilled = true;
+                    let logits = models.prefill(&self.tokens[i..], &mut self.states)?;
+                    self.fed = self.tokens.len();
+                    self.prefilled_logits = Some(logits);
+                }
+                let event = self.skip_prompt_token(&mut models.tokenizer)?;
+                return Ok(Some(event));
+            }
+            Mode::Stateful if self.prefilled_logits.is_some() => {
+                self.prefilled_logits.take().unwrap()
+            }
+            Mode::Stateful => {
+                let logits = models.step(self.tokens[i], &mut self.states)?;
+                self.fed = i + 1;
+                logits
+            }
+            Mode::Stateless => {
+                if self.stateless_logits.is_empty() {
+                    self.stateless_logits = models.stateless_logits(&self.tokens, i)?.into();
+                }
+                self.stateless_logits.pop_front().unwrap()
+            }
+        };
+        let event = self.consume_logits(&mut models.tokenizer, processor, logits)?;
+        Ok(Some(event))
+    }
+
+    /// On the first call, emits the first prompt token (if present).
+    pub(crate) fn start(
+        &mut self,
+        tokenizer: &mut TokenOutputStream,
+    ) -> anyhow::Result<Option<GenerationEvent>> {
+        if self.is_started {
+            return Ok(None);
+        }
+        self.is_started = true;
+        let Some(&id) = self.tokens.first() else {
+            return Ok(None);
+        };
+        let text = tokenizer.next_token(id)?;
+        Ok(Some(GenerationEvent::Token(TokenEvent {
+            id,
+            text,
+            is_prompt: true,
+            logit: None,
+            prob: None,
+            logprobs: None,
+        })))
+    }
+
+    /// Finishes the generation if any of the limits was reached.
+    pub(crate) fn check_limits(
+        &mut self,
+        tokenizer: &mut TokenOutputStream,
+    ) -> anyhow::Result<Option<GenerationEvent>> {
+        if self.step >= self.config.sample_len || self.tokens.is_empty() {
+            return self.finish(tokenizer, FinishReason::Length).map(Some);
+        }
+        let is_prompt = self.step + 1 < self.tokens.len();
+        if !is_prompt
+            && self
+                .config
+                .max_new_tokens
+                .is_some_and(|max| self.new_tokens >= max)
+        {
+            return self.finish(tokenizer, FinishReason::Length).map(Some);
+        }
+        if self
+            .config
+            .time_budget
+            .is_some_and(|budget| self.started_at.elapsed() >= budget)
+        {
+            return self.finish(tokenizer, FinishReason::Deadline).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Emits the next prompt token without consuming any logits.
+    ///
+    /// The states must already contain that token, as is the case after a prefill.
+    pub(crate) fn skip_prompt_token(
+        &mut self,
+        tokenizer: &mut TokenOutputStream,
+    ) -> anyhow::Result<GenerationEvent> {
+        self.step += 1;
+        let id = self.tokens[self.step];
+        let text = tokenizer.next_token(id)?;
+        Ok(GenerationEvent::Token(TokenEvent {
+            id,
+            text,
+            is_prompt: true,
+            logit: None,
+            prob: None,
+            logprobs: None,
+        }))
+    }
+
+    /// Consumes the `logits` of the current step, and emits either the next token or the finish.
+    pub(crate) fn consume_logits(
+        &mut self,
+        tokenizer: &mut TokenOutputStream,
+        processor: &mut LogitsProcessorWrapper,
+        logits: Vec<f32>,
+    ) -> anyhow::Result<GenerationEvent> {
+        let i = self.step;
+        let is_prompt = i + 1 < self.tokens.len();
+        let mut next_logits = logits.clone();
+        if !is_prompt && self.new_tokens < self.config.min_new_tokens {
+            if let Some(eos_logit) = next_logits.get_mut(self.eos_token as usize) {
+                *eos_logit = f32::NEG_INFINITY;
+            }
+        }
+        let next_token = processor.add_logits(i, &mut self.tokens, &mut next_logits)?;
+        let logit = logits[next_token as usize];
+        let prob = log_softmax(&logits)[next_token as usize].exp();
+        let logprobs = self.config.top_logprobs.map(|top_n| {
+            TokenLogprobs::from_logits(&logits, next_token, top_n, tokenizer.tokenizer())
+        });
+        if !is_prompt {
+            self.new_tokens += 1;
+            if next_token == self.eos_token && !self.config.ignore_eos {
+                return self.finish(tokenizer, FinishReason::Eos);
+            }
+        }
+        self.step += 1;
+
+        let mut text = tokenizer.next_token(next_token)?;
+        if !is_prompt && !self.config.stop.is_empty() {
+            let released = text.take().unwrap_or_default();
+            match self.check_stop(tokenizer, &released)? {
+                StopCheck::Emit(emit) => text = (!emit.is_empty()).then_some(emit),
+                StopCheck::Stopped { text, stop } => {
+                    self.finish_reason = Some(FinishReason::Stop(stop.clone()));
+                    let text = (!text.is_empty()).then_some(text);
+                    return Ok(GenerationEvent::Finish {
+                        reason: FinishReason::Stop(stop),
+                        text,
+                    });
+                }
+            }
+        }
+        Ok(GenerationEvent::Token(TokenEvent {
+            id: next_token,
+            text,
+            is_prompt,
+            logit: Some(logit),
+            prob: Some(prob),
+            logprobs,
+        }))
+    }
+
+    /// Appends the `released` text and checks for the stop strings.
+    ///
+    /// The text that the tokenizer has not yet released is also considered, so that a stop
+    /// string is detected as soon as it's generated.
+    /// The text that may be the start of a stop string is held back.
+    fn check_stop(
+        &mut self,
+        tokenizer: &TokenOutputStream,
+        released: &str,
+    ) -> anyhow::Result<StopCheck> {
+        self.held_text += released;
+        let unreleased = tokenizer.decode_rest()?.unwrap_or_default();
+        let candidate = format!("{}{unreleased}", self.held_text);
+
+        // the earliest match wins
+        let matched = self
+            .config
+            .stop
+            .iter()
+            .filter(|stop| !stop.is_empty())
+            .filter_map(|stop| candidate.find(stop.as_str()).map(|pos| (pos, stop)))
+            .min_by_key(|(pos, _stop)| *pos);
+        if let Some((pos, stop)) = matched {
+            self.held_text.clear();
+            return Ok(StopCheck::Stopped {
+                text: candidate[..pos].to_string(),
+                stop: stop.clone(),
+            });
+        }
+
+        // holds back the longest suffix that is the start of a stop string
+        let hold_from = candidate
+            .char_indices()
+            .map(|(pos, _c)| pos)
+            .find(|&pos| {
+                let suffix = &candidate[pos..];
+                self.config.stop.iter().any(|stop| stop.starts_with(suffix))
+            })
+            .unwrap_or(candidate.len())
+            .min(self.held_text.len());
+        let emit = self.held_text[..hold_from].to_string();
+        self.held_text.replace_range(..hold_from, "");
+        Ok(StopCheck::Emit(emit))
+    }
+
+    fn finish(
+        &mut self,
+        tokenizer: &TokenOutputStream,
+        reason: FinishReason,
+    ) -> anyhow::Result<GenerationEvent> {
+        self.finish_reason = Some(reason.clone());
+        let mut text = std::mem::take(&mut self.held_text);
+        if let Some(rest) = tokenizer.decode_rest()? {
+            text += &rest;
+        }
+        let text = (!text.is_empty()).then_some(text);
+        Ok(GenerationEvent::Finish { reason, text })
+    }
+}
+
+enum StopCheck {
+    /// No stop string was matched, and this text can be emitted.
+    Emit(String),
+    /// A stop string was matched, and only the text before it should be emitted.
+    Stopped { text: String, stop: String },
+}
+
+/// An [Iterator] over the events of a [Session].
+pub struct Generation<'a, M: MambaModel = mamba::Mamba<f32, Cpu>> {
+    pub models: &'a mut MambaWrapper<M>,
+    pub processor: &'a mut LogitsProcessorWrapper,
+    pub session: Session,
+}
+
+impl<'a, M: MambaModel> Iterator for Generation<'a, M> {
+    type Item = anyhow::Result<GenerationEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.session.next_event(self.models, self.processor) {
+            Ok(event) => event.map(Ok),
+            Err(err) => {
+                // avoids stepping into the same error again
+                self.session.finish_reason = Some(FinishReason::Error(format!("{err:#}")));
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl<M: MambaModel> MambaWrapper<M> {
+    /// Resets the tokenizer and starts a generation for the `prompt`.
+    pub fn generate<'a>(
+        &'a mut self,
+        prompt: &str,
+        config: GenerationConfig,
+        processor: &'a mut LogitsProcessorWrapper,
+    ) -> anyhow::Result<Generation<'a, M>> {
+        let session = Session::new(self, prompt, config)?;
+        Ok(Generation {
+            models: self,
+            processor,
+            session,
+        })
+    }
+
+    /// Continues the generation of an existing `session`, such as one restored from a [Snapshot](crate::snapshot::Snapshot).
+    ///
+    /// Note: the tokenizer is not reset.
+    pub fn resume<'a>(
+        &'a mut self,
+        session: Session,
+        processor: &'a mut LogitsProcessorWrapper,
+    ) -> Generation<'a, M> {
+        Generation {
+            models: self,
+            processor,
+            session,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logits::LogitBias;
+    use crate::test_utils;
+    use std::collections::HashMap;
+
+    /// Greedily samples the `words` in order, and then eos.
+    fn scripted(words: &str) -> LogitsProcessorWrapper {
+        let tokenizer = test_utils::word_level_tokenizer(test_utils::WORDS.split(' '));
+        let mut script = words
+            .split(' ')
+            .map(|word| tokenizer.token_to_id(word).unwrap())
+            .collect::<Vec<_>>()
+            .into_iter();
+        LogitsProcessorWrapper::new(0, None, None, 1., 0)
+            .unwrap()
+            .with(move |_: &[u32], logits: &mut [f32]| -> anyhow::Result<()> {
+                let token = script.next().unwrap_or(test_utils::EOS);
+                logits.fill(f32::NEG_INFINITY);
+                logits[token as usize] = 0.;
+                Ok(())
+            })
+    }
+
+    /// Greedily samples with the eos logit biased by `bias`.
+    fn eos_biased(bias: f32) -> LogitsProcessorWrapper {
+        LogitsProcessorWrapper::new(0, None, None, 1., 0)
+            .unwrap()
+            .with(LogitBias(HashMap::from([(test_utils::EOS, bias)])))
+    }
+
+    /// Runs the generation for the "the" prompt, and returns the events.
+    fn run(
+        config: GenerationConfig,
+        mut processor: LogitsProcessorWrapper,
+    ) -> anyhow::Result<Vec<GenerationEvent>> {
+        let mut models = test_utils::models(0);
+        models
+            .generate("the", config, &mut processor)?
+            .collect::<anyhow::Result<_>>()
+    }
+
+    fn text(events: &[GenerationEvent]) -> String {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                GenerationEvent::Token(token) => token.text.clone(),
+                GenerationEvent::Finish { text, .. } => text.clone(),
+            })
+            .collect()
+    }
+
+    fn new_tokens(events: &[GenerationEvent]) -> Vec<u32> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                GenerationEvent::Token(token) if !token.is_prompt => Some(token.id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn finish_reason(events: &[GenerationEvent]) -> &FinishReason {
+        match events.last() {
+            Some(GenerationEvent::Finish { reason, .. }) => reason,
+            last => panic!("expected a finish event, got {last:?}"),
+        }
+    }
+
+    #[test]
+    fn a_stop_string_can_span_tokens() -> anyhow::Result<()> {
+        let config = GenerationConfig {
+            max_new_tokens: Some(8),
+            stop: vec!["cat sat".into()],
+            ..Default::default()
+        };
+        let events = run(config, scripted("a cat sat on"))?;
+        assert_eq!(
+            finish_reason(&events),
+            &FinishReason::Stop("cat sat".into())
+        );
+        // "cat" is held back, and the stop string is not emitted
+        assert_eq!(text(&events), "the a ");
+        Ok(())
+    }
+
+    #[test]
+    fn held_back_text_is_released() -> anyhow::Result<()> {
+        let config = GenerationConfig {
+            max_new_tokens: Some(3),
+            stop: vec!["cat sat".into()],
+            ..Default::default()
+        };
+        let events = run(config, scripted("a cat on"))?;
+        assert_eq!(finish_reason(&events), &FinishReason::Length);
+        assert_eq!(text(&events), "the a cat on");
+        // "cat" only got released together with "on"
+        let GenerationEvent::Token(cat) = &events[events.len() - 3] else {
+            panic!("expected a token event");
+        };
+        assert_eq!(cat.text.as_deref(), Some(" "));
+        Ok(())
+    }
+
+    #[test]
+    fn min_new_tokens_suppresses_eos() -> anyhow::Result<()> {
+        let config = GenerationConfig {
+            min_new_tokens: 3,
+            ..Default::default()
+        };
+        let events = run(config, eos_biased(100.))?;
+        assert_eq!(finish_reason(&events), &FinishReason::Eos);
+        // the eos token itself is not emitted as a token
+        let tokens = new_tokens(&events);
+        assert_eq!(tokens.len(), 3);
+        assert!(!tokens.contains(&test_utils::EOS));
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_eos_runs_to_max_new_tokens() -> anyhow::Result<()> {
+        let config = GenerationConfig {
+            max_new_tokens: Some(4),
+            ignore_eos: true,
+            ..Default::default()
+        };
+        let events = run(config, eos_biased(100.))?;
+        assert_eq!(finish_reason(&events), &FinishReason::Length);
+        assert_eq!(new_tokens(&events), vec![test_utils::EOS; 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn an_exhausted_time_budget_is_a_deadline() -> anyhow::Result<()> {
+        let config = GenerationConfig {
+            time_budget: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let events = run(config, eos_biased(0.))?;
+        assert_eq!(finish_reason(&events), &FinishReason::Deadline);
+        assert!(new_tokens(&events).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn a_failed_step_finishes_with_the_error() -> anyhow::Result<()> {
+        let mut models = test_utils::models(0);
+        let mut processor = LogitsProcessorWrapper::new(0, None, None, 1., 0)
+            .unwrap()
+            .with(|_: &[u32], _: &mut [f32]| -> anyhow::Result<()> {
+                anyhow::bail!("no logits today")
+            });
+        let mut generation =
+            models.generate("the cat", GenerationConfig::default(), &mut processor)?;
+        // the prompt tokens are emitted before any logits get processed
+        for _ in 0..2 {
+            assert!(generation.next().unwrap().is_ok());
+        }
+        let err = generation.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("no logits today"));
+        assert!(generation.next().is_none());
+        assert_eq!(
+            generation.session.finish_reason(),
+            Some(&FinishReason::Error("no logits today".into()))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,702 @@
+//! Logits processing and sampling.
+//!
+//! A [LogitsProcessorWrapper] runs a chain of [LogitsProcessor] over the raw logits,
+//! and then samples the next token with a [Sampler] (temperature and top-p).
+//!
+//! Everything works in place over a borrowed `&mut [f32]`, such as the logits that were just
+//! copied out of the dfdx tensor, so no extra tensor gets built for each sampled token.
+//!
+//! Built-in processors: [RepeatPenalty], [FrequencyPresencePenalty], [LogitBias],
+//! [TopK], [MinP], [TypicalP] and [TailFree].
+//! Custom processors can be plugged in by implementing [LogitsProcessor],
+//! which is also implemented for closures.
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::SeedableRng;
+use std::collections::HashMap;
+
+/// Modifies the logits before the next token gets sampled.
+///
+/// Tokens can be excluded from the sampling by setting their logits to `f32::NEG_INFINITY`.
+pub trait LogitsProcessor: Send {
+    /// `tokens` contains the context up to (and including) the token that produced the `logits`.
+    fn process(&mut self, tokens: &[u32], logits: &mut [f32]) -> anyhow::Result<()>;
+}
+
+impl<F> LogitsProcessor for F
+where
+    F: FnMut(&[u32], &mut [f32]) -> anyhow::Result<()> + Send,
+{
+    fn process(&mut self, tokens: &[u32], logits: &mut [f32]) -> anyhow::Result<()> {
+        self(tokens, logits)
+    }
+}
+
+pub struct LogitsProcessorWrapper {
+    sampler: Sampler,
+    processors: Vec<Box<dyn LogitsProcessor>>,
+    seed: u64,
+    temp: Option<f64>,
+    top_p: Option<f64>,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+    /// How many tokens were sampled, which is how far the rng has advanced from the `seed`.
+    samples: u64,
+}
+
+impl LogitsProcessorWrapper {
+    /// Creates a sampler and, if `repeat_penalty != 1`, a [RepeatPenalty] processor.
+    ///
+    /// Fails if the `repeat_penalty` is not positive (see [RepeatPenalty::new]).
+    pub fn new(
+        seed: u64,
+        temp: Option<f64>,
+        top_p: Option<f64>,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+    ) -> anyhow::Result<Self> {
+        let sampler = Sampler::new(seed, temp, top_p);
+        let mut processors: Vec<Box<dyn LogitsProcessor>> = vec![];
+        let penalty = RepeatPenalty::new(repeat_penalty, repeat_last_n)?;
+        if repeat_penalty != 1. {
+            processors.push(Box::new(penalty));
+        }
+        Ok(Self {
+            sampler,
+            processors,
+            seed,
+            temp,
+            top_p,
+            repeat_penalty,
+            repeat_last_n,
+            samples: 0,
+        })
+    }
+
+    /// Appends a `processor` to the end of the chain.
+    pub fn with(mut self, processor: impl LogitsProcessor + 'static) -> Self {
+        self.push(Box::new(processor));
+        self
+    }
+
+    /// Appends a `processor` to the end of the chain.
+    pub fn push(&mut self, processor: Box<dyn LogitsProcessor>) {
+        self.processors.push(processor);
+    }
+
+    /// The parameters from [LogitsProcessorWrapper::new], in the same order.
+    pub fn params(&self) -> (u64, Option<f64>, Option<f64>, f32, usize) {
+        (
+            self.seed,
+            self.temp,
+            self.top_p,
+            self.repeat_penalty,
+            self.repeat_last_n,
+        )
+    }
+
+    /// How many tokens were sampled so far.
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Advances the rng as if `samples` tokens were sampled.
+    ///
+    /// Each sample draws a single value from the rng regardless of the logits,
+    /// so this replays the draws on a dummy logits.
+    pub fn skip_samples(&mut self, samples: u64) -> anyhow::Result<()> {
+        for _ in 0..samples {
+            self.sampler.sample(&mut [0f32, 0.])?;
+            self.samples += 1;
+        }
+        Ok(())
+    }
+
+    /// Add logits that represents a token.
+    ///
+    /// `i` is the i-th call. For the first call, `i` should be `0`.
+    ///
+    /// The `logits` are processed (and then overwritten by the sampler) in place.
+    pub fn add_logits(
+        &mut self,
+        i: usize,
+        tokens: &mut Vec<u32>,
+        logits: &mut [f32],
+    ) -> anyhow::Result<u32> {
+        let next_token;
+        if i + 1 < tokens.len() {
+            // don't try to predict the next token (it was pre-defined)
+            // also don't increment the "tokens" list (this token was already part of the list)
+            next_token = tokens[i + 1];
+
+            // should it still sample? idk
+            // let _discarded_token = logits_processor.sample(&logits)?;
+        } else {
+            for processor in self.processors.iter_mut() {
+                processor.process(&tokens[..i + 1], logits)?;
+            }
+            // otherwise the softmax would be NaN everywhere
+            anyhow::ensure!(
+                logits.iter().any(|&logit| logit > f32::NEG_INFINITY),
+                "the logits processors masked all of the tokens"
+            );
+
+            // try to predict the next token
+            next_token = self.sampler.sample(logits)?;
+            self.samples += 1;
+            // add the token to the "tokens" list
+            tokens.push(next_token);
+        }
+        Ok(next_token)
+    }
+
+    /// Same as [LogitsProcessorWrapper::add_logits], but for logits that are in a candle tensor.
+    #[cfg(feature = "candle")]
+    pub fn add_logits_tensor(
+        &mut self,
+        i: usize,
+        tokens: &mut Vec<u32>,
+        logits: &candle_core::Tensor,
+    ) -> anyhow::Result<u32> {
+        let mut logits = logits
+            .to_dtype(candle_core::DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        self.add_logits(i, tokens, &mut logits)
+    }
+}
+
+/// Samples a token from the logits, with an optional temperature and top-p (nucleus sampling).
+///
+/// Without a temperature the most likely token is taken and the rng is not used.
+/// Otherwise each sample draws a single value from the rng, which is seeded by `seed`.
+///
+/// This follows candle's `LogitsProcessor`, so the same seed samples the same tokens.
+pub struct Sampler {
+    rng: rand::rngs::StdRng,
+    temp: Option<f64>,
+    top_p: Option<f64>,
+    /// Reused across the samples, for the top-p ordering.
+    indices: Vec<usize>,
+}
+
+impl Sampler {
+    pub fn new(seed: u64, temp: Option<f64>, top_p: Option<f64>) -> Self {
+        let temp = temp.filter(|&temp| temp >= 1e-7);
+        Self {
+            rng: rand::rngs::StdRng::seed_from_u64(seed),
+            temp,
+            top_p,
+            indices: vec![],
+        }
+    }
+
+    /// Samples a token from the `logits`, which get overwritten by the probabilities.
+    pub fn sample(&mut self, logits: &mut [f32]) -> anyhow::Result<u32> {
+        let Some(temp) = self.temp else {
+            return Ok(argmax(logits));
+        };
+        let scale = (1. / temp) as f32;
+        for logit in logits.iter_mut() {
+            *logit *= scale;
+        }
+        softmax_in_place(logits);
+        let probs = logits;
+        match self.top_p {
+            Some(top_p) if 0. < top_p && top_p < 1. => self.sample_top_p(probs, top_p as f32),
+            _ => self.sample_multinomial(probs),
+        }
+    }
+
+    /// Zeroes the least likely tokens once the cumulative probability reaches `top_p`.
+    fn sample_top_p(&mut self, probs: &mut [f32], top_p: f32) -> anyhow::Result<u32> {
+        self.indices.clear();
+        self.indices.extend(0..probs.len());
+        self.indices.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+        let mut cumulative = 0.;
+        for &i in &self.indices {
+            if cumulative >= top_p {
+                probs[i] = 0.;
+            } else {
+                cumulative += probs[i];
+            }
+        }
+        self.sample_multinomial(probs)
+    }
+
+    fn sample_multinomial(&mut self, probs: &[f32]) -> anyhow::Result<u32> {
+        let distribution = WeightedIndex::new(probs)
+            .map_err(|e| anyhow::anyhow!("cannot sample from the probabilities: {e}"))?;
+        Ok(distribution.sample(&mut self.rng) as u32)
+    }
+}
+
+/// The index of the largest logit.
+fn argmax(logits: &[f32]) -> u32 {
+    logits
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i as u32)
+        .unwrap_or_default()
+}
+
+/// Penalizes the tokens that appeared in the last `last_n` tokens before the current one,
+/// or that are the current one.
+///
+/// Positive logits are divided by the `penalty`, and negative logits are multiplied by it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RepeatPenalty {
+    penalty: f32,
+    last_n: usize,
+}
+
+impl RepeatPenalty {
+    /// The `penalty` must be positive.
+    pub fn new(penalty: f32, last_n: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            penalty.is_finite() && penalty > 0.,
+            "the repeat penalty must be positive, got {penalty}"
+        );
+        Ok(Self { penalty, last_n })
+    }
+}
+
+impl LogitsProcessor for RepeatPenalty {
+    fn process(&mut self, tokens: &[u32], logits: &mut [f32]) -> anyhow::Result<()> {
+        let start_at = tokens.len().saturating_sub(self.last_n + 1);
+        let mut seen = std::collections::HashSet::new();
+        for &token in &tokens[start_at..] {
+            if !seen.insert(token) {
+                continue;
+            }
+            if let Some(logit) = logits.get_mut(token as usize) {
+                if *logit >= 0. {
+                    *logit /= self.penalty
+                } else {
+                    *logit *= self.penalty
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Subtracts `count * frequency + presence` from the logits of the tokens that appeared
+/// (`count` times) in the last `last_n` tokens.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrequencyPresencePenalty {
+    pub frequency: f32,
+    pub presence: f32,
+    /// If `None`, all of the tokens are considered.
+    pub last_n: Option<usize>,
+}
+
+impl LogitsProcessor for FrequencyPresencePenalty {
+    fn process(&mut self, tokens: &[u32], logits: &mut [f32]) -> anyhow::Result<()> {
+        let start_at = match self.last_n {
+            Some(last_n) => tokens.len().saturating_sub(last_n),
+            None => 0,
+        };
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for &token in &tokens[start_at..] {
+            *counts.entry(token).or_default() += 1;
+        }
+        for (token, count) in counts {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit -= count as f32 * self.frequency + self.presence;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds a fixed bias to the logits of some tokens.
+///
+/// A bias of `f32::NEG_INFINITY` bans the token.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LogitBias(pub HashMap<u32, f32>);
+
+impl LogitsProcessor for LogitBias {
+    fn process(&mut self, _tokens: &[u32], logits: &mut [f32]) -> anyhow::Result<()> {
+        for (&token, &bias) in self.0.iter() {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit += bias;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Only keeps the `k` most likely tokens.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopK(usize);
+
+impl TopK {
+    /// `k` must be at least `1`.
+    pub fn new(k: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(k >= 1, "top-k must keep at least one token");
+        Ok(Self(k))
+    }
+}
+
+impl LogitsProcessor for TopK {
+    fn process(&mut self, _tokens: &[u32], logits: &mut [f32]) -> anyhow::Result<()> {
+        let sorted = sorted_desc(logits);
+        mask_except(logits, &sorted[..self.0.min(sorted.len())]);
+        Ok(())
+    }
+}
+
+/// Only keeps the tokens whose probability is at least `p` times the probability of the most likely token.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MinP(f32);
+
+impl MinP {
+    /// `p` must be in `[0, 1]`, as a larger `p` would mask all of the tokens.
+    pub fn new(p: f32) -> anyhow::Result<Self> {
+        anyhow::ensure!((0. ..=1.).contains(&p), "min-p must be in [0, 1], got {p}");
+        Ok(Self(p))
+    }
+}
+
+impl LogitsProcessor for MinP {
+    fn process(&mut self, _tokens: &[u32], logits: &mut [f32]) -> anyhow::Result<()> {
+        let probs = softmax(logits);
+        let max = probs.iter().copied().fold(0f32, f32::max);
+        let threshold = max * self.0;
+        for (logit, prob) in logits.iter_mut().zip(probs) {
+            if prob < threshold {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Locally typical sampling: keeps the tokens whose information content is the closest
+/// to the entropy, until their cumulative probability reaches `p`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypicalP(f32);
+
+impl TypicalP {
+    /// `p` must be in `(0, 1]`.
+    pub fn new(p: f32) -> anyhow::Result<Self> {
+        anyhow::ensure!(p > 0. && p <= 1., "typical-p must be in (0, 1], got {p}");
+        Ok(Self(p))
+    }
+}
+
+impl LogitsProcessor for TypicalP {
+    fn process(&mut self, _tokens: &[u32], logits: &mut [f32]) -> anyhow::Result<()> {
+        let probs = softmax(logits);
+        let entropy: f32 = probs
+            .iter()
+            .filter(|&&p| p > 0.)
+            .map(|&p| -p * p.ln())
+            .sum();
+        let mut indices: Vec<usize> = (0..probs.len()).filter(|&i| probs[i] > 0.).collect();
+        let distance = |i: usize| (-probs[i].ln() - entropy).abs();
+        indices.sort_by(|&a, &b| distance(a).total_cmp(&distance(b)));
+        let keep = keep_until_cumulative(&indices, &probs, self.0);
+        mask_except(logits, &indices[..keep]);
+        Ok(())
+    }
+}
+
+/// Tail free sampling: removes the tail of the sorted probabilities, where the tail begins once the
+/// cumulative (normalized) second derivative of the probabilities exceeds `z`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TailFree(f32);
+
+impl TailFree {
+    /// `z` must be in `(0, 1]`.
+    pub fn new(z: f32) -> anyhow::Result<Self> {
+        anyhow::ensure!(z > 0. && z <= 1., "tail free z must be in (0, 1], got {z}");
+        Ok(Self(z))
+    }
+}
+
+impl LogitsProcessor for TailFree {
+    fn process(&mut self, _tokens: &[u32], logits: &mut [f32]) -> anyhow::Result<()> {
+        let probs = softmax(logits);
+        let sorted = sorted_desc(&probs);
+        if sorted.len() <= 2 {
+            return Ok(());
+        }
+        let first: Vec<f32> = sorted
+            .windows(2)
+            .map(|w| probs[w[0]] - probs[w[1]])
+            .collect();
+        let second: Vec<f32> = first.windows(2).map(|w| (w[0] - w[1]).abs()).collect();
+        let sum: f32 = second.iter().sum();
+        if sum <= 0. {
+            return Ok(());
+        }
+        let mut keep = sorted.len();
+        let mut cumulative = 0.;
+        for (i, d) in second.iter().enumerate() {
+            cumulative += d / sum;
+            if cumulative > self.0 && i >= 1 {
+                keep = i;
+                break;
+            }
+        }
+        mask_except(logits, &sorted[..keep]);
+        Ok(())
+    }
+}
+
+/// The softmax of the `logits`.
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let mut probs = logits.to_vec();
+    softmax_in_place(&mut probs);
+    probs
+}
+
+/// Replaces the `logits` by their softmax.
+pub fn softmax_in_place(logits: &mut [f32]) {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.;
+    for logit in logits.iter_mut() {
+        *logit = (*logit - max).exp();
+        sum += *logit;
+    }
+    for prob in logits.iter_mut() {
+        *prob /= sum;
+    }
+}
+
+/// The indices of `values`, sorted by decreasing value.
+fn sorted_desc(values: &[f32]) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..values.len()).collect();
+    indices.sort_by(|&a, &b| values[b].total_cmp(&values[a]));
+    indices
+}
+
+/// How many of the `indices` (in order) are needed for their cumulative probability to reach `p`.
+fn keep_until_cumulative(indices: &[usize], probs: &[f32], p: f32) -> usize {
+    let mut cumulative = 0.;
+    for (keep, &i) in indices.iter().enumerate() {
+        cumulative += probs[i];
+        if cumulative >= p {
+            return keep + 1;
+        }
+    }
+    indices.len()
+}
+
+/// Sets all logits to `f32::NEG_INFINITY`, except for the ones at `keep`.
+fn mask_except(logits: &mut [f32], keep: &[usize]) {
+    let kept: Vec<(usize, f32)> = keep.iter().map(|&i| (i, logits[i])).collect();
+    logits.fill(f32::NEG_INFINITY);
+    for (i, logit) in kept {
+        logits[i] = logit;
+    }
+}
+
+/// The log-probability of a token, as in OpenAI-style `logprobs`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopLogprob {
+    pub id: u32,
+    /// The decoded token, if it has a valid representation on its own.
+    pub text: Option<String>,
+    pub logprob: f32,
+}
+
+/// The log-probability and rank of a chosen token, and the most likely alternatives.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenLogprobs {
+    pub logprob: f32,
+    /// How many tokens were more likely than the chosen one. The most likely token has rank `0`.
+    pub rank: usize,
+    /// The most likely tokens, most likely first (which may include the chosen token).
+    pub top: Vec<TopLogprob>,
+}
+
+impl TokenLogprobs {
+    /// Calculates the logprobs of the `chosen` token from the raw `logits`,
+    /// including the `top_n` most likely alternatives.
+    pub fn from_logits(
+        logits: &[f32],
+        chosen: u32,
+        top_n: usize,
+        tokenizer: &tokenizers::Tokenizer,
+    ) -> Self {
+        let log_probs = log_softmax(logits);
+        let logprob = log_probs[chosen as usize];
+        let rank = log_probs.iter().filter(|&&lp| lp > logprob).count();
+        let top = sorted_desc(&log_probs)
+            .into_iter()
+            .take(top_n)
+            .map(|id| TopLogprob {
+                id: id as u32,
+                text: tokenizer
+                    .decode(&[id as u32], false)
+                    .ok()
+                    .filter(|text| !text.contains('\u{FFFD}')),
+                logprob: log_probs[id],
+            })
+            .collect();
+        Self { logprob, rank, top }
+    }
+}
+
+/// The log-softmax of the `logits`.
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln();
+    logits.iter().map(|l| l - max - log_sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kept(logits: &[f32]) -> Vec<usize> {
+        (0..logits.len())
+            .filter(|&i| logits[i] > f32::NEG_INFINITY)
+            .collect()
+    }
+
+    #[test]
+    fn repeat_penalty_covers_the_current_token_and_the_last_n_before_it() -> anyhow::Result<()> {
+        let mut logits = vec![2., 2., -2., 2., 2.];
+        RepeatPenalty::new(2., 2)?.process(&[0, 1, 2, 3, 3], &mut logits)?;
+        assert_eq!(logits, vec![2., 2., -4., 1., 2.]);
+        Ok(())
+    }
+
+    #[test]
+    fn frequency_presence_penalty_counts_the_tokens() -> anyhow::Result<()> {
+        let mut logits = vec![0.; 4];
+        let mut penalty = FrequencyPresencePenalty {
+            frequency: 1.,
+            presence: 0.5,
+            last_n: Some(3),
+        };
+        penalty.process(&[1, 2, 2, 3], &mut logits)?;
+        assert_eq!(logits, vec![0., 0., -2.5, -1.5]);
+        Ok(())
+    }
+
+    #[test]
+    fn logit_bias_bans_tokens() -> anyhow::Result<()> {
+        let mut logits = vec![1.; 3];
+        LogitBias(HashMap::from([(1, f32::NEG_INFINITY), (2, 0.5)])).process(&[], &mut logits)?;
+        assert_eq!(logits, vec![1., f32::NEG_INFINITY, 1.5]);
+        Ok(())
+    }
+
+    #[test]
+    fn top_k_keeps_the_largest() -> anyhow::Result<()> {
+        let mut logits = vec![0.1, 3., -1., 2., 0.];
+        TopK::new(2)?.process(&[], &mut logits)?;
+        assert_eq!(kept(&logits), vec![1, 3]);
+        assert_eq!(logits[1], 3.);
+        assert!(TopK::new(0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn min_p_is_relative_to_the_most_likely() -> anyhow::Result<()> {
+        // probabilities proportional to 4, 2, 1
+        let mut logits = vec![4f32.ln(), 2f32.ln(), 0.];
+        MinP::new(0.4)?.process(&[], &mut logits)?;
+        assert_eq!(kept(&logits), vec![0, 1]);
+
+        let mut logits = vec![4f32.ln(), 2f32.ln(), 0.];
+        MinP::new(1.)?.process(&[], &mut logits)?;
+        assert_eq!(kept(&logits), vec![0]);
+
+        assert!(MinP::new(1.5).is_err());
+        assert!(MinP::new(-0.1).is_err());
+        assert!(MinP::new(f32::NAN).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn typical_p_keeps_the_tokens_closest_to_the_entropy() -> anyhow::Result<()> {
+        // a uniform distribution keeps all of the tokens, and a sharp one keeps the peak
+        let mut logits = vec![0.; 4];
+        TypicalP::new(1.)?.process(&[], &mut logits)?;
+        assert_eq!(kept(&logits), vec![0, 1, 2, 3]);
+
+        let mut logits = vec![10., 0., 0., 0.];
+        TypicalP::new(0.5)?.process(&[], &mut logits)?;
+        assert_eq!(kept(&logits), vec![0]);
+
+        assert!(TypicalP::new(0.).is_err());
+        assert!(TypicalP::new(1.1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tail_free_cuts_where_the_curvature_accumulates() -> anyhow::Result<()> {
+        // the second derivatives are 0.1, 0.04 and 0.19, and then 0
+        let probs = [0.4f32, 0.35, 0.2, 0.01, 0.01, 0.01, 0.01, 0.01];
+        let mut logits: Vec<f32> = probs.iter().map(|p| p.ln()).collect();
+        TailFree::new(0.5)?.process(&[], &mut logits)?;
+        assert_eq!(kept(&logits), vec![0, 1]);
+        assert!(TailFree::new(0.).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_repeat_penalties_are_rejected() {
+        for penalty in [0., -1.1, f32::NAN, f32::INFINITY] {
+            assert!(LogitsProcessorWrapper::new(0, None, None, penalty, 64).is_err());
+        }
+        assert!(LogitsProcessorWrapper::new(0, None, None, 1., 0).is_ok());
+    }
+
+    #[test]
+    fn masking_all_tokens_is_an_error() {
+        let mut processor = LogitsProcessorWrapper::new(0, Some(1.), None, 1., 0)
+            .unwrap()
+            .with(LogitBias(HashMap::from([
+                (0, f32::NEG_INFINITY),
+                (1, f32::NEG_INFINITY),
+            ])));
+        let result = processor.add_logits(0, &mut vec![0], &mut [1., 2.]);
+        assert!(result.is_err());
+        assert_eq!(processor.samples(), 0);
+    }
+
+    #[test]
+    fn greedy_takes_the_argmax() -> anyhow::Result<()> {
+        let mut sampler = Sampler::new(0, None, None);
+        assert_eq!(sampler.sample(&mut [0., 3., 1.])?, 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "candle")]
+    #[test]
+    fn sampler_matches_candle() -> anyhow::Result<()> {
+        use candle_core::{Device, Tensor};
+        use rand::distributions::Uniform;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let uniform = Uniform::new(-5f32, 5.);
+        let logits: Vec<Vec<f32>> = (0..64)
+            .map(|_| uniform.sample_iter(&mut rng).take(100).collect())
+            .collect();
+        for (temp, top_p) in [
+            (None, None),
+            (Some(0.7), None),
+            (Some(1.), Some(0.9)),
+            (Some(1.3), Some(0.5)),
+        ] {
+            for seed in [0, 42] {
+                let mut sampler = Sampler::new(seed, temp, top_p);
+                let mut candle =
+                    candle_transformers::generation::LogitsProcessor::new(seed, temp, top_p);
+                for logits in &logits {
+                    let expected = candle.sample(&Tensor::new(logits.as_slice(), &Device::Cpu)?)?;
+                    assert_eq!(sampler.sample(&mut logits.clone())?, expected);
+                }
+            }
+        }
+        Ok(())
+    }
+}
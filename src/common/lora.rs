@@ -0,0 +1,1081 @@
+//! Low-rank adapters (LoRA) for the `in_proj`, `x_proj` and `out_proj` of each block.
+//!
+//! Each adapted projection gets an extra `scale * B·A` term, where `A: (rank, in)` is randomly
+//! initialized, `B: (out, rank)` starts as zeros (so a new adapter changes nothing) and
+//! `scale = alpha / rank`. Only the adapters get trained, while the base weights stay frozen.
+//!
+//! A [LoraMamba] runs the base model with an optional [LoraAdapter], which can be switched at
+//! runtime without reloading the base model. An adapter can also be merged into the base weights
+//! (see [LoraAdapter::merge_into]), after which it has no extra cost.
+//!
+//! The adapters are saved (and loaded) as small safetensors files, with their `rank`, `alpha` and
+//! targets in the metadata (see [LoraAdapter::to_safetensors]).
+
+use crate::mamba::prefill::{
+    check_input_states, mamba_block_try_forward, rms_norm_mixed, BlockInputWithStates, BlockParts,
+    MixedDevice, VocabInputWithStates, RMS_NORM_EPS,
+};
+use crate::mamba::stateless::{BlockInput, VocabInput};
+use crate::mamba::{
+    Batch, DConv, DInner, DModel, DState, DtRank, Mamba, MambaBlockDyn, Sequence, Vocab,
+};
+use crate::model::MambaModel;
+use crate::precision::MambaDtype;
+use crate::train::{self, StepReport, TrainConfig};
+use dfdx::prelude::*;
+use safetensors::tensor::{Dtype as StDtype, SafeTensors, TensorView};
+use std::collections::HashMap;
+
+/// A projection of the [MambaBlock] that can be adapted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LoraTarget {
+    InProj,
+    XProj,
+    OutProj,
+}
+
+impl LoraTarget {
+    pub const ALL: [LoraTarget; 3] = [LoraTarget::InProj, LoraTarget::XProj, LoraTarget::OutProj];
+
+    /// The name of the projection in the [MambaBlock], also used in the safetensors files.
+    pub fn name(self) -> &'static str {
+        match self {
+            LoraTarget::InProj => "in_proj",
+            LoraTarget::XProj => "x_proj",
+            LoraTarget::OutProj => "out_proj",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|target| target.name() == name)
+    }
+
+    /// The `(out, in)` weight of the projection.
+    pub fn weight<E: Dtype, D: Device<E>>(
+        self,
+        block: &MambaBlockDyn<E, D>,
+    ) -> &Tensor<(usize, usize), E, D> {
+        match self {
+            LoraTarget::InProj => &block.in_proj.weight,
+            LoraTarget::XProj => &block.x_proj.weight,
+            LoraTarget::OutProj => &block.out_proj.weight,
+        }
+    }
+
+    fn weight_mut<E: Dtype, D: Device<E>>(
+        self,
+        block: &mut MambaBlockDyn<E, D>,
+    ) -> &mut Tensor<(usize, usize), E, D> {
+        match self {
+            LoraTarget::InProj => &mut block.in_proj.weight,
+            LoraTarget::XProj => &mut block.x_proj.weight,
+            LoraTarget::OutProj => &mut block.out_proj.weight,
+        }
+    }
+}
+
+impl std::fmt::Display for LoraTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoraConfig {
+    /// Defaults to `8`.
+    pub rank: usize,
+    /// The adapters are scaled by `alpha / rank`.
+    ///
+    /// Defaults to `16`.
+    pub alpha: f32,
+    /// Which projections get adapted, in every layer.
+    ///
+    /// Defaults to all of them.
+    pub targets: Vec<LoraTarget>,
+}
+
+impl Default for LoraConfig {
+    fn default() -> Self {
+        Self {
+            rank: 8,
+            alpha: 16.,
+            targets: LoraTarget::ALL.to_vec(),
+        }
+    }
+}
+
+impl LoraConfig {
+    pub fn scale(&self) -> f32 {
+        self.alpha / self.rank as f32
+    }
+}
+
+/// The low-rank pair of a single projection.
+#[derive(Default, Debug, Clone, CustomModule)]
+#[built(LoraLinear)]
+pub struct LoraLinearConfig {
+    /// `in -> rank`.
+    #[module]
+    pub a: MatMulConfig<usize, usize>,
+    /// `rank -> out`.
+    #[module]
+    pub b: MatMulConfig<usize, usize>,
+}
+
+/// The adapters of all layers. For each projection, there's either an adapter per layer or none.
+#[derive(Default, Debug, Clone, CustomModule)]
+#[built(LoraLayers)]
+pub struct LoraLayersConfig {
+    #[module]
+    pub in_proj: Vec<LoraLinearConfig>,
+    #[module]
+    pub x_proj: Vec<LoraLinearConfig>,
+    #[module]
+    pub out_proj: Vec<LoraLinearConfig>,
+}
+
+impl LoraLayersConfig {
+    fn get_mut(&mut self, target: LoraTarget) -> &mut Vec<LoraLinearConfig> {
+        match target {
+            LoraTarget::InProj => &mut self.in_proj,
+            LoraTarget::XProj => &mut self.x_proj,
+            LoraTarget::OutProj => &mut self.out_proj,
+        }
+    }
+}
+
+impl<E: Dtype, D: Device<E>> LoraLayers<E, D> {
+    /// The adapters of the `target`, one per layer (or empty if not adapted).
+    pub fn get(&self, target: LoraTarget) -> &Vec<LoraLinear<E, D>> {
+        match target {
+            LoraTarget::InProj => &self.in_proj,
+            LoraTarget::XProj => &self.x_proj,
+            LoraTarget::OutProj => &self.out_proj,
+        }
+    }
+
+    fn get_mut(&mut self, target: LoraTarget) -> &mut Vec<LoraLinear<E, D>> {
+        match target {
+            LoraTarget::InProj => &mut self.in_proj,
+            LoraTarget::XProj => &mut self.x_proj,
+            LoraTarget::OutProj => &mut self.out_proj,
+        }
+    }
+}
+
+/// The adapters for a model, with their [LoraConfig].
+#[derive(Clone, Debug)]
+pub struct LoraAdapter {
+    pub config: LoraConfig,
+    pub layers: LoraLayers<f32, Cpu>,
+}
+
+impl LoraAdapter {
+    /// Creates adapters that match the `mamba` projections.
+    ///
+    /// The `A` matrices are randomly initialized from the `seed`, and the `B` matrices are zeros.
+    pub fn new<E: Dtype>(
+        mamba: &Mamba<E, Cpu>,
+        config: LoraConfig,
+        seed: u64,
+    ) -> anyhow::Result<Self> {
+        if config.rank == 0 {
+            anyhow::bail!("the lora rank must not be zero");
+        }
+        if config.targets.is_empty() {
+            anyhow::bail!("missing the lora targets");
+        }
+        let mut config = config;
+        config.targets.sort_by_key(|target| target.name());
+        config.targets.dedup();
+
+        let mut layers_config = LoraLayersConfig::default();
+        for layer in mamba.layers.iter() {
+            let block = &layer.res.0 .1;
+            for &target in &config.targets {
+                let (out, inp) = *target.weight(block).shape();
+                layers_config.get_mut(target).push(LoraLinearConfig {
+                    a: MatMulConfig {
+                        inp,
+                        out: config.rank,
+                    },
+                    b: MatMulConfig {
+                        inp: config.rank,
+                        out,
+                    },
+                });
+            }
+        }
+        let device = Cpu::seed_from_u64(seed);
+        let mut layers: LoraLayers<f32, Cpu> = device.try_build_module::<f32>(layers_config)?;
+        for target in LoraTarget::ALL {
+            for lora in layers.get_mut(target).iter_mut() {
+                lora.b.weight = device.try_zeros_like(lora.b.weight.shape())?;
+            }
+        }
+        Ok(Self { config, layers })
+    }
+
+    /// Checks that the adapters match the `mamba` projections.
+    pub fn check<E: Dtype>(&self, mamba: &Mamba<E, Cpu>) -> anyhow::Result<()> {
+        for &target in &self.config.targets {
+            let adapters = self.layers.get(target);
+            if adapters.len() != mamba.layers.len() {
+                anyhow::bail!(
+                    "the model has {} layers but the {target} adapters have {}",
+                    mamba.layers.len(),
+                    adapters.len()
+                );
+            }
+            for (i, (layer, lora)) in mamba.layers.iter().zip(adapters).enumerate() {
+                let (out, inp) = *target.weight(&layer.res.0 .1).shape();
+                let (rank, a_inp) = *lora.a.weight.shape();
+                let (b_out, b_rank) = *lora.b.weight.shape();
+                if (a_inp, b_out) != (inp, out)
+                    || (rank, b_rank) != (self.config.rank, self.config.rank)
+                {
+                    anyhow::bail!(
+                        "layer {i}: the {target} adapter has (out, in) = {:?} but the model has {:?}",
+                        (b_out, a_inp),
+                        (out, inp)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// How many parameters the adapters have.
+    pub fn num_params(&self) -> usize {
+        LoraTarget::ALL
+            .into_iter()
+            .flat_map(|target| self.layers.get(target))
+            .map(|lora| lora.a.weight.shape().num_elements() + lora.b.weight.shape().num_elements())
+            .sum()
+    }
+
+    /// `scale * B·A` of the `target` in the layer `i`, with the same shape as the projection weight.
+    pub fn delta(
+        &self,
+        target: LoraTarget,
+        i: usize,
+    ) -> anyhow::Result<Option<Tensor<(usize, usize), f32, Cpu>>> {
+        let Some(lora) = self.layers.get(target).get(i) else {
+            return Ok(None);
+        };
+        let delta = lora
+            .b
+            .weight
+            .clone()
+            .try_matmul(lora.a.weight.clone())?
+            .try_mul(self.config.scale())?;
+        Ok(Some(delta))
+    }
+
+    /// Adds the adapters into the `mamba` projection weights.
+    ///
+    /// The sum is made in `f32`, and then converted back into `E`.
+    pub fn merge_into<E: Dtype>(&self, mamba: &mut Mamba<E, Cpu>) -> anyhow::Result<()>
+    where
+        Cpu: MixedDevice<E, f32>,
+    {
+        self.check(mamba)?;
+        for (i, layer) in mamba.layers.iter_mut().enumerate() {
+            let block = &mut layer.res.0 .1;
+            for &target in &self.config.targets {
+                if let Some(delta) = self.delta(target, i)? {
+                    let weight = target.weight_mut(block);
+                    *weight = weight
+                        .clone()
+                        .try_to_dtype::<f32>()?
+                        .try_add(delta)?
+                        .try_to_dtype::<E>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Serializes the adapters into safetensors, where the `A` and `B` of the `in_proj` adapter
+    /// of the first layer are named `in_proj.0.a.weight` and `in_proj.0.b.weight`.
+    ///
+    /// The `rank`, `alpha` and `targets` (comma-separated) are kept in the metadata.
+    pub fn to_safetensors(&self) -> anyhow::Result<Vec<u8>> {
+        let mut tensors = vec![];
+        for &target in &self.config.targets {
+            for (i, lora) in self.layers.get(target).iter().enumerate() {
+                for (name, weight) in [("a", &lora.a.weight), ("b", &lora.b.weight)] {
+                    let (rows, cols) = *weight.shape();
+                    let data: Vec<u8> = weight
+                        .as_vec()
+                        .iter()
+                        .flat_map(|v| v.to_le_bytes())
+                        .collect();
+                    tensors.push((
+                        format!("{target}.{i}.{name}.weight"),
+                        vec![rows, cols],
+                        data,
+                    ));
+                }
+            }
+        }
+        let targets: Vec<&str> = self.config.targets.iter().map(|t| t.name()).collect();
+        let metadata = HashMap::from([
+            ("rank".to_string(), self.config.rank.to_string()),
+            ("alpha".to_string(), self.config.alpha.to_string()),
+            ("targets".to_string(), targets.join(",")),
+        ]);
+        let views = tensors
+            .iter()
+            .map(|(name, shape, data)| {
+                Ok((
+                    name.clone(),
+                    TensorView::new(StDtype::F32, shape.clone(), data)?,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(safetensors::serialize(views, &Some(metadata))?)
+    }
+
+    /// Deserializes the adapters saved by [LoraAdapter::to_safetensors].
+    pub fn from_safetensors(bytes: &[u8], device: &Cpu) -> anyhow::Result<Self> {
+        let (_, metadata) = SafeTensors::read_metadata(bytes)?;
+        let metadata = metadata.metadata().clone().unwrap_or_default();
+        let get = |key: &str| {
+            metadata
+                .get(key)
+                .ok_or_else(|| anyhow::anyhow!("missing the lora {key} metadata"))
+        };
+        let targets = get("targets")?
+            .split(',')
+            .map(|name| {
+                LoraTarget::from_name(name)
+                    .ok_or_else(|| anyhow::anyhow!("unknown lora target: {name:?}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let config = LoraConfig {
+            rank: get("rank")?.parse()?,
+            alpha: get("alpha")?.parse()?,
+            targets,
+        };
+
+        let tensors = SafeTensors::deserialize(bytes)?;
+        let matrix = |name: &str| -> anyhow::Result<Tensor<(usize, usize), f32, Cpu>> {
+            let view = tensors.tensor(name)?;
+            let (rows, cols) = match (view.dtype(), view.shape()) {
+                (StDtype::F32, &[rows, cols]) => (rows, cols),
+                (dtype, shape) => {
+                    anyhow::bail!("{name}: expected a F32 matrix, got {dtype:?} {shape:?}")
+                }
+            };
+            let values = view
+                .data()
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+            Ok(device.try_tensor_from_vec(values, (rows, cols))?)
+        };
+        let mut layers_config = LoraLayersConfig::default();
+        let mut weights = vec![];
+        for &target in &config.targets {
+            for i in 0.. {
+                let prefix = format!("{target}.{i}");
+                if tensors.tensor(&format!("{prefix}.a.weight")).is_err() {
+                    break;
+                }
+                let a = matrix(&format!("{prefix}.a.weight"))?;
+                let b = matrix(&format!("{prefix}.b.weight"))?;
+                let ((rank, inp), (out, _)) = (*a.shape(), *b.shape());
+                layers_config.get_mut(target).push(LoraLinearConfig {
+                    a: MatMulConfig { inp, out: rank },
+                    b: MatMulConfig { inp: rank, out },
+                });
+                weights.push((target, a, b));
+            }
+        }
+        let mut layers: LoraLayers<f32, Cpu> = layers_config.try_build_on_device(device)?;
+        let mut next = HashMap::<LoraTarget, usize>::new();
+        for (target, a, b) in weights {
+            let i = next.entry(target).or_default();
+            let lora = &mut layers.get_mut(target)[*i];
+            lora.a.weight = a;
+            lora.b.weight = b;
+            *i += 1;
+        }
+        Ok(Self { config, layers })
+    }
+}
+
+/// A block of the base model, plus its adapters (if any).
+struct LoraBlock<'a, E: Dtype> {
+    base: &'a MambaBlockDyn<E, Cpu>,
+    adapter: Option<&'a LoraAdapter>,
+    /// The layer of the block.
+    i: usize,
+}
+
+impl<'a, E: Dtype> LoraBlock<'a, E> {
+    /// Adds the adapter output of the `x` input into the `y` output of the `target`.
+    fn adapt<T: Tape<f32, Cpu>>(
+        &self,
+        target: LoraTarget,
+        x: BlockInput<f32, Cpu, T>,
+        y: BlockInput<f32, Cpu, T>,
+    ) -> Result<BlockInput<f32, Cpu, T>, Error> {
+        let Some(adapter) = self.adapter else {
+            return Ok(y);
+        };
+        let Some(lora) = adapter.layers.get(target).get(self.i) else {
+            return Ok(y);
+        };
+        let delta = lora
+            .b
+            .try_forward(lora.a.try_forward(x)?)?
+            .try_mul(adapter.config.scale())?;
+        y.try_add(delta)
+    }
+}
+
+impl<'a, E: Dtype> BlockParts<f32, Cpu> for LoraBlock<'a, E>
+where
+    Cpu: MixedDevice<E, f32>,
+{
+    fn dims(&self) -> (DInner, DState, DConv, DtRank) {
+        BlockParts::<f32, Cpu>::dims(self.base)
+    }
+    fn in_proj(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        let y = BlockParts::<f32, Cpu>::in_proj(self.base, x.clone())?;
+        self.adapt(LoraTarget::InProj, x, y)
+    }
+    fn x_proj(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        let y = BlockParts::<f32, Cpu>::x_proj(self.base, x.clone())?;
+        self.adapt(LoraTarget::XProj, x, y)
+    }
+    fn dt_proj(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        BlockParts::<f32, Cpu>::dt_proj(self.base, x)
+    }
+    fn out_proj(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        let y = BlockParts::<f32, Cpu>::out_proj(self.base, x.clone())?;
+        self.adapt(LoraTarget::OutProj, x, y)
+    }
+    fn conv1d(
+        &self,
+    ) -> Result<
+        (
+            Tensor<(DInner, DConv), f32, Cpu>,
+            Tensor<(DInner,), f32, Cpu>,
+        ),
+        Error,
+    > {
+        BlockParts::<f32, Cpu>::conv1d(self.base)
+    }
+    fn a_log(&self) -> Result<Tensor<(DInner, DState), f32, Cpu>, Error> {
+        BlockParts::<f32, Cpu>::a_log(self.base)
+    }
+    fn d(&self) -> Result<Tensor<(DInner,), f32, Cpu>, Error> {
+        BlockParts::<f32, Cpu>::d(self.base)
+    }
+}
+
+/// A tensor whose operations are being recorded for the adapter gradients.
+type Taped<S> = Tensor<S, f32, Cpu, OwnedTape<f32, Cpu>>;
+
+impl<'a> LoraBlock<'a, f32> {
+    /// The frozen `weight` projection of the `target`, plus its adapter.
+    fn try_proj_taped(
+        &self,
+        target: LoraTarget,
+        weight: &Tensor<(usize, usize), f32, Cpu>,
+        x: Taped<(Batch, Sequence, usize)>,
+    ) -> Result<Taped<(Batch, Sequence, usize)>, Error> {
+        let lora_x = x.with_empty_tape();
+        let y = try_frozen_linear(x, weight)?;
+        self.adapt(target, lora_x, y)
+    }
+
+    /// The stateless forward of the block (see [mamba_block_try_forward]), starting from empty
+    /// states and recording on the tape.
+    ///
+    /// A taped tensor can't be cloned, so each reused tensor is branched with an empty tape,
+    /// and the tapes left in the branched tensors are merged back into the output.
+    fn try_forward_taped(
+        &self,
+        x: Taped<(Batch, Sequence, DModel)>,
+    ) -> Result<Taped<(Batch, Sequence, DModel)>, Error> {
+        let (batch, seq, _d_model) = *x.shape();
+        let (d_inner, d_state, d_conv, dt_rank) = BlockParts::<f32, Cpu>::dims(self.base);
+        let device = x.device().clone();
+
+        // (batch, seq, d_inner * 2)
+        let xr = self.try_proj_taped(LoraTarget::InProj, &self.base.in_proj.weight, x)?;
+        let xs = xr.with_empty_tape().try_slice((.., .., 0..d_inner))?;
+        let res = xr.try_slice((.., .., d_inner..d_inner * 2))?;
+
+        // causal depthwise conv, with the empty conv state as the left padding
+        let pad = device
+            .try_zeros_like(&(batch, d_conv - 1, d_inner))?
+            .retaped::<OwnedTape<f32, Cpu>>();
+        // (batch, seq + d_conv - 1, d_inner)
+        let xs = (pad, xs).try_concat_along(Axis::<1>)?;
+        let shape = (batch, seq, d_inner);
+        let (conv_weight, conv_bias) = BlockParts::<f32, Cpu>::conv1d(self.base)?;
+        let w_k = |k: usize| {
+            conv_weight
+                .clone()
+                .try_slice((.., k..k + 1))?
+                .try_reshape_like(&(d_inner,))?
+                .try_broadcast_like::<_, Axes2<0, 1>>(&shape)
+        };
+        let mut conv = xs
+            .with_empty_tape()
+            .try_slice((.., 0..seq, ..))?
+            .try_mul(w_k(0)?)?
+            .try_add(conv_bias.try_broadcast_like::<_, Axes2<0, 1>>(&shape)?)?;
+        for k in 1..d_conv {
+            let xs_k = xs.with_empty_tape().try_slice((.., k..k + seq, ..))?;
+            conv = conv.try_add(xs_k.try_mul(w_k(k)?)?)?;
+        }
+        let conv = try_merge_tape(conv, xs);
+        // (batch, seq, d_inner)
+        let xs = silu_taped(conv)?;
+
+        // (batch, seq, dt_rank + d_state * 2)
+        let x_dbl = self.try_proj_taped(
+            LoraTarget::XProj,
+            &self.base.x_proj.weight,
+            xs.with_empty_tape(),
+        )?;
+        let delta = x_dbl.with_empty_tape().try_slice((.., .., 0..dt_rank))?;
+        let b = x_dbl
+            .with_empty_tape()
+            .try_slice((.., .., dt_rank..dt_rank + d_state))?;
+        let c = x_dbl.try_slice((.., .., dt_rank + d_state..dt_rank + d_state * 2))?;
+        // (batch, seq, d_inner)
+        let dt_bias = self
+            .base
+            .dt_proj
+            .bias
+            .clone()
+            .try_broadcast_like::<_, Axes2<0, 1>>(&shape)?;
+        let delta = try_frozen_linear(delta, &self.base.dt_proj.weight)?.try_add(dt_bias)?;
+        let delta = softplus_taped(delta)?;
+
+        // (d_inner, d_state)
+        let a = BlockParts::<f32, Cpu>::a_log(self.base)?
+            .try_exp()?
+            .try_negate()?;
+
+        // discretization
+        let shape4 = (batch, seq, d_inner, d_state);
+        // (batch, seq, d_inner, d_state)
+        let delta_a = delta
+            .with_empty_tape()
+            .try_broadcast_like::<_, Axis<3>>(&shape4)?
+            .try_mul(a.try_broadcast_like::<_, Axes2<0, 1>>(&shape4)?)?
+            .try_exp()?;
+        // (batch, seq, d_inner, d_state)
+        let delta_b_u = delta
+            .try_mul(xs.with_empty_tape())?
+            .try_broadcast_like::<_, Axis<3>>(&shape4)?
+            .try_mul(b.try_broadcast_like::<_, Axis<2>>(&shape4)?)?;
+
+        // selective scan
+        let shape3 = (batch, d_inner, d_state);
+        let mut ssm_state = device
+            .try_zeros_like(&shape3)?
+            .retaped::<OwnedTape<f32, Cpu>>();
+        let mut ys = Vec::with_capacity(seq);
+        for t in 0..seq {
+            let delta_a_t = delta_a
+                .with_empty_tape()
+                .try_slice((.., t..t + 1, .., ..))?
+                .try_reshape_like(&shape3)?;
+            let delta_b_u_t = delta_b_u
+                .with_empty_tape()
+                .try_slice((.., t..t + 1, .., ..))?
+                .try_reshape_like(&shape3)?;
+            ssm_state = delta_a_t.try_mul(ssm_state)?.try_add(delta_b_u_t)?;
+
+            // (batch, d_inner)
+            let c_t = c
+                .with_empty_tape()
+                .try_slice((.., t..t + 1, ..))?
+                .try_reshape_like(&(batch, d_state))?
+                .try_broadcast_like::<_, Axis<1>>(&shape3)?;
+            let y_t = ssm_state
+                .with_empty_tape()
+                .try_mul(c_t)?
+                .try_sum::<_, Axis<2>>()?;
+            ys.push(y_t);
+        }
+        // (batch, seq, d_inner)
+        let y = ys.try_stack()?.try_permute::<_, Axes3<1, 0, 2>>()?;
+        let d =
+            BlockParts::<f32, Cpu>::d(self.base)?.try_broadcast_like::<_, Axes2<0, 1>>(&shape)?;
+        let y = y.try_add(xs.try_mul(d)?)?;
+        let y = try_merge_tape(y, ssm_state);
+        let y = try_merge_tape(y, delta_a);
+        let y = try_merge_tape(y, delta_b_u);
+        let y = try_merge_tape(y, c);
+
+        // (batch, seq, d_model)
+        let y = y.try_mul(silu_taped(res)?)?;
+        self.try_proj_taped(LoraTarget::OutProj, &self.base.out_proj.weight, y)
+    }
+}
+
+/// The stateless forward of the `base` model with the `adapter`, recording on the tape of the
+/// `grads` only what the adapter gradients need.
+///
+/// The base weights are frozen: the embedding lookup happens before the tape starts,
+/// and the projections (see [try_frozen_linear]) don't allocate gradients for their weights.
+fn try_forward_taped(
+    base: &Mamba<f32, Cpu>,
+    adapter: &LoraAdapter,
+    x: VocabInput<Cpu, NoneTape>,
+    grads: Gradients<f32, Cpu>,
+) -> Result<Taped<(Batch, Sequence, Vocab)>, Error> {
+    let mut x = base.embedding.try_forward(x)?.traced(grads);
+    for (i, layer) in base.layers.iter().enumerate() {
+        let (norm, block) = &layer.res.0;
+        let block = LoraBlock {
+            base: block,
+            adapter: Some(adapter),
+            i,
+        };
+        let x2 = rms_norm_taped(&norm.gamma, x.with_empty_tape())?;
+        let x2 = block.try_forward_taped(x2)?;
+        x = x.try_add(x2)?;
+    }
+    let x = rms_norm_taped(&base.norm_f.gamma, x)?;
+    let shape = (x.shape().0, x.shape().1, base.lm_head.weight.shape().0);
+    let bias = base
+        .lm_head
+        .bias
+        .clone()
+        .try_broadcast_like::<_, Axes2<0, 1>>(&shape)?;
+    try_frozen_linear(x, &base.lm_head.weight)?.try_add(bias)
+}
+
+/// `x · weightᵀ`, where only the `x` gets a gradient.
+///
+/// A matmul would also allocate a gradient for the `weight`, as large as the weight itself.
+fn try_frozen_linear(
+    x: Taped<(Batch, Sequence, usize)>,
+    weight: &Tensor<(usize, usize), f32, Cpu>,
+) -> Result<Taped<(Batch, Sequence, usize)>, Error> {
+    let (x, mut tape) = x.split_tape();
+    let y = x
+        .clone()
+        .try_matmul(weight.clone().try_permute::<_, Axes2<1, 0>>()?)?;
+    let (weight, y_ghost) = (weight.clone(), y.clone());
+    tape.add_backward_op(move |grads| {
+        // (batch, seq, out) -> (batch, seq, in)
+        let grad_x = grads.get(&y_ghost).try_matmul(weight)?.as_vec();
+        let x_grad = grads.get_or_alloc_mut(&x)?;
+        for (g, v) in x_grad.iter_mut().zip(grad_x) {
+            *g += v;
+        }
+        Ok(())
+    });
+    Ok(y.put_tape(tape))
+}
+
+/// Moves the tape left in `from` into `x`.
+fn try_merge_tape<S: Shape, S2: Shape>(x: Taped<S>, from: Taped<S2>) -> Taped<S> {
+    let (x, tape) = x.split_tape();
+    let (_from, from_tape) = from.split_tape();
+    x.put_tape(tape.merge(from_tape))
+}
+
+/// `x / sqrt(mean(x^2) + eps) * gamma`.
+fn rms_norm_taped(
+    gamma: &Tensor<(DModel,), f32, Cpu>,
+    x: Taped<(Batch, Sequence, DModel)>,
+) -> Result<Taped<(Batch, Sequence, DModel)>, Error> {
+    let shape = *x.shape();
+    let gamma = gamma.clone().try_broadcast_like::<_, Axes2<0, 1>>(&shape)?;
+    let inv_rms = x
+        .with_empty_tape()
+        .try_square()?
+        .try_mean::<_, Axis<2>>()?
+        .try_add(RMS_NORM_EPS as f32)?
+        .try_sqrt()?
+        .try_recip()?
+        .try_broadcast_like::<_, Axis<2>>(&shape)?;
+    x.try_mul(inv_rms)?.try_mul(gamma)
+}
+
+/// `x * sigmoid(x)`
+fn silu_taped<S: Shape>(x: Taped<S>) -> Result<Taped<S>, Error> {
+    x.with_empty_tape().try_sigmoid()?.try_mul(x)
+}
+
+/// `ln(1 + exp(x))`, calculated as `relu(x) + ln(1 + exp(-|x|))` to avoid overflows.
+fn softplus_taped<S: Shape>(x: Taped<S>) -> Result<Taped<S>, Error> {
+    let tail = x
+        .with_empty_tape()
+        .try_abs()?
+        .try_negate()?
+        .try_exp()?
+        .try_add(1.)?
+        .try_ln()?;
+    x.try_relu()?.try_add(tail)
+}
+
+/// A [Mamba] with an optional [LoraAdapter] on top of it.
+pub struct LoraMamba<E: Dtype = f32> {
+    pub base: Mamba<E, Cpu>,
+    adapter: Option<LoraAdapter>,
+}
+
+impl<E: Dtype> LoraMamba<E> {
+    pub fn new(base: Mamba<E, Cpu>, adapter: Option<LoraAdapter>) -> anyhow::Result<Self> {
+        let mut mamba = Self {
+            base,
+            adapter: None,
+        };
+        mamba.set_adapter(adapter)?;
+        Ok(mamba)
+    }
+
+    pub fn adapter(&self) -> Option<&LoraAdapter> {
+        self.adapter.as_ref()
+    }
+
+    /// Replaces (or removes) the adapter, returning the previous one.
+    ///
+    /// The base model is kept as-is, so this is cheap.
+    pub fn set_adapter(
+        &mut self,
+        adapter: Option<LoraAdapter>,
+    ) -> anyhow::Result<Option<LoraAdapter>> {
+        if let Some(adapter) = &adapter {
+            adapter.check(&self.base)?;
+        }
+        Ok(std::mem::replace(&mut self.adapter, adapter))
+    }
+}
+
+impl<E: Dtype> LoraMamba<E>
+where
+    Cpu: MixedDevice<E, f32>,
+{
+    /// A copy of the base model with the adapter merged into its weights.
+    pub fn merged(&self) -> anyhow::Result<Mamba<E, Cpu>> {
+        let mut mamba = self.base.clone();
+        if let Some(adapter) = &self.adapter {
+            adapter.merge_into(&mut mamba)?;
+        }
+        Ok(mamba)
+    }
+}
+
+impl<E: MambaDtype> MambaModel for LoraMamba<E>
+where
+    Cpu: MixedDevice<E, f32>,
+{
+    fn weights_format(&self) -> String {
+        match &self.adapter {
+            Some(adapter) => {
+                let targets: Vec<&str> = adapter.config.targets.iter().map(|t| t.name()).collect();
+                format!(
+                    "{} + lora (rank {}, {})",
+                    E::NAME,
+                    adapter.config.rank,
+                    targets.join(", ")
+                )
+            }
+            None => E::NAME.into(),
+        }
+    }
+
+    fn device(&self) -> Cpu {
+        self.base.embedding.weight.device().clone()
+    }
+
+    fn state_dims(&self) -> Vec<(DState, DConv, DInner)> {
+        self.base.state_dims()
+    }
+
+    fn try_forward_hidden_f32(
+        &self,
+        x: VocabInputWithStates<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInputWithStates<f32, Cpu>, Error> {
+        let (x, states) = x;
+        check_input_states(&self.state_dims(), x.shape().0, &states)?;
+        let x: BlockInput<E, Cpu, NoneTape> = self.base.embedding.try_forward(x)?;
+        let mut x: BlockInput<f32, Cpu, NoneTape> = x.try_to_dtype::<f32>()?;
+
+        let mut new_states = Vec::with_capacity(states.len());
+        for (i, (layer, state)) in self.base.layers.iter().zip(states.into_iter()).enumerate() {
+            let (norm, base) = &layer.res.0;
+            let block = LoraBlock {
+                base,
+                adapter: self.adapter.as_ref(),
+                i,
+            };
+            let x2 = rms_norm_mixed(&norm.gamma, x.clone())?;
+            let (x2, new_state) = mamba_block_try_forward(&block, x2, state)?;
+            new_states.push(new_state);
+            x = x.try_add(x2)?;
+        }
+
+        let x = rms_norm_mixed(&self.base.norm_f.gamma, x)?;
+        Ok((x, new_states))
+    }
+
+    fn try_lm_head_f32(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        self.base.try_lm_head_mixed(x)
+    }
+
+    fn to_hf_safetensors(&self) -> anyhow::Result<Vec<u8>> {
+        crate::mamba::save::to_hf_safetensors(&self.merged()?)
+    }
+}
+
+/// The optimizer of the adapters, while the base model stays frozen.
+///
+/// Each step runs the taped stateless forward of the base model with the adapters (as in a
+/// [LoraMamba]), where only the adapters are traced. The base model is neither copied nor gets
+/// gradients for its weights, and only the adapters get an optimizer state.
+pub struct LoraTrainer {
+    pub config: TrainConfig,
+    optimizer: Adam<LoraLayers<f32, Cpu>, f32, Cpu>,
+    /// How many steps were made, starting at `0`.
+    pub step: usize,
+}
+
+impl LoraTrainer {
+    pub fn new(adapter: &LoraAdapter, config: TrainConfig) -> Self {
+        let optimizer = Adam::new(
+            &adapter.layers,
+            AdamConfig {
+                lr: config.lr_at(0),
+                betas: config.betas,
+                eps: config.eps,
+                weight_decay: Some(WeightDecay::Decoupled(config.weight_decay)),
+            },
+        );
+        Self {
+            config,
+            optimizer,
+            step: 0,
+        }
+    }
+
+    /// Makes an optimizer step of the `adapter` over the `batch`,
+    /// where each window has `seq_len + 1` tokens.
+    pub fn train_step(
+        &mut self,
+        base: &Mamba<f32, Cpu>,
+        adapter: &mut LoraAdapter,
+        batch: &[&[u32]],
+    ) -> anyhow::Result<StepReport> {
+        adapter.check(base)?;
+        let device = base.embedding.weight.device();
+        let (inputs, targets) = train::try_batch_tensors(device, batch, self.config.seq_len)?;
+        let grads = adapter.layers.try_alloc_grads()?;
+        let logits = try_forward_taped(base, adapter, inputs, grads)?;
+        let (loss, mut lora_grads) = train::try_backward_cross_entropy(logits, targets)?;
+
+        let layers = &mut adapter.layers;
+        let grad_norm = layers.try_grads_norm_squared(&lora_grads)?.sqrt();
+        if let Some(max_grad_norm) = self.config.max_grad_norm {
+            layers.try_grads_clip_norm(&mut lora_grads, grad_norm, max_grad_norm as f32)?;
+        }
+
+        let lr = self.config.lr_at(self.step);
+        self.optimizer.cfg.lr = lr;
+        self.optimizer
+            .update(layers, &lora_grads)
+            .map_err(|e| anyhow::anyhow!("failed to update the adapters: {e:?}"))?;
+
+        let report = StepReport {
+            step: self.step,
+            loss,
+            lr,
+            grad_norm,
+        };
+        self.step += 1;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    const VOCAB: usize = 32;
+    const SEQ_LEN: usize = 8;
+
+    fn base() -> Mamba<f32, Cpu> {
+        let mut mamba = test_utils::mamba(0);
+        train::tie_lm_head(&mut mamba);
+        mamba.lm_head.bias.fill_with_zeros();
+        mamba
+    }
+
+    /// An adapter of the `targets` whose `B` matrices are not zero, so that it changes the outputs.
+    fn adapter(base: &Mamba<f32, Cpu>, targets: &[LoraTarget], seed: u64) -> LoraAdapter {
+        let config = LoraConfig {
+            targets: targets.to_vec(),
+            ..Default::default()
+        };
+        let mut adapter = LoraAdapter::new(base, config, seed).unwrap();
+        let device = Cpu::seed_from_u64(seed + 1);
+        for target in LoraTarget::ALL {
+            for lora in adapter.layers.get_mut(target).iter_mut() {
+                lora.b.weight = device.sample_normal_like(lora.b.weight.shape()) * 0.1;
+            }
+        }
+        adapter
+    }
+
+    fn window() -> Vec<u32> {
+        (0..SEQ_LEN as u32 + 1)
+            .map(|t| t * 7 % VOCAB as u32)
+            .collect()
+    }
+
+    fn logits(mamba: &LoraMamba) -> Vec<f32> {
+        let window = window();
+        let (inputs, _targets) =
+            train::try_batch_tensors(&mamba.device(), &[&window], SEQ_LEN).unwrap();
+        mamba.try_forward_stateless_f32(inputs).unwrap().as_vec()
+    }
+
+    fn assert_close(expected: &[f32], actual: &[f32]) {
+        assert_eq!(expected.len(), actual.len());
+        for (e, a) in expected.iter().zip(actual) {
+            assert!(
+                (e - a).abs() <= 1e-4 * e.abs().max(1.),
+                "expected {e}, got {a}"
+            );
+        }
+    }
+
+    #[test]
+    fn taped_forward_matches_lora_mamba() {
+        let base = base();
+        let adapter = adapter(&base, &LoraTarget::ALL, 1);
+        let window = window();
+        let device = base.embedding.weight.device().clone();
+        let (inputs, _targets) = train::try_batch_tensors(&device, &[&window], SEQ_LEN).unwrap();
+
+        let grads = adapter.layers.alloc_grads();
+        let (taped, _tape) = try_forward_taped(&base, &adapter, inputs, grads)
+            .unwrap()
+            .split_tape();
+        let lora_mamba = LoraMamba::new(base, Some(adapter)).unwrap();
+        assert_close(&logits(&lora_mamba), &taped.as_vec());
+    }
+
+    #[test]
+    fn merged_adapters_match_lora_mamba() {
+        let base = base();
+        let adapter = adapter(&base, &LoraTarget::ALL, 1);
+        let mut merged = base.clone();
+        adapter.merge_into(&mut merged).unwrap();
+
+        let lora_mamba = LoraMamba::new(base, Some(adapter)).unwrap();
+        let merged = LoraMamba::new(merged, None).unwrap();
+        assert_close(&logits(&lora_mamba), &logits(&merged));
+        assert_close(
+            &logits(&lora_mamba),
+            &logits(&LoraMamba::new(lora_mamba.merged().unwrap(), None).unwrap()),
+        );
+    }
+
+    #[test]
+    fn adapters_round_trip_through_safetensors() {
+        let base = base();
+        for targets in [
+            &LoraTarget::ALL[..],
+            &[LoraTarget::OutProj, LoraTarget::InProj][..],
+        ] {
+            let adapter = adapter(&base, targets, 1);
+            let bytes = adapter.to_safetensors().unwrap();
+            let loaded =
+                LoraAdapter::from_safetensors(&bytes, base.embedding.weight.device()).unwrap();
+
+            assert_eq!(loaded.config, adapter.config);
+            assert_eq!(loaded.num_params(), adapter.num_params());
+            for target in LoraTarget::ALL {
+                let (expected, actual) = (adapter.layers.get(target), loaded.layers.get(target));
+                assert_eq!(expected.len(), actual.len());
+                for (expected, actual) in expected.iter().zip(actual) {
+                    assert_eq!(expected.a.weight.as_vec(), actual.a.weight.as_vec());
+                    assert_eq!(expected.b.weight.as_vec(), actual.b.weight.as_vec());
+                }
+            }
+            let expected = logits(&LoraMamba::new(base.clone(), Some(adapter)).unwrap());
+            let actual = logits(&LoraMamba::new(base.clone(), Some(loaded)).unwrap());
+            assert_eq!(expected, actual);
+        }
+    }
+
+    #[test]
+    fn adapters_can_be_removed_and_swapped() {
+        let base = base();
+        let expected_base = logits(&LoraMamba::new(base.clone(), None).unwrap());
+        let first = adapter(&base, &LoraTarget::ALL, 1);
+        let second = adapter(&base, &[LoraTarget::XProj], 3);
+        let expected_second = logits(&LoraMamba::new(base.clone(), Some(second.clone())).unwrap());
+
+        let mut lora_mamba = LoraMamba::new(base, Some(first)).unwrap();
+        let expected_first = logits(&lora_mamba);
+        assert_ne!(expected_first, expected_base);
+        assert_ne!(expected_first, expected_second);
+
+        let previous = lora_mamba.set_adapter(None).unwrap();
+        assert!(previous.is_some());
+        assert_eq!(logits(&lora_mamba), expected_base);
+
+        assert!(lora_mamba.set_adapter(Some(second)).unwrap().is_none());
+        assert_eq!(logits(&lora_mamba), expected_second);
+        lora_mamba.set_adapter(previous).unwrap();
+        assert_eq!(logits(&lora_mamba), expected_first);
+    }
+
+    #[test]
+    fn train_step_lowers_the_loss() {
+        let base = base();
+        let mut adapter = adapter(&base, &LoraTarget::ALL, 1);
+        let config = TrainConfig {
+            seq_len: SEQ_LEN,
+            batch_size: 1,
+            steps: 20,
+            lr: 1e-2,
+            min_lr: 1e-2,
+            warmup_steps: 0,
+            weight_decay: 0.,
+            ..Default::default()
+        };
+        let mut trainer = LoraTrainer::new(&adapter, config);
+        let window = window();
+        let first = trainer.train_step(&base, &mut adapter, &[&window]).unwrap();
+        let mut last = first.clone();
+        for _ in 1..20 {
+            last = trainer.train_step(&base, &mut adapter, &[&window]).unwrap();
+        }
+        assert!(first.grad_norm > 0.);
+        assert!(last.loss < first.loss, "{first} -> {last}");
+    }
+}
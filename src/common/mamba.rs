@@ -129,6 +129,184 @@ impl MambaConfig {
             },
         }
     }
+
+    /// Builds the config from the contents of a Hugging Face `config.json` file.
+    ///
+    /// The vocab size is padded according to `pad_vocab_size_multiple`, and the optional
+    /// dimensions (`d_state`, `d_conv`, `expand`/`d_inner`, `dt_rank`) fallback to their defaults.
+    ///
+    /// See [hf_config::HfMambaConfig] for the accepted fields.
+    pub fn from_hf_config_json(json: &str) -> anyhow::Result<Self> {
+        let hf_config: hf_config::HfMambaConfig = serde_json::from_str(json)
+            .map_err(|e| anyhow::anyhow!("failed to parse the mamba config.json: {e}"))?;
+        hf_config.try_into_config()
+    }
+}
+
+/// Parsing of the Hugging Face `config.json` that accompanies the Mamba checkpoints.
+pub mod hf_config {
+    use super::*;
+    use serde::Deserialize;
+
+    /// The `config.json` fields that are relevant for building a [MambaConfig].
+    ///
+    /// Both the state-spaces layout (`d_model`, `n_layer` and the optional `ssm_cfg` object)
+    /// and the transformers layout (`hidden_size`, `num_hidden_layers`, `state_size`, etc)
+    /// are accepted, and a field may be in both layouts if the values agree.
+    /// Unknown fields are ignored.
+    #[derive(Debug, Clone, Default, Deserialize)]
+    pub struct HfMambaConfig {
+        pub d_model: Option<usize>,
+        /// The transformers layout for `d_model`.
+        pub hidden_size: Option<usize>,
+        pub n_layer: Option<usize>,
+        /// The transformers layout for `n_layer`, which the transformers checkpoints
+        /// also have next to `n_layer`.
+        pub num_hidden_layers: Option<usize>,
+        /// The unpadded vocab size.
+        pub vocab_size: Option<usize>,
+        /// Defaults to `8`.
+        pub pad_vocab_size_multiple: Option<usize>,
+        /// Only RMSNorm is supported. Defaults to `true`.
+        pub rms_norm: Option<bool>,
+        /// The state-spaces layout for the SSM dimensions.
+        #[serde(default)]
+        pub ssm_cfg: SsmConfig,
+        /// The transformers layout for the SSM dimensions.
+        #[serde(flatten)]
+        pub ssm_top_level: SsmConfigTopLevel,
+        /// The transformers layout for `d_inner`.
+        pub intermediate_size: Option<usize>,
+    }
+
+    /// The `ssm_cfg` object from the state-spaces layout.
+    #[derive(Debug, Clone, Default, Deserialize)]
+    pub struct SsmConfig {
+        pub d_state: Option<usize>,
+        pub d_conv: Option<usize>,
+        pub expand: Option<usize>,
+        pub dt_rank: Option<HfDtRank>,
+    }
+
+    /// The SSM dimensions from the transformers layout, which are top-level fields.
+    #[derive(Debug, Clone, Default, Deserialize)]
+    pub struct SsmConfigTopLevel {
+        pub state_size: Option<usize>,
+        pub conv_kernel: Option<usize>,
+        pub expand: Option<usize>,
+        pub time_step_rank: Option<HfDtRank>,
+    }
+
+    /// The `dt_rank` is either `"auto"` or an explicit value.
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(untagged)]
+    pub enum HfDtRank {
+        Value(usize),
+        Named(String),
+    }
+
+    impl HfMambaConfig {
+        /// Validates the fields and converts them into a [MambaConfig].
+        pub fn try_into_config(self) -> anyhow::Result<MambaConfig> {
+            use anyhow::{anyhow, bail};
+
+            let d_model = merge_field("d_model", self.d_model, self.hidden_size)?
+                .ok_or_else(|| anyhow!("config.json is missing `d_model` (or `hidden_size`)"))?;
+            let n_layer = merge_field("n_layer", self.n_layer, self.num_hidden_layers)?
+                .ok_or_else(|| {
+                    anyhow!("config.json is missing `n_layer` (or `num_hidden_layers`)")
+                })?;
+            let vocab_size = self
+                .vocab_size
+                .ok_or_else(|| anyhow!("config.json is missing `vocab_size`"))?;
+            let pad = self.pad_vocab_size_multiple.unwrap_or(8);
+
+            if d_model == 0 {
+                bail!("config.json has `d_model` set to zero");
+            }
+            if n_layer == 0 {
+                bail!("config.json has `n_layer` set to zero");
+            }
+            if vocab_size == 0 {
+                bail!("config.json has `vocab_size` set to zero");
+            }
+            if pad == 0 {
+                bail!("config.json has `pad_vocab_size_multiple` set to zero");
+            }
+            if self.rms_norm == Some(false) {
+                bail!("config.json has `rms_norm` set to false, but only RMSNorm is supported");
+            }
+
+            let d_state = merge_field(
+                "d_state",
+                self.ssm_cfg.d_state,
+                self.ssm_top_level.state_size,
+            )?;
+            let d_conv = merge_field(
+                "d_conv",
+                self.ssm_cfg.d_conv,
+                self.ssm_top_level.conv_kernel,
+            )?;
+            let expand = merge_field("expand", self.ssm_cfg.expand, self.ssm_top_level.expand)?;
+            let dt_rank = merge_field(
+                "dt_rank",
+                self.ssm_cfg.dt_rank,
+                self.ssm_top_level.time_step_rank,
+            )?;
+
+            let dt_rank = match dt_rank {
+                None => None,
+                Some(HfDtRank::Value(0)) => bail!("config.json has `dt_rank` set to zero"),
+                Some(HfDtRank::Value(v)) => Some(v),
+                Some(HfDtRank::Named(s)) if s == "auto" => None,
+                Some(HfDtRank::Named(s)) => {
+                    bail!("config.json has an unsupported `dt_rank` value: {s:?}")
+                }
+            };
+            let d_inner = match (expand, self.intermediate_size) {
+                (_, Some(0)) | (Some(0), _) => {
+                    bail!("config.json has `expand` or `intermediate_size` set to zero")
+                }
+                (Some(expand), Some(d_inner)) if expand * d_model != d_inner => bail!(
+                    "config.json has inconsistent `expand` ({expand}) and `intermediate_size` ({d_inner}) for `d_model` {d_model}"
+                ),
+                (_, Some(d_inner)) => Some(d_inner),
+                (Some(expand), None) => Some(expand * d_model),
+                (None, None) => None,
+            };
+            if d_state == Some(0) {
+                bail!("config.json has `d_state` set to zero");
+            }
+            if d_conv == Some(0) {
+                bail!("config.json has `d_conv` set to zero");
+            }
+
+            let padded_vocab_size = (vocab_size + pad - 1) / pad * pad;
+            Ok(MambaConfig::new(
+                n_layer,
+                padded_vocab_size,
+                d_model,
+                d_state,
+                dt_rank,
+                d_conv,
+                d_inner,
+            ))
+        }
+    }
+
+    /// Merges a field that may be present in both the state-spaces and the transformers layouts.
+    fn merge_field<T: PartialEq + std::fmt::Debug>(
+        name: &str,
+        state_spaces: Option<T>,
+        transformers: Option<T>,
+    ) -> anyhow::Result<Option<T>> {
+        match (state_spaces, transformers) {
+            (Some(a), Some(b)) if a != b => anyhow::bail!(
+                "config.json has inconsistent values for `{name}`: {a:?} (state-spaces layout) and {b:?} (transformers layout)"
+            ),
+            (a, b) => Ok(a.or(b)),
+        }
+    }
 }
 
 pub mod stateless {
@@ -141,7 +319,7 @@ pub mod stateless {
 
     /// The Output for [Mamba] (stateless).
     ///
-    /// Also the [MambaBlock] (stateless) Input/Output. Each instance contains all of it's timesteps.
+    /// Also the [MambaBlock] (stateless) Input/Output. Each instance contains all of its timesteps.
     pub type BlockInput<E, D, T> = Tensor<(Batch, Sequence, DModel), E, D, T>;
 
     // mamba
@@ -192,7 +370,7 @@ pub mod stateful {
     /// The [MambaBlock] (stateful) Input/Output. Contains a [SingleInput] and the last [StateCache].
     pub type BlockInputWithState<E, D, T> = (SingleInput<E, D, T>, StateCache<E, D, T>);
 
-    /// The new "single" input. Each instance contains only the last of it's timesteps.
+    /// The new "single" input. Each instance contains only the last of its timesteps.
     pub type SingleInput<E, D, T> = Tensor<(Batch, DModel), E, D, T>;
 
     /// A [MambaStateCache] set to runtime values.
@@ -202,6 +380,128 @@ pub mod stateful {
     /// A list containing a [MambaStateCache] per [MambaBlock] (stateful).
     pub type MambaStatesDyn<E, D, T> = Vec<StateCache<E, D, T>>;
 
+    /// A [MambaStateCacheConfig] set to runtime values.
+    pub type StateCacheConfig = dfdx_mamba::MambaStateCacheConfig<Batch, DState, DConv, DInner>;
+
+    /// Selects instances from the batch of each state, by their batch `indices`.
+    ///
+    /// This can drop, reorder or duplicate the instances, and the new batch size is `indices.len()`.
+    pub fn select_batch<E: Dtype, D: Device<E>>(
+        states: &mut MambaStatesDyn<E, D, NoneTape>,
+        indices: &[usize],
+    ) -> Result<(), Error> {
+        let Some(first) = states.first() else {
+            return Ok(());
+        };
+        let device = first.ssm_state.device().clone();
+        let indices = device.try_tensor_from_vec(indices.to_vec(), (indices.len(),))?;
+        for state in states.iter_mut() {
+            state.conv_state = state.conv_state.clone().try_gather(indices.clone())?;
+            state.ssm_state = state.ssm_state.clone().try_gather(indices.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Concatenates the instances of each states batch, in order, into a single states batch.
+    ///
+    /// All of the `batches` must have the same layers and state dimensions.
+    pub fn concat_batches<E: Dtype, D: Device<E>>(
+        batches: Vec<MambaStatesDyn<E, D, NoneTape>>,
+    ) -> Result<MambaStatesDyn<E, D, NoneTape>, Error> {
+        let mut batches = batches.into_iter();
+        let Some(mut states) = batches.next() else {
+            return Ok(vec![]);
+        };
+        for other in batches {
+            for (state, other) in states.iter_mut().zip(other) {
+                state.conv_state =
+                    (state.conv_state.clone(), other.conv_state).try_concat_along(Axis::<0>)?;
+                state.ssm_state =
+                    (state.ssm_state.clone(), other.ssm_state).try_concat_along(Axis::<0>)?;
+            }
+        }
+        Ok(states)
+    }
+
+    impl<E: Dtype, D: Device<E>> Mamba<E, D> {
+        /// The state dimensions `(d_state, d_conv, d_inner)` of each layer, as read from its parameters.
+        pub fn state_dims(&self) -> Vec<(DState, DConv, DInner)> {
+            self.layers
+                .iter()
+                .map(|layer| {
+                    let mamba_block = &layer.res.0 .1;
+                    let (d_inner, d_state) = *mamba_block.a_log.shape();
+                    let d_conv = mamba_block.conv1d.weight.shape().2;
+                    (d_state, d_conv, d_inner)
+                })
+                .collect()
+        }
+
+        /// The [StateCacheConfig] for each layer, so that the states match the model.
+        pub fn state_configs(&self, batch: Batch) -> Vec<StateCacheConfig> {
+            self.state_dims()
+                .into_iter()
+                .map(|(d_state, d_conv, d_inner)| {
+                    dfdx_mamba::MambaStateCacheConfig::new(batch, d_state, d_conv, d_inner)
+                })
+                .collect()
+        }
+
+        /// Checks that the `states` match the model, and returns their batch size.
+        ///
+        /// The `states` may have a different dtype than the model.
+        pub fn check_states<A: Dtype, T>(
+            &self,
+            states: &MambaStatesDyn<A, D, T>,
+        ) -> anyhow::Result<Batch>
+        where
+            D: Device<A>,
+        {
+            check_states(&self.state_dims(), states)
+        }
+    }
+
+    /// Checks that the `states` match the state dimensions `(d_state, d_conv, d_inner)` of each layer,
+    /// and returns their batch size.
+    pub fn check_states<A: Dtype, D: Device<A>, T>(
+        state_dims: &[(DState, DConv, DInner)],
+        states: &MambaStatesDyn<A, D, T>,
+    ) -> anyhow::Result<Batch> {
+        if state_dims.len() != states.len() {
+            anyhow::bail!(
+                "the model has {} layers but {} states were given",
+                state_dims.len(),
+                states.len()
+            );
+        }
+        let mut batch = None;
+        for (i, (&(d_state, d_conv, d_inner), state)) in
+            state_dims.iter().zip(states.iter()).enumerate()
+        {
+            let (b, conv_d_inner, conv_d_conv) = *state.conv_state.shape();
+            let (b2, ssm_d_inner, ssm_d_state) = *state.ssm_state.shape();
+            if (conv_d_inner, conv_d_conv) != (d_inner, d_conv) {
+                anyhow::bail!(
+                    "layer {i}: the conv state has (d_inner, d_conv) = {:?} but the model expects {:?}",
+                    (conv_d_inner, conv_d_conv),
+                    (d_inner, d_conv)
+                );
+            }
+            if (ssm_d_inner, ssm_d_state) != (d_inner, d_state) {
+                anyhow::bail!(
+                    "layer {i}: the ssm state has (d_inner, d_state) = {:?} but the model expects {:?}",
+                    (ssm_d_inner, ssm_d_state),
+                    (d_inner, d_state)
+                );
+            }
+            if b != b2 || batch.is_some_and(|batch| batch != b) {
+                anyhow::bail!("layer {i}: the states have inconsistent batch sizes");
+            }
+            batch = Some(b);
+        }
+        Ok(batch.unwrap_or_default())
+    }
+
     // mamba
     impl<E: Dtype, D: Device<E>, T: Tape<E, D>> Module<VocabInputWithStates<E, D, T>> for Mamba<E, D>
     where
@@ -253,9 +553,446 @@ pub mod stateful {
     }
 }
 
+/// A sequence-mode forward that starts from, and returns, the states.
+///
+/// This processes a whole sequence in a single call (as in [stateless]), while still
+/// carrying the conv window and the SSM state across calls (as in [stateful]).
+/// It's mainly used for prefilling the states with a prompt before a stateful decoding.
+///
+/// The activations and the states may also have a different dtype than the weights
+/// (see [Mamba::try_forward_hidden_mixed]), so that half-precision weights can still
+/// have the selective scan and the norms calculated in `f32`.
+pub mod prefill {
+    use super::*;
+    use dfdx::tensor_ops::ToDtypeKernel;
+    use stateful::StateCache;
+    use stateless::{BlockInput, VocabInput};
+
+    /// A device that can convert between the weights dtype `E` and the activations dtype `A`.
+    pub trait MixedDevice<E: Dtype, A: Dtype>:
+        Device<E> + Device<A> + ToDtypeKernel<E, A> + ToDtypeKernel<A, E>
+    {
+    }
+    impl<E: Dtype, A: Dtype, D> MixedDevice<E, A> for D where
+        D: Device<E> + Device<A> + ToDtypeKernel<E, A> + ToDtypeKernel<A, E>
+    {
+    }
+
+    /// The epsilon of the RMS norms, the same as the one of [LayerRMSNorm1D].
+    pub const RMS_NORM_EPS: f64 = 1e-5;
+
+    /// Checks that the `states` match the state dimensions `(d_state, d_conv, d_inner)` of each
+    /// layer (see [stateful::check_states]) and the `batch` of the input.
+    ///
+    /// The forward calls can only return a dfdx [Error], so a mismatch is reported as
+    /// [Error::WrongNumElements].
+    pub fn check_input_states<A: Dtype, D: Device<A>>(
+        state_dims: &[(DState, DConv, DInner)],
+        batch: Batch,
+        states: &stateful::MambaStatesDyn<A, D, NoneTape>,
+    ) -> Result<(), Error> {
+        match stateful::check_states(state_dims, states) {
+            Ok(states_batch) if states_batch == batch => Ok(()),
+            _ => Err(Error::WrongNumElements),
+        }
+    }
+
+    /// The input for [Mamba] (prefill).
+    ///
+    /// Contains the vocab [Embedding] input for all timesteps and a list of the last [StateCache].
+    pub type VocabInputWithStates<E, D, T> = (VocabInput<D, T>, Vec<StateCache<E, D, T>>);
+
+    /// The output for [Mamba] (prefill).
+    ///
+    /// Contains the logits for all timesteps and the states after the last timestep.
+    pub type OutputWithStates<E, D, T> = (BlockInput<E, D, T>, Vec<StateCache<E, D, T>>);
+
+    /// The [MambaBlock] (prefill) Input/Output. Contains all timesteps and the last [StateCache].
+    pub type BlockInputWithState<E, D, T> = (BlockInput<E, D, T>, StateCache<E, D, T>);
+
+    impl<E: Dtype, D: MixedDevice<E, E>> Mamba<E, D> {
+        /// Runs the embedding, all layers and the final norm, but not the `lm_head`.
+        ///
+        /// This avoids calculating the logits for timesteps that are not needed.
+        pub fn try_forward_hidden(
+            &self,
+            x: VocabInputWithStates<E, D, NoneTape>,
+        ) -> Result<BlockInputWithStates<E, D>, Error> {
+            let (x, states) = x;
+            check_input_states(&self.state_dims(), x.shape().0, &states)?;
+            let mut x: BlockInput<E, D, NoneTape> = self.embedding.try_forward(x)?;
+
+            let mut new_states = Vec::with_capacity(states.len());
+            for (layer, state) in self.layers.iter().zip(states.into_iter()) {
+                let (new_x, new_state) = layer.try_forward((x, state))?;
+                new_states.push(new_state);
+                x = new_x;
+            }
+
+            let x: BlockInput<E, D, NoneTape> = self.norm_f.try_forward(x)?;
+            Ok((x, new_states))
+        }
+    }
+
+    /// The hidden output of all timesteps and the states after the last timestep.
+    pub type BlockInputWithStates<E, D> =
+        (BlockInput<E, D, NoneTape>, Vec<StateCache<E, D, NoneTape>>);
+
+    impl<E: Dtype, D: Device<E>> Mamba<E, D> {
+        /// Same as [Mamba::try_forward_hidden], but the activations and the `states` are in `A`.
+        ///
+        /// Only the matmuls (and the embedding lookup) use the weights dtype `E`, while the
+        /// conv, the selective scan, the norms and the residual connections are calculated in `A`.
+        pub fn try_forward_hidden_mixed<A: Dtype>(
+            &self,
+            x: VocabInputWithStates<A, D, NoneTape>,
+        ) -> Result<BlockInputWithStates<A, D>, Error>
+        where
+            D: MixedDevice<E, A>,
+        {
+            let (x, states) = x;
+            check_input_states(&self.state_dims(), x.shape().0, &states)?;
+            let x: BlockInput<E, D, NoneTape> = self.embedding.try_forward(x)?;
+            let mut x: BlockInput<A, D, NoneTape> = x.try_to_dtype::<A>()?;
+
+            let mut new_states = Vec::with_capacity(states.len());
+            for (layer, state) in self.layers.iter().zip(states.into_iter()) {
+                let (norm, mamba_block) = &layer.res.0;
+                let x2 = rms_norm_mixed(&norm.gamma, x.clone())?;
+                let (x2, new_state) = mamba_block_try_forward(mamba_block, x2, state)?;
+                new_states.push(new_state);
+                x = x.try_add(x2)?;
+            }
+
+            let x = rms_norm_mixed(&self.norm_f.gamma, x)?;
+            Ok((x, new_states))
+        }
+
+        /// Applies the `lm_head` (in `E`) over the hidden activations (in `A`).
+        pub fn try_lm_head_mixed<A: Dtype>(
+            &self,
+            x: BlockInput<A, D, NoneTape>,
+        ) -> Result<BlockInput<A, D, NoneTape>, Error>
+        where
+            D: MixedDevice<E, A>,
+        {
+            linear_mixed(&self.lm_head, x)
+        }
+    }
+
+    /// Applies the `module` (in `E`) over the `x` activations (in `A`).
+    pub fn linear_mixed<E: Dtype, A: Dtype, D: MixedDevice<E, A>, M>(
+        module: &M,
+        x: BlockInput<A, D, NoneTape>,
+    ) -> Result<BlockInput<A, D, NoneTape>, Error>
+    where
+        M: Module<BlockInput<E, D, NoneTape>, Output = BlockInput<E, D, NoneTape>>,
+    {
+        module
+            .try_forward(x.try_to_dtype::<E>()?)?
+            .try_to_dtype::<A>()
+    }
+
+    /// `x / sqrt(mean(x^2) + eps) * gamma`, calculated in the activations dtype `A`.
+    pub fn rms_norm_mixed<E: Dtype, A: Dtype, D: MixedDevice<E, A>>(
+        gamma: &Tensor<(DModel,), E, D>,
+        x: BlockInput<A, D, NoneTape>,
+    ) -> Result<BlockInput<A, D, NoneTape>, Error> {
+        let shape = *x.shape();
+        let gamma = gamma
+            .clone()
+            .try_to_dtype::<A>()?
+            .try_broadcast_like::<_, Axes2<0, 1>>(&shape)?;
+        let inv_rms = x
+            .clone()
+            .try_square()?
+            .try_mean::<_, Axis<2>>()?
+            .try_add(A::from_f64(RMS_NORM_EPS).unwrap())?
+            .try_sqrt()?
+            .try_recip()?
+            .try_broadcast_like::<_, Axis<2>>(&shape)?;
+        x.try_mul(inv_rms)?.try_mul(gamma)
+    }
+
+    // mamba
+    impl<E: Dtype, D: MixedDevice<E, E>> Module<VocabInputWithStates<E, D, NoneTape>> for Mamba<E, D> {
+        type Output = OutputWithStates<E, D, NoneTape>;
+        fn try_forward(
+            &self,
+            x: VocabInputWithStates<E, D, NoneTape>,
+        ) -> Result<Self::Output, Error> {
+            let (x, states) = self.try_forward_hidden(x)?;
+            let x = self.lm_head.try_forward(x)?;
+            Ok((x, states))
+        }
+    }
+
+    // residual connection
+    impl<E: Dtype, D: MixedDevice<E, E>> Module<BlockInputWithState<E, D, NoneTape>>
+        for ResidualMambaBlock<E, D>
+    {
+        type Output = BlockInputWithState<E, D, NoneTape>;
+        fn try_forward(
+            &self,
+            x: BlockInputWithState<E, D, NoneTape>,
+        ) -> Result<Self::Output, Error> {
+            let (x, state) = x;
+            let (norm, mamba_block) = &self.res.0;
+            let x2: BlockInput<E, D, NoneTape> = norm.try_forward(x.clone())?;
+            let (x2, state) = mamba_block_try_forward(mamba_block, x2, state)?;
+            let x: BlockInput<E, D, NoneTape> = x.try_add(x2)?;
+            Ok((x, state))
+        }
+    }
+
+    /// The parameters of a [MambaBlock], as seen from activations in `A`.
+    ///
+    /// This allows the same [mamba_block_try_forward] to run over blocks that store their
+    /// weights differently, such as in another dtype or quantized (see [crate::quant]).
+    pub trait BlockParts<A: Dtype, D: Device<A>> {
+        /// `(d_inner, d_state, d_conv, dt_rank)`.
+        fn dims(&self) -> (DInner, DState, DConv, DtRank);
+        /// `(batch, seq, d_model) -> (batch, seq, d_inner * 2)`.
+        fn in_proj(
+            &self,
+            x: BlockInput<A, D, NoneTape>,
+        ) -> Result<BlockInput<A, D, NoneTape>, Error>;
+        /// `(batch, seq, d_inner) -> (batch, seq, dt_rank + d_state * 2)`.
+        fn x_proj(
+            &self,
+            x: BlockInput<A, D, NoneTape>,
+        ) -> Result<BlockInput<A, D, NoneTape>, Error>;
+        /// `(batch, seq, dt_rank) -> (batch, seq, d_inner)`, including the bias.
+        fn dt_proj(
+            &self,
+            x: BlockInput<A, D, NoneTape>,
+        ) -> Result<BlockInput<A, D, NoneTape>, Error>;
+        /// `(batch, seq, d_inner) -> (batch, seq, d_model)`.
+        fn out_proj(
+            &self,
+            x: BlockInput<A, D, NoneTape>,
+        ) -> Result<BlockInput<A, D, NoneTape>, Error>;
+        /// The depthwise conv weight `(d_inner, d_conv)` and bias `(d_inner,)`.
+        #[allow(clippy::type_complexity)]
+        fn conv1d(&self)
+            -> Result<(Tensor<(DInner, DConv), A, D>, Tensor<(DInner,), A, D>), Error>;
+        /// `(d_inner, d_state)`.
+        fn a_log(&self) -> Result<Tensor<(DInner, DState), A, D>, Error>;
+        /// `(d_inner,)`.
+        fn d(&self) -> Result<Tensor<(DInner,), A, D>, Error>;
+    }
+
+    impl<E: Dtype, A: Dtype, D: MixedDevice<E, A>> BlockParts<A, D> for MambaBlockDyn<E, D> {
+        fn dims(&self) -> (DInner, DState, DConv, DtRank) {
+            let (d_inner, d_state) = *self.a_log.shape();
+            let d_conv = self.conv1d.weight.shape().2;
+            let dt_rank = self.dt_proj.weight.shape().1;
+            (d_inner, d_state, d_conv, dt_rank)
+        }
+        fn in_proj(
+            &self,
+            x: BlockInput<A, D, NoneTape>,
+        ) -> Result<BlockInput<A, D, NoneTape>, Error> {
+            linear_mixed(&self.in_proj, x)
+        }
+        fn x_proj(
+            &self,
+            x: BlockInput<A, D, NoneTape>,
+        ) -> Result<BlockInput<A, D, NoneTape>, Error> {
+            linear_mixed(&self.x_proj, x)
+        }
+        fn dt_proj(
+            &self,
+            x: BlockInput<A, D, NoneTape>,
+        ) -> Result<BlockInput<A, D, NoneTape>, Error> {
+            linear_mixed(&self.dt_proj, x)
+        }
+        fn out_proj(
+            &self,
+            x: BlockInput<A, D, NoneTape>,
+        ) -> Result<BlockInput<A, D, NoneTape>, Error> {
+            linear_mixed(&self.out_proj, x)
+        }
+        fn conv1d(
+            &self,
+        ) -> Result<(Tensor<(DInner, DConv), A, D>, Tensor<(DInner,), A, D>), Error> {
+            let (d_inner, _d_state, d_conv, _dt_rank) = BlockParts::<A, D>::dims(self);
+            let weight = self
+                .conv1d
+                .weight
+                .clone()
+                .try_to_dtype::<A>()?
+                .try_reshape_like(&(d_inner, d_conv))?;
+            let bias = self.conv1d_bias.bias.clone().try_to_dtype::<A>()?;
+            Ok((weight, bias))
+        }
+        fn a_log(&self) -> Result<Tensor<(DInner, DState), A, D>, Error> {
+            self.a_log.clone().try_to_dtype::<A>()
+        }
+        fn d(&self) -> Result<Tensor<(DInner,), A, D>, Error> {
+            self.d.clone().try_to_dtype::<A>()
+        }
+    }
+
+    /// The sequence-mode forward of a [MambaBlock], starting from the `state`.
+    ///
+    /// The conv window of the `state` acts as the left padding of the causal conv,
+    /// and the SSM state is the initial state of the selective scan.
+    ///
+    /// The activations and the `state` are in `A`, while the `block` may store
+    /// its weights in another dtype (see [BlockParts]).
+    ///
+    /// Fails on an empty sequence, which has no last timestep to take the new `state` from.
+    pub fn mamba_block_try_forward<A: Dtype, D: Device<A>, B: BlockParts<A, D>>(
+        block: &B,
+        x: BlockInput<A, D, NoneTape>,
+        state: StateCache<A, D, NoneTape>,
+    ) -> Result<BlockInputWithState<A, D, NoneTape>, Error> {
+        let (batch, seq, _d_model) = *x.shape();
+        if seq == 0 {
+            // there is no last timestep to take the states from
+            return Err(Error::WrongNumElements);
+        }
+        let (d_inner, d_state, d_conv, dt_rank) = block.dims();
+        let mut state = state;
+
+        // (batch, seq, d_inner * 2)
+        let xr = block.in_proj(x)?;
+        let xs = xr.clone().try_slice((.., .., 0..d_inner))?;
+        let res = xr.try_slice((.., .., d_inner..d_inner * 2))?;
+
+        // causal depthwise conv
+        //
+        // the conv state contains the last d_conv inputs,
+        // and the last (d_conv - 1) of those are used as the left padding.
+        // (batch, d_conv - 1, d_inner)
+        let prev = state
+            .conv_state
+            .clone()
+            .try_slice((.., .., 1..d_conv))?
+            .try_permute::<_, Axes3<0, 2, 1>>()?;
+        // (batch, seq + d_conv - 1, d_inner)
+        let xs = (prev, xs).try_concat_along(Axis::<1>)?;
+        // (batch, d_inner, d_conv)
+        let conv_state = xs
+            .clone()
+            .try_slice((.., seq - 1..seq + d_conv - 1, ..))?
+            .try_permute::<_, Axes3<0, 2, 1>>()?;
+        let shape = (batch, seq, d_inner);
+        let (conv_weight, conv_bias) = block.conv1d()?;
+        let mut conv = conv_bias.try_broadcast_like::<_, Axes2<0, 1>>(&shape)?;
+        for k in 0..d_conv {
+            let w_k = conv_weight
+                .clone()
+                .try_slice((.., k..k + 1))?
+                .try_reshape_like(&(d_inner,))?
+                .try_broadcast_like::<_, Axes2<0, 1>>(&shape)?;
+            let xs_k = xs.clone().try_slice((.., k..k + seq, ..))?;
+            conv = conv.try_add(xs_k.try_mul(w_k)?)?;
+        }
+        // (batch, seq, d_inner)
+        let xs = silu(conv)?;
+
+        // (batch, seq, dt_rank + d_state * 2)
+        let x_dbl = block.x_proj(xs.clone())?;
+        let delta = x_dbl.clone().try_slice((.., .., 0..dt_rank))?;
+        let b = x_dbl
+            .clone()
+            .try_slice((.., .., dt_rank..dt_rank + d_state))?;
+        let c = x_dbl.try_slice((.., .., dt_rank + d_state..dt_rank + d_state * 2))?;
+        // (batch, seq, d_inner)
+        let delta = softplus(block.dt_proj(delta)?)?;
+
+        // (d_inner, d_state)
+        let a = block.a_log()?.try_exp()?.try_negate()?;
+
+        // discretization
+        let shape4 = (batch, seq, d_inner, d_state);
+        // (batch, seq, d_inner, d_state)
+        let delta_a = delta
+            .clone()
+            .try_broadcast_like::<_, Axis<3>>(&shape4)?
+            .try_mul(a.try_broadcast_like::<_, Axes2<0, 1>>(&shape4)?)?
+            .try_exp()?;
+        // (batch, seq, d_inner, d_state)
+        let delta_b_u = delta
+            .try_mul(xs.clone())?
+            .try_broadcast_like::<_, Axis<3>>(&shape4)?
+            .try_mul(b.try_broadcast_like::<_, Axis<2>>(&shape4)?)?;
+
+        // selective scan
+        let shape3 = (batch, d_inner, d_state);
+        let mut ssm_state = state.ssm_state.clone();
+        let mut ys = Vec::with_capacity(seq);
+        for t in 0..seq {
+            let delta_a_t = delta_a
+                .clone()
+                .try_slice((.., t..t + 1, .., ..))?
+                .try_reshape_like(&shape3)?;
+            let delta_b_u_t = delta_b_u
+                .clone()
+                .try_slice((.., t..t + 1, .., ..))?
+                .try_reshape_like(&shape3)?;
+            ssm_state = delta_a_t.try_mul(ssm_state)?.try_add(delta_b_u_t)?;
+
+            // (batch, d_inner)
+            let c_t = c
+                .clone()
+                .try_slice((.., t..t + 1, ..))?
+                .try_reshape_like(&(batch, d_state))?
+                .try_broadcast_like::<_, Axis<1>>(&shape3)?;
+            let y_t = ssm_state.clone().try_mul(c_t)?.try_sum::<_, Axis<2>>()?;
+            ys.push(y_t);
+        }
+        // (batch, seq, d_inner)
+        let y = ys.try_stack()?.try_permute::<_, Axes3<1, 0, 2>>()?;
+        let y = y.try_add(xs.try_mul(block.d()?.try_broadcast_like::<_, Axes2<0, 1>>(&shape)?)?)?;
+
+        // (batch, seq, d_model)
+        let y = y.try_mul(silu(res)?)?;
+        let y = block.out_proj(y)?;
+
+        state.conv_state = conv_state;
+        state.ssm_state = ssm_state;
+        Ok((y, state))
+    }
+
+    /// `x * sigmoid(x)`
+    fn silu<S: Shape, E: Dtype, D: Device<E>>(
+        x: Tensor<S, E, D, NoneTape>,
+    ) -> Result<Tensor<S, E, D, NoneTape>, Error> {
+        x.clone().try_sigmoid()?.try_mul(x)
+    }
+
+    /// `ln(1 + exp(x))`, calculated as `relu(x) + ln(1 + exp(-|x|))` to avoid overflows.
+    fn softplus<S: Shape, E: Dtype, D: Device<E>>(
+        x: Tensor<S, E, D, NoneTape>,
+    ) -> Result<Tensor<S, E, D, NoneTape>, Error> {
+        let tail = x
+            .clone()
+            .try_abs()?
+            .try_negate()?
+            .try_exp()?
+            .try_add(E::ONE)?
+            .try_ln()?;
+        x.try_relu()?.try_add(tail)
+    }
+}
+
 pub mod load {
+    use super::*;
+    use crate::precision::{self, MambaDtype};
+    use safetensors::SafeTensors;
     use std::collections::HashMap;
 
+    /// The name of an untied `lm_head` weight in the Hugging Face layout.
+    ///
+    /// The original checkpoints tie the `lm_head` to the embedding, so they lack this tensor.
+    pub const HF_LM_HEAD: &str = "lm_head.weight";
+
+    /// From the dfdx names into the Hugging Face names.
+    ///
+    /// The `lm_head` weight maps into the embedding, to which it's tied.
     #[allow(clippy::useless_format)]
     pub fn load_renames(n_layer: usize) -> HashMap<String, String> {
         let mut load_renames = vec![];
@@ -306,4 +1043,379 @@ pub mod load {
 
         load_renames.into_iter().collect()
     }
+
+    /// Loads the Hugging Face `tensors` (see [load_renames]) into the `mamba` parameters.
+    ///
+    /// Each tensor is converted into `E` straight from its view, which may be memory-mapped,
+    /// so the checkpoint is never copied as a whole. The `lm_head` weight is read from
+    /// [HF_LM_HEAD] when present, otherwise it shares the embedding. The `lm_head` bias,
+    /// which the checkpoints lack, is left as it is.
+    pub fn load_hf_safetensors<E: MambaDtype>(
+        mamba: &mut Mamba<E, Cpu>,
+        tensors: &SafeTensors,
+    ) -> anyhow::Result<()> {
+        let load_renames = load_renames(mamba.layers.len());
+        let loader = HfLoader {
+            load_renames: &load_renames,
+            tensors,
+        };
+        loader.read("embedding.weight", &mut mamba.embedding.weight)?;
+        for (i, layer) in mamba.layers.iter_mut().enumerate() {
+            let (norm, block) = &mut layer.res.0;
+            let k = format!("layers.{i}.res.0");
+            loader.read(&format!("{k}.0.gamma"), &mut norm.gamma)?;
+            loader.read(&format!("{k}.1.in_proj.weight"), &mut block.in_proj.weight)?;
+            loader.read(&format!("{k}.1.conv1d.weight"), &mut block.conv1d.weight)?;
+            loader.read(
+                &format!("{k}.1.conv1d_bias.bias"),
+                &mut block.conv1d_bias.bias,
+            )?;
+            loader.read(&format!("{k}.1.x_proj.weight"), &mut block.x_proj.weight)?;
+            loader.read(&format!("{k}.1.dt_proj.weight"), &mut block.dt_proj.weight)?;
+            loader.read(&format!("{k}.1.dt_proj.bias"), &mut block.dt_proj.bias)?;
+            loader.read(&format!("{k}.1.a_log"), &mut block.a_log)?;
+            loader.read(&format!("{k}.1.d"), &mut block.d)?;
+            loader.read(
+                &format!("{k}.1.out_proj.weight"),
+                &mut block.out_proj.weight,
+            )?;
+        }
+        loader.read("norm_f.gamma", &mut mamba.norm_f.gamma)?;
+        if tensors.tensor(HF_LM_HEAD).is_ok() {
+            loader.read_as(HF_LM_HEAD, &mut mamba.lm_head.weight)?;
+        } else {
+            mamba.lm_head.weight = mamba.embedding.weight.clone();
+        }
+        Ok(())
+    }
+
+    /// The tensors being deserialized.
+    struct HfLoader<'a> {
+        load_renames: &'a HashMap<String, String>,
+        tensors: &'a SafeTensors<'a>,
+    }
+
+    impl HfLoader<'_> {
+        /// Reads the `tensor` from the Hugging Face name of the dfdx `key`.
+        fn read<S: Shape, E: MambaDtype>(
+            &self,
+            key: &str,
+            tensor: &mut Tensor<S, E, Cpu>,
+        ) -> anyhow::Result<()> {
+            let name = self
+                .load_renames
+                .get(key)
+                .ok_or_else(|| anyhow::anyhow!("missing the Hugging Face name of {key}"))?;
+            self.read_as(name, tensor)
+        }
+
+        fn read_as<S: Shape, E: MambaDtype>(
+            &self,
+            name: &str,
+            tensor: &mut Tensor<S, E, Cpu>,
+        ) -> anyhow::Result<()> {
+            let view = self.tensors.tensor(name)?;
+            let values = precision::read_values::<E>(view.data(), view.dtype())?;
+            let shape = *tensor.shape();
+            if values.len() != shape.num_elements() {
+                anyhow::bail!(
+                    "{name}: expected {} values for the shape {:?}, got {}",
+                    shape.num_elements(),
+                    shape.concrete(),
+                    values.len()
+                );
+            }
+            *tensor = tensor.device().try_tensor_from_vec(values, shape)?;
+            Ok(())
+        }
+    }
+
+    /// The inverse of [load_renames], from the Hugging Face names into the dfdx names.
+    ///
+    /// The embedding name only maps into the embedding, since the tied `lm_head` has no name of its own.
+    pub fn hf_renames(n_layer: usize) -> HashMap<String, String> {
+        load_renames(n_layer)
+            .into_iter()
+            .filter(|(key, _)| key != "lm_head.weight")
+            .map(|(key, name)| (name, key))
+            .collect()
+    }
+}
+
+/// Saving into the Hugging Face names and layout, the inverse of [load].
+pub mod save {
+    use super::*;
+    use crate::precision::{self, MambaDtype};
+    use prefill::MixedDevice;
+    use safetensors::tensor::{Dtype as SafeDtype, TensorView};
+    use std::collections::HashMap;
+
+    /// Serializes the `mamba` parameters as safetensors, with the Hugging Face names and shapes
+    /// (see [load::load_renames]) and in the `E` dtype, so that the reference implementations
+    /// can load them.
+    ///
+    /// As in the original checkpoints, the `lm_head` is omitted when it's tied to the embedding.
+    /// Otherwise it's written as [load::HF_LM_HEAD], which [crate::source::load_mamba] then
+    /// prefers over the embedding. The `lm_head` bias has no place in that layout, so it must be zero.
+    pub fn to_hf_safetensors<E: MambaDtype>(mamba: &Mamba<E, Cpu>) -> anyhow::Result<Vec<u8>>
+    where
+        Cpu: MixedDevice<E, f32>,
+    {
+        let load_renames = load::load_renames(mamba.layers.len());
+        let mut tensors = HfTensors {
+            load_renames: &load_renames,
+            tensors: vec![],
+        };
+        tensors.push("embedding.weight", &mamba.embedding.weight)?;
+        for (i, layer) in mamba.layers.iter().enumerate() {
+            let (norm, block) = &layer.res.0;
+            let k = format!("layers.{i}.res.0");
+            tensors.push(&format!("{k}.0.gamma"), &norm.gamma)?;
+            tensors.push(&format!("{k}.1.in_proj.weight"), &block.in_proj.weight)?;
+            tensors.push(&format!("{k}.1.conv1d.weight"), &block.conv1d.weight)?;
+            tensors.push(&format!("{k}.1.conv1d_bias.bias"), &block.conv1d_bias.bias)?;
+            tensors.push(&format!("{k}.1.x_proj.weight"), &block.x_proj.weight)?;
+            tensors.push(&format!("{k}.1.dt_proj.weight"), &block.dt_proj.weight)?;
+            tensors.push(&format!("{k}.1.dt_proj.bias"), &block.dt_proj.bias)?;
+            tensors.push(&format!("{k}.1.a_log"), &block.a_log)?;
+            tensors.push(&format!("{k}.1.d"), &block.d)?;
+            tensors.push(&format!("{k}.1.out_proj.weight"), &block.out_proj.weight)?;
+        }
+        tensors.push("norm_f.gamma", &mamba.norm_f.gamma)?;
+
+        let lm_head_bias = mamba.lm_head.bias.clone().try_to_dtype::<f32>()?.as_vec();
+        if lm_head_bias.iter().any(|&b| b != 0.) {
+            anyhow::bail!("the lm_head has a bias, which the Hugging Face layout lacks");
+        }
+        if mamba.lm_head.weight.as_vec() != mamba.embedding.weight.as_vec() {
+            tensors.push_as(load::HF_LM_HEAD, &mamba.lm_head.weight)?;
+        }
+
+        let views = tensors
+            .tensors
+            .iter()
+            .map(|(name, dtype, shape, data)| {
+                Ok((name.clone(), TensorView::new(*dtype, shape.clone(), data)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(safetensors::serialize(views, &None)?)
+    }
+
+    /// Writes the `mamba` parameters into a safetensors file (see [to_hf_safetensors]).
+    pub fn save_hf_safetensors<E: MambaDtype>(
+        mamba: &Mamba<E, Cpu>,
+        path: impl AsRef<std::path::Path>,
+    ) -> anyhow::Result<()>
+    where
+        Cpu: MixedDevice<E, f32>,
+    {
+        std::fs::write(path, to_hf_safetensors(mamba)?)?;
+        Ok(())
+    }
+
+    /// The tensors being serialized.
+    struct HfTensors<'a> {
+        load_renames: &'a HashMap<String, String>,
+        tensors: Vec<(String, SafeDtype, Vec<usize>, Vec<u8>)>,
+    }
+
+    impl HfTensors<'_> {
+        /// Pushes the `tensor` under the Hugging Face name of the dfdx `key`.
+        fn push<S: Shape, E: MambaDtype>(
+            &mut self,
+            key: &str,
+            tensor: &Tensor<S, E, Cpu>,
+        ) -> anyhow::Result<()>
+        where
+            Cpu: MixedDevice<E, f32>,
+        {
+            let name = self
+                .load_renames
+                .get(key)
+                .ok_or_else(|| anyhow::anyhow!("missing the Hugging Face name of {key}"))?
+                .clone();
+            self.push_as(&name, tensor)
+        }
+
+        fn push_as<S: Shape, E: MambaDtype>(
+            &mut self,
+            name: &str,
+            tensor: &Tensor<S, E, Cpu>,
+        ) -> anyhow::Result<()>
+        where
+            Cpu: MixedDevice<E, f32>,
+        {
+            let shape = tensor.shape().concrete().into_iter().collect();
+            let values: Vec<u8> = tensor
+                .clone()
+                .try_to_dtype::<f32>()?
+                .as_vec()
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect();
+            let data = precision::convert_data(&values, SafeDtype::F32, E::SAFETENSORS)?;
+            self.tensors
+                .push((name.into(), E::SAFETENSORS, shape, data));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::precision::{bf16, f16, MambaDtype};
+
+    /// The `config.json` of `state-spaces/mamba-130m`.
+    const MAMBA_130M: &str = r#"{"d_model": 768, "n_layer": 24, "vocab_size": 50277, "ssm_cfg": {}, "rms_norm": true, "residual_in_fp32": true, "fused_add_norm": true, "pad_vocab_size_multiple": 8}"#;
+
+    /// The `config.json` of `state-spaces/mamba-130m-hf`.
+    const MAMBA_130M_HF: &str = r#"{"architectures": ["MambaForCausalLM"], "bos_token_id": 0, "conv_kernel": 4, "eos_token_id": 0, "expand": 2, "hidden_act": "silu", "hidden_size": 768, "initializer_range": 0.1, "intermediate_size": 1536, "layer_norm_epsilon": 1e-05, "model_type": "mamba", "n_layer": 24, "num_hidden_layers": 24, "pad_token_id": 0, "rescale_prenorm_residual": false, "residual_in_fp32": true, "state_size": 16, "time_step_floor": 0.0001, "time_step_init_scheme": "random", "time_step_max": 0.1, "time_step_min": 0.001, "time_step_rank": 48, "time_step_scale": 1.0, "torch_dtype": "float32", "transformers_version": "4.39.0.dev0", "use_bias": false, "use_cache": true, "use_conv_bias": true, "vocab_size": 50280}"#;
+
+    /// [MambaConfig] has no `PartialEq`, but its `Debug` lists all of the dimensions.
+    fn assert_same(config: &MambaConfig, expected: &MambaConfig) {
+        assert_eq!(format!("{config:?}"), format!("{expected:?}"));
+    }
+
+    fn with_field(json: &str, key: &str, value: serde_json::Value) -> String {
+        let mut json: serde_json::Value = serde_json::from_str(json).unwrap();
+        match value {
+            serde_json::Value::Null => json.as_object_mut().unwrap().remove(key),
+            value => json.as_object_mut().unwrap().insert(key.into(), value),
+        };
+        json.to_string()
+    }
+
+    fn states(
+        mamba: &Mamba<f32, Cpu>,
+        batch: usize,
+    ) -> stateful::MambaStatesDyn<f32, Cpu, NoneTape> {
+        let device = mamba.embedding.weight.device().clone();
+        mamba
+            .state_configs(batch)
+            .into_iter()
+            .map(|config| device.build_module::<f32>(config))
+            .collect()
+    }
+
+    #[test]
+    fn prefill_rejects_empty_inputs_and_mismatched_states() {
+        let mamba = crate::test_utils::mamba(0);
+        let device = mamba.embedding.weight.device().clone();
+        let input = |seq: usize| device.tensor_from_vec(vec![1usize; seq], (1, seq));
+
+        assert!(mamba
+            .try_forward_hidden((input(3), states(&mamba, 1)))
+            .is_ok());
+        assert!(mamba
+            .try_forward_hidden((input(0), states(&mamba, 1)))
+            .is_err());
+        assert!(mamba
+            .try_forward_hidden_mixed::<f32>((input(0), states(&mamba, 1)))
+            .is_err());
+
+        // a different batch size, or a missing layer
+        assert!(mamba
+            .try_forward_hidden((input(3), states(&mamba, 2)))
+            .is_err());
+        let mut missing = states(&mamba, 1);
+        missing.pop();
+        assert!(mamba.try_forward_hidden((input(3), missing)).is_err());
+        let mut missing = states(&mamba, 1);
+        missing.pop();
+        assert!(mamba
+            .try_forward_hidden_mixed::<f32>((input(3), missing))
+            .is_err());
+    }
+
+    /// The logits of the `tokens`, with the weights in `E` and the activations in `f32`.
+    fn mixed_logits<E: MambaDtype>(
+        mamba: &Mamba<E, Cpu>,
+        states: stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+    ) -> Vec<f32>
+    where
+        Cpu: prefill::MixedDevice<E, E> + prefill::MixedDevice<E, f32>,
+    {
+        let device = mamba.embedding.weight.device().clone();
+        let tokens = device.tensor_from_vec(vec![3usize, 1, 4, 1, 5, 9], (1, 6));
+        let (x, _states) = mamba
+            .try_forward_hidden_mixed::<f32>((tokens, states))
+            .unwrap();
+        mamba.try_lm_head_mixed(x).unwrap().as_vec()
+    }
+
+    #[test]
+    fn half_precision_weights_follow_f32() {
+        const CONFIG_JSON: &str =
+            r#"{"d_model": 16, "n_layer": 2, "vocab_size": 32, "pad_vocab_size_multiple": 8}"#;
+        let mut mamba = crate::test_utils::mamba(0);
+        crate::train::tie_lm_head(&mut mamba);
+        mamba.lm_head.bias.fill_with_zeros();
+        let weights = save::to_hf_safetensors(&mamba).unwrap();
+        let expected = mixed_logits(&mamba, states(&mamba, 1));
+
+        let f16_mamba = crate::source::load_mamba::<f16>(CONFIG_JSON, &weights).unwrap();
+        let bf16_mamba = crate::source::load_mamba::<bf16>(CONFIG_JSON, &weights).unwrap();
+        for (actual, tolerance) in [
+            (mixed_logits(&f16_mamba, states(&mamba, 1)), 2e-2),
+            (mixed_logits(&bf16_mamba, states(&mamba, 1)), 1e-1),
+        ] {
+            for (e, a) in expected.iter().zip(&actual) {
+                assert!(
+                    (e - a).abs() <= tolerance * e.abs().max(1.),
+                    "expected {e}, got {a}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mamba_130m_configs() -> anyhow::Result<()> {
+        let expected = MambaConfig::new(24, 50280, 768, None, None, None, None);
+        assert_same(&MambaConfig::from_hf_config_json(MAMBA_130M)?, &expected);
+        assert_same(&MambaConfig::from_hf_config_json(MAMBA_130M_HF)?, &expected);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_fields_are_rejected() {
+        for key in ["d_model", "n_layer", "vocab_size"] {
+            let json = with_field(MAMBA_130M, key, serde_json::Value::Null);
+            let error = MambaConfig::from_hf_config_json(&json).unwrap_err();
+            assert!(error.to_string().contains(key), "{error}");
+        }
+        // the transformers layout has both `n_layer` and `num_hidden_layers`
+        let json = with_field(MAMBA_130M_HF, "n_layer", serde_json::Value::Null);
+        assert!(MambaConfig::from_hf_config_json(&json).is_ok());
+        let json = with_field(&json, "num_hidden_layers", serde_json::Value::Null);
+        assert!(MambaConfig::from_hf_config_json(&json).is_err());
+    }
+
+    #[test]
+    fn inconsistent_layouts_are_rejected() {
+        let json = with_field(MAMBA_130M_HF, "num_hidden_layers", 12.into());
+        assert!(MambaConfig::from_hf_config_json(&json).is_err());
+        let json = with_field(MAMBA_130M_HF, "intermediate_size", 1000.into());
+        assert!(MambaConfig::from_hf_config_json(&json).is_err());
+    }
+
+    #[test]
+    fn vocab_is_padded() -> anyhow::Result<()> {
+        for (vocab_size, pad, padded) in [
+            (50277, Some(8), 50280),
+            (50277, None, 50280),
+            (50277, Some(16), 50288),
+            (50280, Some(8), 50280),
+            (50277, Some(1), 50277),
+        ] {
+            let mut json = with_field(MAMBA_130M, "vocab_size", vocab_size.into());
+            json = with_field(&json, "pad_vocab_size_multiple", pad.into());
+            let config = MambaConfig::from_hf_config_json(&json)?;
+            assert_eq!(config.embedding.vocab, padded);
+            assert_eq!(config.lm_head.out, padded);
+        }
+        let json = with_field(MAMBA_130M, "pad_vocab_size_multiple", 0.into());
+        assert!(MambaConfig::from_hf_config_json(&json).is_err());
+        Ok(())
+    }
 }
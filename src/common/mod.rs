@@ -1,8 +1,27 @@
+pub mod batch;
+pub mod beam;
+pub mod constraint;
+pub mod eval;
+pub mod generation;
+pub mod logits;
+pub mod lora;
 pub mod mamba;
+pub mod model;
+pub mod precision;
+pub mod quant;
+pub mod registry;
+pub mod snapshot;
+pub mod source;
+#[cfg(test)]
+pub(crate) mod test_utils;
 pub mod token_output_stream;
+pub mod train;
 
-use candle_transformers::generation::LogitsProcessor;
 use dfdx::prelude::*;
+pub use generation::{GenerationConfig, GenerationEvent};
+pub use logits::{LogitsProcessor, LogitsProcessorWrapper};
+pub use model::MambaModel;
+pub use precision::MambaDtype;
 use token_output_stream::TokenOutputStream;
 use tokenizers::Tokenizer;
 
@@ -32,20 +51,18 @@ pub mod hf {
     }
 }
 
-pub struct MambaWrapper {
+/// The tokenizer and the model.
+///
+/// Regardless of how the model stores its weights (see [MambaModel]),
+/// the activations, the states and the logits are always `f32`.
+pub struct MambaWrapper<M: MambaModel = mamba::Mamba<f32, Cpu>> {
     pub tokenizer: TokenOutputStream,
-    pub mamba: mamba::Mamba<f32, Cpu>,
+    pub mamba: M,
 }
 
-pub struct LogitsProcessorWrapper {
-    logits_processor: LogitsProcessor,
-    repeat_penalty: f32,
-    repeat_last_n: usize,
-}
-
-impl MambaWrapper {
+impl<M: MambaModel> MambaWrapper<M> {
     #[allow(clippy::too_many_arguments)]
-    pub fn new(tokenizer: Tokenizer, mamba: mamba::Mamba<f32, Cpu>) -> Self {
+    pub fn new(tokenizer: Tokenizer, mamba: M) -> Self {
         Self {
             tokenizer: TokenOutputStream::new(tokenizer),
             mamba,
@@ -56,6 +73,11 @@ impl MambaWrapper {
     /// and also the eos token.
     pub fn reset_prompt(&mut self, prompt: &str) -> anyhow::Result<(Vec<u32>, u32)> {
         self.tokenizer.clear();
+        self.encode_prompt(prompt)
+    }
+
+    /// Returns the `prompt` as a list of Vocab tokens and also the eos token.
+    pub fn encode_prompt(&self, prompt: &str) -> anyhow::Result<(Vec<u32>, u32)> {
         let tokens = self
             .tokenizer
             .tokenizer()
@@ -63,26 +85,30 @@ impl MambaWrapper {
             .map_err(anyhow::Error::msg)?
             .get_ids()
             .to_vec();
-        let eos_token = match self.tokenizer.get_token("<|endoftext|>") {
-            Some(token) => token,
-            None => anyhow::bail!("cannot find the </s> token"),
-        };
+        let eos_token = self.eos_token()?;
         Ok((tokens, eos_token))
     }
 
+    /// The token the model uses to signal the end of the generation.
+    pub fn eos_token(&self) -> anyhow::Result<u32> {
+        match self.tokenizer.get_token("<|endoftext|>") {
+            Some(token) => Ok(token),
+            None => anyhow::bail!("cannot find the </s> token"),
+        }
+    }
+
     /// Initializes a list of empty (zero, null) [mamba::stateful::StateCache] for a stateful run.
+    ///
+    /// The state dimensions are read from each layer of the model,
+    /// and each state holds `batch_size` independent instances.
     pub fn empty_states(
         &self,
+        batch_size: usize,
     ) -> anyhow::Result<mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>> {
-        let cpu = self.mamba.embedding.weight.device();
+        let cpu = self.mamba.device();
         let mut states = vec![];
-        for _ in 0..self.mamba.layers.len() {
-            let state = cpu.try_build_module::<f32>(dfdx_mamba::MambaStateCacheConfig::new(
-                1,
-                16,
-                4,
-                768 * 2,
-            ))?;
+        for state_config in self.mamba.state_configs(batch_size) {
+            let state = cpu.try_build_module::<f32>(state_config)?;
             states.push(state);
         }
         Ok(states)
@@ -95,57 +121,12 @@ impl MambaWrapper {
         sample_len: usize,
         logits_processor_config: &mut LogitsProcessorWrapper,
     ) -> anyhow::Result<()> {
-        use std::io::Write;
-        let (mut tokens, eos_token) = self.reset_prompt(prompt)?;
-        let cpu = self.mamba.embedding.weight.device();
-
-        // prints the first token (if present), as this is used as *input* to the model
-        if let Some(t) = tokens.first() {
-            if let Some(t) = self.tokenizer.next_token(*t)? {
-                print!("{t}")
-            }
-        }
-        std::io::stdout().flush()?;
-
-        let mut i = 0;
-        'outer: while i < sample_len {
-            let input = cpu
-                .tensor_from_vec(tokens.clone(), (1, tokens.len()))
-                .to_dtype::<usize>();
-            let logits_list = <mamba::Mamba<f32, Cpu> as Module<
-                mamba::stateless::VocabInput<Cpu, NoneTape>,
-            >>::try_forward(&self.mamba, input)?;
-            let shape = (logits_list.shape().2,);
-            let logits_list = logits_list.as_vec();
-
-            // logits contains an output for each timestep
-            let logits_list = logits_list
-                .chunks_exact(50280)
-                .map(|chunk| {
-                    candle_core::Tensor::from_slice(chunk, shape, &candle_core::Device::Cpu)
-                })
-                .skip(i)
-                .collect::<Result<Vec<_>, _>>()?;
-            for logits in logits_list.into_iter() {
-                let next_token = logits_processor_config.add_logits(i, &mut tokens, logits)?;
-                if next_token == eos_token {
-                    break 'outer;
-                }
-
-                // if the token has some valid representation, print it
-                if let Some(t) = self.tokenizer.next_token(next_token)? {
-                    #[allow(unused_imports)]
-                    use std::io::Write;
-                    print!("{t}");
-                    std::io::stdout().flush()?;
-                }
-                i += 1;
-            }
-        }
-        if let Some(rest) = self.tokenizer.decode_rest().map_err(anyhow::Error::msg)? {
-            print!("{rest}");
-        }
-        Ok(())
+        let config = GenerationConfig {
+            mode: generation::Mode::Stateless,
+            sample_len,
+            ..Default::default()
+        };
+        self.print_generation(prompt, config, logits_processor_config)
     }
 
     /// Reset and make up to `sample_len - 1` stateful calls to generate up to `sample_len - 1` tokens.
@@ -155,119 +136,160 @@ impl MambaWrapper {
         sample_len: usize,
         logits_processor_config: &mut LogitsProcessorWrapper,
     ) -> anyhow::Result<()> {
-        use std::io::Write;
-        let (mut tokens, eos_token) = self.reset_prompt(prompt)?;
-
-        // prints the first token (if present), as this is used as *input* to the model
-        if let Some(t) = tokens.first() {
-            if let Some(t) = self.tokenizer.next_token(*t)? {
-                print!("{t}")
-            }
-        }
-        std::io::stdout().flush()?;
-
-        let mut states = self.empty_states()?;
-
-        let mut i = 0;
-        while i < sample_len {
-            let next_logits = self.step(tokens[i], &mut states)?;
-            let next_token = logits_processor_config.add_logits(i, &mut tokens, next_logits)?;
-            if next_token == eos_token {
-                break;
-            }
+        let config = GenerationConfig {
+            mode: generation::Mode::Stateful,
+            sample_len,
+            ..Default::default()
+        };
+        self.print_generation(prompt, config, logits_processor_config)
+    }
 
+    /// Reset and print each text fragment as soon as it gets generated.
+    fn print_generation(
+        &mut self,
+        prompt: &str,
+        config: GenerationConfig,
+        logits_processor_config: &mut LogitsProcessorWrapper,
+    ) -> anyhow::Result<()> {
+        use std::io::Write;
+        for event in self.generate(prompt, config, logits_processor_config)? {
+            let text = match event? {
+                GenerationEvent::Token(token) => token.text,
+                GenerationEvent::Finish { text, .. } => text,
+            };
             // if the token has some valid representation, print it
-            if let Some(t) = self.tokenizer.next_token(next_token)? {
-                #[allow(unused_imports)]
-                use std::io::Write;
+            if let Some(t) = text {
                 print!("{t}");
                 std::io::stdout().flush()?;
             }
-
-            i += 1;
-        }
-        if let Some(rest) = self.tokenizer.decode_rest().map_err(anyhow::Error::msg)? {
-            print!("{rest}");
         }
         Ok(())
     }
 
+    /// Make a stateless call over all `tokens` and return the logits of each timestep,
+    /// skipping the first `skip` timesteps.
+    pub fn stateless_logits(&self, tokens: &[u32], skip: usize) -> anyhow::Result<Vec<Vec<f32>>> {
+        let cpu = self.mamba.device();
+        let input = cpu
+            .tensor_from_vec(tokens.to_vec(), (1, tokens.len()))
+            .to_dtype::<usize>();
+        let logits_list = self.mamba.try_forward_stateless_f32(input)?;
+        let vocab = logits_list.shape().2;
+        let logits_list = logits_list.as_vec();
+
+        // logits contains an output for each timestep
+        let logits_list = logits_list
+            .chunks_exact(vocab)
+            .skip(skip)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        Ok(logits_list)
+    }
+
     /// Make a stateful call to generate a logits.
     ///
     /// `i` is the i-th call. For the first call, `i` should be `0`.
+    ///
+    /// The `states` must have a batch size of `1`.
     pub fn step(
         &self,
         input: u32,
         states: &mut mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
-    ) -> anyhow::Result<candle_core::Tensor> {
-        let cpu = self.mamba.embedding.weight.device();
-        let input = cpu.tensor_from_vec(vec![input], (1,)).to_dtype::<usize>();
-        let states_owned = std::mem::take(states);
-        let input = (input, states_owned);
-        let (logits, new_states) = <mamba::Mamba<f32, Cpu> as Module<
-            mamba::stateful::VocabInputWithStates<f32, Cpu, NoneTape>,
-        >>::try_forward(&self.mamba, input)?;
-        *states = new_states;
-
-        let shape = (logits.shape().1,);
-        let logits =
-            candle_core::Tensor::from_vec(logits.as_vec(), shape, &candle_core::Device::Cpu)?
-                .to_dtype(candle_core::DType::F32)?;
-        Ok(logits)
+    ) -> anyhow::Result<Vec<f32>> {
+        let batch_size = self.mamba.check_states(states)?;
+        if batch_size != 1 {
+            anyhow::bail!("expected states with a batch size of 1, got {batch_size}");
+        }
+        let logits = self.forward_single(&[input], states)?;
+        Ok(logits.as_vec())
     }
-}
 
-impl LogitsProcessorWrapper {
-    pub fn new(
-        seed: u64,
-        temp: Option<f64>,
-        top_p: Option<f64>,
-        repeat_penalty: f32,
-        repeat_last_n: usize,
-    ) -> Self {
-        let logits_processor = LogitsProcessor::new(seed, temp, top_p);
-        Self {
-            logits_processor,
-            repeat_penalty,
-            repeat_last_n,
+    /// Make a single sequence-mode call over all `tokens`, starting from the `states`,
+    /// and return the logits of the last timestep.
+    ///
+    /// The `states` get updated as if each token were fed through [MambaWrapper::step],
+    /// so the stateful decoding can continue from them.
+    ///
+    /// The `states` must have a batch size of `1`.
+    pub fn prefill(
+        &self,
+        tokens: &[u32],
+        states: &mut mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+    ) -> anyhow::Result<Vec<f32>> {
+        if tokens.is_empty() {
+            anyhow::bail!("cannot prefill an empty list of tokens");
+        }
+        let batch_size = self.mamba.check_states(states)?;
+        if batch_size != 1 {
+            anyhow::bail!("expected states with a batch size of 1, got {batch_size}");
         }
+        let hidden = self.forward_hidden(tokens, (1, tokens.len()), states)?;
+
+        // only the last timestep needs the logits
+        let seq = hidden.shape().1;
+        let hidden = hidden.try_slice((.., seq - 1..seq, ..))?;
+        let logits = self.mamba.try_lm_head_f32(hidden)?;
+        Ok(logits.as_vec())
     }
 
-    /// Add logits that represents a token.
+    /// Make a stateful call for a batch of inputs, and return the logits of each instance.
     ///
-    /// `i` is the i-th call. For the first call, `i` should be `0`.
-    pub fn add_logits(
-        &mut self,
-        i: usize,
-        tokens: &mut Vec<u32>,
-        logits: candle_core::Tensor,
-    ) -> anyhow::Result<u32> {
-        let logits = if self.repeat_penalty == 1. {
-            logits
-        } else {
-            let start_at = i.saturating_sub(self.repeat_last_n);
-            candle_transformers::utils::apply_repeat_penalty(
-                &logits,
-                self.repeat_penalty,
-                &tokens[start_at..i + 1],
-            )?
-        };
+    /// The `states` must have a batch size of `inputs.len()`.
+    pub fn step_batch(
+        &self,
+        inputs: &[u32],
+        states: &mut mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        let batch_size = self.mamba.check_states(states)?;
+        if batch_size != inputs.len() {
+            anyhow::bail!(
+                "expected states with a batch size of {}, got {batch_size}",
+                inputs.len()
+            );
+        }
+        let logits = self.forward_single(inputs, states)?;
 
-        let next_token;
-        if i + 1 < tokens.len() {
-            // don't try to predict the next token (it was pre-defined)
-            // also don't increment the "tokens" list (this token was already part of the list)
-            next_token = tokens[i + 1];
+        let vocab = logits.shape().1;
+        let logits = logits
+            .as_vec()
+            .chunks_exact(vocab)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        Ok(logits)
+    }
 
-            // should it still sample? idk
-            // let _discarded_token = logits_processor.sample(&logits)?;
-        } else {
-            // try to predict the next token
-            next_token = self.logits_processor.sample(&logits)?;
-            // add the token to the "tokens" list
-            tokens.push(next_token);
-            // *generated_tokens += 1;
-        }
-        Ok(next_token)
+    /// Make a stateful call over a single timestep of each instance, starting from and updating
+    /// the `states`, and return the `(batch, vocab)` logits.
+    fn forward_single(
+        &self,
+        inputs: &[u32],
+        states: &mut mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+    ) -> anyhow::Result<Tensor<(usize, usize), f32, Cpu>> {
+        let cpu = self.mamba.device();
+        let input = cpu
+            .tensor_from_vec(inputs.to_vec(), (inputs.len(),))
+            .to_dtype::<usize>();
+        let states_owned = std::mem::take(states);
+        let (logits, new_states) = self.mamba.try_forward_stateful_f32((input, states_owned))?;
+        *states = new_states;
+        Ok(logits)
+    }
+
+    /// Make a sequence-mode call over the `tokens`, laid out as `(batch, seq)`, starting from
+    /// and updating the `states`, and return the `f32` hidden activations of all timesteps.
+    pub(crate) fn forward_hidden(
+        &self,
+        tokens: &[u32],
+        shape: (usize, usize),
+        states: &mut mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+    ) -> anyhow::Result<mamba::stateless::BlockInput<f32, Cpu, NoneTape>> {
+        let cpu = self.mamba.device();
+        let input = cpu
+            .tensor_from_vec(tokens.to_vec(), shape)
+            .to_dtype::<usize>();
+        let states_owned = std::mem::take(states);
+        let (hidden, new_states) = self.mamba.try_forward_hidden_f32((input, states_owned))?;
+        *states = new_states;
+        Ok(hidden)
     }
 }
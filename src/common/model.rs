@@ -0,0 +1,167 @@
+//! The models that a [crate::MambaWrapper] can run.
+//!
+//! Regardless of how a model stores its weights, the activations, the states and the logits
+//! it exchanges with the wrapper are always `f32`.
+//!
+//! `f32` weights run the stateless and stateful modules (see [mamba::stateless] and
+//! [mamba::stateful]) as-is, while the other models run those calls through the sequence-mode
+//! forward of [mamba::prefill] (see [try_forward_stateless_mixed] and [try_forward_stateful_mixed]).
+
+use crate::mamba::prefill::{BlockInputWithStates, MixedDevice, VocabInputWithStates};
+use crate::mamba::stateful::{MambaStatesDyn, SingleOutputWithStates, StateCacheConfig};
+use crate::mamba::stateless::BlockInput;
+use crate::mamba::{self, Batch, DConv, DInner, DState};
+use crate::precision::MambaDtype;
+use dfdx::prelude::*;
+use std::any::Any;
+
+pub trait MambaModel {
+    /// A short description of how the weights are stored, for the logs.
+    fn weights_format(&self) -> String;
+
+    /// The device of the model.
+    fn device(&self) -> Cpu;
+
+    /// The state dimensions `(d_state, d_conv, d_inner)` of each layer.
+    fn state_dims(&self) -> Vec<(DState, DConv, DInner)>;
+
+    /// Runs the embedding, all layers and the final norm, but not the `lm_head`,
+    /// starting from the `f32` states.
+    fn try_forward_hidden_f32(
+        &self,
+        x: VocabInputWithStates<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInputWithStates<f32, Cpu>, Error>;
+
+    /// Applies the `lm_head` over the hidden activations.
+    fn try_lm_head_f32(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error>;
+
+    /// Serializes the weights as safetensors in the Hugging Face layout
+    /// (see [mamba::save::to_hf_safetensors]), with the quantized weights dequantized
+    /// and the adapters merged.
+    fn to_hf_safetensors(&self) -> anyhow::Result<Vec<u8>>;
+
+    /// A stateless call over all timesteps, `(batch, seq) -> (batch, seq, vocab)`.
+    ///
+    /// Defaults to [try_forward_stateless_mixed].
+    fn try_forward_stateless_f32(
+        &self,
+        x: mamba::stateless::VocabInput<Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        try_forward_stateless_mixed(self, x)
+    }
+
+    /// A stateful call over a single timestep, `(batch,) -> (batch, vocab)`,
+    /// starting from and returning the states.
+    ///
+    /// Defaults to [try_forward_stateful_mixed].
+    fn try_forward_stateful_f32(
+        &self,
+        x: mamba::stateful::VocabInputWithStates<f32, Cpu, NoneTape>,
+    ) -> Result<SingleOutputWithStates<f32, Cpu, NoneTape>, Error> {
+        try_forward_stateful_mixed(self, x)
+    }
+
+    /// The [StateCacheConfig] for each layer, so that the states match the model.
+    fn state_configs(&self, batch: Batch) -> Vec<StateCacheConfig> {
+        self.state_dims()
+            .into_iter()
+            .map(|(d_state, d_conv, d_inner)| {
+                dfdx_mamba::MambaStateCacheConfig::new(batch, d_state, d_conv, d_inner)
+            })
+            .collect()
+    }
+
+    /// Checks that the `states` match the model, and returns their batch size.
+    fn check_states(&self, states: &MambaStatesDyn<f32, Cpu, NoneTape>) -> anyhow::Result<Batch> {
+        mamba::stateful::check_states(&self.state_dims(), states)
+    }
+}
+
+/// A stateless call as a sequence-mode call (see [MambaModel::try_forward_hidden_f32])
+/// that starts from empty states.
+pub fn try_forward_stateless_mixed<M: MambaModel + ?Sized>(
+    model: &M,
+    x: mamba::stateless::VocabInput<Cpu, NoneTape>,
+) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+    let device = model.device();
+    let states = model
+        .state_configs(x.shape().0)
+        .into_iter()
+        .map(|config| device.try_build_module::<f32>(config))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (hidden, _states) = model.try_forward_hidden_f32((x, states))?;
+    model.try_lm_head_f32(hidden)
+}
+
+/// A stateful call as a sequence-mode call (see [MambaModel::try_forward_hidden_f32])
+/// over a single timestep.
+pub fn try_forward_stateful_mixed<M: MambaModel + ?Sized>(
+    model: &M,
+    x: mamba::stateful::VocabInputWithStates<f32, Cpu, NoneTape>,
+) -> Result<SingleOutputWithStates<f32, Cpu, NoneTape>, Error> {
+    let (x, states) = x;
+    let batch = x.shape().0;
+    let x = model.device().try_tensor_from_vec(x.as_vec(), (batch, 1))?;
+    let (hidden, states) = model.try_forward_hidden_f32((x, states))?;
+    let logits = model.try_lm_head_f32(hidden)?;
+    let vocab = logits.shape().2;
+    Ok((logits.try_reshape_like(&(batch, vocab))?, states))
+}
+
+impl<E: MambaDtype> MambaModel for mamba::Mamba<E, Cpu>
+where
+    Cpu: MixedDevice<E, f32>,
+{
+    fn weights_format(&self) -> String {
+        E::NAME.into()
+    }
+
+    fn device(&self) -> Cpu {
+        self.embedding.weight.device().clone()
+    }
+
+    fn state_dims(&self) -> Vec<(DState, DConv, DInner)> {
+        mamba::Mamba::state_dims(self)
+    }
+
+    fn try_forward_hidden_f32(
+        &self,
+        x: VocabInputWithStates<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInputWithStates<f32, Cpu>, Error> {
+        self.try_forward_hidden_mixed(x)
+    }
+
+    fn try_lm_head_f32(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        self.try_lm_head_mixed(x)
+    }
+
+    fn try_forward_stateless_f32(
+        &self,
+        x: mamba::stateless::VocabInput<Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        match (self as &dyn Any).downcast_ref::<mamba::Mamba<f32, Cpu>>() {
+            Some(mamba) => mamba.try_forward(x),
+            None => try_forward_stateless_mixed(self, x),
+        }
+    }
+
+    fn try_forward_stateful_f32(
+        &self,
+        x: mamba::stateful::VocabInputWithStates<f32, Cpu, NoneTape>,
+    ) -> Result<SingleOutputWithStates<f32, Cpu, NoneTape>, Error> {
+        match (self as &dyn Any).downcast_ref::<mamba::Mamba<f32, Cpu>>() {
+            Some(mamba) => mamba.try_forward(x),
+            None => try_forward_stateful_mixed(self, x),
+        }
+    }
+
+    fn to_hf_safetensors(&self) -> anyhow::Result<Vec<u8>> {
+        mamba::save::to_hf_safetensors(self)
+    }
+}
@@ -0,0 +1,99 @@
+//! The dtypes that the model weights can be loaded into.
+//!
+//! The weights are kept in the [MambaDtype], while the activations and the states are always
+//! `f32` (see [crate::mamba::prefill::MixedDevice]). Half-precision weights halve the memory.
+//!
+//! The checkpoints may be in `f32`, `f16` or `bf16`, and their tensors are converted into the
+//! [MambaDtype] while loading, one at a time (see [crate::mamba::load::load_hf_safetensors]).
+
+use dfdx::prelude::*;
+use safetensors::tensor::Dtype as SafeDtype;
+
+pub use half::{bf16, f16};
+
+/// A dtype for the model weights.
+pub trait MambaDtype: Dtype {
+    /// The name used in the cli and in the logs.
+    const NAME: &'static str;
+    /// The matching safetensors dtype.
+    const SAFETENSORS: SafeDtype;
+
+    /// Rounds the `value` into this dtype.
+    fn from_f32(value: f32) -> Self;
+}
+
+impl MambaDtype for f32 {
+    const NAME: &'static str = "f32";
+    const SAFETENSORS: SafeDtype = SafeDtype::F32;
+
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
+
+impl MambaDtype for f16 {
+    const NAME: &'static str = "f16";
+    const SAFETENSORS: SafeDtype = SafeDtype::F16;
+
+    fn from_f32(value: f32) -> Self {
+        f16::from_f32(value)
+    }
+}
+
+impl MambaDtype for bf16 {
+    const NAME: &'static str = "bf16";
+    const SAFETENSORS: SafeDtype = SafeDtype::BF16;
+
+    fn from_f32(value: f32) -> Self {
+        bf16::from_f32(value)
+    }
+}
+
+/// Converts the little-endian `data` from the `source` dtype into `E` values.
+///
+/// The conversion goes through `f32`, which represents all of the supported dtypes exactly.
+pub fn read_values<E: MambaDtype>(data: &[u8], source: SafeDtype) -> anyhow::Result<Vec<E>> {
+    Ok(decode(data, source)?.map(E::from_f32).collect())
+}
+
+/// Converts the little-endian `data` from the `source` into the `target` dtype.
+pub(crate) fn convert_data(
+    data: &[u8],
+    source: SafeDtype,
+    target: SafeDtype,
+) -> anyhow::Result<Vec<u8>> {
+    let values: Vec<f32> = decode(data, source)?.collect();
+    let bytes = match target {
+        SafeDtype::F32 => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        SafeDtype::F16 => values
+            .iter()
+            .flat_map(|v| f16::from_f32(*v).to_le_bytes())
+            .collect(),
+        SafeDtype::BF16 => values
+            .iter()
+            .flat_map(|v| bf16::from_f32(*v).to_le_bytes())
+            .collect(),
+        other => anyhow::bail!("cannot convert into {other:?}"),
+    };
+    Ok(bytes)
+}
+
+/// The values of the little-endian `data` of the `source` dtype, as `f32`.
+fn decode(data: &[u8], source: SafeDtype) -> anyhow::Result<Box<dyn Iterator<Item = f32> + '_>> {
+    let values: Box<dyn Iterator<Item = f32> + '_> = match source {
+        SafeDtype::F32 => Box::new(
+            data.chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        ),
+        SafeDtype::F16 => Box::new(
+            data.chunks_exact(2)
+                .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32()),
+        ),
+        SafeDtype::BF16 => Box::new(
+            data.chunks_exact(2)
+                .map(|b| bf16::from_le_bytes([b[0], b[1]]).to_f32()),
+        ),
+        other => anyhow::bail!("cannot convert from {other:?}"),
+    };
+    Ok(values)
+}
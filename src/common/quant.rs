@@ -0,0 +1,1104 @@
+//! Weight-only quantization of the big matrices, into int8 or grouped 4-bit.
+//!
+//! The `in_proj`, `x_proj`, `dt_proj` and `out_proj` of each block, the embedding and the `lm_head`
+//! are stored as signed integers with an `f32` scale for each group of a row (symmetric, absmax),
+//! and get dequantized on the fly during the forward, a few rows at a time. The embedding only
+//! dequantizes the rows of the input tokens, and a `lm_head` that's tied to the embedding shares
+//! its quantized matrix.
+//!
+//! The remaining (small) parameters are kept in `f32`, and so are the activations and the states.
+//!
+//! A [QMamba] can be quantized from a [mamba::Mamba], and saved to (or loaded from) a safetensors
+//! file that keeps the Hugging Face names of the tensors (see [QMamba::to_safetensors]).
+
+use crate::mamba::prefill::{
+    check_input_states, mamba_block_try_forward, rms_norm_mixed, BlockInputWithStates, BlockParts,
+    VocabInputWithStates,
+};
+use crate::mamba::stateless::BlockInput;
+use crate::mamba::{self, DConv, DInner, DModel, DState, DtRank};
+use crate::model::MambaModel;
+use dfdx::prelude::*;
+use safetensors::tensor::{Dtype as StDtype, SafeTensors, TensorView};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuantFormat {
+    /// 8 bits, with a single scale per output channel (row).
+    Int8,
+    /// 4 bits, with a scale for each `group_size` consecutive inputs of a row.
+    Int4 { group_size: usize },
+}
+
+impl QuantFormat {
+    /// The default `group_size` of [QuantFormat::Int4].
+    pub const DEFAULT_GROUP_SIZE: usize = 64;
+
+    /// The largest quantized magnitude.
+    fn max_q(&self) -> f32 {
+        match self {
+            Self::Int8 => 127.,
+            Self::Int4 { .. } => 7.,
+        }
+    }
+
+    /// How many consecutive values of a row share a scale.
+    fn group_size(&self, cols: usize) -> usize {
+        match *self {
+            Self::Int8 => cols.max(1),
+            Self::Int4 { group_size } => group_size.min(cols).max(1),
+        }
+    }
+
+    /// How many bytes each row takes.
+    fn row_bytes(&self, cols: usize) -> usize {
+        match self {
+            Self::Int8 => cols,
+            Self::Int4 { .. } => cols.div_ceil(2),
+        }
+    }
+}
+
+/// How many rows [QMatrix::matmul_t] dequantizes at a time.
+const TILE_ROWS: usize = 16;
+
+impl std::fmt::Display for QuantFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int8 => write!(f, "int8 (per-channel)"),
+            Self::Int4 { group_size } => write!(f, "int4 (groups of {group_size})"),
+        }
+    }
+}
+
+/// A quantized `(rows, cols)` matrix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QMatrix {
+    pub rows: usize,
+    pub cols: usize,
+    pub format: QuantFormat,
+    /// The quantized values, row by row.
+    ///
+    /// For int8, each byte is an `i8`. For int4, each byte holds two values offset by `8`,
+    /// the first one in the low nibble, and an odd row is padded with a zero nibble.
+    pub data: Vec<u8>,
+    /// The scales, `(rows, groups per row)`.
+    pub scales: Vec<f32>,
+}
+
+impl QMatrix {
+    /// Quantizes the row-major `(rows, cols)` `values`.
+    pub fn quantize(
+        values: &[f32],
+        rows: usize,
+        cols: usize,
+        format: QuantFormat,
+    ) -> anyhow::Result<Self> {
+        if values.len() != rows * cols {
+            anyhow::bail!(
+                "expected {rows}x{cols} values to quantize, got {}",
+                values.len()
+            );
+        }
+        if let QuantFormat::Int4 { group_size: 0 } = format {
+            anyhow::bail!("the group size must not be zero");
+        }
+        let group_size = format.group_size(cols);
+        let max_q = format.max_q();
+        let mut data = Vec::with_capacity(rows * format.row_bytes(cols));
+        let mut scales = Vec::with_capacity(rows * cols.div_ceil(group_size));
+        for row in values.chunks_exact(cols.max(1)).take(rows) {
+            let mut quantized = Vec::with_capacity(cols);
+            for group in row.chunks(group_size) {
+                let absmax = group.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+                let scale = absmax / max_q;
+                let inv_scale = if scale > 0. { scale.recip() } else { 0. };
+                scales.push(scale);
+                quantized.extend(
+                    group
+                        .iter()
+                        .map(|v| (v * inv_scale).round().clamp(-max_q, max_q) as i8),
+                );
+            }
+            match format {
+                QuantFormat::Int8 => data.extend(quantized.iter().map(|&q| q as u8)),
+                QuantFormat::Int4 { .. } => data.extend(quantized.chunks(2).map(|pair| {
+                    let low = (pair[0] + 8) as u8;
+                    let high = pair.get(1).map_or(0, |&q| (q + 8) as u8);
+                    low | (high << 4)
+                })),
+            }
+        }
+        Ok(Self {
+            rows,
+            cols,
+            format,
+            data,
+            scales,
+        })
+    }
+
+    /// Dequantizes the `rows`, in order, into a row-major list.
+    pub fn dequantize_rows(&self, rows: &[usize]) -> Vec<f32> {
+        let mut values = Vec::with_capacity(rows.len() * self.cols);
+        for &row in rows {
+            self.dequantize_row(row, &mut values);
+        }
+        values
+    }
+
+    /// Dequantizes the whole matrix into a row-major list.
+    pub fn dequantize(&self) -> Vec<f32> {
+        let mut values = Vec::with_capacity(self.rows * self.cols);
+        for row in 0..self.rows {
+            self.dequantize_row(row, &mut values);
+        }
+        values
+    }
+
+    /// Dequantizes the whole matrix into a `(rows, cols)` tensor.
+    pub fn try_dequantize_tensor(
+        &self,
+        device: &Cpu,
+    ) -> Result<Tensor<(usize, usize), f32, Cpu>, Error> {
+        device.try_tensor_from_vec(self.dequantize(), (self.rows, self.cols))
+    }
+
+    /// Multiplies the row-major `(n, cols)` `x` by the transposed matrix, into `(n, rows)`.
+    ///
+    /// The rows are dequantized a tile at a time, so the whole matrix is never dense in memory.
+    pub fn matmul_t(&self, x: &[f32]) -> Vec<f32> {
+        let cols = self.cols.max(1);
+        let n = x.len() / cols;
+        let mut y = vec![0.; n * self.rows];
+        let mut tile = Vec::with_capacity(TILE_ROWS * self.cols);
+        for start in (0..self.rows).step_by(TILE_ROWS) {
+            let end = (start + TILE_ROWS).min(self.rows);
+            tile.clear();
+            for row in start..end {
+                self.dequantize_row(row, &mut tile);
+            }
+            for (x_row, y_row) in x.chunks_exact(cols).zip(y.chunks_exact_mut(self.rows)) {
+                for (w_row, y) in tile.chunks_exact(cols).zip(&mut y_row[start..end]) {
+                    *y = x_row.iter().zip(w_row).map(|(x, w)| x * w).sum();
+                }
+            }
+        }
+        y
+    }
+
+    fn dequantize_row(&self, row: usize, values: &mut Vec<f32>) {
+        let group_size = self.format.group_size(self.cols);
+        let groups = self.cols.div_ceil(group_size);
+        let row_bytes = self.format.row_bytes(self.cols);
+        let data = &self.data[row * row_bytes..(row + 1) * row_bytes];
+        let scales = &self.scales[row * groups..(row + 1) * groups];
+        values.extend((0..self.cols).map(|col| {
+            let q = match self.format {
+                QuantFormat::Int8 => data[col] as i8,
+                QuantFormat::Int4 { .. } => {
+                    let byte = data[col / 2];
+                    let nibble = if col % 2 == 0 { byte & 0xf } else { byte >> 4 };
+                    nibble as i8 - 8
+                }
+            };
+            q as f32 * scales[col / group_size]
+        }));
+    }
+
+    /// The amount of memory the quantized values and the scales take, in bytes.
+    pub fn memory(&self) -> usize {
+        self.data.len() + self.scales.len() * std::mem::size_of::<f32>()
+    }
+
+    /// The amount of memory the matrix would take in `f32`, in bytes.
+    pub fn dense_memory(&self) -> usize {
+        self.rows * self.cols * std::mem::size_of::<f32>()
+    }
+}
+
+/// A linear layer with a quantized weight `(out, in)` and an optional `f32` bias `(out,)`.
+///
+/// The weight may be shared, as with a `lm_head` that's tied to the embedding.
+#[derive(Clone, Debug)]
+pub struct QLinear {
+    pub weight: Arc<QMatrix>,
+    pub bias: Option<Tensor<(usize,), f32, Cpu>>,
+}
+
+impl QLinear {
+    pub fn quantize(
+        weight: &Tensor<(usize, usize), f32, Cpu>,
+        bias: Option<&Tensor<(usize,), f32, Cpu>>,
+        format: QuantFormat,
+    ) -> anyhow::Result<Self> {
+        let (rows, cols) = *weight.shape();
+        Ok(Self {
+            weight: Arc::new(QMatrix::quantize(&weight.as_vec(), rows, cols, format)?),
+            bias: bias.cloned(),
+        })
+    }
+
+    /// `(batch, seq, in) -> (batch, seq, out)`, see [QMatrix::matmul_t].
+    ///
+    /// Fails with [Error::WrongNumElements] if `in` doesn't match the weight.
+    pub fn try_forward(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        let (batch, seq, cols) = *x.shape();
+        if cols != self.weight.cols {
+            return Err(Error::WrongNumElements);
+        }
+        let y = self.weight.matmul_t(&x.as_vec());
+        let y: BlockInput<f32, Cpu, NoneTape> = x
+            .device()
+            .try_tensor_from_vec(y, (batch, seq, self.weight.rows))?;
+        match &self.bias {
+            Some(bias) => {
+                let shape = *y.shape();
+                y.try_add(bias.clone().try_broadcast_like::<_, Axes2<0, 1>>(&shape)?)
+            }
+            None => Ok(y),
+        }
+    }
+
+    /// The amount of memory the layer takes, in bytes, counting the (possibly shared) weight.
+    pub fn memory(&self) -> usize {
+        self.weight.memory() + self.bias_memory()
+    }
+
+    /// The amount of memory the layer would take in `f32`, in bytes.
+    pub fn dense_memory(&self) -> usize {
+        self.weight.dense_memory() + self.bias_memory()
+    }
+
+    fn bias_memory(&self) -> usize {
+        self.bias
+            .as_ref()
+            .map_or(0, |bias| bias.shape().0 * std::mem::size_of::<f32>())
+    }
+}
+
+/// A [mamba::MambaBlockDyn], with its norm, whose projections are quantized.
+#[derive(Clone, Debug)]
+pub struct QMambaBlock {
+    /// The gamma of the norm that precedes the block.
+    pub norm: Tensor<(DModel,), f32, Cpu>,
+    pub in_proj: QLinear,
+    pub conv1d_weight: Tensor<(DInner, DConv), f32, Cpu>,
+    pub conv1d_bias: Tensor<(DInner,), f32, Cpu>,
+    pub x_proj: QLinear,
+    pub dt_proj: QLinear,
+    pub a_log: Tensor<(DInner, DState), f32, Cpu>,
+    pub d: Tensor<(DInner,), f32, Cpu>,
+    pub out_proj: QLinear,
+}
+
+impl BlockParts<f32, Cpu> for QMambaBlock {
+    fn dims(&self) -> (DInner, DState, DConv, DtRank) {
+        let (d_inner, d_state) = *self.a_log.shape();
+        let d_conv = self.conv1d_weight.shape().1;
+        let dt_rank = self.dt_proj.weight.cols;
+        (d_inner, d_state, d_conv, dt_rank)
+    }
+    fn in_proj(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        self.in_proj.try_forward(x)
+    }
+    fn x_proj(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        self.x_proj.try_forward(x)
+    }
+    fn dt_proj(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        self.dt_proj.try_forward(x)
+    }
+    fn out_proj(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        self.out_proj.try_forward(x)
+    }
+    fn conv1d(
+        &self,
+    ) -> Result<
+        (
+            Tensor<(DInner, DConv), f32, Cpu>,
+            Tensor<(DInner,), f32, Cpu>,
+        ),
+        Error,
+    > {
+        Ok((self.conv1d_weight.clone(), self.conv1d_bias.clone()))
+    }
+    fn a_log(&self) -> Result<Tensor<(DInner, DState), f32, Cpu>, Error> {
+        Ok(self.a_log.clone())
+    }
+    fn d(&self) -> Result<Tensor<(DInner,), f32, Cpu>, Error> {
+        Ok(self.d.clone())
+    }
+}
+
+/// A [mamba::Mamba] whose big matrices are quantized.
+#[derive(Clone, Debug)]
+pub struct QMamba {
+    pub format: QuantFormat,
+    /// The embedding weight `(vocab, d_model)`, which a tied `lm_head` shares.
+    pub embedding: Arc<QMatrix>,
+    pub layers: Vec<QMambaBlock>,
+    /// The gamma of the final norm.
+    pub norm_f: Tensor<(DModel,), f32, Cpu>,
+    pub lm_head: QLinear,
+}
+
+impl QMamba {
+    /// Quantizes the `mamba` model into the `format`.
+    ///
+    /// A `lm_head` that's tied to the embedding (has the same weight) is quantized only once.
+    pub fn quantize(mamba: &mamba::Mamba<f32, Cpu>, format: QuantFormat) -> anyhow::Result<Self> {
+        let (vocab, d_model) = *mamba.embedding.weight.shape();
+        let embedding_values = mamba.embedding.weight.as_vec();
+        let embedding = Arc::new(QMatrix::quantize(
+            &embedding_values,
+            vocab,
+            d_model,
+            format,
+        )?);
+        let mut layers = Vec::with_capacity(mamba.layers.len());
+        for layer in mamba.layers.iter() {
+            let (norm, block) = &layer.res.0;
+            let (d_inner, _d_state) = *block.a_log.shape();
+            let d_conv = block.conv1d.weight.shape().2;
+            layers.push(QMambaBlock {
+                norm: norm.gamma.clone(),
+                in_proj: QLinear::quantize(&block.in_proj.weight, None, format)?,
+                conv1d_weight: block
+                    .conv1d
+                    .weight
+                    .clone()
+                    .try_reshape_like(&(d_inner, d_conv))?,
+                conv1d_bias: block.conv1d_bias.bias.clone(),
+                x_proj: QLinear::quantize(&block.x_proj.weight, None, format)?,
+                dt_proj: QLinear::quantize(
+                    &block.dt_proj.weight,
+                    Some(&block.dt_proj.bias),
+                    format,
+                )?,
+                a_log: block.a_log.clone(),
+                d: block.d.clone(),
+                out_proj: QLinear::quantize(&block.out_proj.weight, None, format)?,
+            });
+        }
+        let lm_head = if mamba.lm_head.weight.as_vec() == embedding_values {
+            QLinear {
+                weight: embedding.clone(),
+                bias: Some(mamba.lm_head.bias.clone()),
+            }
+        } else {
+            QLinear::quantize(&mamba.lm_head.weight, Some(&mamba.lm_head.bias), format)?
+        };
+        Ok(Self {
+            format,
+            embedding,
+            layers,
+            norm_f: mamba.norm_f.gamma.clone(),
+            lm_head,
+        })
+    }
+
+    /// Whether the `lm_head` shares the embedding matrix.
+    pub fn is_tied(&self) -> bool {
+        Arc::ptr_eq(&self.embedding, &self.lm_head.weight)
+    }
+
+    /// Dequantizes the model back into dense `f32` weights.
+    ///
+    /// All layers are expected to share the same dimensions, as in [mamba::MambaConfig::new].
+    pub fn dequantize(&self) -> anyhow::Result<mamba::Mamba<f32, Cpu>> {
+        let device = self.norm_f.device().clone();
+        let Some(first) = self.layers.first() else {
+            anyhow::bail!("the quantized model has no layers");
+        };
+        let (d_inner, d_state, d_conv, dt_rank) = first.dims();
+        let config = mamba::MambaConfig::new(
+            self.layers.len(),
+            self.embedding.rows,
+            self.embedding.cols,
+            Some(d_state),
+            Some(dt_rank),
+            Some(d_conv),
+            Some(d_inner),
+        );
+        let mut mamba: mamba::Mamba<f32, Cpu> = config.try_build_on_device(&device)?;
+        mamba.embedding.weight = self.embedding.try_dequantize_tensor(&device)?;
+        for (i, (layer, qlayer)) in mamba.layers.iter_mut().zip(&self.layers).enumerate() {
+            if qlayer.dims() != (d_inner, d_state, d_conv, dt_rank) {
+                anyhow::bail!("layer {i} has different dimensions than the first one");
+            }
+            let (norm, block) = &mut layer.res.0;
+            norm.gamma = qlayer.norm.clone();
+            block.in_proj.weight = qlayer.in_proj.weight.try_dequantize_tensor(&device)?;
+            let conv1d_shape = *block.conv1d.weight.shape();
+            block.conv1d.weight = qlayer
+                .conv1d_weight
+                .clone()
+                .try_reshape_like(&conv1d_shape)?;
+            block.conv1d_bias.bias = qlayer.conv1d_bias.clone();
+            block.x_proj.weight = qlayer.x_proj.weight.try_dequantize_tensor(&device)?;
+            block.dt_proj.weight = qlayer.dt_proj.weight.try_dequantize_tensor(&device)?;
+            if let Some(bias) = &qlayer.dt_proj.bias {
+                block.dt_proj.bias = bias.clone();
+            }
+            block.a_log = qlayer.a_log.clone();
+            block.d = qlayer.d.clone();
+            block.out_proj.weight = qlayer.out_proj.weight.try_dequantize_tensor(&device)?;
+        }
+        mamba.norm_f.gamma = self.norm_f.clone();
+        mamba.lm_head.weight = self.lm_head.weight.try_dequantize_tensor(&device)?;
+        if let Some(bias) = &self.lm_head.bias {
+            mamba.lm_head.bias = bias.clone();
+        }
+        Ok(mamba)
+    }
+
+    /// Runs the embedding, all layers and the final norm, but not the `lm_head`.
+    pub fn try_forward_hidden(
+        &self,
+        x: VocabInputWithStates<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInputWithStates<f32, Cpu>, Error> {
+        let (x, states) = x;
+        let (batch, seq) = *x.shape();
+        check_input_states(&self.state_dims(), batch, &states)?;
+        let device = x.device().clone();
+        let embedded = self.embedding.dequantize_rows(&x.as_vec());
+        let mut x: BlockInput<f32, Cpu, NoneTape> =
+            device.try_tensor_from_vec(embedded, (batch, seq, self.embedding.cols))?;
+
+        let mut new_states = Vec::with_capacity(states.len());
+        for (layer, state) in self.layers.iter().zip(states.into_iter()) {
+            let x2 = rms_norm_mixed(&layer.norm, x.clone())?;
+            let (x2, new_state) = mamba_block_try_forward(layer, x2, state)?;
+            new_states.push(new_state);
+            x = x.try_add(x2)?;
+        }
+
+        let x = rms_norm_mixed(&self.norm_f, x)?;
+        Ok((x, new_states))
+    }
+
+    /// The amount of memory the weights take, in bytes.
+    pub fn memory(&self) -> usize {
+        self.embedding.memory()
+            + self.f32_memory()
+            + self
+                .layers
+                .iter()
+                .map(|layer| {
+                    layer.in_proj.memory()
+                        + layer.x_proj.memory()
+                        + layer.dt_proj.memory()
+                        + layer.out_proj.memory()
+                })
+                .sum::<usize>()
+            + match self.is_tied() {
+                true => self.lm_head.bias_memory(),
+                false => self.lm_head.memory(),
+            }
+    }
+
+    /// The amount of memory the weights would take in `f32`, in bytes.
+    pub fn dense_memory(&self) -> usize {
+        self.embedding.dense_memory()
+            + self.f32_memory()
+            + self
+                .layers
+                .iter()
+                .map(|layer| {
+                    layer.in_proj.dense_memory()
+                        + layer.x_proj.dense_memory()
+                        + layer.dt_proj.dense_memory()
+                        + layer.out_proj.dense_memory()
+                })
+                .sum::<usize>()
+            + self.lm_head.dense_memory()
+    }
+
+    /// The amount of memory of the parameters that are kept in `f32`, in bytes.
+    fn f32_memory(&self) -> usize {
+        let layers: usize = self
+            .layers
+            .iter()
+            .map(|layer| {
+                let (d_inner, d_state, d_conv, _dt_rank) = layer.dims();
+                // norm, conv1d weight and bias, a_log and d
+                layer.norm.shape().0 + d_inner * (d_conv + 1 + d_state + 1)
+            })
+            .sum();
+        (layers + self.norm_f.shape().0) * std::mem::size_of::<f32>()
+    }
+
+    /// Serializes the model as safetensors, keeping the Hugging Face tensor names.
+    ///
+    /// Each quantized matrix `{name}` is stored as `{name}.q` (`I8` for int8, packed `U8` for int4)
+    /// and `{name}.scales` (`F32`, `(rows, groups)`), with its amount of columns in the `{name}.cols`
+    /// metadata. The `quant` and `group_size` metadata describe the [QuantFormat].
+    /// A tied `lm_head` has no weight of its own, only its bias.
+    pub fn to_safetensors(&self) -> anyhow::Result<Vec<u8>> {
+        let mut tensors = Tensors::default();
+        tensors.matrix("backbone.embedding.weight", &self.embedding);
+        for (i, layer) in self.layers.iter().enumerate() {
+            let (d_inner, _d_state, d_conv, _dt_rank) = layer.dims();
+            let prefix = format!("backbone.layers.{i}");
+            tensors.f32(&format!("{prefix}.norm.weight"), &layer.norm);
+            tensors.linear(&format!("{prefix}.mixer.in_proj"), &layer.in_proj);
+            tensors.f32_with_shape(
+                &format!("{prefix}.mixer.conv1d.weight"),
+                layer.conv1d_weight.as_vec(),
+                vec![d_inner, 1, d_conv],
+            );
+            tensors.f32(&format!("{prefix}.mixer.conv1d.bias"), &layer.conv1d_bias);
+            tensors.linear(&format!("{prefix}.mixer.x_proj"), &layer.x_proj);
+            tensors.linear(&format!("{prefix}.mixer.dt_proj"), &layer.dt_proj);
+            tensors.f32(&format!("{prefix}.mixer.A_log"), &layer.a_log);
+            tensors.f32(&format!("{prefix}.mixer.D"), &layer.d);
+            tensors.linear(&format!("{prefix}.mixer.out_proj"), &layer.out_proj);
+        }
+        tensors.f32("backbone.norm_f.weight", &self.norm_f);
+        if self.is_tied() {
+            if let Some(bias) = &self.lm_head.bias {
+                tensors.f32("lm_head.bias", bias);
+            }
+        } else {
+            tensors.linear("lm_head", &self.lm_head);
+        }
+
+        let mut metadata = tensors.metadata;
+        let (quant, group_size) = match self.format {
+            QuantFormat::Int8 => ("int8", 0),
+            QuantFormat::Int4 { group_size } => ("int4", group_size),
+        };
+        metadata.insert("quant".into(), quant.into());
+        metadata.insert("group_size".into(), group_size.to_string());
+        let views = tensors
+            .tensors
+            .iter()
+            .map(|(name, dtype, shape, data)| {
+                Ok((name.clone(), TensorView::new(*dtype, shape.clone(), data)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(safetensors::serialize(views, &Some(metadata))?)
+    }
+
+    /// Whether the safetensors `bytes` contain a quantized model (see [QMamba::to_safetensors]).
+    pub fn is_quantized(bytes: &[u8]) -> anyhow::Result<bool> {
+        let (_, metadata) = SafeTensors::read_metadata(bytes)?;
+        Ok(metadata
+            .metadata()
+            .as_ref()
+            .is_some_and(|metadata| metadata.contains_key("quant")))
+    }
+
+    /// Deserializes a model saved by [QMamba::to_safetensors].
+    pub fn from_safetensors(bytes: &[u8], device: &Cpu) -> anyhow::Result<Self> {
+        let (_, metadata) = SafeTensors::read_metadata(bytes)?;
+        let metadata = metadata.metadata().clone().unwrap_or_default();
+        let format = match metadata.get("quant").map(String::as_str) {
+            Some("int8") => QuantFormat::Int8,
+            Some("int4") => QuantFormat::Int4 {
+                group_size: metadata
+                    .get("group_size")
+                    .ok_or_else(|| anyhow::anyhow!("missing the group_size metadata"))?
+                    .parse()?,
+            },
+            other => anyhow::bail!("unknown quantization format: {other:?}"),
+        };
+        let loader = Loader {
+            tensors: SafeTensors::deserialize(bytes)?,
+            metadata: &metadata,
+            format,
+            device,
+        };
+
+        let mut layers = vec![];
+        for i in 0.. {
+            let prefix = format!("backbone.layers.{i}");
+            if loader
+                .tensors
+                .tensor(&format!("{prefix}.norm.weight"))
+                .is_err()
+            {
+                break;
+            }
+            let a_log = loader.f32(&format!("{prefix}.mixer.A_log"))?;
+            let (d_inner, d_state) = match a_log.shape()[..] {
+                [d_inner, d_state] => (d_inner, d_state),
+                _ => anyhow::bail!("{prefix}: unexpected A_log shape {:?}", a_log.shape()),
+            };
+            let conv1d_weight = loader.f32(&format!("{prefix}.mixer.conv1d.weight"))?;
+            let d_conv = conv1d_weight.data().len() / std::mem::size_of::<f32>() / d_inner.max(1);
+            layers.push(QMambaBlock {
+                norm: loader.vector(&format!("{prefix}.norm.weight"))?,
+                in_proj: loader.linear(&format!("{prefix}.mixer.in_proj"), false)?,
+                conv1d_weight: loader.tensor(conv1d_weight, (d_inner, d_conv))?,
+                conv1d_bias: loader.vector(&format!("{prefix}.mixer.conv1d.bias"))?,
+                x_proj: loader.linear(&format!("{prefix}.mixer.x_proj"), false)?,
+                dt_proj: loader.linear(&format!("{prefix}.mixer.dt_proj"), true)?,
+                a_log: loader.tensor(a_log, (d_inner, d_state))?,
+                d: loader.vector(&format!("{prefix}.mixer.D"))?,
+                out_proj: loader.linear(&format!("{prefix}.mixer.out_proj"), false)?,
+            });
+        }
+        if layers.is_empty() {
+            anyhow::bail!("the quantized model has no layers");
+        }
+        let embedding = Arc::new(loader.matrix("backbone.embedding.weight")?);
+        let lm_head = match loader.tensors.tensor("lm_head.weight.q") {
+            Ok(_) => loader.linear("lm_head", true)?,
+            Err(_) => QLinear {
+                weight: embedding.clone(),
+                bias: Some(loader.vector("lm_head.bias")?),
+            },
+        };
+        Ok(Self {
+            format,
+            embedding,
+            layers,
+            norm_f: loader.vector("backbone.norm_f.weight")?,
+            lm_head,
+        })
+    }
+}
+
+impl MambaModel for QMamba {
+    fn weights_format(&self) -> String {
+        self.format.to_string()
+    }
+
+    fn device(&self) -> Cpu {
+        self.norm_f.device().clone()
+    }
+
+    fn state_dims(&self) -> Vec<(DState, DConv, DInner)> {
+        self.layers
+            .iter()
+            .map(|layer| {
+                let (d_inner, d_state, d_conv, _dt_rank) = layer.dims();
+                (d_state, d_conv, d_inner)
+            })
+            .collect()
+    }
+
+    fn try_forward_hidden_f32(
+        &self,
+        x: VocabInputWithStates<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInputWithStates<f32, Cpu>, Error> {
+        self.try_forward_hidden(x)
+    }
+
+    fn try_lm_head_f32(
+        &self,
+        x: BlockInput<f32, Cpu, NoneTape>,
+    ) -> Result<BlockInput<f32, Cpu, NoneTape>, Error> {
+        self.lm_head.try_forward(x)
+    }
+
+    fn to_hf_safetensors(&self) -> anyhow::Result<Vec<u8>> {
+        mamba::save::to_hf_safetensors(&self.dequantize()?)
+    }
+}
+
+/// The tensors and metadata being serialized.
+#[derive(Default)]
+struct Tensors {
+    tensors: Vec<(String, StDtype, Vec<usize>, Vec<u8>)>,
+    metadata: HashMap<String, String>,
+}
+
+impl Tensors {
+    fn f32<S: Shape>(&mut self, name: &str, tensor: &Tensor<S, f32, Cpu>) {
+        let shape = tensor.shape().concrete().into_iter().collect();
+        self.f32_with_shape(name, tensor.as_vec(), shape);
+    }
+
+    fn f32_with_shape(&mut self, name: &str, values: Vec<f32>, shape: Vec<usize>) {
+        let data = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.tensors.push((name.into(), StDtype::F32, shape, data));
+    }
+
+    fn matrix(&mut self, name: &str, matrix: &QMatrix) {
+        let (dtype, row_bytes) = match matrix.format {
+            QuantFormat::Int8 => (StDtype::I8, matrix.cols),
+            QuantFormat::Int4 { .. } => (StDtype::U8, matrix.format.row_bytes(matrix.cols)),
+        };
+        self.tensors.push((
+            format!("{name}.q"),
+            dtype,
+            vec![matrix.rows, row_bytes],
+            matrix.data.clone(),
+        ));
+        let groups = matrix.scales.len() / matrix.rows.max(1);
+        self.f32_with_shape(
+            &format!("{name}.scales"),
+            matrix.scales.clone(),
+            vec![matrix.rows, groups],
+        );
+        self.metadata
+            .insert(format!("{name}.cols"), matrix.cols.to_string());
+    }
+
+    fn linear(&mut self, prefix: &str, linear: &QLinear) {
+        self.matrix(&format!("{prefix}.weight"), &linear.weight);
+        if let Some(bias) = &linear.bias {
+            self.f32(&format!("{prefix}.bias"), bias);
+        }
+    }
+}
+
+/// The tensors being deserialized.
+struct Loader<'a> {
+    tensors: SafeTensors<'a>,
+    metadata: &'a HashMap<String, String>,
+    format: QuantFormat,
+    device: &'a Cpu,
+}
+
+impl<'a> Loader<'a> {
+    fn f32(&self, name: &str) -> anyhow::Result<TensorView<'a>> {
+        let view = self.tensors.tensor(name)?;
+        if view.dtype() != StDtype::F32 {
+            anyhow::bail!("{name}: expected F32, got {:?}", view.dtype());
+        }
+        Ok(view)
+    }
+
+    fn tensor<S: Shape>(
+        &self,
+        view: TensorView<'a>,
+        shape: S,
+    ) -> anyhow::Result<Tensor<S, f32, Cpu>> {
+        let values: Vec<f32> = view
+            .data()
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        if values.len() != shape.num_elements() {
+            anyhow::bail!(
+                "expected {} values for the shape {:?}, got {}",
+                shape.num_elements(),
+                shape.concrete(),
+                values.len()
+            );
+        }
+        Ok(self.device.try_tensor_from_vec(values, shape)?)
+    }
+
+    fn vector(&self, name: &str) -> anyhow::Result<Tensor<(usize,), f32, Cpu>> {
+        let view = self.f32(name)?;
+        let len = view.data().len() / 4;
+        self.tensor(view, (len,))
+    }
+
+    fn matrix(&self, name: &str) -> anyhow::Result<QMatrix> {
+        let cols: usize = self
+            .metadata
+            .get(&format!("{name}.cols"))
+            .ok_or_else(|| anyhow::anyhow!("missing the {name}.cols metadata"))?
+            .parse()?;
+        let q = self.tensors.tensor(&format!("{name}.q"))?;
+        let rows = q.shape().first().copied().unwrap_or_default();
+        let scales = self.f32(&format!("{name}.scales"))?;
+        let matrix = QMatrix {
+            rows,
+            cols,
+            format: self.format,
+            data: q.data().to_vec(),
+            scales: scales
+                .data()
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        };
+        let groups = cols.div_ceil(self.format.group_size(cols));
+        if matrix.data.len() != rows * self.format.row_bytes(cols)
+            || matrix.scales.len() != rows * groups
+        {
+            anyhow::bail!("{name}: inconsistent quantized data for {rows}x{cols}");
+        }
+        Ok(matrix)
+    }
+
+    fn linear(&self, prefix: &str, has_bias: bool) -> anyhow::Result<QLinear> {
+        let weight = Arc::new(self.matrix(&format!("{prefix}.weight"))?);
+        let bias = if has_bias {
+            Some(self.vector(&format!("{prefix}.bias"))?)
+        } else {
+            None
+        };
+        Ok(QLinear { weight, bias })
+    }
+}
+
+/// The perplexity of the quantized model compared to the reference (unquantized) model.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccuracyReport {
+    pub format: QuantFormat,
+    pub reference: crate::eval::EvalReport,
+    pub quantized: crate::eval::EvalReport,
+    /// The memory of the reference (`f32`) weights, in bytes.
+    pub reference_memory: usize,
+    /// The memory of the quantized weights, in bytes.
+    pub quantized_memory: usize,
+}
+
+impl AccuracyReport {
+    /// Fails if no token was scored.
+    pub fn perplexity_delta(&self) -> anyhow::Result<f64> {
+        Ok(self.quantized.perplexity()? - self.reference.perplexity()?)
+    }
+}
+
+impl std::fmt::Display for AccuracyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "reference: {}", self.reference)?;
+        writeln!(f, "{}: {}", self.format, self.quantized)?;
+        if let (Ok(reference), Ok(quantized)) =
+            (self.reference.perplexity(), self.quantized.perplexity())
+        {
+            let delta = quantized - reference;
+            write!(
+                f,
+                "perplexity: {reference:.4} -> {quantized:.4} ({delta:+.4}, {:+.2}%), ",
+                delta / reference * 100.,
+            )?;
+        }
+        write!(
+            f,
+            "memory: {:.1}MB -> {:.1}MB",
+            self.reference_memory as f64 / 1e6,
+            self.quantized_memory as f64 / 1e6,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{EvalConfig, EvalReport};
+    use crate::{test_utils, MambaWrapper};
+    use rand::distributions::{Distribution, Uniform};
+    use rand::SeedableRng;
+
+    const FORMATS: [QuantFormat; 3] = [
+        QuantFormat::Int8,
+        QuantFormat::Int4 { group_size: 4 },
+        QuantFormat::Int4 { group_size: 64 },
+    ];
+
+    fn values(len: usize, seed: u64) -> Vec<f32> {
+        let rng = rand::rngs::StdRng::seed_from_u64(seed);
+        Uniform::new(-2f32, 2.).sample_iter(rng).take(len).collect()
+    }
+
+    #[test]
+    fn qmatrix_round_trip() {
+        // an odd amount of columns, so that int4 rows are padded and the last group is partial
+        let (rows, cols) = (5, 11);
+        for format in FORMATS {
+            let mut values = values(rows * cols, 0);
+            values[cols..2 * cols].fill(0.);
+            let matrix = QMatrix::quantize(&values, rows, cols, format).unwrap();
+            assert_eq!(matrix.data.len(), rows * format.row_bytes(cols));
+            let dequantized = matrix.dequantize();
+            assert_eq!(dequantized.len(), values.len());
+
+            let group_size = format.group_size(cols);
+            let groups = cols.div_ceil(group_size);
+            for (i, (v, d)) in values.iter().zip(&dequantized).enumerate() {
+                let (row, col) = (i / cols, i % cols);
+                let scale = matrix.scales[row * groups + col / group_size];
+                assert!((v - d).abs() <= scale / 2. + 1e-6, "{format}: {v} vs {d}");
+            }
+            assert!(dequantized[cols..2 * cols].iter().all(|&d| d == 0.));
+            assert_eq!(matrix.dequantize_rows(&[3, 1]), {
+                let mut rows = dequantized[3 * cols..4 * cols].to_vec();
+                rows.extend(&dequantized[cols..2 * cols]);
+                rows
+            });
+        }
+    }
+
+    #[test]
+    fn matmul_t_matches_the_dense_product() {
+        // more rows than a tile
+        let (rows, cols, n) = (TILE_ROWS * 2 + 3, 9, 4);
+        let x = values(n * cols, 1);
+        for format in FORMATS {
+            let matrix = QMatrix::quantize(&values(rows * cols, 2), rows, cols, format).unwrap();
+            let weight = matrix.dequantize();
+            let y = matrix.matmul_t(&x);
+            assert_eq!(y.len(), n * rows);
+            for (i, x_row) in x.chunks_exact(cols).enumerate() {
+                for (j, w_row) in weight.chunks_exact(cols).enumerate() {
+                    let expected: f32 = x_row.iter().zip(w_row).map(|(x, w)| x * w).sum();
+                    assert!((y[i * rows + j] - expected).abs() < 1e-4);
+                }
+            }
+        }
+    }
+
+    fn mamba(tied: bool) -> mamba::Mamba<f32, Cpu> {
+        let mut mamba = test_utils::mamba(0);
+        if tied {
+            crate::train::tie_lm_head(&mut mamba);
+        }
+        mamba
+    }
+
+    fn assert_same_linear(a: &QLinear, b: &QLinear) {
+        assert_eq!(a.weight, b.weight);
+        assert_eq!(
+            a.bias.as_ref().map(|bias| bias.as_vec()),
+            b.bias.as_ref().map(|bias| bias.as_vec())
+        );
+    }
+
+    fn assert_same(a: &QMamba, b: &QMamba) {
+        assert_eq!(a.format, b.format);
+        assert_eq!(a.embedding, b.embedding);
+        assert_eq!(a.layers.len(), b.layers.len());
+        for (a, b) in a.layers.iter().zip(&b.layers) {
+            assert_eq!(a.norm.as_vec(), b.norm.as_vec());
+            assert_same_linear(&a.in_proj, &b.in_proj);
+            assert_eq!(a.conv1d_weight.as_vec(), b.conv1d_weight.as_vec());
+            assert_eq!(a.conv1d_bias.as_vec(), b.conv1d_bias.as_vec());
+            assert_same_linear(&a.x_proj, &b.x_proj);
+            assert_same_linear(&a.dt_proj, &b.dt_proj);
+            assert_eq!(a.a_log.as_vec(), b.a_log.as_vec());
+            assert_eq!(a.d.as_vec(), b.d.as_vec());
+            assert_same_linear(&a.out_proj, &b.out_proj);
+        }
+        assert_eq!(a.norm_f.as_vec(), b.norm_f.as_vec());
+        assert_same_linear(&a.lm_head, &b.lm_head);
+        assert_eq!(a.is_tied(), b.is_tied());
+    }
+
+    #[test]
+    fn safetensors_round_trip() {
+        for tied in [true, false] {
+            let mamba = mamba(tied);
+            for format in FORMATS {
+                let quantized = QMamba::quantize(&mamba, format).unwrap();
+                assert_eq!(quantized.is_tied(), tied);
+
+                let bytes = quantized.to_safetensors().unwrap();
+                assert!(QMamba::is_quantized(&bytes).unwrap());
+                let loaded = QMamba::from_safetensors(&bytes, &quantized.device()).unwrap();
+                assert_same(&quantized, &loaded);
+                assert_eq!(quantized.memory(), loaded.memory());
+            }
+        }
+    }
+
+    #[test]
+    fn tied_lm_head_is_stored_once() {
+        let tied = QMamba::quantize(&mamba(true), QuantFormat::Int8).unwrap();
+        let untied = QMamba::quantize(&mamba(false), QuantFormat::Int8).unwrap();
+        assert_eq!(
+            untied.memory() - tied.memory(),
+            untied.lm_head.weight.memory()
+        );
+    }
+
+    #[test]
+    fn quantized_logits_follow_the_reference() {
+        let tokens: Vec<usize> = (0..8).map(|t| t * 5 % 32).collect();
+        for tied in [true, false] {
+            let mamba = mamba(tied);
+            let device = mamba.embedding.weight.device().clone();
+            let x = || device.tensor_from_vec(tokens.clone(), (1, tokens.len()));
+            let expected = mamba.try_forward_stateless_f32(x()).unwrap().as_vec();
+            let norm = expected.iter().map(|e| e * e).sum::<f32>().sqrt();
+            for format in FORMATS {
+                let tolerance = match format {
+                    QuantFormat::Int8 => 0.02,
+                    QuantFormat::Int4 { .. } => 0.25,
+                };
+                let quantized = QMamba::quantize(&mamba, format).unwrap();
+                let actual = quantized.try_forward_stateless_f32(x()).unwrap().as_vec();
+                let error = expected
+                    .iter()
+                    .zip(&actual)
+                    .map(|(e, a)| (e - a) * (e - a))
+                    .sum::<f32>()
+                    .sqrt();
+                assert!(
+                    error <= tolerance * norm,
+                    "{format} (tied: {tied}): relative error {}",
+                    error / norm
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn quantized_forward_rejects_mismatched_inputs() {
+        let quantized = QMamba::quantize(&mamba(true), QuantFormat::Int8).unwrap();
+        let device = quantized.device();
+        let x: BlockInput<f32, Cpu, NoneTape> = device.tensor_from_vec(vec![0.; 30], (1, 2, 15));
+        assert!(quantized.lm_head.try_forward(x).is_err());
+
+        let tokens = || device.tensor_from_vec(vec![1usize, 2], (1, 2));
+        let states = || -> mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape> {
+            quantized
+                .state_configs(1)
+                .into_iter()
+                .map(|config| device.build_module::<f32>(config))
+                .collect()
+        };
+        assert!(quantized.try_forward_hidden((tokens(), states())).is_ok());
+        let mut states = states();
+        states.pop();
+        assert!(quantized.try_forward_hidden((tokens(), states)).is_err());
+    }
+
+    #[test]
+    fn perplexity_delta_of_the_quantized_model() {
+        let reference = test_utils::models(0);
+        let documents = || [anyhow::Ok("the cat sat on the mat")];
+        let config = EvalConfig::default();
+        let reference_report = reference.eval_corpus(documents(), &config).unwrap();
+        let quantized = MambaWrapper::new(
+            test_utils::word_level_tokenizer(test_utils::WORDS.split(' ')),
+            QMamba::quantize(&reference.mamba, QuantFormat::Int8).unwrap(),
+        );
+        let quantized_report = quantized.eval_corpus(documents(), &config).unwrap();
+
+        let report = AccuracyReport {
+            format: QuantFormat::Int8,
+            reference: reference_report.clone(),
+            quantized: quantized_report.clone(),
+            reference_memory: quantized.mamba.dense_memory(),
+            quantized_memory: quantized.mamba.memory(),
+        };
+        let delta = report.perplexity_delta().unwrap();
+        let reference_perplexity = reference_report.perplexity().unwrap();
+        assert_eq!(
+            delta,
+            quantized_report.perplexity().unwrap() - reference_perplexity
+        );
+        assert!(delta.abs() < 0.05 * reference_perplexity, "{report}");
+
+        let empty = AccuracyReport {
+            quantized: EvalReport::default(),
+            ..report
+        };
+        assert!(empty.perplexity_delta().is_err());
+    }
+}
@@ -0,0 +1,303 @@
+//! The known state-spaces Mamba checkpoints.
+//!
+//! All of them share the [hf::tokenizer] and have the same file names, but their `model.safetensors`
+//! files are in different revisions (pull requests that converted the original `pytorch_model.bin`).
+
+use crate::source::HfHubSource;
+use crate::{hf, mamba};
+use dfdx::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KnownModel {
+    /// A short name, such as `mamba-130m`.
+    pub name: &'static str,
+    /// A [hf_hub::types::RepoId].
+    pub repo_id: &'static str,
+    /// A [hf_hub::types::RevisionPath] that contains the `model.safetensors` file.
+    pub revision: &'static str,
+    /// A [hf_hub::types::FilePath].
+    pub file_path_config_json: &'static str,
+    /// A [hf_hub::types::FilePath].
+    pub file_path_model_safetensors: &'static str,
+    /// The expected `d_model` from the `config.json`.
+    pub d_model: usize,
+    /// The expected `n_layer` from the `config.json`.
+    pub n_layer: usize,
+    /// The expected (unpadded) `vocab_size` from the `config.json`.
+    pub vocab_size: usize,
+}
+
+impl KnownModel {
+    const fn state_spaces(
+        name: &'static str,
+        repo_id: &'static str,
+        revision: &'static str,
+        d_model: usize,
+        n_layer: usize,
+    ) -> Self {
+        Self {
+            name,
+            repo_id,
+            revision,
+            file_path_config_json: hf::mamba_130m::FILE_PATH_CONFIG_JSON,
+            file_path_model_safetensors: hf::mamba_130m::FILE_PATH_MODEL_SAFETENSORS,
+            d_model,
+            n_layer,
+            vocab_size: 50277,
+        }
+    }
+}
+
+pub const MAMBA_130M: KnownModel = KnownModel::state_spaces(
+    "mamba-130m",
+    hf::mamba_130m::REPO_ID,
+    hf::mamba_130m::REVISION_PATH,
+    768,
+    24,
+);
+pub const MAMBA_370M: KnownModel = KnownModel::state_spaces(
+    "mamba-370m",
+    "state-spaces/mamba-370m",
+    "refs/pr/1",
+    1024,
+    48,
+);
+pub const MAMBA_790M: KnownModel = KnownModel::state_spaces(
+    "mamba-790m",
+    "state-spaces/mamba-790m",
+    "refs/pr/1",
+    1536,
+    48,
+);
+pub const MAMBA_1_4B: KnownModel = KnownModel::state_spaces(
+    "mamba-1.4b",
+    "state-spaces/mamba-1.4b",
+    "refs/pr/1",
+    2048,
+    48,
+);
+pub const MAMBA_2_8B: KnownModel = KnownModel::state_spaces(
+    "mamba-2.8b",
+    "state-spaces/mamba-2.8b",
+    "refs/pr/4",
+    2560,
+    64,
+);
+pub const MAMBA_2_8B_SLIMPJ: KnownModel = KnownModel::state_spaces(
+    "mamba-2.8b-slimpj",
+    "state-spaces/mamba-2.8b-slimpj",
+    "refs/pr/1",
+    2560,
+    64,
+);
+
+/// All known checkpoints, from the smallest to the largest.
+pub const KNOWN_MODELS: &[KnownModel] = &[
+    MAMBA_130M,
+    MAMBA_370M,
+    MAMBA_790M,
+    MAMBA_1_4B,
+    MAMBA_2_8B,
+    MAMBA_2_8B_SLIMPJ,
+];
+
+impl Default for KnownModel {
+    fn default() -> Self {
+        MAMBA_130M
+    }
+}
+
+impl KnownModel {
+    /// Finds a known checkpoint by its name or by its repository id.
+    pub fn find(name: &str) -> Option<&'static KnownModel> {
+        KNOWN_MODELS
+            .iter()
+            .find(|known| known.name == name || known.repo_id == name)
+    }
+
+    /// The config that the checkpoint's `config.json` is expected to build.
+    pub fn config(&self) -> mamba::MambaConfig {
+        let pad = 8;
+        let padded_vocab_size = (self.vocab_size + pad - 1) / pad * pad;
+        mamba::MambaConfig::new(
+            self.n_layer,
+            padded_vocab_size,
+            self.d_model,
+            None,
+            None,
+            None,
+            None,
+        )
+    }
+
+    /// Errors if the `config` differs from the expected [KnownModel::config].
+    pub fn check_config(&self, config: &mamba::MambaConfig) -> anyhow::Result<()> {
+        self.check_dims(
+            config.embedding.model,
+            config.layers.len(),
+            config.embedding.vocab,
+        )
+    }
+
+    /// Errors if the built `mamba` differs from the expected [KnownModel::config].
+    pub fn check_mamba<E: Dtype, D: Device<E>>(
+        &self,
+        mamba: &mamba::Mamba<E, D>,
+    ) -> anyhow::Result<()> {
+        let (vocab, d_model) = *mamba.embedding.weight.shape();
+        self.check_dims(d_model, mamba.layers.len(), vocab)
+    }
+
+    fn check_dims(
+        &self,
+        d_model: usize,
+        n_layer: usize,
+        padded_vocab: usize,
+    ) -> anyhow::Result<()> {
+        let expected = self.config();
+        let found = (d_model, n_layer, padded_vocab);
+        let wanted = (
+            expected.embedding.model,
+            expected.layers.len(),
+            expected.embedding.vocab,
+        );
+        if found != wanted {
+            anyhow::bail!(
+                "unexpected config for {}: (d_model, n_layer, padded vocab) is {found:?}, expected {wanted:?}",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    /// The approximate amount of parameters, including the separate (untied) lm head.
+    pub fn params(&self) -> usize {
+        let d_model = self.d_model;
+        let d_inner = d_model * 2;
+        let d_state = 16;
+        let d_conv = 4;
+        let dt_rank = (d_model + 15) / 16;
+        let vocab = self.config().embedding.vocab;
+        let block = d_model // norm
+            + d_model * d_inner * 2 // in_proj
+            + d_inner * d_conv + d_inner // conv1d
+            + d_inner * (dt_rank + d_state * 2) // x_proj
+            + dt_rank * d_inner + d_inner // dt_proj
+            + d_inner * d_state // a_log
+            + d_inner // d
+            + d_inner * d_model; // out_proj
+        vocab * d_model // embedding
+            + self.n_layer * block
+            + d_model // norm_f
+            + vocab * d_model + vocab // lm_head
+    }
+
+    /// The approximate memory required for the weights in `E`, in bytes.
+    pub fn approx_memory<E: Dtype>(&self) -> usize {
+        self.params() * std::mem::size_of::<E>()
+    }
+
+    /// The hf-hub repositories of this checkpoint.
+    pub fn hf_hub_source(&self, offline: bool) -> HfHubSource {
+        HfHubSource {
+            tokenizer_repo: hf::tokenizer::REPO_ID.into(),
+            model_repo: self.repo_id.into(),
+            model_revision: self.revision.into(),
+            offline,
+        }
+    }
+}
+
+impl std::fmt::Display for KnownModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}@{}, d_model {}, n_layer {}, ~{:.2}G params, ~{:.2}GB f32 or ~{:.2}GB f16 memory)",
+            self.name,
+            self.repo_id,
+            self.revision,
+            self.d_model,
+            self.n_layer,
+            self.params() as f64 / 1e9,
+            self.approx_memory::<f32>() as f64 / 1e9,
+            self.approx_memory::<crate::precision::f16>() as f64 / 1e9,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    /// The `config.json` of [MAMBA_130M].
+    const CONFIG_JSON_130M: &str = r#"{
+        "d_model": 768, "n_layer": 24, "vocab_size": 50277, "ssm_cfg": {}, "rms_norm": true,
+        "residual_in_fp32": true, "fused_add_norm": true, "pad_vocab_size_multiple": 8
+    }"#;
+
+    /// A known model with the dimensions of [test_utils::mamba].
+    const SMALL: KnownModel = KnownModel {
+        name: "small",
+        d_model: 16,
+        n_layer: 2,
+        vocab_size: 32,
+        ..MAMBA_130M
+    };
+
+    #[test]
+    fn models_are_found_by_name_or_repo_id() {
+        assert_eq!(KnownModel::find("mamba-130m"), Some(&MAMBA_130M));
+        assert_eq!(KnownModel::find(MAMBA_130M.repo_id), Some(&MAMBA_130M));
+        assert_eq!(KnownModel::find("mamba-1m"), None);
+    }
+
+    #[test]
+    fn the_130m_config_is_checked() {
+        let config = mamba::MambaConfig::from_hf_config_json(CONFIG_JSON_130M).unwrap();
+        assert_eq!(config.embedding.vocab, 50280);
+        MAMBA_130M.check_config(&config).unwrap();
+        assert!(MAMBA_370M.check_config(&config).is_err());
+
+        let config = mamba::MambaConfig::from_hf_config_json(
+            &CONFIG_JSON_130M.replace("\"n_layer\": 24", "\"n_layer\": 23"),
+        )
+        .unwrap();
+        assert!(MAMBA_130M.check_config(&config).is_err());
+    }
+
+    #[test]
+    fn built_models_are_checked() {
+        let mamba = test_utils::mamba(0);
+        SMALL.check_mamba(&mamba).unwrap();
+        let err = MAMBA_130M.check_mamba(&mamba).unwrap_err();
+        assert!(err.to_string().contains("mamba-130m"), "{err}");
+    }
+
+    #[test]
+    fn params_count_every_weight() {
+        let mamba = test_utils::mamba(0);
+        let mut params = mamba.embedding.weight.shape().num_elements()
+            + mamba.norm_f.gamma.shape().num_elements()
+            + mamba.lm_head.weight.shape().num_elements()
+            + mamba.lm_head.bias.shape().num_elements();
+        for layer in &mamba.layers {
+            let (norm, block) = &layer.res.0;
+            params += norm.gamma.shape().num_elements()
+                + block.in_proj.weight.shape().num_elements()
+                + block.conv1d.weight.shape().num_elements()
+                + block.conv1d_bias.bias.shape().num_elements()
+                + block.x_proj.weight.shape().num_elements()
+                + block.dt_proj.weight.shape().num_elements()
+                + block.dt_proj.bias.shape().num_elements()
+                + block.a_log.shape().num_elements()
+                + block.d.shape().num_elements()
+                + block.out_proj.weight.shape().num_elements();
+        }
+        assert_eq!(SMALL.params(), params);
+
+        // ~129M with the lm head tied to the embedding, plus the untied lm head.
+        assert_eq!(MAMBA_130M.params(), 167_800_680);
+        assert_eq!(MAMBA_130M.approx_memory::<f32>(), 167_800_680 * 4);
+    }
+}
@@ -0,0 +1,414 @@
+//! Saving and restoring an ongoing generation.
+//!
+//! A [Snapshot] contains everything needed to resume a [Session]: the states, the token
+//! history, the tokenizer stream and the sampler rng position.
+//! The tensors are stored as safetensors, and everything else as a json sidecar.
+//!
+//! The sampler rng is not serialized directly. Instead, its seed and the amount of samples
+//! are stored, and the rng gets replayed on restore.
+
+use crate::generation::{FinishReason, GenerationConfig, Session};
+use crate::{mamba, LogitsProcessorWrapper, MambaModel, MambaWrapper};
+use dfdx::prelude::*;
+use safetensors::tensor::{Dtype as StDtype, SafeTensors, TensorView};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+#[cfg(target_arch = "wasm32")]
+use web_time::Instant;
+
+/// Increased whenever the [SnapshotMeta] layout changes.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// The tensors of a [Snapshot], by their safetensors name.
+pub type SnapshotTensors = HashMap<String, (Vec<usize>, Vec<f32>)>;
+
+/// A saved [Session], tokenizer stream and logits processor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub meta: SnapshotMeta,
+    /// The states (`layers.{i}.conv_state` and `layers.{i}.ssm_state`) and any pending logits.
+    pub tensors: SnapshotTensors,
+}
+
+/// The json sidecar of a [Snapshot].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotMeta {
+    pub version: u32,
+    pub config: GenerationConfig,
+    pub step: usize,
+    pub tokens: Vec<u32>,
+    pub eos_token: u32,
+    pub new_tokens: usize,
+    /// How many of the `tokens` are contained in the states.
+    #[serde(default)]
+    pub fed: usize,
+    /// How many layer states were saved.
+    pub n_layers: usize,
+    pub held_text: String,
+    /// How much of the `time_budget` was already used.
+    pub elapsed: Duration,
+    pub is_started: bool,
+    pub is_prefilled: bool,
+    pub finish_reason: Option<FinishReason>,
+    pub stream: StreamMeta,
+    pub sampler: SamplerMeta,
+}
+
+/// The state of a [TokenOutputStream](crate::token_output_stream::TokenOutputStream).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StreamMeta {
+    pub tokens: Vec<u32>,
+    pub prev_index: usize,
+    pub current_index: usize,
+}
+
+/// The parameters and rng position of a [LogitsProcessorWrapper].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SamplerMeta {
+    pub seed: u64,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    pub samples: u64,
+}
+
+const PREFILLED_LOGITS: &str = "prefilled_logits";
+
+fn conv_state_name(layer: usize) -> String {
+    format!("layers.{layer}.conv_state")
+}
+
+fn ssm_state_name(layer: usize) -> String {
+    format!("layers.{layer}.ssm_state")
+}
+
+impl Snapshot {
+    /// Captures the `session` together with the tokenizer stream of `models` and the `processor`.
+    pub fn capture<M: MambaModel>(
+        models: &MambaWrapper<M>,
+        session: &Session,
+        processor: &LogitsProcessorWrapper,
+    ) -> anyhow::Result<Self> {
+        let mut tensors = SnapshotTensors::new();
+        for (i, state) in session.states.iter().enumerate() {
+            let (b, d_inner, d_conv) = *state.conv_state.shape();
+            tensors.insert(
+                conv_state_name(i),
+                (vec![b, d_inner, d_conv], state.conv_state.as_vec()),
+            );
+            let (b, d_inner, d_state) = *state.ssm_state.shape();
+            tensors.insert(
+                ssm_state_name(i),
+                (vec![b, d_inner, d_state], state.ssm_state.as_vec()),
+            );
+        }
+        if let Some(logits) = &session.prefilled_logits {
+            tensors.insert(
+                PREFILLED_LOGITS.to_string(),
+                (vec![logits.len()], logits.clone()),
+            );
+        }
+
+        let (stream_tokens, prev_index, current_index) = models.tokenizer.stream_state();
+        let (seed, temperature, top_p, repeat_penalty, repeat_last_n) = processor.params();
+        let meta = SnapshotMeta {
+            version: SNAPSHOT_VERSION,
+            config: session.config.clone(),
+            step: session.step,
+            tokens: session.tokens.clone(),
+            eos_token: session.eos_token,
+            new_tokens: session.new_tokens,
+            fed: session.fed,
+            n_layers: session.states.len(),
+            held_text: session.held_text.clone(),
+            elapsed: session.started_at.elapsed(),
+            is_started: session.is_started,
+            is_prefilled: session.is_prefilled,
+            finish_reason: session.finish_reason.clone(),
+            stream: StreamMeta {
+                tokens: stream_tokens.to_vec(),
+                prev_index,
+                current_index,
+            },
+            sampler: SamplerMeta {
+                seed,
+                temperature,
+                top_p,
+                repeat_penalty,
+                repeat_last_n,
+                samples: processor.samples(),
+            },
+        };
+        Ok(Self { meta, tensors })
+    }
+
+    /// Restores the session and the logits processor, and also restores the tokenizer stream of `models`.
+    ///
+    /// Note: only the processors created by [LogitsProcessorWrapper::new] are restored,
+    /// so any other processors must be added back into the chain.
+    pub fn restore<M: MambaModel>(
+        &self,
+        models: &mut MambaWrapper<M>,
+    ) -> anyhow::Result<(Session, LogitsProcessorWrapper)> {
+        let meta = &self.meta;
+        if meta.version != SNAPSHOT_VERSION {
+            anyhow::bail!(
+                "unsupported snapshot version {} (expected {SNAPSHOT_VERSION})",
+                meta.version
+            );
+        }
+
+        let mut states: mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape> = vec![];
+        if meta.n_layers != 0 {
+            let (conv_shape, _) = self.tensor(&conv_state_name(0))?;
+            states = models.empty_states(conv_shape[0])?;
+            if states.len() != meta.n_layers {
+                anyhow::bail!(
+                    "the snapshot has {} layer states but the model has {} layers",
+                    meta.n_layers,
+                    states.len()
+                );
+            }
+            let cpu = models.mamba.device();
+            for (i, state) in states.iter_mut().enumerate() {
+                let (shape, data) = self.tensor(&conv_state_name(i))?;
+                let [b, d_inner, d_conv] = shape3(&conv_state_name(i), shape)?;
+                state.conv_state = cpu.try_tensor_from_vec(data.clone(), (b, d_inner, d_conv))?;
+                let (shape, data) = self.tensor(&ssm_state_name(i))?;
+                let [b, d_inner, d_state] = shape3(&ssm_state_name(i), shape)?;
+                state.ssm_state = cpu.try_tensor_from_vec(data.clone(), (b, d_inner, d_state))?;
+            }
+            models.mamba.check_states(&states)?;
+        }
+
+        let mut session =
+            Session::with_tokens(meta.tokens.clone(), meta.eos_token, meta.config.clone());
+        session.step = meta.step;
+        session.new_tokens = meta.new_tokens;
+        session.fed = meta.fed;
+        session.states = states;
+        // the stateless logits are re-calculated on demand
+        session.stateless_logits = VecDeque::new();
+        session.prefilled_logits = self
+            .tensors
+            .get(PREFILLED_LOGITS)
+            .map(|(_shape, data)| data.clone());
+        session.is_prefilled = meta.is_prefilled;
+        session.held_text = meta.held_text.clone();
+        session.started_at = Instant::now()
+            .checked_sub(meta.elapsed)
+            .unwrap_or_else(Instant::now);
+        session.is_started = meta.is_started;
+        session.finish_reason = meta.finish_reason.clone();
+
+        models.tokenizer.set_stream_state(
+            meta.stream.tokens.clone(),
+            meta.stream.prev_index,
+            meta.stream.current_index,
+        );
+
+        let sampler = &meta.sampler;
+        let mut processor = LogitsProcessorWrapper::new(
+            sampler.seed,
+            sampler.temperature,
+            sampler.top_p,
+            sampler.repeat_penalty,
+            sampler.repeat_last_n,
+        )?;
+        processor.skip_samples(sampler.samples)?;
+
+        Ok((session, processor))
+    }
+
+    fn tensor(&self, name: &str) -> anyhow::Result<&(Vec<usize>, Vec<f32>)> {
+        self.tensors
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("the snapshot is missing the tensor {name}"))
+    }
+
+    /// Serializes the tensors (as safetensors) and the sidecar (as json).
+    pub fn to_bytes(&self) -> anyhow::Result<(Vec<u8>, String)> {
+        let bytes: HashMap<&String, Vec<u8>> = self
+            .tensors
+            .iter()
+            .map(|(name, (_shape, data))| {
+                (name, data.iter().flat_map(|x| x.to_le_bytes()).collect())
+            })
+            .collect();
+        let mut views = vec![];
+        for (name, (shape, _data)) in self.tensors.iter() {
+            let view = TensorView::new(StDtype::F32, shape.clone(), &bytes[name])
+                .map_err(|e| anyhow::anyhow!("failed to serialize {name}: {e:?}"))?;
+            views.push((name.clone(), view));
+        }
+        let tensors = safetensors::tensor::serialize(views, &None)
+            .map_err(|e| anyhow::anyhow!("failed to serialize the snapshot tensors: {e:?}"))?;
+        let meta = serde_json::to_string_pretty(&self.meta)?;
+        Ok((tensors, meta))
+    }
+
+    /// Deserializes from the output of [Snapshot::to_bytes].
+    pub fn from_bytes(tensors: &[u8], meta: &str) -> anyhow::Result<Self> {
+        let meta: SnapshotMeta = serde_json::from_str(meta)
+            .map_err(|e| anyhow::anyhow!("failed to parse the snapshot sidecar: {e}"))?;
+        let safetensors = SafeTensors::deserialize(tensors)
+            .map_err(|e| anyhow::anyhow!("failed to parse the snapshot tensors: {e:?}"))?;
+        let mut tensors = SnapshotTensors::new();
+        for (name, view) in safetensors.tensors() {
+            if view.dtype() != StDtype::F32 {
+                anyhow::bail!("the snapshot tensor {name} is not f32");
+            }
+            let data = view
+                .data()
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+            tensors.insert(name, (view.shape().to_vec(), data));
+        }
+        Ok(Self { meta, tensors })
+    }
+
+    /// Writes the tensors into `path` and the json sidecar into `path` with a `.json` extension.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let (tensors, meta) = self.to_bytes()?;
+        std::fs::write(path, tensors)?;
+        std::fs::write(path.with_extension("json"), meta)?;
+        Ok(())
+    }
+
+    /// Reads the files written by [Snapshot::save].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load(path: impl AsRef<std::path::Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let tensors = std::fs::read(path)?;
+        let meta = std::fs::read_to_string(path.with_extension("json"))?;
+        Self::from_bytes(&tensors, &meta)
+    }
+}
+
+fn shape3(name: &str, shape: &[usize]) -> anyhow::Result<[usize; 3]> {
+    match *shape {
+        [a, b, c] => Ok([a, b, c]),
+        _ => anyhow::bail!("the snapshot tensor {name} should have 3 dimensions, got {shape:?}"),
+    }
+}
+
+impl<M: MambaModel> MambaWrapper<M> {
+    /// Saves the `session`, the tokenizer stream and the `processor` into `path` (safetensors)
+    /// and `path` with a `.json` extension (sidecar).
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save_snapshot(
+        &self,
+        session: &Session,
+        processor: &LogitsProcessorWrapper,
+        path: impl AsRef<std::path::Path>,
+    ) -> anyhow::Result<()> {
+        Snapshot::capture(self, session, processor)?.save(path)
+    }
+
+    /// Loads a snapshot saved by [MambaWrapper::save_snapshot], restoring the tokenizer stream
+    /// and returning the session and logits processor, so the generation can be resumed.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_snapshot(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+    ) -> anyhow::Result<(Session, LogitsProcessorWrapper)> {
+        Snapshot::load(path)?.restore(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generation::{Generation, GenerationEvent};
+    use crate::test_utils;
+
+    const PROMPT: &str = "the cat sat on";
+    const NEW_TOKENS: usize = 12;
+    /// How many events are emitted before the snapshot.
+    const SNAPSHOT_AT: usize = 9;
+
+    fn new_processor() -> LogitsProcessorWrapper {
+        LogitsProcessorWrapper::new(7, Some(0.9), Some(0.95), 1.1, 4).unwrap()
+    }
+
+    fn config() -> GenerationConfig {
+        GenerationConfig {
+            max_new_tokens: Some(NEW_TOKENS),
+            ignore_eos: true,
+            ..Default::default()
+        }
+    }
+
+    fn events(generation: &mut Generation, limit: usize) -> Vec<GenerationEvent> {
+        generation
+            .by_ref()
+            .take(limit)
+            .collect::<anyhow::Result<_>>()
+            .unwrap()
+    }
+
+    fn states(session: &Session) -> Vec<(Vec<f32>, Vec<f32>)> {
+        session
+            .states
+            .iter()
+            .map(|state| (state.conv_state.as_vec(), state.ssm_state.as_vec()))
+            .collect()
+    }
+
+    /// The next few samples of the rng, drawn from the same logits.
+    fn rng_stream(processor: &mut LogitsProcessorWrapper) -> Vec<u32> {
+        let mut tokens = vec![test_utils::EOS];
+        (0..8)
+            .map(|i| {
+                let mut logits: Vec<f32> = (0..32).map(|t| (t % 5) as f32 * 0.3).collect();
+                processor.add_logits(i, &mut tokens, &mut logits).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resume_from_snapshot() {
+        let mut models = test_utils::models(0);
+        let mut processor = new_processor();
+        let session = Session::new(&mut models, PROMPT, config()).unwrap();
+        let mut generation = models.resume(session, &mut processor);
+        let expected = events(&mut generation, usize::MAX);
+        let expected_states = states(&generation.session);
+        let expected_rng = rng_stream(&mut processor);
+        assert!(SNAPSHOT_AT < expected.len());
+        let new_tokens = expected
+            .iter()
+            .filter(|event| matches!(event, GenerationEvent::Token(t) if !t.is_prompt))
+            .count();
+        assert_eq!(new_tokens, NEW_TOKENS);
+
+        let mut models = test_utils::models(0);
+        let mut processor = new_processor();
+        let session = Session::new(&mut models, PROMPT, config()).unwrap();
+        let mut generation = models.resume(session, &mut processor);
+        let mut resumed = events(&mut generation, SNAPSHOT_AT);
+        let session = generation.session;
+        let (tensors, meta) = Snapshot::capture(&models, &session, &processor)
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        drop((session, processor));
+
+        // a fresh model, whose tokenizer stream is also restored
+        let mut models = test_utils::models(0);
+        let snapshot = Snapshot::from_bytes(&tensors, &meta).unwrap();
+        let (session, mut processor) = snapshot.restore(&mut models).unwrap();
+        let mut generation = models.resume(session, &mut processor);
+        resumed.extend(events(&mut generation, usize::MAX));
+        assert_eq!(resumed, expected);
+        assert_eq!(states(&generation.session), expected_states);
+        assert_eq!(rng_stream(&mut processor), expected_rng);
+    }
+}
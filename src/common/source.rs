@@ -0,0 +1,424 @@
+//! Where the tokenizer and the model files are loaded from.
+//!
+//! Besides the hf-hub api, the files can come from a local directory, explicit file paths
+//! or in-memory bytes, none of which touch the network.
+//! The hf-hub source also has an offline mode, which only reads from the hf-hub cache.
+
+use crate::mamba::prefill::MixedDevice;
+use crate::precision::MambaDtype;
+use crate::quant::{QMamba, QuantFormat};
+use crate::{hf, mamba, MambaWrapper};
+use dfdx::prelude::*;
+#[allow(unused_imports)]
+use hf_hub::{
+    types::{FilePath, RepoId, RevisionPath},
+    Repo, RepoType,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModelSource {
+    /// A directory containing `tokenizer.json`, `config.json` and `model.safetensors`.
+    #[cfg(not(target_arch = "wasm32"))]
+    Directory(PathBuf),
+    /// Explicit file paths.
+    #[cfg(not(target_arch = "wasm32"))]
+    Files(ModelFiles),
+    /// The file contents, already in memory.
+    Bytes(ModelBytes),
+    /// The hf-hub repositories (or only their cache, when offline).
+    HfHub(HfHubSource),
+}
+
+impl Default for ModelSource {
+    fn default() -> Self {
+        Self::HfHub(HfHubSource::default())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelFiles {
+    /// The `tokenizer.json` file.
+    pub tokenizer: PathBuf,
+    /// The `config.json` file.
+    pub config: PathBuf,
+    /// The `model.safetensors` file.
+    pub weights: PathBuf,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ModelFiles {
+    /// The files with their default names inside of `dir`.
+    pub fn in_dir(dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        Self {
+            tokenizer: dir.join(hf::tokenizer::FILE_PATH_TOKENIZER_JSON),
+            config: dir.join(hf::mamba_130m::FILE_PATH_CONFIG_JSON),
+            weights: dir.join(hf::mamba_130m::FILE_PATH_MODEL_SAFETENSORS),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ModelBytes {
+    /// The `tokenizer.json` contents.
+    pub tokenizer: Vec<u8>,
+    /// The `config.json` contents.
+    pub config: Vec<u8>,
+    /// The `model.safetensors` contents.
+    pub weights: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HfHubSource {
+    pub tokenizer_repo: String,
+    pub model_repo: String,
+    pub model_revision: String,
+    /// Whether to only read from the hf-hub cache, never touching the network.
+    ///
+    /// Fails if a file is not already cached.
+    pub offline: bool,
+}
+
+impl Default for HfHubSource {
+    fn default() -> Self {
+        Self {
+            tokenizer_repo: hf::tokenizer::REPO_ID.into(),
+            model_repo: hf::mamba_130m::REPO_ID.into(),
+            model_revision: hf::mamba_130m::REVISION_PATH.into(),
+            offline: false,
+        }
+    }
+}
+
+impl HfHubSource {
+    fn tokenizer_repo(&self) -> Repo {
+        Repo::new(RepoId(self.tokenizer_repo.clone()), RepoType::Model)
+    }
+
+    fn model_repo(&self) -> Repo {
+        Repo::with_revision(
+            RepoId(self.model_repo.clone()),
+            RepoType::Model,
+            RevisionPath(self.model_revision.clone()),
+        )
+    }
+
+    /// Gets the files, downloading them if they are not cached (unless offline).
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn files(&self) -> anyhow::Result<ModelFiles> {
+        Ok(ModelFiles {
+            tokenizer: self.tokenizer_file()?,
+            config: self.config_file()?,
+            weights: self.weights_file()?,
+        })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn tokenizer_file(&self) -> anyhow::Result<PathBuf> {
+        self.get(
+            self.tokenizer_repo(),
+            hf::tokenizer::FILE_PATH_TOKENIZER_JSON,
+        )
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn config_file(&self) -> anyhow::Result<PathBuf> {
+        self.get(self.model_repo(), hf::mamba_130m::FILE_PATH_CONFIG_JSON)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn weights_file(&self) -> anyhow::Result<PathBuf> {
+        self.get(
+            self.model_repo(),
+            hf::mamba_130m::FILE_PATH_MODEL_SAFETENSORS,
+        )
+    }
+
+    /// Gets a file from the cache or, unless offline, from the api.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn get(&self, repo: Repo, file: &str) -> anyhow::Result<PathBuf> {
+        let file_path = FilePath(file.into());
+        if self.offline {
+            // note: the cache is only read from the disk
+            hf_hub::Cache::default()
+                .repo(repo.clone())
+                .get(&file_path)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "{file} from {} is not in the hf-hub cache (offline mode)",
+                        repo.url()
+                    )
+                })
+        } else {
+            let api = hf_hub::api::sync::Api::new()?;
+            Ok(api.repo(repo).get(&file_path)?)
+        }
+    }
+
+    /// Gets the file contents, downloading them if they are not cached.
+    ///
+    /// Note: the wasm api can't check its cache without also being able to reach the network,
+    /// so the offline mode is not supported. Use [ModelSource::Bytes] instead.
+    #[cfg(target_arch = "wasm32")]
+    pub async fn bytes(&self) -> anyhow::Result<ModelBytes> {
+        if self.offline {
+            anyhow::bail!(
+                "the offline mode is not supported on wasm, load the files as bytes instead"
+            );
+        }
+        let api = hf_hub::api::wasm::Api::new().await?;
+        let mut files = vec![];
+        for (repo, file) in [
+            (
+                self.tokenizer_repo(),
+                hf::tokenizer::FILE_PATH_TOKENIZER_JSON,
+            ),
+            (self.model_repo(), hf::mamba_130m::FILE_PATH_CONFIG_JSON),
+            (
+                self.model_repo(),
+                hf::mamba_130m::FILE_PATH_MODEL_SAFETENSORS,
+            ),
+        ] {
+            let key = api.repo(repo).get(&FilePath(file.into())).await?;
+            files.push(api.load_bytes(&key).await?);
+        }
+        let weights = files.pop().unwrap();
+        let config = files.pop().unwrap();
+        let tokenizer = files.pop().unwrap();
+        Ok(ModelBytes {
+            tokenizer,
+            config,
+            weights,
+        })
+    }
+}
+
+impl ModelSource {
+    /// Loads the tokenizer and the model, with the weights converted into `E`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load<E: MambaDtype>(&self) -> anyhow::Result<MambaWrapper<mamba::Mamba<E, Cpu>>>
+    where
+        Cpu: MixedDevice<E, f32>,
+    {
+        match self {
+            Self::Directory(dir) => MambaWrapper::from_files(&ModelFiles::in_dir(dir)),
+            Self::Files(files) => MambaWrapper::from_files(files),
+            Self::Bytes(bytes) => MambaWrapper::from_bytes(bytes),
+            Self::HfHub(hub) => MambaWrapper::from_files(&hub.files()?),
+        }
+    }
+
+    /// Loads the tokenizer and the model, with the weights converted into `E`.
+    #[cfg(target_arch = "wasm32")]
+    pub async fn load<E: MambaDtype>(&self) -> anyhow::Result<MambaWrapper<mamba::Mamba<E, Cpu>>>
+    where
+        Cpu: MixedDevice<E, f32>,
+    {
+        match self {
+            Self::Bytes(bytes) => MambaWrapper::from_bytes(bytes),
+            Self::HfHub(hub) => MambaWrapper::from_bytes(&hub.bytes().await?),
+        }
+    }
+
+    /// Loads the tokenizer and a quantized model.
+    ///
+    /// If the weights were saved by [QMamba::to_safetensors], they are loaded as they are
+    /// (in their own format). Otherwise they are loaded in `f32` and quantized into the `format`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_quantized(&self, format: QuantFormat) -> anyhow::Result<MambaWrapper<QMamba>> {
+        let files = match self {
+            Self::Directory(dir) => ModelFiles::in_dir(dir),
+            Self::Files(files) => files.clone(),
+            Self::Bytes(bytes) => return MambaWrapper::from_bytes_quantized(bytes, format),
+            Self::HfHub(hub) => hub.files()?,
+        };
+        let tokenizer =
+            tokenizers::Tokenizer::from_file(&files.tokenizer).map_err(anyhow::Error::msg)?;
+        let config = std::fs::read_to_string(&files.config)?;
+        let weights = map_file(&files.weights)?;
+        let mamba = load_qmamba(&config, &weights, format)?;
+        Ok(MambaWrapper::new(tokenizer, mamba))
+    }
+
+    /// Loads the tokenizer and a quantized model (see [ModelSource::load_quantized]).
+    #[cfg(target_arch = "wasm32")]
+    pub async fn load_quantized(
+        &self,
+        format: QuantFormat,
+    ) -> anyhow::Result<MambaWrapper<QMamba>> {
+        match self {
+            Self::Bytes(bytes) => MambaWrapper::from_bytes_quantized(bytes, format),
+            Self::HfHub(hub) => MambaWrapper::from_bytes_quantized(&hub.bytes().await?, format),
+        }
+    }
+}
+
+impl MambaWrapper<QMamba> {
+    /// Loads the tokenizer and a quantized model from their file contents
+    /// (see [ModelSource::load_quantized]).
+    pub fn from_bytes_quantized(bytes: &ModelBytes, format: QuantFormat) -> anyhow::Result<Self> {
+        let tokenizer =
+            tokenizers::Tokenizer::from_bytes(&bytes.tokenizer).map_err(anyhow::Error::msg)?;
+        let config = std::str::from_utf8(&bytes.config)?;
+        let mamba = load_qmamba(config, &bytes.weights, format)?;
+        Ok(Self::new(tokenizer, mamba))
+    }
+}
+
+/// Loads the already quantized `weights`, or loads them in `f32` and quantizes them into the `format`.
+pub fn load_qmamba(config: &str, weights: &[u8], format: QuantFormat) -> anyhow::Result<QMamba> {
+    if QMamba::is_quantized(weights)? {
+        QMamba::from_safetensors(weights, &Cpu::default())
+    } else {
+        QMamba::quantize(&load_mamba::<f32>(config, weights)?, format)
+    }
+}
+
+impl<E: MambaDtype> MambaWrapper<mamba::Mamba<E, Cpu>>
+where
+    Cpu: MixedDevice<E, f32>,
+{
+    /// Loads the tokenizer and the model from their file contents.
+    pub fn from_bytes(bytes: &ModelBytes) -> anyhow::Result<Self> {
+        let tokenizer =
+            tokenizers::Tokenizer::from_bytes(&bytes.tokenizer).map_err(anyhow::Error::msg)?;
+        let config = std::str::from_utf8(&bytes.config)?;
+        let mamba = load_mamba(config, &bytes.weights)?;
+        Ok(Self::new(tokenizer, mamba))
+    }
+
+    /// Loads the tokenizer and the model from local files.
+    ///
+    /// The weights file is memory-mapped rather than read, so the tensors get copied
+    /// straight from the page cache (see [map_file]).
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_files(files: &ModelFiles) -> anyhow::Result<Self> {
+        let tokenizer =
+            tokenizers::Tokenizer::from_file(&files.tokenizer).map_err(anyhow::Error::msg)?;
+        let config = std::fs::read_to_string(&files.config)?;
+        let weights = map_file(&files.weights)?;
+        let mamba = load_mamba(&config, &weights)?;
+        Ok(Self::new(tokenizer, mamba))
+    }
+}
+
+/// Memory-maps the (read-only) `path`.
+///
+/// Note: the file must not be modified while mapped, which is assumed for the model files.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn map_file(path: impl AsRef<Path>) -> anyhow::Result<memmap2::Mmap> {
+    let path = path.as_ref();
+    let file =
+        std::fs::File::open(path).map_err(|e| anyhow::anyhow!("failed to open {path:?}: {e}"))?;
+    // safety: the model files are not expected to change while the model gets loaded
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    Ok(mmap)
+}
+
+/// Builds a model from the `config.json` contents and loads the `weights`
+/// (the `model.safetensors` contents) into it, converting each tensor into `E` if needed
+/// (see [mamba::load::load_hf_safetensors]).
+///
+/// The parameters are allocated without the random initialization, since they all get
+/// overwritten by the `weights`. The only parameter the checkpoints lack, the `lm_head` bias,
+/// is then left as zeros.
+///
+/// The `lm_head` weight is tied to the embedding, unless the `weights` have an untied
+/// [mamba::load::HF_LM_HEAD] (see [mamba::save::to_hf_safetensors]).
+pub fn load_mamba<E: MambaDtype>(
+    config: &str,
+    weights: &[u8],
+) -> anyhow::Result<mamba::Mamba<E, Cpu>>
+where
+    Cpu: MixedDevice<E, f32>,
+{
+    let config = mamba::MambaConfig::from_hf_config_json(config)?;
+    let cpu = Cpu::default();
+    let mut mamba: mamba::Mamba<E, Cpu> = config.try_build_on_device(&cpu)?;
+    let tensors = safetensors::SafeTensors::deserialize(weights)?;
+    mamba::load::load_hf_safetensors(&mut mamba, &tensors)?;
+    Ok(mamba)
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::{test_utils, MambaModel};
+
+    const CONFIG_JSON: &str =
+        r#"{"d_model": 16, "n_layer": 2, "vocab_size": 32, "pad_vocab_size_multiple": 8}"#;
+
+    /// A temporary directory with the files of an exported model, removed on drop.
+    struct ModelDir(PathBuf);
+
+    impl ModelDir {
+        fn new(name: &str, model: &mamba::Mamba<f32, Cpu>) -> Self {
+            let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let files = ModelFiles::in_dir(&dir);
+            test_utils::word_level_tokenizer(test_utils::WORDS.split(' '))
+                .save(&files.tokenizer, false)
+                .unwrap();
+            std::fs::write(&files.config, CONFIG_JSON).unwrap();
+            mamba::save::save_hf_safetensors(model, &files.weights).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for ModelDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn logits<M: MambaModel>(model: &M) -> Vec<f32> {
+        let tokens = vec![3, 1, 4, 1, 5, 9];
+        let x = model.device().tensor_from_vec(tokens, (1, 6));
+        model.try_forward_stateless_f32(x).unwrap().as_vec()
+    }
+
+    #[test]
+    fn local_sources_load_the_exported_model() {
+        let mut model = test_utils::mamba(0);
+        model.lm_head.bias.fill_with_zeros();
+        let dir = ModelDir::new("model-source", &model);
+        let files = ModelFiles::in_dir(&dir.0);
+        let bytes = ModelBytes {
+            tokenizer: std::fs::read(&files.tokenizer).unwrap(),
+            config: std::fs::read(&files.config).unwrap(),
+            weights: std::fs::read(&files.weights).unwrap(),
+        };
+        let expected = logits(&model);
+        let prompt = test_utils::models(0).encode_prompt("the cat sat").unwrap();
+
+        for source in [
+            ModelSource::Directory(dir.0.clone()),
+            ModelSource::Files(files.clone()),
+            ModelSource::Bytes(bytes.clone()),
+        ] {
+            let models = source.load::<f32>().unwrap();
+            assert_eq!(models.encode_prompt("the cat sat").unwrap(), prompt);
+            assert_eq!(logits(&models.mamba), expected);
+
+            let quantized = source.load_quantized(QuantFormat::Int8).unwrap();
+            assert_eq!(
+                quantized.mamba.weights_format(),
+                QuantFormat::Int8.to_string()
+            );
+        }
+
+        let missing = ModelFiles {
+            weights: dir.0.join("missing.safetensors"),
+            ..files
+        };
+        assert!(ModelSource::Files(missing).load::<f32>().is_err());
+        let corrupted = ModelBytes {
+            weights: bytes.weights[..bytes.weights.len() / 2].to_vec(),
+            ..bytes
+        };
+        assert!(ModelSource::Bytes(corrupted).load::<f32>().is_err());
+    }
+}
@@ -0,0 +1,52 @@
+//! Small offline fixtures for the unit tests.
+
+use crate::mamba::{Mamba, MambaConfig};
+use crate::MambaWrapper;
+use dfdx::prelude::*;
+use serde_json::json;
+
+/// The id of the `<|endoftext|>` token of [word_level_tokenizer].
+pub const EOS: u32 = 0;
+
+/// The words of the [models] tokenizer, which fill a vocab of `32` with the eos token.
+pub const WORDS: &str = "a b c d e f g h i j k l m n o p q r s t u v w x y z the cat sat on mat";
+
+/// A tokenizer with the special `<|endoftext|>` token ([EOS]) followed by the `words`.
+///
+/// The text is split on whitespace and punctuation, and the decoded tokens are joined by spaces.
+pub fn word_level_tokenizer<S: Into<String>>(
+    words: impl IntoIterator<Item = S>,
+) -> tokenizers::Tokenizer {
+    let mut vocab = serde_json::Map::new();
+    vocab.insert("<|endoftext|>".into(), json!(EOS));
+    for word in words {
+        let id = vocab.len();
+        vocab.insert(word.into(), json!(id));
+    }
+    let tokenizer = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [{
+            "id": EOS, "content": "<|endoftext|>", "single_word": false, "lstrip": false,
+            "rstrip": false, "normalized": false, "special": true
+        }],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "<|endoftext|>"}
+    });
+    tokenizers::Tokenizer::from_bytes(tokenizer.to_string()).unwrap()
+}
+
+/// A small random model, initialized from the `seed`.
+pub fn mamba(seed: u64) -> Mamba<f32, Cpu> {
+    let config = MambaConfig::new(2, 32, 16, None, None, None, None);
+    Cpu::seed_from_u64(seed).build_module::<f32>(config)
+}
+
+/// A small random model (see [mamba]) with the [WORDS] tokenizer.
+pub fn models(seed: u64) -> MambaWrapper {
+    MambaWrapper::new(word_level_tokenizer(WORDS.split(' ')), mamba(seed))
+}
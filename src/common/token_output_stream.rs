@@ -1,6 +1,6 @@
 //! This is a copy-and-paste from [candle-examples](https://github.com/huggingface/candle/blob/main/candle-examples/src/token_output_stream.rs).
 
-use candle_core::Result;
+use anyhow::Result;
 
 /// This is a wrapper around a tokenizer to ensure that tokens can be returned to the user in a
 /// streaming way rather than having to wait for the full decoding.
@@ -28,7 +28,7 @@ impl TokenOutputStream {
     fn decode(&self, tokens: &[u32]) -> Result<String> {
         match self.tokenizer.decode(tokens, true) {
             Ok(str) => Ok(str),
-            Err(err) => candle_core::bail!("cannot decode: {err}"),
+            Err(err) => anyhow::bail!("cannot decode: {err}"),
         }
     }
 
@@ -80,6 +80,18 @@ impl TokenOutputStream {
         &self.tokenizer
     }
 
+    /// The streamed tokens, and the indices that mark the text that was already released.
+    pub fn stream_state(&self) -> (&[u32], usize, usize) {
+        (&self.tokens, self.prev_index, self.current_index)
+    }
+
+    /// Restores a state that was previously read from [TokenOutputStream::stream_state].
+    pub fn set_stream_state(&mut self, tokens: Vec<u32>, prev_index: usize, current_index: usize) {
+        self.tokens = tokens;
+        self.prev_index = prev_index;
+        self.current_index = current_index;
+    }
+
     pub fn clear(&mut self) {
         self.tokens.clear();
         self.prev_index = 0;
@@ -0,0 +1,557 @@
+//! Fine-tuning over a local text corpus, with the stateless forward.
+//!
+//! The documents are tokenized (each prepended with the eos token, as in [crate::eval]),
+//! concatenated and packed into windows of `seq_len + 1` tokens, so that each window holds
+//! `seq_len` inputs and their next-token targets. Each step runs a batch of windows with an
+//! [OwnedTape], and optimizes the next-token cross-entropy with AdamW (decoupled weight decay,
+//! only on the embedding and the 2-D projections), a linear warmup followed by a cosine decay of
+//! the learning rate, and gradient norm clipping.
+//!
+//! The `lm_head` is tied to the embedding, as in the Hugging Face checkpoints:
+//! the logits are calculated from the embedding weight, which then gets copied into the `lm_head`
+//! after each step. The checkpoints then omit the `lm_head`, as the original ones do
+//! (see [mamba::save::to_hf_safetensors]).
+
+use crate::eval::CorpusChunk;
+use crate::mamba::stateless::{BlockInput, VocabInput};
+use crate::mamba::{self, Mamba};
+use crate::{MambaModel, MambaWrapper};
+use dfdx::prelude::*;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrainConfig {
+    /// How many tokens each window feeds into the model.
+    ///
+    /// Defaults to `128`.
+    pub seq_len: usize,
+    /// How many windows are in each batch.
+    ///
+    /// Defaults to `4`.
+    pub batch_size: usize,
+    /// How many optimizer steps to run.
+    ///
+    /// Defaults to `1000`.
+    pub steps: usize,
+    /// The peak learning rate, reached at the end of the warmup.
+    ///
+    /// Defaults to `1e-4`.
+    pub lr: f64,
+    /// The learning rate at the end of the cosine decay.
+    ///
+    /// Defaults to `1e-5`.
+    pub min_lr: f64,
+    /// How many steps the learning rate linearly increases for.
+    ///
+    /// Defaults to `100`.
+    pub warmup_steps: usize,
+    /// The decoupled (AdamW) weight decay, which only applies to the embedding (and so the tied
+    /// `lm_head`) and the 2-D projections (see [decay_weights]).
+    ///
+    /// Defaults to `0.1`.
+    pub weight_decay: f64,
+    /// Defaults to `[0.9, 0.95]`.
+    pub betas: [f64; 2],
+    /// Defaults to `1e-8`.
+    pub eps: f64,
+    /// If the global norm of the gradients is larger than this, they are scaled down to it.
+    ///
+    /// Defaults to `Some(1.0)`.
+    pub max_grad_norm: Option<f64>,
+    /// The seed for shuffling the windows.
+    ///
+    /// Defaults to `0`.
+    pub seed: u64,
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        Self {
+            seq_len: 128,
+            batch_size: 4,
+            steps: 1000,
+            lr: 1e-4,
+            min_lr: 1e-5,
+            warmup_steps: 100,
+            weight_decay: 0.1,
+            betas: [0.9, 0.95],
+            eps: 1e-8,
+            max_grad_norm: Some(1.),
+            seed: 0,
+        }
+    }
+}
+
+impl TrainConfig {
+    /// The learning rate for the `step` (starting at `0`).
+    ///
+    /// Linearly increases up to `lr` during the warmup, and then follows a cosine decay
+    /// down to `min_lr` at the last step.
+    pub fn lr_at(&self, step: usize) -> f64 {
+        if step < self.warmup_steps {
+            return self.lr * (step + 1) as f64 / self.warmup_steps as f64;
+        }
+        let decay_steps = self.steps.saturating_sub(self.warmup_steps + 1).max(1);
+        let progress = ((step - self.warmup_steps) as f64 / decay_steps as f64).min(1.);
+        let cosine = 0.5 * (1. + (std::f64::consts::PI * progress).cos());
+        self.min_lr + (self.lr - self.min_lr) * cosine
+    }
+}
+
+/// The windows of `seq_len + 1` tokens, shuffled into batches.
+///
+/// Once all windows were used, they are shuffled again for the next epoch.
+/// Windows that don't fit in the last batch of an epoch are left for the next one.
+pub struct Batches {
+    windows: Vec<Vec<u32>>,
+    batch_size: usize,
+    order: Vec<usize>,
+    next: usize,
+    rng: rand::rngs::StdRng,
+    /// How many times all windows were shuffled.
+    pub epoch: usize,
+}
+
+impl Batches {
+    pub fn new(windows: Vec<Vec<u32>>, batch_size: usize, seed: u64) -> anyhow::Result<Self> {
+        if batch_size == 0 {
+            anyhow::bail!("the batch size must not be zero");
+        }
+        if windows.len() < batch_size {
+            anyhow::bail!(
+                "the corpus only has {} windows, but the batch size is {batch_size}",
+                windows.len()
+            );
+        }
+        let mut batches = Self {
+            order: (0..windows.len()).collect(),
+            windows,
+            batch_size,
+            next: 0,
+            rng: rand::rngs::StdRng::seed_from_u64(seed),
+            epoch: 0,
+        };
+        batches.order.shuffle(&mut batches.rng);
+        Ok(batches)
+    }
+
+    /// How many windows there are.
+    pub fn windows(&self) -> usize {
+        self.windows.len()
+    }
+
+    /// The next `batch_size` windows.
+    pub fn next_batch(&mut self) -> Vec<&[u32]> {
+        if self.next + self.batch_size > self.order.len() {
+            self.order.shuffle(&mut self.rng);
+            self.next = 0;
+            self.epoch += 1;
+        }
+        let batch = self.order[self.next..self.next + self.batch_size]
+            .iter()
+            .map(|&i| self.windows[i].as_slice())
+            .collect();
+        self.next += self.batch_size;
+        batch
+    }
+}
+
+impl<M: MambaModel> MambaWrapper<M> {
+    /// Tokenizes the `documents` and packs them into windows of `seq_len + 1` tokens.
+    ///
+    /// Consecutive windows overlap by a single token, so that every token is a target once.
+    /// The tokens at the end that don't fill a window are dropped.
+    ///
+    /// Note: although the documents may be streamed (see [crate::eval::read_corpus]), the whole
+    /// tokenized corpus is held in memory as `u32` tokens, twice while packing (as the token
+    /// list and as the windows). For English text, that is about the size of the text each.
+    pub fn pack_corpus<D: Into<CorpusChunk>>(
+        &self,
+        documents: impl IntoIterator<Item = anyhow::Result<D>>,
+        seq_len: usize,
+    ) -> anyhow::Result<Vec<Vec<u32>>> {
+        if seq_len == 0 {
+            anyhow::bail!("the sequence length must not be zero");
+        }
+        let mut tokens = vec![];
+        for chunk in documents {
+            let chunk = chunk?.into();
+            let (chunk_tokens, eos_token) = self.encode_prompt(&chunk.text)?;
+            if !chunk.continues {
+                tokens.push(eos_token);
+            }
+            tokens.extend(chunk_tokens);
+        }
+        let windows = tokens
+            .windows(seq_len + 1)
+            .step_by(seq_len)
+            .map(|window| window.to_vec())
+            .collect();
+        Ok(windows)
+    }
+}
+
+/// The results of a [Trainer::train_step].
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepReport {
+    /// The step, starting at `0`.
+    pub step: usize,
+    /// The mean next-token cross-entropy of the batch, in nats.
+    pub loss: f32,
+    pub lr: f64,
+    /// The global norm of the gradients, before the clipping.
+    pub grad_norm: f32,
+}
+
+impl std::fmt::Display for StepReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "step: {}, loss: {:.4}, lr: {:.3e}, grad norm: {:.4}",
+            self.step, self.loss, self.lr, self.grad_norm
+        )
+    }
+}
+
+/// The optimizer and the reused gradients.
+pub struct Trainer {
+    pub config: TrainConfig,
+    optimizer: Adam<Mamba<f32, Cpu>, f32, Cpu>,
+    grads: Option<Gradients<f32, Cpu>>,
+    /// How many steps were made, starting at `0`.
+    pub step: usize,
+}
+
+impl Trainer {
+    pub fn new(mamba: &Mamba<f32, Cpu>, config: TrainConfig) -> Self {
+        let optimizer = Adam::new(
+            mamba,
+            AdamConfig {
+                lr: config.lr_at(0),
+                betas: config.betas,
+                eps: config.eps,
+                // applied separately, see `decay_weights`
+                weight_decay: None,
+            },
+        );
+        Self {
+            config,
+            optimizer,
+            grads: None,
+            step: 0,
+        }
+    }
+
+    /// Makes an optimizer step over the `batch`, where each window has `seq_len + 1` tokens.
+    pub fn train_step(
+        &mut self,
+        mamba: &mut Mamba<f32, Cpu>,
+        batch: &[&[u32]],
+    ) -> anyhow::Result<StepReport> {
+        let grads = match self.grads.take() {
+            Some(grads) => grads,
+            None => mamba.try_alloc_grads()?,
+        };
+        let (loss, mut grads) = try_loss(mamba, batch, self.config.seq_len, grads)?;
+
+        let grad_norm = mamba.try_grads_norm_squared(&grads)?.sqrt();
+        if let Some(max_grad_norm) = self.config.max_grad_norm {
+            mamba.try_grads_clip_norm(&mut grads, grad_norm, max_grad_norm as f32)?;
+        }
+
+        let lr = self.config.lr_at(self.step);
+        decay_weights(mamba, (lr * self.config.weight_decay) as f32)?;
+        self.optimizer.cfg.lr = lr;
+        self.optimizer
+            .update(mamba, &grads)
+            .map_err(|e| anyhow::anyhow!("failed to update the parameters: {e:?}"))?;
+        tie_lm_head(mamba);
+        mamba.try_zero_grads(&mut grads)?;
+        self.grads = Some(grads);
+
+        let report = StepReport {
+            step: self.step,
+            loss,
+            lr,
+            grad_norm,
+        };
+        self.step += 1;
+        Ok(report)
+    }
+}
+
+/// Scales the embedding and the 2-D projections by `1 - decay`, as the decoupled weight decay
+/// does before the Adam update.
+///
+/// The `a_log`, `d`, conv1d, norm gammas and biases are not decayed, and neither is the tied
+/// `lm_head`, which gets copied from the embedding.
+pub fn decay_weights(mamba: &mut Mamba<f32, Cpu>, decay: f32) -> Result<(), Error> {
+    if decay == 0. {
+        return Ok(());
+    }
+    let scale = |weight: &mut Tensor<(usize, usize), f32, Cpu>| -> Result<(), Error> {
+        *weight = weight.clone().try_mul(1. - decay)?;
+        Ok(())
+    };
+    scale(&mut mamba.embedding.weight)?;
+    for layer in mamba.layers.iter_mut() {
+        let (_norm, block) = &mut layer.res.0;
+        scale(&mut block.in_proj.weight)?;
+        scale(&mut block.x_proj.weight)?;
+        scale(&mut block.dt_proj.weight)?;
+        scale(&mut block.out_proj.weight)?;
+    }
+    Ok(())
+}
+
+/// Runs the `batch` (of windows with `seq_len + 1` tokens) and backpropagates the mean
+/// next-token cross-entropy into the `grads`, returning the loss.
+fn try_loss(
+    mamba: &Mamba<f32, Cpu>,
+    batch: &[&[u32]],
+    seq_len: usize,
+    grads: Gradients<f32, Cpu>,
+) -> anyhow::Result<(f32, Gradients<f32, Cpu>)> {
+    let device = mamba.embedding.weight.device();
+    let (inputs, targets) = try_batch_tensors(device, batch, seq_len)?;
+    let logits = try_forward_tied(mamba, inputs.traced(grads))?;
+    try_backward_cross_entropy(logits, targets)
+}
+
+/// The `(batch, seq_len)` inputs and next-token targets of the `batch`,
+/// where each window has `seq_len + 1` tokens.
+#[allow(clippy::type_complexity)]
+pub(crate) fn try_batch_tensors(
+    device: &Cpu,
+    batch: &[&[u32]],
+    seq_len: usize,
+) -> anyhow::Result<(
+    VocabInput<Cpu, NoneTape>,
+    Tensor<(usize, usize), usize, Cpu>,
+)> {
+    if let Some(window) = batch.iter().find(|window| window.len() != seq_len + 1) {
+        anyhow::bail!(
+            "expected windows of {} tokens, got {}",
+            seq_len + 1,
+            window.len()
+        );
+    }
+    let (inputs, targets): (Vec<Vec<usize>>, Vec<Vec<usize>>) = batch
+        .iter()
+        .map(|window| {
+            let window: Vec<usize> = window.iter().map(|&t| t as usize).collect();
+            (window[..seq_len].to_vec(), window[1..].to_vec())
+        })
+        .unzip();
+    let shape = (batch.len(), seq_len);
+    let inputs = device.try_tensor_from_vec(inputs.concat(), shape)?;
+    let targets = device.try_tensor_from_vec(targets.concat(), shape)?;
+    Ok((inputs, targets))
+}
+
+/// Backpropagates the mean next-token cross-entropy of the `logits`, returning the loss.
+pub(crate) fn try_backward_cross_entropy(
+    logits: BlockInput<f32, Cpu, OwnedTape<f32, Cpu>>,
+    targets: Tensor<(usize, usize), usize, Cpu>,
+) -> anyhow::Result<(f32, Gradients<f32, Cpu>)> {
+    let loss = logits
+        .try_log_softmax::<Axis<2>>()?
+        .try_select(targets)?
+        .try_mean()?
+        .try_negate()?;
+    let loss_value = loss.array();
+    let grads = loss.try_backward()?;
+    Ok((loss_value, grads))
+}
+
+/// The stateless forward, except that the logits are calculated from the embedding weight
+/// instead of the `lm_head`.
+fn try_forward_tied(
+    mamba: &Mamba<f32, Cpu>,
+    x: VocabInput<Cpu, OwnedTape<f32, Cpu>>,
+) -> Result<BlockInput<f32, Cpu, OwnedTape<f32, Cpu>>, Error> {
+    let x = mamba.embedding.try_forward(x)?;
+    let x = mamba.layers.try_forward(x)?;
+    let x = mamba.norm_f.try_forward(x)?;
+    let weight = mamba.embedding.weight.clone();
+    x.try_matmul(weight.try_permute::<_, Axes2<1, 0>>()?)
+}
+
+/// Copies the embedding weight into the `lm_head`, whose bias is kept at zero.
+pub fn tie_lm_head(mamba: &mut Mamba<f32, Cpu>) {
+    mamba.lm_head.weight = mamba.embedding.weight.clone();
+}
+
+/// Saves the `mamba` parameters into a safetensors file in the Hugging Face layout
+/// (see [mamba::save::to_hf_safetensors]), so that it can be loaded with `--weights-file`.
+pub fn save_checkpoint(
+    mamba: &Mamba<f32, Cpu>,
+    path: impl AsRef<std::path::Path>,
+) -> anyhow::Result<()> {
+    mamba::save::save_hf_safetensors(mamba, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{source, test_utils};
+
+    const CONFIG_JSON: &str =
+        r#"{"d_model": 16, "n_layer": 2, "vocab_size": 32, "pad_vocab_size_multiple": 8}"#;
+
+    fn schedule(warmup_steps: usize) -> TrainConfig {
+        TrainConfig {
+            steps: 10,
+            lr: 1e-3,
+            min_lr: 1e-4,
+            warmup_steps,
+            ..Default::default()
+        }
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() <= 1e-12, "expected {b}, got {a}");
+    }
+
+    fn logits(mamba: &Mamba<f32, Cpu>, tokens: &[u32]) -> Vec<f32> {
+        let dev = mamba.embedding.weight.device().clone();
+        let x: VocabInput<Cpu, NoneTape> = dev.tensor_from_vec(
+            tokens.iter().map(|&t| t as usize).collect(),
+            (1, tokens.len()),
+        );
+        mamba.try_forward(x).unwrap().as_vec()
+    }
+
+    #[test]
+    fn the_lr_warms_up_and_decays_to_min_lr() {
+        let config = schedule(3);
+        assert_close(config.lr_at(0), config.lr / 3.);
+        assert_close(config.lr_at(2), config.lr);
+        for step in 3..9 {
+            assert!(config.lr_at(step + 1) < config.lr_at(step));
+        }
+        assert_close(config.lr_at(9), config.min_lr);
+        assert_close(config.lr_at(100), config.min_lr);
+
+        let config = schedule(0);
+        assert_close(config.lr_at(0), config.lr);
+        assert_close(config.lr_at(9), config.min_lr);
+    }
+
+    #[test]
+    fn packed_windows_overlap_by_one_token() {
+        let models = test_utils::models(0);
+        let documents = ["the cat sat on the mat", "a b c d e f g"].map(anyhow::Ok);
+        let windows = models.pack_corpus(documents, 4).unwrap();
+
+        // Both documents start with an eos, for 15 tokens in total.
+        assert_eq!(windows.len(), 3);
+        assert!(windows.iter().all(|window| window.len() == 5));
+        for pair in windows.windows(2) {
+            assert_eq!(pair[0][4], pair[1][0]);
+        }
+        let (tokens, eos) = models.encode_prompt("the cat sat on the mat").unwrap();
+        assert_eq!(windows[0][0], eos);
+        assert_eq!(windows[0][1..], tokens[..4]);
+        assert_eq!(windows[1][3], eos);
+    }
+
+    #[test]
+    fn every_epoch_reshuffles_all_windows() {
+        let windows: Vec<Vec<u32>> = (0..8).map(|i| vec![i; 3]).collect();
+        assert!(Batches::new(windows.clone(), 9, 0).is_err());
+        let mut batches = Batches::new(windows, 2, 0).unwrap();
+        let mut orders = vec![];
+        for epoch in 0..3 {
+            let mut order = vec![];
+            for _ in 0..4 {
+                order.extend(batches.next_batch().iter().map(|window| window[0]));
+            }
+            assert_eq!(batches.epoch, epoch);
+            let mut sorted = order.clone();
+            sorted.sort();
+            assert_eq!(sorted, (0..8).collect::<Vec<u32>>());
+            orders.push(order);
+        }
+        assert!(orders[0] != orders[1] || orders[1] != orders[2]);
+    }
+
+    #[test]
+    fn checkpoints_load_back() {
+        let mut mamba = test_utils::mamba(0);
+        tie_lm_head(&mut mamba);
+        mamba.lm_head.bias.fill_with_zeros();
+        let path =
+            std::env::temp_dir().join(format!("checkpoint-{}.safetensors", std::process::id()));
+        save_checkpoint(&mamba, &path).unwrap();
+        let weights = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = source::load_mamba::<f32>(CONFIG_JSON, &weights).unwrap();
+        let tokens = [3, 1, 4, 1, 5, 9];
+        assert_eq!(logits(&mamba, &tokens), logits(&loaded, &tokens));
+    }
+
+    #[test]
+    fn train_step_lowers_the_loss() {
+        let mut mamba = test_utils::mamba(0);
+        tie_lm_head(&mut mamba);
+        let config = TrainConfig {
+            seq_len: 8,
+            batch_size: 1,
+            steps: 20,
+            lr: 1e-2,
+            min_lr: 1e-2,
+            warmup_steps: 0,
+            weight_decay: 0.,
+            ..Default::default()
+        };
+        let mut trainer = Trainer::new(&mamba, config);
+        let window: Vec<u32> = (0..9).map(|t| t * 7 % 32).collect();
+        let first = trainer.train_step(&mut mamba, &[&window]).unwrap();
+        let mut last = first.clone();
+        for _ in 1..20 {
+            last = trainer.train_step(&mut mamba, &[&window]).unwrap();
+        }
+        assert_eq!(last.step, 19);
+        assert!(first.grad_norm > 0.);
+        assert!(last.loss < first.loss, "{first} -> {last}");
+        assert_eq!(
+            mamba.lm_head.weight.as_vec(),
+            mamba.embedding.weight.as_vec()
+        );
+    }
+
+    #[test]
+    fn only_the_projections_and_embedding_are_decayed() {
+        let mut mamba = test_utils::mamba(0);
+        tie_lm_head(&mut mamba);
+        let before = mamba.clone();
+        decay_weights(&mut mamba, 0.5).unwrap();
+
+        let halved = |a: &Tensor<(usize, usize), f32, Cpu>,
+                      b: &Tensor<(usize, usize), f32, Cpu>| {
+            let expected: Vec<f32> = a.as_vec().iter().map(|v| v * 0.5).collect();
+            assert_eq!(b.as_vec(), expected);
+        };
+        halved(&before.embedding.weight, &mamba.embedding.weight);
+        for (before, after) in before.layers.iter().zip(mamba.layers.iter()) {
+            let ((norm0, block0), (norm1, block1)) = (&before.res.0, &after.res.0);
+            halved(&block0.in_proj.weight, &block1.in_proj.weight);
+            halved(&block0.x_proj.weight, &block1.x_proj.weight);
+            halved(&block0.dt_proj.weight, &block1.dt_proj.weight);
+            halved(&block0.out_proj.weight, &block1.out_proj.weight);
+            assert_eq!(norm0.gamma.as_vec(), norm1.gamma.as_vec());
+            assert_eq!(block0.dt_proj.bias.as_vec(), block1.dt_proj.bias.as_vec());
+            assert_eq!(block0.conv1d.weight.as_vec(), block1.conv1d.weight.as_vec());
+            assert_eq!(block0.a_log.as_vec(), block1.a_log.as_vec());
+            assert_eq!(block0.d.as_vec(), block1.d.as_vec());
+        }
+        assert_eq!(before.norm_f.gamma.as_vec(), mamba.norm_f.gamma.as_vec());
+        assert_eq!(
+            before.lm_head.weight.as_vec(),
+            mamba.lm_head.weight.as_vec()
+        );
+    }
+}
@@ -0,0 +1,1010 @@
+//! The command-line interface of the native binary.
+
+use super::repl;
+use crate::generation::{FinishReason, Mode};
+use crate::mamba::prefill::MixedDevice;
+use crate::precision::{bf16, f16, MambaDtype};
+use crate::quant::{self, QMamba, QuantFormat};
+use crate::registry::{self, KnownModel};
+use crate::source::{HfHubSource, ModelFiles, ModelSource};
+use crate::{eval, hf, lora, train, GenerationConfig, GenerationEvent, LogitsProcessorWrapper};
+use crate::{logits::TokenLogprobs, MambaModel, MambaWrapper};
+use clap::builder::PossibleValuesParser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use dfdx::tensor::Cpu;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(about = "Mamba text generation with dfdx")]
+pub struct Cli {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Defaults to `generate` with the "Mamba is the" prompt.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Generates text from a prompt.
+    Generate(GenerateArgs),
+    /// Reports the nll, perplexity and bits-per-byte over a text or .jsonl corpus.
+    Eval(EvalArgs),
+    /// Starts an interactive session that keeps the states across turns.
+    Repl(ReplArgs),
+    /// Serves an OpenAI-compatible completions API over HTTP.
+    #[cfg(feature = "server")]
+    Serve(ServeArgs),
+    /// Lists the known checkpoints, for `--model`.
+    Models,
+    /// Quantizes the f32 weights into `--quant` (int8 by default), reporting the perplexity delta.
+    Quantize(QuantizeArgs),
+    /// Fine-tunes the f32 model (or only a LoRA adapter, with `--lora-rank`) over a text or
+    /// .jsonl corpus, saving safetensors checkpoints.
+    Train(TrainArgs),
+    /// Merges the `--lora` adapter into the f32 weights, saving a checkpoint.
+    MergeLora(MergeLoraArgs),
+    /// Writes the weights with the Hugging Face names and shapes, for the reference
+    /// implementations. Quantized weights are dequantized, and a `--lora` adapter is merged.
+    Export(ExportArgs),
+}
+
+/// Where the model and tokenizer files come from.
+///
+/// Local files take precedence over the Hugging Face repositories,
+/// and `--offline` only reads the Hugging Face files from the local cache.
+#[derive(Debug, Clone, Args)]
+pub struct ModelArgs {
+    /// The Hugging Face repository of the tokenizer.
+    #[arg(long, global = true, default_value = hf::tokenizer::REPO_ID)]
+    pub tokenizer_repo: String,
+    /// A local `tokenizer.json`.
+    #[arg(long, global = true)]
+    pub tokenizer_file: Option<PathBuf>,
+    /// One of the known checkpoints (see the `models` command).
+    #[arg(
+        long,
+        global = true,
+        default_value = registry::MAMBA_130M.name,
+        value_parser = PossibleValuesParser::new(registry::KNOWN_MODELS.iter().map(|known| known.name)),
+    )]
+    pub model: String,
+    /// The Hugging Face repository of the model. Defaults to the one from `--model`.
+    #[arg(long, global = true)]
+    pub model_repo: Option<String>,
+    /// The revision of the model repository. Defaults to the one from `--model`.
+    #[arg(long, global = true)]
+    pub model_revision: Option<String>,
+    /// A local directory with the `tokenizer.json`, `config.json` and `model.safetensors` files.
+    ///
+    /// The files are never downloaded. The `--*-file` flags still take precedence.
+    #[arg(long, global = true)]
+    pub model_dir: Option<PathBuf>,
+    /// Never touches the network, only reading the files from the hf-hub cache.
+    #[arg(long, global = true)]
+    pub offline: bool,
+    /// The dtype of the weights. The activations and the states are always f32.
+    #[arg(long, global = true, value_enum, default_value_t = CliDtype::F32)]
+    pub dtype: CliDtype,
+    /// Quantizes the big matrices of the f32 weights, or loads already quantized weights
+    /// (from the `quantize` command) in their own format.
+    #[arg(long, global = true, value_enum, conflicts_with = "dtype")]
+    pub quant: Option<CliQuant>,
+    /// How many consecutive inputs share a scale, for `--quant int4`.
+    #[arg(long, global = true, default_value_t = QuantFormat::DEFAULT_GROUP_SIZE)]
+    pub group_size: usize,
+    /// A LoRA adapter (from `train --lora-rank`) to run on top of the model.
+    #[arg(long, global = true, conflicts_with = "quant")]
+    pub lora: Option<PathBuf>,
+    /// A local model `config.json`.
+    #[arg(long, global = true)]
+    pub config_file: Option<PathBuf>,
+    /// A local model `model.safetensors`.
+    #[arg(long, global = true)]
+    pub weights_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct GenerateArgs {
+    /// The prompt. If missing, it's read from `--prompt-file` or from stdin (if not a terminal).
+    pub prompt: Option<String>,
+    /// Reads the prompt from a file, or from stdin if `-`.
+    #[arg(long, conflicts_with = "prompt")]
+    pub prompt_file: Option<PathBuf>,
+    /// The maximum amount of steps, counting both the prompt and the generated tokens.
+    #[arg(long, default_value_t = 5000)]
+    pub sample_len: usize,
+    /// The maximum amount of generated tokens, not counting the prompt.
+    #[arg(long)]
+    pub max_new_tokens: Option<usize>,
+    #[arg(long, default_value_t = 299792458)]
+    pub seed: u64,
+    /// If missing, the most likely token is always chosen.
+    #[arg(long)]
+    pub temperature: Option<f64>,
+    #[arg(long)]
+    pub top_p: Option<f64>,
+    #[arg(long, default_value_t = 1.1)]
+    pub repeat_penalty: f32,
+    /// How many of the last tokens are considered for the repeat penalty.
+    #[arg(long, default_value_t = 1024)]
+    pub repeat_last_n: usize,
+    #[arg(long, value_enum, default_value_t = CliMode::Stateful)]
+    pub mode: CliMode,
+    /// Stops the generation once this string gets generated. Can be repeated.
+    #[arg(long)]
+    pub stop: Vec<String>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+    pub format: OutputFormat,
+    /// For the jsonl format, how many top alternatives to include for each token.
+    #[arg(long)]
+    pub top_logprobs: Option<usize>,
+}
+
+impl Default for GenerateArgs {
+    fn default() -> Self {
+        #[derive(Parser)]
+        struct Wrapper {
+            #[command(flatten)]
+            args: GenerateArgs,
+        }
+        Wrapper::parse_from(["generate"]).args
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct EvalArgs {
+    /// A text file (a single document) or a .jsonl file (a document per line).
+    pub corpus: PathBuf,
+    #[arg(long, value_enum, default_value_t = CliEvalMode::Windowed)]
+    pub mode: CliEvalMode,
+    /// How many tokens are fed in each call, for the windowed mode.
+    #[arg(long, default_value_t = 128)]
+    pub window: usize,
+    /// Don't prepend the eos token to each document (the first token is then not scored).
+    #[arg(long)]
+    pub no_prepend_eos: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ReplArgs {
+    #[arg(long, default_value_t = 299792458)]
+    pub seed: u64,
+    /// If missing, the most likely token is always chosen.
+    #[arg(long)]
+    pub temperature: Option<f64>,
+    #[arg(long)]
+    pub top_p: Option<f64>,
+    #[arg(long, default_value_t = 1.1)]
+    pub repeat_penalty: f32,
+    /// How many of the last tokens are considered for the repeat penalty.
+    #[arg(long, default_value_t = 1024)]
+    pub repeat_last_n: usize,
+    /// The maximum amount of generated tokens for each turn.
+    #[arg(long, default_value_t = 256)]
+    pub max_new_tokens: usize,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct QuantizeArgs {
+    /// Where to write the quantized safetensors, loadable with `--weights-file` and `--quant`.
+    #[arg(long)]
+    pub out: Option<PathBuf>,
+    /// A text file or a .jsonl file, to compare the perplexity against the f32 model.
+    #[arg(long)]
+    pub corpus: Option<PathBuf>,
+    /// How many tokens are fed in each call, for the comparison.
+    #[arg(long, default_value_t = 128)]
+    pub window: usize,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct TrainArgs {
+    /// A text file (a single document) or a .jsonl file (a document per line).
+    pub corpus: PathBuf,
+    /// Where to write the checkpoint, loadable with `--weights-file`.
+    #[arg(long)]
+    pub out: PathBuf,
+    /// Also writes the checkpoint every this many steps.
+    #[arg(long)]
+    pub save_every: Option<usize>,
+    /// Prints the loss every this many steps.
+    #[arg(long, default_value_t = 10)]
+    pub log_every: usize,
+    /// How many tokens each window feeds into the model.
+    #[arg(long, default_value_t = 128)]
+    pub seq_len: usize,
+    #[arg(long, default_value_t = 4)]
+    pub batch_size: usize,
+    #[arg(long, default_value_t = 1000)]
+    pub steps: usize,
+    /// The peak learning rate, reached at the end of the warmup.
+    #[arg(long, default_value_t = 1e-4)]
+    pub lr: f64,
+    /// The learning rate at the end of the cosine decay.
+    #[arg(long, default_value_t = 1e-5)]
+    pub min_lr: f64,
+    #[arg(long, default_value_t = 100)]
+    pub warmup_steps: usize,
+    #[arg(long, default_value_t = 0.1)]
+    pub weight_decay: f64,
+    /// The gradients are scaled down if their global norm is larger. `0` disables the clipping.
+    #[arg(long, default_value_t = 1.)]
+    pub max_grad_norm: f64,
+    /// The seed for shuffling the windows (and for initializing a new LoRA adapter).
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+    /// Only trains a LoRA adapter of this rank, which gets saved into `--out`.
+    ///
+    /// If `--lora` is given, that adapter is trained further instead.
+    #[arg(long)]
+    pub lora_rank: Option<usize>,
+    /// The LoRA adapters are scaled by `alpha / rank`.
+    #[arg(long, default_value_t = 16.)]
+    pub lora_alpha: f32,
+    /// The projections that get a LoRA adapter.
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [CliLoraTarget::InProj, CliLoraTarget::XProj, CliLoraTarget::OutProj])]
+    pub lora_targets: Vec<CliLoraTarget>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct MergeLoraArgs {
+    /// Where to write the checkpoint, loadable with `--weights-file`.
+    #[arg(long)]
+    pub out: PathBuf,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ExportArgs {
+    /// Where to write the safetensors. The dimensions are kept, so the original `config.json` applies.
+    #[arg(long)]
+    pub out: PathBuf,
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Args)]
+pub struct ServeArgs {
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub addr: String,
+    /// The model id reported by `/v1/models`. Defaults to the model repository.
+    #[arg(long)]
+    pub model_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CliMode {
+    Stateful,
+    Stateless,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CliEvalMode {
+    Windowed,
+    Stateful,
+    Stateless,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Prints the text as it gets generated.
+    Plain,
+    /// Prints a json object per line, for each token and for the finish.
+    Jsonl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CliDtype {
+    F32,
+    /// Half the memory of f32.
+    F16,
+    /// Half the memory of f32, with the range of f32 but less precision than f16.
+    Bf16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CliQuant {
+    /// A quarter of the memory of f32, with a scale per output channel.
+    Int8,
+    /// An eighth of the memory of f32, with a scale per `--group-size` inputs.
+    Int4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CliLoraTarget {
+    InProj,
+    XProj,
+    OutProj,
+}
+
+/// Loads the tokenizer and the model.
+type LoadModels<M> = fn(&ModelArgs) -> anyhow::Result<MambaWrapper<M>>;
+
+impl Cli {
+    pub fn run(self) -> anyhow::Result<()> {
+        match (self.model.quant, self.model.dtype, &self.model.lora) {
+            (Some(_), _, _) => self.run_with(load_quantized_models),
+            (None, CliDtype::F32, Some(_)) => self.run_with(load_lora_models::<f32>),
+            (None, CliDtype::F16, Some(_)) => self.run_with(load_lora_models::<f16>),
+            (None, CliDtype::Bf16, Some(_)) => self.run_with(load_lora_models::<bf16>),
+            (None, CliDtype::F32, None) => self.run_with(load_models::<f32>),
+            (None, CliDtype::F16, None) => self.run_with(load_models::<f16>),
+            (None, CliDtype::Bf16, None) => self.run_with(load_models::<bf16>),
+        }
+    }
+
+    fn run_with<M: MambaModel>(self, load: LoadModels<M>) -> anyhow::Result<()> {
+        match self.command {
+            None => {
+                let args = GenerateArgs {
+                    prompt: Some("Mamba is the".into()),
+                    ..Default::default()
+                };
+                run_generate(&self.model, args, load)
+            }
+            Some(Command::Generate(args)) => run_generate(&self.model, args, load),
+            Some(Command::Eval(args)) => run_eval(&self.model, args, load),
+            Some(Command::Repl(args)) => run_repl(&self.model, args, load),
+            #[cfg(feature = "server")]
+            Some(Command::Serve(args)) => run_serve(&self.model, args, load),
+            Some(Command::Models) => {
+                for known in registry::KNOWN_MODELS {
+                    println!("{known}");
+                }
+                Ok(())
+            }
+            Some(Command::Quantize(args)) => run_quantize(&self.model, args),
+            Some(Command::Train(args)) => run_train(&self.model, args),
+            Some(Command::MergeLora(args)) => run_merge_lora(&self.model, args),
+            Some(Command::Export(args)) => run_export(&self.model, args, load),
+        }
+    }
+}
+
+fn run_generate<M: MambaModel>(
+    model: &ModelArgs,
+    args: GenerateArgs,
+    load: LoadModels<M>,
+) -> anyhow::Result<()> {
+    use std::io::{IsTerminal, Read, Write};
+
+    let prompt = match (&args.prompt, &args.prompt_file) {
+        (Some(prompt), _) => prompt.clone(),
+        (None, Some(path)) if path.as_os_str() == "-" => {
+            let mut prompt = String::new();
+            std::io::stdin().read_to_string(&mut prompt)?;
+            prompt
+        }
+        (None, Some(path)) => std::fs::read_to_string(path)?,
+        (None, None) if !std::io::stdin().is_terminal() => {
+            let mut prompt = String::new();
+            std::io::stdin().read_to_string(&mut prompt)?;
+            prompt
+        }
+        (None, None) => {
+            anyhow::bail!("missing the prompt (as an argument, --prompt-file or stdin)")
+        }
+    };
+
+    let mut models = load(model)?;
+    let mut processor = LogitsProcessorWrapper::new(
+        args.seed,
+        args.temperature,
+        args.top_p,
+        args.repeat_penalty,
+        args.repeat_last_n,
+    )?;
+    let config = GenerationConfig {
+        mode: match args.mode {
+            CliMode::Stateful => Mode::Stateful,
+            CliMode::Stateless => Mode::Stateless,
+        },
+        sample_len: args.sample_len,
+        max_new_tokens: args.max_new_tokens,
+        stop: args.stop.clone(),
+        top_logprobs: match args.format {
+            OutputFormat::Plain => None,
+            OutputFormat::Jsonl => Some(args.top_logprobs.unwrap_or(0)),
+        },
+        ..Default::default()
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    for event in models.generate(&prompt, config, &mut processor)? {
+        let event = event?;
+        match args.format {
+            OutputFormat::Plain => {
+                let text = match event {
+                    GenerationEvent::Token(token) => token.text,
+                    GenerationEvent::Finish { text, .. } => text,
+                };
+                if let Some(t) = text {
+                    write!(stdout, "{t}")?;
+                    stdout.flush()?;
+                }
+            }
+            OutputFormat::Jsonl => writeln!(stdout, "{}", event_json(&event))?,
+        }
+    }
+    if args.format == OutputFormat::Plain {
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+/// A json representation of the `event`, for the jsonl output.
+pub fn event_json(event: &GenerationEvent) -> serde_json::Value {
+    use serde_json::json;
+    match event {
+        GenerationEvent::Token(token) => json!({
+            "type": "token",
+            "id": token.id,
+            "text": token.text,
+            "is_prompt": token.is_prompt,
+            "logit": token.logit,
+            "prob": token.prob,
+            "logprobs": token.logprobs.as_ref().map(logprobs_json),
+        }),
+        GenerationEvent::Finish { reason, text } => json!({
+            "type": "finish",
+            "reason": match reason {
+                FinishReason::Eos => "eos",
+                FinishReason::Length => "length",
+                FinishReason::Stop(_) => "stop",
+                FinishReason::Deadline => "deadline",
+                FinishReason::Error(_) => "error",
+            },
+            "stop": match reason {
+                FinishReason::Stop(stop) => Some(stop),
+                _ => None,
+            },
+            "text": text,
+        }),
+    }
+}
+
+fn logprobs_json(logprobs: &TokenLogprobs) -> serde_json::Value {
+    use serde_json::json;
+    json!({
+        "logprob": logprobs.logprob,
+        "rank": logprobs.rank,
+        "top": logprobs.top.iter().map(|top| json!({
+            "id": top.id,
+            "text": top.text,
+            "logprob": top.logprob,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+fn run_eval<M: MambaModel>(
+    model: &ModelArgs,
+    args: EvalArgs,
+    load: LoadModels<M>,
+) -> anyhow::Result<()> {
+    let config = eval::EvalConfig {
+        mode: match args.mode {
+            CliEvalMode::Windowed => eval::EvalMode::Windowed,
+            CliEvalMode::Stateful => eval::EvalMode::Stateful,
+            CliEvalMode::Stateless => eval::EvalMode::Stateless,
+        },
+        window: args.window,
+        prepend_eos: !args.no_prepend_eos,
+    };
+    let models = load(model)?;
+    let start = std::time::Instant::now();
+    let report = models.eval_corpus(eval::read_corpus(&args.corpus)?, &config)?;
+    println!(
+        "evaluated {:?} ({:?}) in {:?}",
+        args.corpus,
+        config.mode,
+        start.elapsed()
+    );
+    println!("{report}");
+    Ok(())
+}
+
+fn run_repl<M: MambaModel>(
+    model: &ModelArgs,
+    args: ReplArgs,
+    load: LoadModels<M>,
+) -> anyhow::Result<()> {
+    let settings = repl::ReplSettings {
+        seed: args.seed,
+        temperature: args.temperature,
+        top_p: args.top_p,
+        repeat_penalty: args.repeat_penalty,
+        repeat_last_n: args.repeat_last_n,
+        max_new_tokens: args.max_new_tokens,
+    };
+    let models = load(model)?;
+    repl::Repl::new(models, settings)?.run()
+}
+
+#[cfg(feature = "server")]
+fn run_serve<M: MambaModel>(
+    model: &ModelArgs,
+    args: ServeArgs,
+    load: LoadModels<M>,
+) -> anyhow::Result<()> {
+    let models = load(model)?;
+    let mut server = super::server::Server {
+        models,
+        model_id: args
+            .model_id
+            .unwrap_or_else(|| model.model_repo().to_string()),
+    };
+    server.serve(&args.addr)
+}
+
+fn run_quantize(model: &ModelArgs, args: QuantizeArgs) -> anyhow::Result<()> {
+    let format = model.quant_format().unwrap_or(QuantFormat::Int8);
+    let reference = load_models::<f32>(model)?;
+
+    let start = std::time::Instant::now();
+    let quantized = QMamba::quantize(&reference.mamba, format)?;
+    eprintln!("quantized into {format} in {:?}", start.elapsed());
+    if let Some(out) = &args.out {
+        std::fs::write(out, quantized.to_safetensors()?)?;
+        eprintln!("saved the quantized weights into {out:?}");
+    }
+
+    let Some(corpus) = &args.corpus else {
+        return Ok(());
+    };
+    let config = eval::EvalConfig {
+        window: args.window,
+        ..Default::default()
+    };
+    let start = std::time::Instant::now();
+    let reference_report = reference.eval_corpus(eval::read_corpus(corpus)?, &config)?;
+    let tokenizer = reference.tokenizer.into_inner();
+    let quantized = MambaWrapper::new(tokenizer, quantized);
+    let quantized_report = quantized.eval_corpus(eval::read_corpus(corpus)?, &config)?;
+    println!("evaluated {corpus:?} in {:?}", start.elapsed());
+    let report = quant::AccuracyReport {
+        format,
+        reference: reference_report,
+        quantized: quantized_report,
+        reference_memory: quantized.mamba.dense_memory(),
+        quantized_memory: quantized.mamba.memory(),
+    };
+    println!("{report}");
+    Ok(())
+}
+
+fn run_train(model: &ModelArgs, args: TrainArgs) -> anyhow::Result<()> {
+    // the gradients and the optimizer only work on the dense f32 weights
+    anyhow::ensure!(
+        model.quant.is_none(),
+        "train doesn't support --quant, as the quantized weights can't be trained"
+    );
+    anyhow::ensure!(
+        model.dtype == CliDtype::F32,
+        "train only supports --dtype f32"
+    );
+    let config = train::TrainConfig {
+        seq_len: args.seq_len,
+        batch_size: args.batch_size,
+        steps: args.steps,
+        lr: args.lr,
+        min_lr: args.min_lr,
+        warmup_steps: args.warmup_steps,
+        weight_decay: args.weight_decay,
+        max_grad_norm: (args.max_grad_norm > 0.).then_some(args.max_grad_norm),
+        seed: args.seed,
+        ..Default::default()
+    };
+    let mut models = load_models::<f32>(model)?;
+
+    let start = std::time::Instant::now();
+    let windows = models.pack_corpus(eval::read_corpus(&args.corpus)?, config.seq_len)?;
+    let mut batches = train::Batches::new(windows, config.batch_size, config.seed)?;
+    eprintln!(
+        "packed {:?} into {} windows of {} tokens in {:?}",
+        args.corpus,
+        batches.windows(),
+        config.seq_len,
+        start.elapsed()
+    );
+
+    let start = std::time::Instant::now();
+    let steps = match (args.lora_rank, &model.lora) {
+        (None, None) => {
+            let mut trainer = train::Trainer::new(&models.mamba, config.clone());
+            train_loop(&args, &config, &mut batches, |batch, save| {
+                let report = trainer.train_step(&mut models.mamba, batch)?;
+                if save {
+                    train::save_checkpoint(&models.mamba, &args.out)?;
+                }
+                Ok(report)
+            })?
+        }
+        (rank, path) => {
+            let mut adapter = match path {
+                Some(path) => lora::LoraAdapter::from_safetensors(
+                    &std::fs::read(path)?,
+                    &models.mamba.device(),
+                )?,
+                None => {
+                    let lora_config = lora::LoraConfig {
+                        rank: rank.unwrap_or(lora::LoraConfig::default().rank),
+                        alpha: args.lora_alpha,
+                        targets: args
+                            .lora_targets
+                            .iter()
+                            .map(|target| match target {
+                                CliLoraTarget::InProj => lora::LoraTarget::InProj,
+                                CliLoraTarget::XProj => lora::LoraTarget::XProj,
+                                CliLoraTarget::OutProj => lora::LoraTarget::OutProj,
+                            })
+                            .collect(),
+                    };
+                    lora::LoraAdapter::new(&models.mamba, lora_config, args.seed)?
+                }
+            };
+            adapter.check(&models.mamba)?;
+            eprintln!(
+                "training a lora adapter of {} parameters",
+                adapter.num_params()
+            );
+            let mut trainer = lora::LoraTrainer::new(&adapter, config.clone());
+            train_loop(&args, &config, &mut batches, |batch, save| {
+                let report = trainer.train_step(&models.mamba, &mut adapter, batch)?;
+                if save {
+                    std::fs::write(&args.out, adapter.to_safetensors()?)?;
+                }
+                Ok(report)
+            })?
+        }
+    };
+    println!(
+        "trained for {steps} steps in {:?}, saved into {:?}",
+        start.elapsed(),
+        args.out
+    );
+    Ok(())
+}
+
+/// Runs the `step` for each batch, logging the reports and asking it to save the checkpoint
+/// every `--save-every` steps and at the end. Returns how many steps were made.
+fn train_loop(
+    args: &TrainArgs,
+    config: &train::TrainConfig,
+    batches: &mut train::Batches,
+    mut step: impl FnMut(&[&[u32]], bool) -> anyhow::Result<train::StepReport>,
+) -> anyhow::Result<usize> {
+    let start = std::time::Instant::now();
+    for steps in 1..=config.steps {
+        let save = steps == config.steps
+            || args
+                .save_every
+                .is_some_and(|every| every != 0 && steps % every == 0);
+        let report = step(&batches.next_batch(), save)?;
+        if args.log_every != 0 && (steps % args.log_every == 0 || steps == config.steps) {
+            let tokens = steps * config.batch_size * config.seq_len;
+            eprintln!(
+                "{report}, epoch: {}, tokens/s: {:.1}",
+                batches.epoch,
+                tokens as f64 / start.elapsed().as_secs_f64()
+            );
+        }
+        if save {
+            eprintln!("saved the checkpoint into {:?}", args.out);
+        }
+    }
+    Ok(config.steps)
+}
+
+fn run_merge_lora(model: &ModelArgs, args: MergeLoraArgs) -> anyhow::Result<()> {
+    let Some(path) = &model.lora else {
+        anyhow::bail!("missing --lora");
+    };
+    let mut models = load_models::<f32>(model)?;
+    let adapter =
+        lora::LoraAdapter::from_safetensors(&std::fs::read(path)?, &models.mamba.device())?;
+    adapter.merge_into(&mut models.mamba)?;
+    train::save_checkpoint(&models.mamba, &args.out)?;
+    println!("merged {path:?} and saved into {:?}", args.out);
+    Ok(())
+}
+
+fn run_export<M: MambaModel>(
+    model: &ModelArgs,
+    args: ExportArgs,
+    load: LoadModels<M>,
+) -> anyhow::Result<()> {
+    let models = load(model)?;
+    std::fs::write(&args.out, models.mamba.to_hf_safetensors()?)?;
+    println!(
+        "exported the model ({}) into {:?}",
+        models.mamba.weights_format(),
+        args.out
+    );
+    Ok(())
+}
+
+/// Loads the tokenizer and the model, downloading the files that are not local (unless offline).
+pub fn load_models<E: MambaDtype>(
+    args: &ModelArgs,
+) -> anyhow::Result<MambaWrapper<crate::mamba::Mamba<E, Cpu>>>
+where
+    Cpu: MixedDevice<E, f32>,
+{
+    let start = std::time::Instant::now();
+    let source = args.source()?;
+    eprintln!("model source: {source:?}");
+    eprintln!("retrieved the files in {:?}", start.elapsed());
+
+    let start = std::time::Instant::now();
+    eprintln!("started loading the model ({})", E::NAME);
+    let models = source.load::<E>()?;
+    eprintln!(
+        "loaded the model in {:?} (peak rss: {})",
+        start.elapsed(),
+        peak_rss_display()
+    );
+    if args.is_known() {
+        args.known().check_mamba(&models.mamba)?;
+    }
+    Ok(models)
+}
+
+/// Loads the tokenizer and the model, with the `--lora` adapter on top of it.
+pub fn load_lora_models<E: MambaDtype>(
+    args: &ModelArgs,
+) -> anyhow::Result<MambaWrapper<lora::LoraMamba<E>>>
+where
+    Cpu: MixedDevice<E, f32>,
+{
+    let path = args
+        .lora
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("missing --lora"))?;
+    let models = load_models::<E>(args)?;
+    let adapter =
+        lora::LoraAdapter::from_safetensors(&std::fs::read(path)?, &models.mamba.device())?;
+    let mamba = lora::LoraMamba::new(models.mamba, Some(adapter))?;
+    eprintln!("loaded the adapter {path:?} ({})", mamba.weights_format());
+    Ok(MambaWrapper::new(models.tokenizer.into_inner(), mamba))
+}
+
+/// Loads the tokenizer and the quantized model (see [ModelSource::load_quantized]).
+pub fn load_quantized_models(args: &ModelArgs) -> anyhow::Result<MambaWrapper<QMamba>> {
+    let format = args
+        .quant_format()
+        .ok_or_else(|| anyhow::anyhow!("missing --quant"))?;
+    let start = std::time::Instant::now();
+    let source = args.source()?;
+    eprintln!("model source: {source:?}");
+    eprintln!("retrieved the files in {:?}", start.elapsed());
+
+    let start = std::time::Instant::now();
+    eprintln!("started loading the model ({format})");
+    let models = source.load_quantized(format)?;
+    eprintln!(
+        "loaded the model ({}, {:.1}MB) in {:?} (peak rss: {})",
+        models.mamba.weights_format(),
+        models.mamba.memory() as f64 / 1e6,
+        start.elapsed(),
+        peak_rss_display()
+    );
+    Ok(models)
+}
+
+/// The peak resident set size of the process so far, in bytes.
+///
+/// Only available on Linux, where it's read from `VmHWM` of `/proc/self/status`.
+pub fn peak_rss() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb: usize = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+fn peak_rss_display() -> String {
+    match peak_rss() {
+        Some(bytes) => format!("{:.1}MB", bytes as f64 / 1e6),
+        None => "unknown".into(),
+    }
+}
+
+impl ModelArgs {
+    /// The quantization format from `--quant` and `--group-size`.
+    pub fn quant_format(&self) -> Option<QuantFormat> {
+        self.quant.map(|quant| match quant {
+            CliQuant::Int8 => QuantFormat::Int8,
+            CliQuant::Int4 => QuantFormat::Int4 {
+                group_size: self.group_size,
+            },
+        })
+    }
+
+    /// The checkpoint selected by `--model`.
+    pub fn known(&self) -> &'static KnownModel {
+        KnownModel::find(&self.model).expect("validated by clap")
+    }
+
+    /// Whether the model files are expected to come from the known checkpoint.
+    pub fn is_known(&self) -> bool {
+        self.model_repo.is_none()
+            && self.model_revision.is_none()
+            && self.model_dir.is_none()
+            && self.config_file.is_none()
+            && self.weights_file.is_none()
+    }
+
+    pub fn model_repo(&self) -> &str {
+        self.model_repo.as_deref().unwrap_or(self.known().repo_id)
+    }
+
+    pub fn model_revision(&self) -> &str {
+        self.model_revision
+            .as_deref()
+            .unwrap_or(self.known().revision)
+    }
+
+    /// Where to load from, with the local files taking precedence over the hf-hub files.
+    ///
+    /// Only the hf-hub files that are not local get retrieved.
+    pub fn source(&self) -> anyhow::Result<ModelSource> {
+        let hub = HfHubSource {
+            tokenizer_repo: self.tokenizer_repo.clone(),
+            model_repo: self.model_repo().to_string(),
+            model_revision: self.model_revision().to_string(),
+            offline: self.offline,
+        };
+        let local = self.model_dir.as_ref().map(ModelFiles::in_dir);
+        if local.is_none()
+            && self.tokenizer_file.is_none()
+            && self.config_file.is_none()
+            && self.weights_file.is_none()
+        {
+            return Ok(ModelSource::HfHub(hub));
+        }
+        let pick = |file: &Option<PathBuf>,
+                    local: Option<&PathBuf>,
+                    hub_file: &dyn Fn() -> anyhow::Result<PathBuf>| {
+            match (file, local) {
+                (Some(file), _) => Ok(file.clone()),
+                (None, Some(local)) => Ok(local.clone()),
+                (None, None) => hub_file(),
+            }
+        };
+        let files = ModelFiles {
+            tokenizer: pick(
+                &self.tokenizer_file,
+                local.as_ref().map(|l| &l.tokenizer),
+                &|| hub.tokenizer_file(),
+            )?,
+            config: pick(
+                &self.config_file,
+                local.as_ref().map(|l| &l.config),
+                &|| hub.config_file(),
+            )?,
+            weights: pick(
+                &self.weights_file,
+                local.as_ref().map(|l| &l.weights),
+                &|| hub.weights_file(),
+            )?,
+        };
+        Ok(ModelSource::Files(files))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("mamba").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn the_subcommands_are_parsed() {
+        let cli = parse(&[]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.model.model, registry::MAMBA_130M.name);
+        assert_eq!(cli.model.dtype, CliDtype::F32);
+        assert!(cli.model.is_known());
+
+        match parse(&[
+            "generate", "hi", "--stop", "a", "--stop", "b", "--dtype", "bf16",
+        ])
+        .unwrap()
+        {
+            Cli {
+                model,
+                command: Some(Command::Generate(args)),
+            } => {
+                assert_eq!(model.dtype, CliDtype::Bf16);
+                assert_eq!(args.prompt.as_deref(), Some("hi"));
+                assert_eq!(args.stop, ["a", "b"]);
+            }
+            cli => panic!("unexpected {cli:?}"),
+        }
+        match parse(&["eval", "corpus.txt", "--mode", "stateful"])
+            .unwrap()
+            .command
+        {
+            Some(Command::Eval(args)) => {
+                assert_eq!(args.corpus, PathBuf::from("corpus.txt"));
+                assert_eq!(args.mode, CliEvalMode::Stateful);
+            }
+            command => panic!("unexpected {command:?}"),
+        }
+        match parse(&["repl", "--max-new-tokens", "8"]).unwrap().command {
+            Some(Command::Repl(args)) => assert_eq!(args.max_new_tokens, 8),
+            command => panic!("unexpected {command:?}"),
+        }
+        #[cfg(feature = "server")]
+        match parse(&["serve", "--addr", "0.0.0.0:80"]).unwrap().command {
+            Some(Command::Serve(args)) => assert_eq!(args.addr, "0.0.0.0:80"),
+            command => panic!("unexpected {command:?}"),
+        }
+        assert!(matches!(
+            parse(&["models"]).unwrap().command,
+            Some(Command::Models)
+        ));
+        let cli = parse(&["quantize", "--quant", "int4", "--group-size", "32"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Quantize(_))));
+        assert_eq!(
+            cli.model.quant_format(),
+            Some(QuantFormat::Int4 { group_size: 32 })
+        );
+        match parse(&[
+            "train",
+            "corpus.txt",
+            "--out",
+            "out.safetensors",
+            "--lora-rank",
+            "4",
+            "--lora-targets",
+            "in-proj,out-proj",
+        ])
+        .unwrap()
+        .command
+        {
+            Some(Command::Train(args)) => {
+                assert_eq!(args.lora_rank, Some(4));
+                assert_eq!(
+                    args.lora_targets,
+                    [CliLoraTarget::InProj, CliLoraTarget::OutProj]
+                );
+            }
+            command => panic!("unexpected {command:?}"),
+        }
+        let cli = parse(&["merge-lora", "--lora", "a.safetensors", "--out", "b"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::MergeLora(_))));
+        assert_eq!(cli.model.lora, Some(PathBuf::from("a.safetensors")));
+        let cli = parse(&["--model-dir", "model", "export", "--out", "x"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Export(_))));
+        assert!(!cli.model.is_known());
+    }
+
+    #[test]
+    fn conflicting_args_are_rejected() {
+        assert!(parse(&["--quant", "int8", "--dtype", "f16"]).is_err());
+        assert!(parse(&["--quant", "int8", "--lora", "a.safetensors"]).is_err());
+        assert!(parse(&["--model", "unknown"]).is_err());
+        assert!(parse(&["generate", "hi", "--prompt-file", "prompt.txt"]).is_err());
+        assert!(parse(&["train", "corpus.txt"]).is_err());
+    }
+
+    #[test]
+    fn train_rejects_quantized_and_half_precision_weights() {
+        for (flag, value) in [("--quant", "int8"), ("--dtype", "f16"), ("--dtype", "bf16")] {
+            let cli = parse(&["train", "corpus.txt", "--out", "x", flag, value]).unwrap();
+            let args = match cli.command {
+                Some(Command::Train(args)) => args,
+                command => panic!("unexpected {command:?}"),
+            };
+            let err = run_train(&cli.model, args).unwrap_err();
+            assert!(err.to_string().contains(flag), "{err}");
+        }
+    }
+}
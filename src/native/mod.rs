@@ -1 +1,4 @@
-// empty mod
+pub mod cli;
+pub mod repl;
+#[cfg(feature = "server")]
+pub mod server;
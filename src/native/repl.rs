@@ -0,0 +1,443 @@
+//! An interactive REPL that keeps the Mamba states across turns.
+//!
+//! Each user line is fed into the existing states, so the history is never re-processed.
+//! Lines starting with `/` are commands, see [HELP].
+
+use crate::generation::Session;
+use crate::snapshot::Snapshot;
+use crate::{mamba, GenerationConfig, GenerationEvent, LogitsProcessorWrapper};
+use crate::{MambaModel, MambaWrapper};
+use dfdx::prelude::*;
+use std::io::{BufRead, Write};
+use std::time::{Duration, Instant};
+
+pub const HELP: &str = "commands:
+    /reset              forgets the conversation
+    /save <file>        saves the conversation (safetensors, plus a .json sidecar)
+    /load <file>        loads a conversation saved by /save
+    /settings           shows the settings
+    /settings <k> <v>   changes a setting (seed, temperature, top_p, repeat_penalty, repeat_last_n, max_new_tokens)
+    /undo               restores the conversation from before the last turn
+    /stats              shows the token counts and speed
+    /help               shows this message
+    /quit               exits";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplSettings {
+    pub seed: u64,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    /// The maximum amount of generated tokens for each turn.
+    pub max_new_tokens: usize,
+}
+
+impl ReplSettings {
+    fn processor(&self) -> anyhow::Result<LogitsProcessorWrapper> {
+        LogitsProcessorWrapper::new(
+            self.seed,
+            self.temperature,
+            self.top_p,
+            self.repeat_penalty,
+            self.repeat_last_n,
+        )
+    }
+
+    /// Changes a setting, unless the new value is invalid.
+    fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        fn optional(value: &str) -> anyhow::Result<Option<f64>> {
+            match value {
+                "none" => Ok(None),
+                value => Ok(Some(value.parse()?)),
+            }
+        }
+        let mut settings = self.clone();
+        match key {
+            "seed" => settings.seed = value.parse()?,
+            "temperature" => settings.temperature = optional(value)?,
+            "top_p" => settings.top_p = optional(value)?,
+            "repeat_penalty" => settings.repeat_penalty = value.parse()?,
+            "repeat_last_n" => settings.repeat_last_n = value.parse()?,
+            "max_new_tokens" => settings.max_new_tokens = value.parse()?,
+            other => anyhow::bail!("unknown setting {other:?}"),
+        }
+        settings.processor()?;
+        *self = settings;
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for ReplSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "seed: {}", self.seed)?;
+        writeln!(f, "temperature: {:?}", self.temperature)?;
+        writeln!(f, "top_p: {:?}", self.top_p)?;
+        writeln!(f, "repeat_penalty: {}", self.repeat_penalty)?;
+        writeln!(f, "repeat_last_n: {}", self.repeat_last_n)?;
+        write!(f, "max_new_tokens: {}", self.max_new_tokens)
+    }
+}
+
+/// The conversation, which is everything that `/undo` restores.
+struct Conversation {
+    /// All tokens so far. The last token may not have been fed yet.
+    tokens: Vec<u32>,
+    /// How many of the `tokens` are contained in the `states`.
+    fed: usize,
+    states: mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+    /// How many tokens the processor has sampled.
+    samples: u64,
+}
+
+#[derive(Default)]
+struct Stats {
+    turns: usize,
+    generated: usize,
+    generating: Duration,
+    last_turn: Option<(usize, Duration)>,
+}
+
+pub struct Repl<M: MambaModel = mamba::Mamba<f32, Cpu>> {
+    pub models: MambaWrapper<M>,
+    pub settings: ReplSettings,
+    processor: LogitsProcessorWrapper,
+    conversation: Conversation,
+    undo: Option<Conversation>,
+    stats: Stats,
+}
+
+impl<M: MambaModel> Repl<M> {
+    pub fn new(models: MambaWrapper<M>, settings: ReplSettings) -> anyhow::Result<Self> {
+        let conversation = Conversation {
+            tokens: vec![],
+            fed: 0,
+            states: models.empty_states(1)?,
+            samples: 0,
+        };
+        Ok(Self {
+            processor: settings.processor()?,
+            models,
+            settings,
+            conversation,
+            undo: None,
+            stats: Stats::default(),
+        })
+    }
+
+    /// Reads lines from stdin until it ends or `/quit` is entered.
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        println!("{HELP}");
+        let stdin = std::io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("> ");
+            std::io::stdout().flush()?;
+            let Some(line) = lines.next() else {
+                break;
+            };
+            let line = line?;
+            let result = match line.trim() {
+                "/quit" | "/exit" => break,
+                command if command.starts_with('/') => self.command(command),
+                "" => Ok(()),
+                _ => self.turn(&line),
+            };
+            if let Err(err) = result {
+                println!("error: {err}");
+            }
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, command: &str) -> anyhow::Result<()> {
+        let mut parts = command.split_whitespace();
+        let name = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+        match (name, args.as_slice()) {
+            ("/help", []) => println!("{HELP}"),
+            ("/reset", []) => {
+                let conversation = Conversation {
+                    tokens: vec![],
+                    fed: 0,
+                    states: self.models.empty_states(1)?,
+                    samples: self.processor.samples(),
+                };
+                self.undo = Some(std::mem::replace(&mut self.conversation, conversation));
+                println!("the conversation was reset");
+            }
+            ("/save", [path]) => {
+                let session = self.session(GenerationConfig::default())?;
+                Snapshot::capture(&self.models, &session, &self.processor)?.save(path)?;
+                println!("saved into {path}");
+            }
+            ("/load", [path]) => {
+                let (session, processor) = Snapshot::load(path)?.restore(&mut self.models)?;
+                let conversation = Conversation {
+                    tokens: session.tokens,
+                    fed: session.fed,
+                    states: session.states,
+                    samples: processor.samples(),
+                };
+                self.check_states(&conversation)?;
+                let (seed, temperature, top_p, repeat_penalty, repeat_last_n) = processor.params();
+                self.settings = ReplSettings {
+                    seed,
+                    temperature,
+                    top_p,
+                    repeat_penalty,
+                    repeat_last_n,
+                    ..self.settings.clone()
+                };
+                self.processor = processor;
+                self.undo = Some(std::mem::replace(&mut self.conversation, conversation));
+                println!("loaded from {path}");
+            }
+            ("/settings", []) => println!("{}", self.settings),
+            ("/settings", [key, value]) => {
+                self.settings.set(key, value)?;
+                self.processor = self.settings.processor()?;
+                self.processor.skip_samples(self.conversation.samples)?;
+                println!("{key} changed");
+            }
+            ("/undo", []) => match self.undo.take() {
+                Some(conversation) => {
+                    if conversation.samples != self.processor.samples() {
+                        self.processor = self.settings.processor()?;
+                        self.processor.skip_samples(conversation.samples)?;
+                    }
+                    self.conversation = conversation;
+                    println!("the last turn was undone");
+                }
+                None => println!("there is nothing to undo"),
+            },
+            ("/stats", []) => {
+                let stats = &self.stats;
+                println!(
+                    "tokens so far: {} ({} in the states)",
+                    self.conversation.tokens.len(),
+                    self.conversation.fed
+                );
+                println!(
+                    "turns: {}, generated tokens: {}",
+                    stats.turns, stats.generated
+                );
+                if let Some((tokens, elapsed)) = stats.last_turn {
+                    println!(
+                        "last turn: {tokens} tokens in {elapsed:?} ({:.2} tokens/s)",
+                        tokens as f64 / elapsed.as_secs_f64()
+                    );
+                }
+                if stats.generated != 0 {
+                    println!(
+                        "overall: {:.2} tokens/s",
+                        stats.generated as f64 / stats.generating.as_secs_f64()
+                    );
+                }
+            }
+            _ => anyhow::bail!("unknown command or wrong arguments: {command}\n{HELP}"),
+        }
+        Ok(())
+    }
+
+    /// Feeds the `line` and prints the generated reply, until the model ends the line.
+    fn turn(&mut self, line: &str) -> anyhow::Result<()> {
+        let (mut line_tokens, eos_token) = self.models.encode_prompt(&format!("{line}\n"))?;
+        if line_tokens.is_empty() {
+            return Ok(());
+        }
+        let backup = Conversation {
+            tokens: self.conversation.tokens.clone(),
+            fed: self.conversation.fed,
+            states: clone_states(&self.models, &self.conversation.states)?,
+            samples: self.processor.samples(),
+        };
+        self.conversation.tokens.append(&mut line_tokens);
+        if let Err(err) = self.reply() {
+            // the conversation is left as it was before the turn
+            if backup.samples != self.processor.samples() {
+                self.processor = self.settings.processor()?;
+                self.processor.skip_samples(backup.samples)?;
+            }
+            self.conversation = backup;
+            return Err(err);
+        }
+        self.undo = Some(backup);
+        Ok(())
+    }
+
+    /// Generates and prints the reply to the (already appended) user line.
+    fn reply(&mut self) -> anyhow::Result<()> {
+        let config = GenerationConfig {
+            max_new_tokens: Some(self.settings.max_new_tokens),
+            stop: vec!["\n".to_string()],
+            ..Default::default()
+        };
+        let session = self.session(config)?;
+        let start = Instant::now();
+        self.models.tokenizer.clear();
+        let mut generation = self.models.resume(session, &mut self.processor);
+        let mut stdout = std::io::stdout().lock();
+        for event in generation.by_ref() {
+            let text = match event? {
+                GenerationEvent::Token(token) if !token.is_prompt => token.text,
+                GenerationEvent::Token(_) => None,
+                GenerationEvent::Finish { text, .. } => text,
+            };
+            if let Some(t) = text {
+                write!(stdout, "{t}")?;
+                stdout.flush()?;
+            }
+        }
+        writeln!(stdout)?;
+        let elapsed = start.elapsed();
+
+        // note: the last token (eg. the newline or the eos) is only fed on the next turn
+        let session = generation.session;
+        self.stats.turns += 1;
+        self.stats.generated += session.new_tokens;
+        self.stats.generating += elapsed;
+        self.stats.last_turn = Some((session.new_tokens, elapsed));
+        self.conversation = Conversation {
+            fed: session.fed,
+            tokens: session.tokens,
+            states: session.states,
+            samples: self.processor.samples(),
+        };
+        Ok(())
+    }
+
+    /// A session that continues the conversation.
+    ///
+    /// The conversation is left untouched, since the session gets a clone of the states
+    /// (which shares their storage).
+    fn session(&self, config: GenerationConfig) -> anyhow::Result<Session> {
+        let eos_token = self.models.eos_token()?;
+        let conversation = &self.conversation;
+        let mut tokens = conversation.tokens.clone();
+        if tokens.is_empty() {
+            // an empty conversation is represented by an eos token
+            tokens.push(eos_token);
+        }
+        let states = clone_states(&self.models, &conversation.states)?;
+        Session::continue_with(tokens, conversation.fed, states, eos_token, config)
+    }
+
+    fn check_states(&self, conversation: &Conversation) -> anyhow::Result<()> {
+        let batch_size = self.models.mamba.check_states(&conversation.states)?;
+        if batch_size != 1 {
+            anyhow::bail!("expected states with a batch size of 1, got {batch_size}");
+        }
+        Ok(())
+    }
+}
+
+fn clone_states<M: MambaModel>(
+    models: &MambaWrapper<M>,
+    states: &mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>,
+) -> anyhow::Result<mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>> {
+    let mut cloned = models.empty_states(models.mamba.check_states(states)?)?;
+    for (cloned, state) in cloned.iter_mut().zip(states.iter()) {
+        cloned.conv_state = state.conv_state.clone();
+        cloned.ssm_state = state.ssm_state.clone();
+    }
+    Ok(cloned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn settings() -> ReplSettings {
+        ReplSettings {
+            seed: 0,
+            temperature: None,
+            top_p: None,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+            max_new_tokens: 4,
+        }
+    }
+
+    fn state_values(states: &mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape>) -> Vec<f32> {
+        states
+            .iter()
+            .flat_map(|state| [state.conv_state.as_vec(), state.ssm_state.as_vec()])
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn settings_are_parsed_and_validated() {
+        let mut settings = settings();
+        settings.set("seed", "42").unwrap();
+        settings.set("temperature", "0.7").unwrap();
+        settings.set("top_p", "0.9").unwrap();
+        settings.set("max_new_tokens", "16").unwrap();
+        assert_eq!(
+            settings,
+            ReplSettings {
+                seed: 42,
+                temperature: Some(0.7),
+                top_p: Some(0.9),
+                max_new_tokens: 16,
+                ..self::settings()
+            }
+        );
+        settings.set("temperature", "none").unwrap();
+        assert_eq!(settings.temperature, None);
+
+        let before = settings.clone();
+        assert!(settings.set("repeat_penalty", "0").is_err());
+        assert!(settings.set("repeat_penalty", "high").is_err());
+        assert!(settings.set("seed", "-1").is_err());
+        assert!(settings.set("unknown", "1").is_err());
+        assert_eq!(settings, before);
+    }
+
+    #[test]
+    fn settings_command_replaces_the_processor() {
+        let mut repl = Repl::new(test_utils::models(0), settings()).unwrap();
+        repl.command("/settings repeat_last_n 8").unwrap();
+        assert_eq!(repl.settings.repeat_last_n, 8);
+        assert_eq!(repl.processor.params().4, 8);
+
+        assert!(repl.command("/settings repeat_last_n").is_err());
+        assert!(repl.command("/settings repeat_penalty -1").is_err());
+        assert_eq!(repl.settings.repeat_penalty, 1.1);
+        assert_eq!(repl.processor.params().3, 1.1);
+    }
+
+    #[test]
+    fn undo_restores_the_conversation() {
+        let mut repl = Repl::new(test_utils::models(0), settings()).unwrap();
+        repl.turn("the cat").unwrap();
+        let tokens = repl.conversation.tokens.clone();
+        let fed = repl.conversation.fed;
+        let states = state_values(&repl.conversation.states);
+        let samples = repl.processor.samples();
+
+        repl.turn("sat on the mat").unwrap();
+        let second_turn = repl.conversation.tokens.clone();
+        assert!(second_turn.len() > tokens.len());
+        repl.command("/undo").unwrap();
+        assert_eq!(repl.conversation.tokens, tokens);
+        assert_eq!(repl.conversation.fed, fed);
+        assert_eq!(state_values(&repl.conversation.states), states);
+        assert_eq!(repl.processor.samples(), samples);
+
+        // there is a single level of undo
+        repl.command("/undo").unwrap();
+        assert_eq!(repl.conversation.tokens, tokens);
+
+        // the undone turn can be replayed
+        repl.turn("sat on the mat").unwrap();
+        assert_eq!(repl.conversation.tokens, second_turn);
+
+        repl.command("/reset").unwrap();
+        assert!(repl.conversation.tokens.is_empty());
+        repl.command("/undo").unwrap();
+        assert_eq!(repl.conversation.tokens, second_turn);
+    }
+}
@@ -0,0 +1,433 @@
+//! A local HTTP server with an OpenAI-compatible completions API.
+//!
+//! Endpoints:
+//! - `GET /v1/models`
+//! - `POST /v1/completions`, optionally streaming the tokens as Server-Sent Events (`"stream": true`).
+//!
+//! Requests are handled one at a time, since there is a single model.
+
+use crate::generation::{FinishReason, Session};
+use crate::logits::{FrequencyPresencePenalty, LogitBias, TokenLogprobs};
+use crate::{mamba, GenerationConfig, GenerationEvent, LogitsProcessorWrapper};
+use crate::{MambaModel, MambaWrapper};
+use dfdx::tensor::Cpu;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+pub struct Server<M: MambaModel = mamba::Mamba<f32, Cpu>> {
+    pub models: MambaWrapper<M>,
+    /// The id reported by `/v1/models`, and accepted in the `model` field of the requests.
+    pub model_id: String,
+}
+
+/// The `/v1/completions` request body.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompletionRequest {
+    pub model: Option<String>,
+    pub prompt: Prompt,
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: usize,
+    #[serde(default = "default_temperature")]
+    pub temperature: f64,
+    pub top_p: Option<f64>,
+    #[serde(default)]
+    pub stream: bool,
+    pub stop: Option<Stop>,
+    pub seed: Option<u64>,
+    /// How many top alternatives to include for each token.
+    pub logprobs: Option<usize>,
+    #[serde(default)]
+    pub echo: bool,
+    #[serde(default)]
+    pub presence_penalty: f32,
+    #[serde(default)]
+    pub frequency_penalty: f32,
+    /// Token id (as a string) to bias.
+    #[serde(default)]
+    pub logit_bias: HashMap<String, f32>,
+}
+
+fn default_max_tokens() -> usize {
+    16
+}
+
+fn default_temperature() -> f64 {
+    1.
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Prompt {
+    Single(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Stop {
+    Single(String),
+    Many(Vec<String>),
+}
+
+/// The result of a single prompt.
+struct Choice {
+    text: String,
+    logprobs: Option<OpenAiLogprobs>,
+    finish_reason: &'static str,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+/// The `logprobs` object of a choice.
+#[derive(Default)]
+struct OpenAiLogprobs {
+    tokens: Vec<String>,
+    token_logprobs: Vec<f32>,
+    top_logprobs: Vec<HashMap<String, f32>>,
+    text_offset: Vec<usize>,
+}
+
+impl OpenAiLogprobs {
+    fn push(&mut self, token: String, logprobs: &TokenLogprobs, text_offset: usize) {
+        self.tokens.push(token);
+        self.token_logprobs.push(logprobs.logprob);
+        self.top_logprobs.push(
+            logprobs
+                .top
+                .iter()
+                .map(|top| (top.text.clone().unwrap_or_default(), top.logprob))
+                .collect(),
+        );
+        self.text_offset.push(text_offset);
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "tokens": self.tokens,
+            "token_logprobs": self.token_logprobs,
+            "top_logprobs": self.top_logprobs,
+            "text_offset": self.text_offset,
+        })
+    }
+}
+
+impl<M: MambaModel> Server<M> {
+    /// Serves on `addr` (eg. `127.0.0.1:8080`) until the process is stopped.
+    pub fn serve(&mut self, addr: &str) -> anyhow::Result<()> {
+        let server = tiny_http::Server::http(addr).map_err(|e| anyhow::anyhow!("{e}"))?;
+        eprintln!("listening on http://{}", server.server_addr());
+        self.serve_on(&server)
+    }
+
+    /// Serves the requests of an already bound `server`, eg. one bound to an ephemeral port.
+    pub fn serve_on(&mut self, server: &tiny_http::Server) -> anyhow::Result<()> {
+        for request in server.incoming_requests() {
+            if let Err(err) = self.handle(request) {
+                eprintln!("request failed: {err}");
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, mut request: tiny_http::Request) -> anyhow::Result<()> {
+        let method = request.method().clone();
+        let url = request.url().to_string();
+        match (method, url.as_str()) {
+            (tiny_http::Method::Get, "/v1/models") => {
+                let body = json!({
+                    "object": "list",
+                    "data": [{
+                        "id": self.model_id,
+                        "object": "model",
+                        "created": 0,
+                        "owned_by": "local",
+                    }],
+                });
+                respond_json(request, 200, &body)
+            }
+            (tiny_http::Method::Post, "/v1/completions") => {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body)?;
+                let completion: CompletionRequest = match serde_json::from_str(&body) {
+                    Ok(completion) => completion,
+                    Err(err) => {
+                        return respond_error(request, 400, &format!("invalid request: {err}"))
+                    }
+                };
+                if let Some(model) = &completion.model {
+                    if model != &self.model_id {
+                        return respond_error(request, 404, &format!("unknown model {model:?}"));
+                    }
+                }
+                if completion.stream {
+                    self.stream_completion(request, completion)
+                } else {
+                    self.completion(request, completion)
+                }
+            }
+            _ => respond_error(request, 404, "not found"),
+        }
+    }
+
+    fn completion(
+        &mut self,
+        request: tiny_http::Request,
+        completion: CompletionRequest,
+    ) -> anyhow::Result<()> {
+        let id = completion_id();
+        let mut choices = vec![];
+        let (mut prompt_tokens, mut completion_tokens) = (0, 0);
+        for (index, prompt) in prompts(&completion).into_iter().enumerate() {
+            let choice = match self.generate(&prompt, &completion, |_text, _logprobs| Ok(())) {
+                Ok(choice) => choice,
+                Err(err) => return respond_error(request, 500, &err.to_string()),
+            };
+            prompt_tokens += choice.prompt_tokens;
+            completion_tokens += choice.completion_tokens;
+            choices.push(json!({
+                "text": choice.text,
+                "index": index,
+                "logprobs": choice.logprobs.as_ref().map(OpenAiLogprobs::to_json),
+                "finish_reason": choice.finish_reason,
+            }));
+        }
+        let body = json!({
+            "id": id,
+            "object": "text_completion",
+            "created": created(),
+            "model": self.model_id,
+            "choices": choices,
+            "usage": {
+                "prompt_tokens": prompt_tokens,
+                "completion_tokens": completion_tokens,
+                "total_tokens": prompt_tokens + completion_tokens,
+            },
+        });
+        respond_json(request, 200, &body)
+    }
+
+    fn stream_completion(
+        &mut self,
+        request: tiny_http::Request,
+        completion: CompletionRequest,
+    ) -> anyhow::Result<()> {
+        let mut writer = request.into_writer();
+        write!(
+            writer,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+        )?;
+        writer.flush()?;
+
+        let id = completion_id();
+        let created = created();
+        let model_id = self.model_id.clone();
+        let chunk =
+            |index: usize, text: &str, logprobs: Option<Value>, finish_reason: Option<&str>| {
+                json!({
+                    "id": id,
+                    "object": "text_completion",
+                    "created": created,
+                    "model": model_id,
+                    "choices": [{
+                        "text": text,
+                        "index": index,
+                        "logprobs": logprobs,
+                        "finish_reason": finish_reason,
+                    }],
+                })
+            };
+        let send = |writer: &mut Box<dyn Write + Send>, chunk: Value| -> anyhow::Result<()> {
+            write!(writer, "data: {chunk}\n\n")?;
+            writer.flush()?;
+            Ok(())
+        };
+
+        for (index, prompt) in prompts(&completion).into_iter().enumerate() {
+            let result = self.generate(&prompt, &completion, |text, logprobs| {
+                send(
+                    &mut writer,
+                    chunk(index, text, logprobs.map(|l| l.to_json()), None),
+                )
+            });
+            match result {
+                Ok(choice) => {
+                    send(
+                        &mut writer,
+                        chunk(index, "", None, Some(choice.finish_reason)),
+                    )?;
+                }
+                Err(err) => {
+                    send(
+                        &mut writer,
+                        json!({ "error": { "message": err.to_string() } }),
+                    )?;
+                    break;
+                }
+            }
+        }
+        write!(writer, "data: [DONE]\n\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Generates for a single `prompt`, calling `on_text` for each new text fragment (and its logprobs).
+    fn generate(
+        &mut self,
+        prompt: &str,
+        completion: &CompletionRequest,
+        mut on_text: impl FnMut(&str, Option<OpenAiLogprobs>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Choice> {
+        let mut processor = processor(completion)?;
+        let config = GenerationConfig {
+            max_new_tokens: Some(completion.max_tokens),
+            stop: match &completion.stop {
+                None => vec![],
+                Some(Stop::Single(stop)) => vec![stop.clone()],
+                Some(Stop::Many(stops)) => stops.clone(),
+            },
+            top_logprobs: completion.logprobs,
+            ..Default::default()
+        };
+        let session = Session::new(&mut self.models, prompt, config)?;
+        let prompt_tokens = session.tokens.clone();
+        let prompt_text = self
+            .models
+            .tokenizer
+            .tokenizer()
+            .decode(&prompt_tokens, true)
+            .map_err(anyhow::Error::msg)?;
+
+        // how many chars of the decoded text (which starts with the prompt) are still not sent
+        //
+        // this is counted in chars, since the streamed fragments are not necessarily split at
+        // the same byte offsets as the prompt text
+        let mut skip = if completion.echo {
+            0
+        } else {
+            prompt_text.chars().count()
+        };
+        let mut logprobs = completion.logprobs.map(|_| OpenAiLogprobs::default());
+        let mut text = String::new();
+        let mut finish_reason = "length";
+        let mut generation = self.models.resume(session, &mut processor);
+        for event in generation.by_ref() {
+            let (fragment, token_logprobs, id) = match event? {
+                GenerationEvent::Token(token) => (token.text, token.logprobs, Some(token.id)),
+                GenerationEvent::Finish { reason, text } => {
+                    finish_reason = match reason {
+                        FinishReason::Eos | FinishReason::Stop(_) => "stop",
+                        FinishReason::Length | FinishReason::Deadline => "length",
+                        FinishReason::Error(_) => "error",
+                    };
+                    (text, None, None)
+                }
+            };
+            let fragment = fragment.unwrap_or_default();
+            let new_text: String = fragment.chars().skip(skip).collect();
+            skip = skip.saturating_sub(fragment.chars().count());
+
+            let mut chunk_logprobs = None;
+            if let (Some(logprobs), Some(token_logprobs), Some(id)) =
+                (logprobs.as_mut(), token_logprobs, id)
+            {
+                let token = generation
+                    .models
+                    .tokenizer
+                    .tokenizer()
+                    .decode(&[id], false)
+                    .map_err(anyhow::Error::msg)?;
+                let offset = text.len();
+                logprobs.push(token.clone(), &token_logprobs, offset);
+                let mut chunk = OpenAiLogprobs::default();
+                chunk.push(token, &token_logprobs, offset);
+                chunk_logprobs = Some(chunk);
+            }
+            if !new_text.is_empty() || chunk_logprobs.is_some() {
+                on_text(&new_text, chunk_logprobs)?;
+            }
+            text += &new_text;
+        }
+        let session = generation.session;
+        Ok(Choice {
+            text,
+            logprobs,
+            finish_reason,
+            prompt_tokens: prompt_tokens.len(),
+            completion_tokens: session.new_tokens,
+        })
+    }
+}
+
+/// Builds the sampler from the request settings.
+fn processor(completion: &CompletionRequest) -> anyhow::Result<LogitsProcessorWrapper> {
+    let temperature = (completion.temperature > 0.).then_some(completion.temperature);
+    let mut processor = LogitsProcessorWrapper::new(
+        completion.seed.unwrap_or(299792458),
+        temperature,
+        completion.top_p,
+        1.,
+        0,
+    )?;
+    if completion.presence_penalty != 0. || completion.frequency_penalty != 0. {
+        processor = processor.with(FrequencyPresencePenalty {
+            frequency: completion.frequency_penalty,
+            presence: completion.presence_penalty,
+            last_n: None,
+        });
+    }
+    if !completion.logit_bias.is_empty() {
+        let bias = completion
+            .logit_bias
+            .iter()
+            .map(|(token, bias)| {
+                let token: u32 = token
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("invalid logit_bias token {token:?}"))?;
+                Ok((token, *bias))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        processor = processor.with(LogitBias(bias));
+    }
+    Ok(processor)
+}
+
+fn prompts(completion: &CompletionRequest) -> Vec<String> {
+    match &completion.prompt {
+        Prompt::Single(prompt) => vec![prompt.clone()],
+        Prompt::Many(prompts) => prompts.clone(),
+    }
+}
+
+fn completion_id() -> String {
+    format!(
+        "cmpl-{:x}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    )
+}
+
+fn created() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn respond_json(request: tiny_http::Request, status: u16, body: &Value) -> anyhow::Result<()> {
+    let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .map_err(|_| anyhow::anyhow!("invalid header"))?;
+    let response = tiny_http::Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header);
+    request.respond(response)?;
+    Ok(())
+}
+
+fn respond_error(request: tiny_http::Request, status: u16, message: &str) -> anyhow::Result<()> {
+    let body = json!({ "error": { "message": message, "type": "invalid_request_error" } });
+    respond_json(request, status, &body)
+}
@@ -1,6 +1,6 @@
-use crate::{hf, mamba};
-use crate::{LogitsProcessorWrapper, MambaWrapper};
-use dfdx::prelude::*;
+use crate::precision::{f16, MambaDtype};
+use crate::{generation, hf, source};
+use crate::{GenerationConfig, GenerationEvent, LogitsProcessorWrapper, MambaWrapper};
 use hf_hub::{
     api::wasm::Api,
     types::{FilePath, RepoId, RevisionPath},
@@ -26,6 +26,9 @@ pub async fn run() -> anyhow::Result<()> {
         RepoType::Model,
         RevisionPath(hf::mamba_130m::REVISION_PATH.into()),
     ));
+    let mamba_config_filename = repo
+        .get(&FilePath(hf::mamba_130m::FILE_PATH_CONFIG_JSON.into()))
+        .await?;
     let mamba_filename = repo
         .get(&FilePath(
             hf::mamba_130m::FILE_PATH_MODEL_SAFETENSORS.into(),
@@ -48,39 +51,21 @@ pub async fn run() -> anyhow::Result<()> {
     let tokenizer = tokenizers::Tokenizer::from_bytes(tokenizer).map_err(anyhow::Error::msg)?;
     log::info!("tokenizer loaded in {}ms", timing.elapsed().as_millis()); // ~200ms
 
-    let cpu = Cpu::default();
+    let mamba_config = api.load_bytes(&mamba_config_filename).await.unwrap();
+    let mamba_config = String::from_utf8(mamba_config)?;
 
     timing = web_time::Instant::now();
     log::info!("loading mamba data");
     let mamba_bytes = api.load_bytes(&mamba_filename).await.unwrap();
     log::info!("mamba data loaded in {}ms", timing.elapsed().as_millis()); // ~2-3s
-    let mamba = {
-        let n_layer = 24;
-        let padded_vocab_size = 50280;
-        let d_model = 768;
-
-        timing = web_time::Instant::now();
-        log::info!("initializing random mamba model");
-        let mamba =
-            mamba::MambaConfig::new(n_layer, padded_vocab_size, d_model, None, None, None, None);
-        let mut m: mamba::Mamba<f32, Cpu> = cpu.try_build_module::<f32>(mamba)?;
-        log::info!(
-            "random mamba model initialized in {}ms",
-            timing.elapsed().as_millis()
-        ); // ~15-20s
 
-        timing = web_time::Instant::now();
-        let load_renames = mamba::load::load_renames(n_layer);
-        let skip_missing = true;
-        let mut key_map = |key: String| load_renames.get(&key).unwrap_or(&key).to_string();
-        log::info!("loading mamba");
-        m.load_safetensors_from_bytes_with(mamba_bytes.as_slice(), skip_missing, &mut key_map)?;
-        m
-    };
+    timing = web_time::Instant::now();
+    log::info!("loading mamba data into {}", f16::NAME);
+    let mamba = source::load_mamba::<f16>(&mamba_config, &mamba_bytes)?;
     log::info!("mamba loaded in {}ms", timing.elapsed().as_millis()); // ~1s
 
     let mut models = MambaWrapper::new(tokenizer, mamba);
-    let mut processor = LogitsProcessorWrapper::new(299792458, None, None, 1.1, 1024);
+    let mut processor = LogitsProcessorWrapper::new(299792458, None, None, 1.1, 1024)?;
 
     let prompt = "Mamba is the";
     let sample_len = 200;
@@ -91,41 +76,33 @@ pub async fn run() -> anyhow::Result<()> {
     let mut last_elapsed = timing.elapsed().as_millis();
     // stateful
     let mut i: usize = 0;
-    {
-        let (mut tokens, eos_token) = models.reset_prompt(prompt)?;
-
-        // gets first token (as if it were an implicit output)
-        if let Some(t) = tokens.first() {
-            if let Some(t) = models.tokenizer.next_token(*t)? {
-                output += &t;
-            }
+    let mut is_first = true;
+    let config = GenerationConfig {
+        mode: generation::Mode::Stateful,
+        sample_len,
+        ..Default::default()
+    };
+    for event in models.generate(prompt, config, &mut processor)? {
+        let this_elapsed = timing.elapsed().as_millis();
+        if this_elapsed > last_elapsed + 1000 {
+            last_elapsed = this_elapsed;
+            log::info!("(generation still running..): {output}");
         }
 
-        // initial states
-        let mut states = models.empty_states()?;
-
-        while i < sample_len {
-            let this_elapsed = timing.elapsed().as_millis();
-            if this_elapsed > last_elapsed + 1000 {
-                last_elapsed = this_elapsed;
-                log::info!("(generation still running..): {output}");
-            }
-
-            let next_logits = models.step(tokens[i], &mut states)?;
-            let next_token = processor.add_logits(i, &mut tokens, next_logits)?;
-            if next_token == eos_token {
-                break;
+        let text = match event? {
+            GenerationEvent::Token(token) => {
+                // the first token is not a model step
+                // (note: the prefilled prompt tokens also have no logits)
+                if !is_first {
+                    i += 1;
+                }
+                is_first = false;
+                token.text
             }
-
-            // if the token has some valid representation, print it
-            if let Some(t) = models.tokenizer.next_token(next_token)? {
-                output += &t;
-            }
-
-            i += 1;
-        }
-        if let Some(rest) = models.tokenizer.decode_rest().map_err(anyhow::Error::msg)? {
-            output += &rest;
+            GenerationEvent::Finish { text, .. } => text,
+        };
+        if let Some(t) = text {
+            output += &t;
         }
     }
     let elapsed = timing.elapsed().as_millis();
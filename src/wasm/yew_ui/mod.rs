@@ -15,6 +15,9 @@ pub enum Msg {
     // Todo,
 
     // fetching, loading, building
+    /// Selects another checkpoint, by its index in [crate::registry::KNOWN_MODELS].
+    /// The current mamba config and data get unloaded, and the new ones get checked.
+    SelectModel(usize),
     /// Starts the huggingface api connection (reqwest and indexeddb clients).
     StartConnectApi,
     /// Concludes the huggingface api connection (reqwest and indexeddb clients).
@@ -66,7 +69,7 @@ pub enum Msg {
     StartModelBuild(ModelSelection),
     /// Concludes building a model from the model data.
     FinishModelBuild(ModelSelection),
-    FailModelBuild,
+    FailModelBuild(ModelSelection, anyhow::Error),
     /// If all required models are built, we move to the next step of being to use the models
     /// for inference (etc).
     TryFinilizeModelsBuilding,
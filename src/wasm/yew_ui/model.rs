@@ -1,3 +1,6 @@
+use crate::generation::Session;
+use crate::precision::{f16, MambaDtype};
+use crate::registry::{self, KnownModel};
 use crate::{hf, mamba, LogitsProcessorWrapper, MambaWrapper};
 use dfdx::tensor::Cpu;
 use hf_hub::{
@@ -17,6 +20,10 @@ pub struct Model {
     pub cache_api: Connection<Api>,
     /// Stores cache and load status information, and also loaded bytes data.
     pub tokenizer: ModelData,
+    /// The selected checkpoint, from which the mamba config and data are fetched.
+    pub known_model: &'static KnownModel,
+    /// Stores cache and load status information, and also loaded bytes data.
+    pub mamba_config: ModelData,
     /// Stores cache and load status information, and also loaded bytes data.
     pub mamba: ModelData,
     /// Consumes loaded bytes data to partially build the required models.
@@ -35,20 +42,15 @@ pub struct Model {
     pub is_generating: bool,
     pub generation_callback_interval: Option<gloo_timers::callback::Interval>,
     //
-    /// Current token step index (for logits selection).
-    pub step: usize,
-    /// Tokens being (at first) introduced into or (later) produced by the generation.
-    pub tokens: Vec<u32>,
     /// Current generation result (token concatenation from each generation step).
     pub output: String,
-    /// The token the model uses to signal the end of the generation.
-    pub eos_token: u32,
 }
 
 impl Model {
     pub fn select(&self, selection: &ModelSelection) -> &ModelData {
         match selection {
             ModelSelection::Tokenizer => &self.tokenizer,
+            ModelSelection::MambaConfig => &self.mamba_config,
             ModelSelection::Mamba => &self.mamba,
         }
     }
@@ -56,6 +58,7 @@ impl Model {
     pub fn select_mut(&mut self, selection: &ModelSelection) -> &mut ModelData {
         match selection {
             ModelSelection::Tokenizer => &mut self.tokenizer,
+            ModelSelection::MambaConfig => &mut self.mamba_config,
             ModelSelection::Mamba => &mut self.mamba,
         }
     }
@@ -80,17 +83,9 @@ impl Default for Model {
                     filepath: FilePath(hf::tokenizer::FILE_PATH_TOKENIZER_JSON.into()),
                 }),
             ),
-            mamba: ModelData::new(
-                "Mamba-130m".into(),
-                ModelDataConfig::Huggingface(HuggingfaceConfig {
-                    endpoint: Endpoint::default(),
-                    url_template: UrlTemplate::default(),
-                    repo_id: RepoId(hf::mamba_130m::REPO_ID.into()),
-                    repo_type: RepoType::Model,
-                    revision: RevisionPath(hf::mamba_130m::REVISION_PATH.into()),
-                    filepath: FilePath(hf::mamba_130m::FILE_PATH_MODEL_SAFETENSORS.into()),
-                }),
-            ),
+            known_model: &registry::MAMBA_130M,
+            mamba_config: ModelData::mamba_config(&registry::MAMBA_130M),
+            mamba: ModelData::mamba(&registry::MAMBA_130M),
             models_wrapper_builder: MambaWrapperBuilder::default(),
 
             // built models
@@ -102,10 +97,7 @@ impl Default for Model {
             is_reset: true,
             is_generating: false,
             generation_callback_interval: None,
-            step: 0,
-            tokens: vec![],
             output: "".into(),
-            eos_token: 0,
         }
     }
 }
@@ -113,7 +105,11 @@ impl Default for Model {
 #[derive(Default)]
 pub struct MambaWrapperBuilder {
     pub tokenizer: Option<Tokenizer>,
-    pub mamba: Option<mamba::Mamba<f32, Cpu>>,
+    pub mamba_config: Option<mamba::MambaConfig>,
+    /// The mamba data, in case it's loaded before the mamba config.
+    pub mamba_data: Option<Vec<u8>>,
+    /// The model, with half-precision weights to halve the memory.
+    pub mamba: Option<mamba::Mamba<f16, Cpu>>,
 }
 
 impl MambaWrapperBuilder {
@@ -123,54 +119,57 @@ impl MambaWrapperBuilder {
     pub fn build(self) -> Wrapper {
         self.into()
     }
-    pub fn with(&mut self, selection: &ModelSelection, data: Vec<u8>, device: &Cpu) {
+    pub fn with(
+        &mut self,
+        selection: &ModelSelection,
+        data: Vec<u8>,
+        device: &Cpu,
+    ) -> anyhow::Result<()> {
         match selection {
             ModelSelection::Tokenizer => {
-                let tokenizer = tokenizers::Tokenizer::from_bytes(data)
-                    .map_err(anyhow::Error::msg)
-                    .unwrap();
+                let tokenizer =
+                    tokenizers::Tokenizer::from_bytes(data).map_err(anyhow::Error::msg)?;
                 self.tokenizer = Some(tokenizer);
             }
+            ModelSelection::MambaConfig => {
+                let config = String::from_utf8(data)?;
+                let config = mamba::MambaConfig::from_hf_config_json(&config)?;
+                self.mamba_config = Some(config);
+                // the mamba data may already be waiting for the config
+                if let Some(data) = self.mamba_data.take() {
+                    self.build_mamba(data, device)?;
+                }
+            }
             ModelSelection::Mamba => {
-                let mamba = {
-                    let n_layer = 24;
-                    let padded_vocab_size = 50280;
-                    let d_model = 768;
-
-                    log::info!("initializing random mamba model");
-                    let mamba = mamba::MambaConfig::new(
-                        n_layer,
-                        padded_vocab_size,
-                        d_model,
-                        None,
-                        None,
-                        None,
-                        None,
-                    );
-                    use dfdx::nn::BuildModuleExt;
-                    let mut m: mamba::Mamba<f32, Cpu> =
-                        device.try_build_module::<f32>(mamba).unwrap();
-                    log::info!("random mamba model initialized"); // ~15-20s
-
-                    let load_renames = mamba::load::load_renames(n_layer);
-                    let skip_missing = true;
-                    let mut key_map =
-                        |key: String| load_renames.get(&key).unwrap_or(&key).to_string();
-
-                    log::info!("loading mamba data");
-                    use dfdx::nn::LoadSafeTensors;
-                    m.load_safetensors_from_bytes_with(data.as_slice(), skip_missing, &mut key_map)
-                        .unwrap();
-                    log::info!("mamba data loaded");
-                    m
-                };
-                self.mamba = Some(mamba);
+                if self.mamba_config.is_some() {
+                    self.build_mamba(data, device)?;
+                } else {
+                    log::info!("mamba data is waiting for the mamba config");
+                    self.mamba_data = Some(data);
+                }
             }
         }
+        Ok(())
+    }
+    fn build_mamba(&mut self, data: Vec<u8>, device: &Cpu) -> anyhow::Result<()> {
+        let config = self.mamba_config.clone().unwrap();
+
+        log::info!("allocating the mamba model");
+        use dfdx::nn::BuildOnDevice;
+        let mut m: mamba::Mamba<f16, Cpu> = config.try_build_on_device(device)?;
+
+        log::info!("loading mamba data into {}", f16::NAME);
+        let tensors = safetensors::SafeTensors::deserialize(&data)?;
+        mamba::load::load_hf_safetensors(&mut m, &tensors)?;
+        log::info!("mamba data loaded");
+        self.mamba = Some(m);
+        Ok(())
     }
     pub fn merge(self, other: Self) -> Self {
         Self {
             tokenizer: self.tokenizer.or(other.tokenizer),
+            mamba_config: self.mamba_config.or(other.mamba_config),
+            mamba_data: self.mamba_data.or(other.mamba_data),
             mamba: self.mamba.or(other.mamba),
         }
     }
@@ -216,18 +215,18 @@ impl<T> Connection<T> {
 }
 
 pub struct Wrapper {
-    pub models: MambaWrapper,
-    pub states: mamba::stateful::MambaStatesDyn<f32, dfdx::tensor::Cpu, dfdx::tensor::NoneTape>,
+    pub models: MambaWrapper<mamba::Mamba<f16, Cpu>>,
+    /// The ongoing generation (tokens, states, etc), if it has been started.
+    pub session: Option<Session>,
     pub processor: LogitsProcessorWrapper,
 }
 
 impl Wrapper {
-    pub fn new(models: MambaWrapper) -> Self {
-        let states = models.empty_states().unwrap();
+    pub fn new(models: MambaWrapper<mamba::Mamba<f16, Cpu>>) -> Self {
         Self {
             models,
-            states,
-            processor: LogitsProcessorWrapper::new(299792458, None, None, 1.1, 1024),
+            session: None,
+            processor: LogitsProcessorWrapper::new(299792458, None, None, 1.1, 1024).unwrap(),
         }
     }
 }
@@ -249,11 +248,34 @@ impl ModelData {
             cache: Cache::default(),
         }
     }
+
+    /// The `config.json` of the `known` checkpoint.
+    pub fn mamba_config(known: &KnownModel) -> Self {
+        Self::new(
+            format!("{} Config", known.name),
+            ModelDataConfig::Huggingface(HuggingfaceConfig::known(
+                known,
+                known.file_path_config_json,
+            )),
+        )
+    }
+
+    /// The `model.safetensors` of the `known` checkpoint.
+    pub fn mamba(known: &KnownModel) -> Self {
+        Self::new(
+            known.name.into(),
+            ModelDataConfig::Huggingface(HuggingfaceConfig::known(
+                known,
+                known.file_path_model_safetensors,
+            )),
+        )
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ModelSelection {
     Tokenizer,
+    MambaConfig,
     Mamba,
 }
 
@@ -320,6 +342,18 @@ pub struct HuggingfaceConfig {
 }
 
 impl HuggingfaceConfig {
+    /// A file from the `known` checkpoint.
+    pub fn known(known: &KnownModel, filepath: &str) -> Self {
+        Self {
+            endpoint: Endpoint::default(),
+            url_template: UrlTemplate::default(),
+            repo_id: RepoId(known.repo_id.into()),
+            repo_type: RepoType::Model,
+            revision: RevisionPath(known.revision.into()),
+            filepath: FilePath(filepath.into()),
+        }
+    }
+
     pub fn api_repo(&self, api: &Api) -> ApiRepo {
         let repo = Repo::with_revision(self.repo_id.clone(), self.repo_type, self.revision.clone());
         api.repo(repo)
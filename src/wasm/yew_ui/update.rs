@@ -1,6 +1,7 @@
 use super::model::ModelSelection;
 pub use super::model::{self, Connection, Model};
 use super::Msg;
+use crate::generation::{GenerationConfig, GenerationEvent, Session};
 use hf_hub::{api::wasm::Api, types::TmpFileBlobKey};
 use yew::prelude::*;
 
@@ -14,6 +15,52 @@ impl model::Model {
             // }
 
             // fetching, loading, building
+            Msg::SelectModel(i) => {
+                let known = &crate::registry::KNOWN_MODELS[i];
+                if std::ptr::eq(known, self.known_model) {
+                    return false;
+                }
+                // the mamba data cannot be replaced while it's in use
+                if self.mamba_config.load.is_busy
+                    || self.mamba_config.cache.is_busy
+                    || self.mamba.load.is_busy
+                    || self.mamba.cache.is_busy
+                {
+                    log::error!("cannot select {} while the model data is busy", known.name);
+                    return false;
+                }
+                if self.is_generating {
+                    ctx.link().send_message_batch(vec![
+                        Msg::StopGeneration,
+                        Msg::ResetStates,
+                        Msg::SelectModel(i),
+                    ]);
+                    return false;
+                }
+
+                // clear built models and the previous mamba data
+                // (the tokenizer is shared between all of the checkpoints)
+                if self.models_wrapper.is_some() {
+                    self.models_wrapper = None;
+                    self.tokenizer.load.is_done = false;
+                }
+                self.models_wrapper_builder.mamba_config = None;
+                self.models_wrapper_builder.mamba_data = None;
+                self.models_wrapper_builder.mamba = None;
+                self.known_model = known;
+                self.mamba_config = model::ModelData::mamba_config(known);
+                self.mamba = model::ModelData::mamba(known);
+                self.output.clear();
+                self.output.shrink_to_fit();
+
+                if self.cache_api.is_exactly_connected() {
+                    ctx.link().send_message_batch(vec![
+                        Msg::StartModelDataCheck(ModelSelection::MambaConfig),
+                        Msg::StartModelDataCheck(ModelSelection::Mamba),
+                    ]);
+                }
+                true
+            }
             Msg::StartConnectApi => {
                 assert!(self.cache_api.is_exactly_disconnected());
                 self.cache_api = Connection::Connecting;
@@ -31,6 +78,10 @@ impl model::Model {
                     ctx.link()
                         .send_message(Msg::StartModelDataCheck(ModelSelection::Tokenizer));
                 }
+                if self.mamba_config.cache.is_checking {
+                    ctx.link()
+                        .send_message(Msg::StartModelDataCheck(ModelSelection::MambaConfig));
+                }
                 if self.mamba.cache.is_checking {
                     ctx.link()
                         .send_message(Msg::StartModelDataCheck(ModelSelection::Mamba));
@@ -183,14 +234,13 @@ impl model::Model {
                 }
 
                 // clear generation-related memory
-                self.tokens.clear();
-                self.tokens.shrink_to_fit();
                 self.output.shrink_to_fit();
 
                 // clear built models
                 if self.models_wrapper.is_some() {
                     self.models_wrapper = None;
                     self.tokenizer.load.is_done = false;
+                    self.mamba_config.load.is_done = false;
                     self.mamba.load.is_done = false;
                 }
                 // in case the models weren't fully built yet,
@@ -200,7 +250,13 @@ impl model::Model {
                     self.tokenizer.load.data.clear();
                     self.tokenizer.load.data.shrink_to_fit();
                     self.tokenizer.load.is_done = false;
+                } else if let ModelSelection::MambaConfig = selection {
+                    self.models_wrapper_builder.mamba_config = None;
+                    self.mamba_config.load.data.clear();
+                    self.mamba_config.load.data.shrink_to_fit();
+                    self.mamba_config.load.is_done = false;
                 } else if let ModelSelection::Mamba = selection {
+                    self.models_wrapper_builder.mamba_data = None;
                     self.models_wrapper_builder.mamba = None;
                     self.mamba.load.data.clear();
                     self.mamba.load.data.shrink_to_fit();
@@ -262,9 +318,20 @@ impl model::Model {
             Msg::StartModelBuild(selection) => {
                 let model_data = self.select_mut(&selection);
                 let data = std::mem::take(&mut model_data.load.data);
-                self.models_wrapper_builder
-                    .with(&selection, data, &self.device);
-                ctx.link().send_message(Msg::FinishModelBuild(selection));
+                let known = self.known_model;
+                let built = self
+                    .models_wrapper_builder
+                    .with(&selection, data, &self.device)
+                    .and_then(|()| match &self.models_wrapper_builder.mamba_config {
+                        Some(config) if selection == ModelSelection::MambaConfig => {
+                            known.check_config(config)
+                        }
+                        _ => Ok(()),
+                    });
+                match built {
+                    Ok(()) => ctx.link().send_message(Msg::FinishModelBuild(selection)),
+                    Err(err) => ctx.link().send_message(Msg::FailModelBuild(selection, err)),
+                }
                 false
             }
             Msg::FinishModelBuild(selection) => {
@@ -274,8 +341,12 @@ impl model::Model {
                 ctx.link().send_message(Msg::TryFinilizeModelsBuilding);
                 true
             }
-            Msg::FailModelBuild => {
-                todo!()
+            Msg::FailModelBuild(selection, err) => {
+                log::error!("failed to build {selection:?}; err: {err:?}");
+                let model_data = self.select_mut(&selection);
+                model_data.load.is_busy = false;
+                model_data.load.is_done = false;
+                true
             }
             Msg::TryFinilizeModelsBuilding => {
                 // consume the built models if they are all ready
@@ -308,18 +379,11 @@ impl model::Model {
                 self.is_generating = true;
                 self.is_reset = false;
                 self.output.clear();
-                self.step = 0;
                 let models_wrapper = self.models_wrapper.as_mut().unwrap();
-                let (tokens, eos_token) = models_wrapper.models.reset_prompt(&self.input).unwrap();
-                self.tokens = tokens;
-                self.eos_token = eos_token;
-
-                // gets first token (as if it were an implicit output)
-                if let Some(t) = self.tokens.first() {
-                    if let Some(t) = models_wrapper.models.tokenizer.next_token(*t).unwrap() {
-                        self.output += &t;
-                    }
-                }
+                let config = GenerationConfig::default();
+                let session =
+                    Session::new(&mut models_wrapper.models, &self.input, config).unwrap();
+                models_wrapper.session = Some(session);
 
                 let link = ctx.link().clone();
                 let interval = gloo_timers::callback::Interval::new(TICK_MILLIS, move || {
@@ -335,37 +399,30 @@ impl model::Model {
                     return true;
                 }
                 let models_wrapper = self.models_wrapper.as_mut().unwrap();
-                let models = &mut models_wrapper.models;
-                let next_logits = models
-                    .step(self.tokens[self.step], &mut models_wrapper.states)
-                    .unwrap();
-                let next_token = models_wrapper
-                    .processor
-                    .add_logits(self.step, &mut self.tokens, next_logits)
+                let session = models_wrapper.session.as_mut().unwrap();
+                let event = session
+                    .next_event(&mut models_wrapper.models, &mut models_wrapper.processor)
                     .unwrap();
-
-                // if the token has some valid representation, print it
-                if let Some(t) = models.tokenizer.next_token(next_token).unwrap() {
-                    self.output += &t;
-                }
-                self.step += 1;
-
-                if next_token == self.eos_token {
-                    self.is_generating = false;
-
-                    if let Some(rest) = models
-                        .tokenizer
-                        .decode_rest()
-                        .map_err(anyhow::Error::msg)
-                        .unwrap()
-                    {
-                        self.output += &rest;
+                match event {
+                    Some(GenerationEvent::Token(token)) => {
+                        // if the token has some valid representation, print it
+                        if let Some(t) = token.text {
+                            self.output += &t;
+                        }
+                    }
+                    Some(GenerationEvent::Finish { text, .. }) => {
+                        if let Some(rest) = text {
+                            self.output += &rest;
+                        }
+                        self.is_generating = false;
+                        self.generation_callback_interval = None;
+                    }
+                    None => {
+                        self.is_generating = false;
+                        self.generation_callback_interval = None;
                     }
-
-                    true
-                } else {
-                    true
                 }
+                true
             }
             Msg::StopGeneration => {
                 self.is_generating = false;
@@ -385,12 +442,9 @@ impl model::Model {
             Msg::ResetStates => {
                 assert!(!self.is_generating);
                 let models_wrapper = self.models_wrapper.as_mut().unwrap();
-                models_wrapper.states = models_wrapper.models.empty_states().unwrap();
-                // for state in models_wrapper.states.iter_mut() {
-                //     ResetParams::<f32, Cpu>::try_reset_params(state).unwrap();
-                // }
+                models_wrapper.session = None;
                 models_wrapper.processor =
-                    crate::LogitsProcessorWrapper::new(299792458, None, None, 1.1, 1024);
+                    crate::LogitsProcessorWrapper::new(299792458, None, None, 1.1, 1024).unwrap();
                 self.is_reset = true;
                 self.is_input_dirty = false;
                 true
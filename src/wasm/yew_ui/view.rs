@@ -143,11 +143,51 @@ impl model::Model {
         };
 
         let tokenizer_model_data = model_data(link, &self.tokenizer, ModelSelection::Tokenizer);
+        let mamba_config_model_data =
+            model_data(link, &self.mamba_config, ModelSelection::MambaConfig);
         let mamba_model_data = model_data(link, &self.mamba, ModelSelection::Mamba);
 
+        let known_models = crate::registry::KNOWN_MODELS
+            .iter()
+            .enumerate()
+            .map(|(i, known)| {
+                let class = if std::ptr::eq(known, self.known_model) {
+                    "button is-info is-selected"
+                } else {
+                    "button"
+                };
+                let title = known.to_string();
+                html_nested! {
+                    <button
+                        class={class}
+                        title={title}
+                        onclick={link.callback(move |_| Msg::SelectModel(i))}
+                    >
+                        {known.name}
+                    </button>
+                }
+            });
+        let memory = humansize::format_size(
+            self.known_model.approx_memory::<crate::precision::f16>(),
+            humansize::DECIMAL,
+        );
+        let model_selection = html_nested! {
+            <div class="tile is-child">
+                <label class="label">{"Checkpoint"}</label>
+                <div class="buttons has-addons">
+                    {for known_models}
+                </div>
+                <label class="help">
+                    {format!("{} requires ~{memory} of memory", self.known_model.name)}
+                </label>
+            </div>
+        };
+
         let caches = html_nested! {
             <div class="tile is-child is-vertical">
+            {model_selection}
             {tokenizer_model_data}
+            {mamba_config_model_data}
             {mamba_model_data}
             </div>
         };
@@ -0,0 +1,57 @@
+//! Fixtures shared by the integration tests.
+//!
+//! The models are small and randomly initialized from a fixed seed, and the tokenizer is built
+//! from a word list, so that the tests run offline.
+#![allow(dead_code)]
+
+use dfdx::prelude::*;
+use mamba_minimal_dfdx_example::mamba::{Mamba, MambaConfig};
+use serde_json::json;
+
+pub const SEED: u64 = 0;
+pub const VOCAB: usize = 32;
+pub const D_MODEL: usize = 16;
+pub const N_LAYER: usize = 2;
+
+/// The id of the `<|endoftext|>` token of [word_level_tokenizer].
+pub const EOS: u32 = 0;
+
+/// `(d_state, dt_rank, d_conv, d_inner)`, where `None` falls back to the defaults.
+pub type Dims = (Option<usize>, Option<usize>, Option<usize>, Option<usize>);
+
+/// A random model with [N_LAYER] layers of [D_MODEL], initialized from [SEED].
+pub fn build(vocab: usize, dims: Dims) -> Mamba<f32, Cpu> {
+    let (d_state, dt_rank, d_conv, d_inner) = dims;
+    let config = MambaConfig::new(N_LAYER, vocab, D_MODEL, d_state, dt_rank, d_conv, d_inner);
+    let dev = Cpu::seed_from_u64(SEED);
+    dev.try_build_module::<f32>(config).unwrap()
+}
+
+/// A tokenizer with the special `<|endoftext|>` token ([EOS]) followed by the `words`.
+///
+/// The text is split on whitespace and punctuation.
+pub fn word_level_tokenizer<S: Into<String>>(
+    words: impl IntoIterator<Item = S>,
+) -> tokenizers::Tokenizer {
+    let mut vocab = serde_json::Map::new();
+    vocab.insert("<|endoftext|>".into(), json!(EOS));
+    for word in words {
+        let id = vocab.len();
+        vocab.insert(word.into(), json!(id));
+    }
+    let tokenizer = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [{
+            "id": EOS, "content": "<|endoftext|>", "single_word": false, "lstrip": false,
+            "rstrip": false, "normalized": false, "special": true
+        }],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "<|endoftext|>"}
+    });
+    tokenizers::Tokenizer::from_bytes(tokenizer.to_string()).unwrap()
+}
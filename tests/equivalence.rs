@@ -0,0 +1,172 @@
+//! Golden equivalence between the forward passes in [mamba::stateless], [mamba::stateful]
+//! and [mamba::prefill].
+
+mod common;
+
+use common::{Dims, SEED, VOCAB};
+use dfdx::prelude::*;
+use mamba_minimal_dfdx_example::mamba::{self, Mamba};
+use rand::distributions::{Distribution, Uniform};
+use rand::SeedableRng;
+
+const SEQUENCE: usize = 12;
+/// Absolute tolerance, which is scaled by the magnitude of the expected logit.
+const TOLERANCE: f32 = 1e-4;
+
+fn tokens(batch: usize, seed: u64) -> Vec<usize> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    Uniform::new(0, VOCAB)
+        .sample_iter(&mut rng)
+        .take(batch * SEQUENCE)
+        .collect()
+}
+
+fn empty_states(
+    mamba: &Mamba<f32, Cpu>,
+    batch: usize,
+) -> mamba::stateful::MambaStatesDyn<f32, Cpu, NoneTape> {
+    let dev = mamba.embedding.weight.device().clone();
+    mamba
+        .state_configs(batch)
+        .into_iter()
+        .map(|config| dev.try_build_module::<f32>(config).unwrap())
+        .collect()
+}
+
+/// The logits of all timesteps from a single [mamba::stateless] call, as `[batch][timestep][vocab]`.
+fn stateless_logits(mamba: &Mamba<f32, Cpu>, tokens: &[usize], batch: usize) -> Vec<f32> {
+    let dev = mamba.embedding.weight.device().clone();
+    let x: mamba::stateless::VocabInput<Cpu, NoneTape> =
+        dev.tensor_from_vec(tokens.to_vec(), (batch, SEQUENCE));
+    let logits = mamba.try_forward(x).unwrap();
+    assert_eq!(*logits.shape(), (batch, SEQUENCE, VOCAB));
+    logits.as_vec()
+}
+
+/// The logits from one [mamba::stateful] call per timestep, as `[batch][timestep][vocab]`.
+fn stateful_logits(mamba: &Mamba<f32, Cpu>, tokens: &[usize], batch: usize) -> Vec<f32> {
+    let dev = mamba.embedding.weight.device().clone();
+    let mut states = empty_states(mamba, batch);
+    let mut steps = vec![];
+    for t in 0..SEQUENCE {
+        let step: Vec<usize> = (0..batch).map(|b| tokens[b * SEQUENCE + t]).collect();
+        let x: mamba::stateful::VocabInput<Cpu, NoneTape> = dev.tensor_from_vec(step, (batch,));
+        let (logits, new_states) = mamba.try_forward((x, states)).unwrap();
+        assert_eq!(*logits.shape(), (batch, VOCAB));
+        steps.push(logits.as_vec());
+        states = new_states;
+    }
+    // from [timestep][batch][vocab] into [batch][timestep][vocab]
+    let mut logits = Vec::with_capacity(batch * SEQUENCE * VOCAB);
+    for b in 0..batch {
+        for step in &steps {
+            logits.extend_from_slice(&step[b * VOCAB..(b + 1) * VOCAB]);
+        }
+    }
+    logits
+}
+
+/// The logits from [mamba::prefill] calls over consecutive `chunks` of the sequence,
+/// carrying the states across them, as `[batch][timestep][vocab]`.
+fn prefill_logits(
+    mamba: &Mamba<f32, Cpu>,
+    tokens: &[usize],
+    batch: usize,
+    chunks: &[usize],
+) -> Vec<f32> {
+    assert_eq!(chunks.iter().sum::<usize>(), SEQUENCE);
+    let dev = mamba.embedding.weight.device().clone();
+    let mut states = empty_states(mamba, batch);
+    let mut outputs = vec![];
+    let mut start = 0;
+    for &len in chunks {
+        let chunk: Vec<usize> = (0..batch)
+            .flat_map(|b| tokens[b * SEQUENCE + start..b * SEQUENCE + start + len].to_vec())
+            .collect();
+        let x: mamba::stateless::VocabInput<Cpu, NoneTape> =
+            dev.tensor_from_vec(chunk, (batch, len));
+        let (logits, new_states) = mamba.try_forward((x, states)).unwrap();
+        assert_eq!(*logits.shape(), (batch, len, VOCAB));
+        outputs.push((len, logits.as_vec()));
+        states = new_states;
+        start += len;
+    }
+    let mut logits = Vec::with_capacity(batch * SEQUENCE * VOCAB);
+    for b in 0..batch {
+        for (len, output) in &outputs {
+            logits.extend_from_slice(&output[b * len * VOCAB..(b + 1) * len * VOCAB]);
+        }
+    }
+    logits
+}
+
+/// Asserts that both `[batch][timestep][vocab]` logits agree on every timestep.
+fn assert_close(name: &str, expected: &[f32], actual: &[f32]) {
+    assert_eq!(expected.len(), actual.len(), "{name}: different lengths");
+    for (i, (e, a)) in expected
+        .chunks_exact(VOCAB)
+        .zip(actual.chunks_exact(VOCAB))
+        .enumerate()
+    {
+        let (b, t) = (i / SEQUENCE, i % SEQUENCE);
+        for (token, (&e, &a)) in e.iter().zip(a).enumerate() {
+            assert!(e.is_finite() && a.is_finite(), "{name}: non-finite logit");
+            let tolerance = TOLERANCE * e.abs().max(1.);
+            assert!(
+                (e - a).abs() <= tolerance,
+                "{name}: batch {b}, timestep {t}, token {token}: expected {e}, got {a}"
+            );
+        }
+    }
+}
+
+fn check(dims: Dims, batch: usize) {
+    let mamba = common::build(VOCAB, dims);
+    let tokens = tokens(batch, SEED);
+    let stateless = stateless_logits(&mamba, &tokens, batch);
+
+    let stateful = stateful_logits(&mamba, &tokens, batch);
+    assert_close("stateful", &stateless, &stateful);
+
+    let prefill = prefill_logits(&mamba, &tokens, batch, &[SEQUENCE]);
+    assert_close("prefill", &stateless, &prefill);
+
+    // the chunks are smaller and larger than the conv window
+    let chunked = prefill_logits(&mamba, &tokens, batch, &[1, 2, 5, 4]);
+    assert_close("chunked prefill", &stateless, &chunked);
+}
+
+#[test]
+fn default_dims() {
+    check((None, None, None, None), 1);
+}
+
+#[test]
+fn default_dims_batched() {
+    check((None, None, None, None), 3);
+}
+
+#[test]
+fn small_d_state() {
+    check((Some(4), None, None, None), 1);
+}
+
+#[test]
+fn long_d_conv() {
+    check((None, None, Some(6), None), 1);
+}
+
+#[test]
+fn short_d_conv() {
+    check((None, None, Some(2), None), 1);
+}
+
+#[test]
+fn large_dt_rank() {
+    check((None, Some(8), None, None), 1);
+}
+
+#[test]
+fn all_non_default() {
+    check((Some(8), Some(3), Some(3), Some(48)), 2);
+}
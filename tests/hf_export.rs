@@ -0,0 +1,82 @@
+//! Round trips through [mamba::save::to_hf_safetensors] and [source::load_mamba].
+
+mod common;
+
+use common::{D_MODEL, N_LAYER, VOCAB};
+use dfdx::prelude::*;
+use mamba_minimal_dfdx_example::mamba::{self, Mamba};
+use mamba_minimal_dfdx_example::{source, train};
+use safetensors::SafeTensors;
+use std::collections::HashSet;
+
+const SEQUENCE: usize = 6;
+const CONFIG_JSON: &str =
+    r#"{"d_model": 16, "n_layer": 2, "vocab_size": 32, "pad_vocab_size_multiple": 8}"#;
+
+/// A random model whose `lm_head` is tied to the embedding, without a bias.
+fn build() -> Mamba<f32, Cpu> {
+    let mut mamba = common::build(VOCAB, Default::default());
+    train::tie_lm_head(&mut mamba);
+    mamba.lm_head.bias.fill_with_zeros();
+    mamba
+}
+
+fn logits(mamba: &Mamba<f32, Cpu>) -> Vec<f32> {
+    let dev = mamba.embedding.weight.device().clone();
+    let tokens = (0..SEQUENCE).map(|t| t * 5 % VOCAB).collect();
+    let x: mamba::stateless::VocabInput<Cpu, NoneTape> = dev.tensor_from_vec(tokens, (1, SEQUENCE));
+    mamba.try_forward(x).unwrap().as_vec()
+}
+
+fn names(bytes: &[u8]) -> HashSet<String> {
+    SafeTensors::deserialize(bytes)
+        .unwrap()
+        .names()
+        .into_iter()
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn tied_names_and_shapes() {
+    let mamba = build();
+    let bytes = mamba::save::to_hf_safetensors(&mamba).unwrap();
+    let expected: HashSet<String> = mamba::load::hf_renames(N_LAYER).into_keys().collect();
+    assert_eq!(names(&bytes), expected);
+
+    let tensors = SafeTensors::deserialize(&bytes).unwrap();
+    let conv1d = tensors
+        .tensor("backbone.layers.0.mixer.conv1d.weight")
+        .unwrap();
+    assert_eq!(conv1d.shape(), &[D_MODEL * 2, 1, 4]);
+    let embedding = tensors.tensor("backbone.embedding.weight").unwrap();
+    assert_eq!(embedding.shape(), &[VOCAB, D_MODEL]);
+}
+
+#[test]
+fn tied_round_trip() {
+    let mamba = build();
+    let bytes = mamba::save::to_hf_safetensors(&mamba).unwrap();
+    let loaded = source::load_mamba::<f32>(CONFIG_JSON, &bytes).unwrap();
+    assert_eq!(logits(&mamba), logits(&loaded));
+}
+
+#[test]
+fn untied_round_trip() {
+    let mut mamba = build();
+    let dev = mamba.embedding.weight.device().clone();
+    mamba.lm_head.weight = dev.sample_normal_like(mamba.lm_head.weight.shape());
+    let bytes = mamba::save::to_hf_safetensors(&mamba).unwrap();
+    assert!(names(&bytes).contains(mamba::load::HF_LM_HEAD));
+
+    let loaded = source::load_mamba::<f32>(CONFIG_JSON, &bytes).unwrap();
+    assert_eq!(logits(&mamba), logits(&loaded));
+}
+
+#[test]
+fn lm_head_bias_is_rejected() {
+    let mut mamba = build();
+    let dev = mamba.embedding.weight.device().clone();
+    mamba.lm_head.bias = dev.ones_like(mamba.lm_head.bias.shape());
+    assert!(mamba::save::to_hf_safetensors(&mamba).is_err());
+}
@@ -0,0 +1,119 @@
+//! Requests against [server::Server] bound to an ephemeral port.
+#![cfg(feature = "server")]
+
+mod common;
+
+use mamba_minimal_dfdx_example::native::server::Server;
+use mamba_minimal_dfdx_example::MambaWrapper;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+const MODEL_ID: &str = "test-mamba";
+/// Besides the eos token, these are the whole vocab, including multi-byte characters.
+const WORDS: &str = "a b c d e f g h i j k l m n o p q r s t u v w x y z é ü ß 日 本";
+
+fn tokenizer() -> tokenizers::Tokenizer {
+    common::word_level_tokenizer(WORDS.split(' '))
+}
+
+/// Starts the server in the background, returning its port.
+fn start() -> u16 {
+    let tokenizer = tokenizer();
+    let vocab = tokenizer.get_vocab_size(true);
+    let mamba = common::build(vocab, Default::default());
+    let mut server = Server {
+        models: MambaWrapper::new(tokenizer, mamba),
+        model_id: MODEL_ID.into(),
+    };
+    let http = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = http.server_addr().to_ip().unwrap().port();
+    std::thread::spawn(move || server.serve_on(&http));
+    port
+}
+
+/// Sends a request and reads the response until the server closes the connection.
+fn request(port: u16, method: &str, path: &str, body: Option<&Value>) -> (u16, String) {
+    let body = body.map(Value::to_string).unwrap_or_default();
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+fn completion(stream: bool) -> Value {
+    json!({
+        "model": MODEL_ID,
+        "prompt": "a é 日",
+        "max_tokens": 12,
+        "temperature": 1.0,
+        "seed": 7,
+        "stream": stream,
+    })
+}
+
+#[test]
+fn models() {
+    let port = start();
+    let (status, body) = request(port, "GET", "/v1/models", None);
+    assert_eq!(status, 200);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["data"][0]["id"], MODEL_ID);
+}
+
+#[test]
+fn json_and_sse_completions_agree() {
+    let port = start();
+
+    let (status, body) = request(port, "POST", "/v1/completions", Some(&completion(false)));
+    assert_eq!(status, 200, "{body}");
+    let body: Value = serde_json::from_str(&body).unwrap();
+    let choice = &body["choices"][0];
+    let text = choice["text"].as_str().unwrap();
+    let finish_reason = choice["finish_reason"].as_str().unwrap();
+    assert!(["stop", "length"].contains(&finish_reason));
+    let completion_tokens = body["usage"]["completion_tokens"].as_u64().unwrap();
+    assert!(completion_tokens <= 12);
+    assert!(!text.starts_with("a é 日"), "the prompt is not echoed");
+
+    let (status, body) = request(port, "POST", "/v1/completions", Some(&completion(true)));
+    assert_eq!(status, 200, "{body}");
+    let events: Vec<&str> = body
+        .split("\n\n")
+        .filter(|event| !event.is_empty())
+        .map(|event| event.strip_prefix("data: ").unwrap())
+        .collect();
+    let (done, chunks) = events.split_last().unwrap();
+    assert_eq!(*done, "[DONE]");
+    let chunks: Vec<Value> = chunks
+        .iter()
+        .map(|chunk| serde_json::from_str(chunk).unwrap())
+        .collect();
+    let streamed: String = chunks
+        .iter()
+        .map(|chunk| chunk["choices"][0]["text"].as_str().unwrap())
+        .collect();
+    assert_eq!(streamed, text);
+    let (last, rest) = chunks.split_last().unwrap();
+    assert_eq!(last["choices"][0]["finish_reason"], finish_reason);
+    assert!(rest
+        .iter()
+        .all(|chunk| chunk["choices"][0]["finish_reason"].is_null()));
+}
+
+#[test]
+fn unknown_model_is_rejected() {
+    let port = start();
+    let mut body = completion(false);
+    body["model"] = json!("other");
+    let (status, _body) = request(port, "POST", "/v1/completions", Some(&body));
+    assert_eq!(status, 404);
+}